@@ -0,0 +1,32 @@
+// Measures compiling and running the workloads of flux_rs::bench with criterion,
+// which keeps the results of the last run in target/criterion and reports the
+// change. Run all of them with 'cargo bench', or the ones whose name contains a
+// filter with 'cargo bench -- fib'
+use criterion::{criterion_group, criterion_main, Criterion};
+use flux_rs::bench::{compile, WORKLOADS};
+use flux_rs::vm::Vm;
+
+fn run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run");
+    for (name, source) in WORKLOADS.iter() {
+        let compiled = compile(source).expect("Expected the workload to compile");
+        // Setting up a fresh vm is measured too, like running a script once
+        group.bench_function(*name, |b| {
+            b.iter(|| Vm::new().run(&compiled).expect("Expected the workload to run"))
+        });
+    }
+    group.finish();
+}
+
+fn compile_workloads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compile");
+    for (name, source) in WORKLOADS.iter() {
+        group.bench_function(*name, |b| {
+            b.iter(|| compile(source).expect("Expected the workload to compile"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, run, compile_workloads);
+criterion_main!(benches);
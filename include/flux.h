@@ -0,0 +1,84 @@
+/* The C API of flux_rs, see src/ffi.rs and src/ffi/plugin.rs for the details.
+ * Embedders link against the library built with the cdylib feature, plugins only
+ * use the FluxApi they are opened with. */
+#ifndef FLUX_H
+#define FLUX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct FluxVm FluxVm;
+typedef struct FluxValue FluxValue;
+
+enum {
+    FLUX_NIL = 0,
+    FLUX_BOOL = 1,
+    FLUX_INT = 2,
+    FLUX_NUMBER = 3,
+    FLUX_STR = 4,
+    FLUX_TABLE = 5,
+    FLUX_FUNCTION = 6,
+    FLUX_OTHER = 7,
+};
+
+/* Gets the arguments in order, returns a new value or NULL to fail the call */
+typedef FluxValue *(*FluxNative)(void *user_data, const FluxValue *const *args, size_t len);
+
+FluxVm *flux_vm_new(void);
+void flux_vm_free(FluxVm *vm);
+FluxValue *flux_eval(FluxVm *vm, const char *source);
+const char *flux_last_error(const FluxVm *vm);
+FluxValue *flux_get_global(const FluxVm *vm, const char *name);
+int flux_register_native(FluxVm *vm, const char *name, int args_len, FluxNative callback,
+                         void *user_data);
+
+void flux_value_free(FluxValue *value);
+int flux_value_type(const FluxValue *value);
+bool flux_value_as_bool(const FluxValue *value);
+int64_t flux_value_as_int(const FluxValue *value);
+double flux_value_as_number(const FluxValue *value);
+char *flux_value_to_string(const FluxValue *value);
+void flux_string_free(char *string);
+FluxValue *flux_value_nil(void);
+FluxValue *flux_value_bool(bool b);
+FluxValue *flux_value_int(int64_t i);
+FluxValue *flux_value_number(double n);
+FluxValue *flux_value_str(const char *string);
+
+/* Plugins, loaded with require_native("path/libname") */
+#define FLUX_ABI_VERSION 1
+
+typedef struct {
+    uint32_t abi_version;
+    void (*value_free)(FluxValue *);
+    int (*value_type)(const FluxValue *);
+    bool (*value_as_bool)(const FluxValue *);
+    int64_t (*value_as_int)(const FluxValue *);
+    double (*value_as_number)(const FluxValue *);
+    char *(*value_to_string)(const FluxValue *);
+    void (*string_free)(char *);
+    FluxValue *(*value_nil)(void);
+    FluxValue *(*value_bool)(bool);
+    FluxValue *(*value_int)(int64_t);
+    FluxValue *(*value_number)(double);
+    FluxValue *(*value_str)(const char *);
+} FluxApi;
+
+typedef struct {
+    const char *name;
+    int args_len;
+    FluxNative function;
+    void *user_data;
+} FluxNativeEntry;
+
+typedef struct {
+    uint32_t abi_version;
+    size_t len;
+    const FluxNativeEntry *natives;
+} FluxModule;
+
+/* A plugin named libname exports: const FluxModule *flux_open_name(const FluxApi *api); */
+typedef const FluxModule *(*FluxOpen)(const FluxApi *api);
+
+#endif
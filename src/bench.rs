@@ -0,0 +1,259 @@
+use crate::compiler::{CompiledSource, Compiler};
+use crate::error::FluxResult;
+use crate::parser::Parser;
+use crate::sourcefile::{MetaData, SourceFile};
+use crate::vm::{RuntimeError, Vm};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Programs for the parts of the vm optimizations usually touch, run by 'cargo bench'
+pub const WORKLOADS: [(&str, &str); 4] = [
+    ("fib", include_str!("../benches/workloads/fib.flux")),
+    ("tables", include_str!("../benches/workloads/tables.flux")),
+    ("strings", include_str!("../benches/workloads/strings.flux")),
+    (
+        "closures",
+        include_str!("../benches/workloads/closures.flux"),
+    ),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchConfig {
+    pub iterations: usize,
+    // Iterations run before the measured ones and left out of the results
+    pub warmup: usize,
+    // A global function called without arguments on every iteration, the script
+    // runs once before. Without it the whole script runs on every iteration
+    pub function: Option<String>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig {
+            iterations: 10,
+            warmup: 2,
+            function: None,
+        }
+    }
+}
+
+// Wall times of the measured iterations
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bench {
+    times: Vec<Duration>,
+    instructions: u64,
+}
+
+impl Bench {
+    pub fn iterations(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn mean(&self) -> Duration {
+        self.total() / self.times.len().max(1) as u32
+    }
+
+    pub fn median(&self) -> Duration {
+        let mut times = self.times.clone();
+        times.sort();
+        match times.len() {
+            0 => Duration::default(),
+            n if n % 2 == 0 => (times[n / 2 - 1] + times[n / 2]) / 2,
+            n => times[n / 2],
+        }
+    }
+
+    pub fn stddev(&self) -> Duration {
+        if self.times.len() < 2 {
+            return Duration::default();
+        }
+        let mean = self.mean().as_secs_f64();
+        let variance = self
+            .times
+            .iter()
+            .map(|time| (time.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / (self.times.len() - 1) as f64;
+        Duration::from_secs_f64(variance.sqrt())
+    }
+
+    // Instructions executed by all the measured iterations
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn instructions_per_second(&self) -> f64 {
+        let total = self.total().as_secs_f64();
+        if total > 0.0 {
+            self.instructions as f64 / total
+        } else {
+            0.0
+        }
+    }
+
+    fn total(&self) -> Duration {
+        self.times.iter().sum()
+    }
+}
+
+impl fmt::Display for Bench {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "iterations  {}", self.iterations())?;
+        writeln!(f, "mean        {:.3?}", self.mean())?;
+        writeln!(f, "median      {:.3?}", self.median())?;
+        writeln!(f, "stddev      {:.3?}", self.stddev())?;
+        writeln!(
+            f,
+            "instr/s     {:.0} ({} instructions per iteration)",
+            self.instructions_per_second(),
+            self.instructions / self.times.len().max(1) as u64
+        )
+    }
+}
+
+// 'args' holds the script path followed by the arguments, like for Vm::set_args
+pub fn bench(
+    compiled: &CompiledSource,
+    args: &[String],
+    config: &BenchConfig,
+) -> FluxResult<Bench> {
+    let mut bench = Bench {
+        times: Vec::with_capacity(config.iterations),
+        instructions: 0,
+    };
+    let mut vm = Vm::new();
+    vm.set_args(args);
+    let function = match &config.function {
+        Some(name) => {
+            vm.run(compiled)?;
+            let function = vm
+                .global(name)
+                .ok_or_else(|| RuntimeError::UndefinedVariable {
+                    name: name.clone(),
+                    suggestion: vm.suggest_global(name),
+                })?;
+            Some(function.clone())
+        }
+        None => None,
+    };
+    for i in 0..config.warmup + config.iterations {
+        // Setting up a fresh vm is not measured
+        let source = match &function {
+            Some(_) => None,
+            None => {
+                vm = Vm::new();
+                vm.set_args(args);
+                Some(compiled)
+            }
+        };
+        let instructions = vm.stats().instructions;
+        let start = Instant::now();
+        match (source, &function) {
+            (Some(source), _) => vm.run(source)?,
+            (None, Some(function)) => vm.call_function(function.clone(), &[])?,
+            (None, None) => unreachable!(),
+        };
+        let time = start.elapsed();
+        if i >= config.warmup {
+            bench.times.push(time);
+            bench.instructions += vm.stats().instructions - instructions;
+        }
+    }
+    Ok(bench)
+}
+
+// Measures parsing and compiling the source, no instructions are executed
+pub fn bench_compile(source: &str, config: &BenchConfig) -> FluxResult<Bench> {
+    let mut bench = Bench {
+        times: Vec::with_capacity(config.iterations),
+        instructions: 0,
+    };
+    for i in 0..config.warmup + config.iterations {
+        let start = Instant::now();
+        compile(source)?;
+        let time = start.elapsed();
+        if i >= config.warmup {
+            bench.times.push(time);
+        }
+    }
+    Ok(bench)
+}
+
+pub fn compile(source: &str) -> FluxResult<CompiledSource> {
+    let ast = Parser::new(source).parse()?;
+    Ok(Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> CompiledSource {
+        let ast = Parser::new(source).parse_all().unwrap();
+        Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn bench_a_function() {
+        let compiled = compile(
+            "
+            var sum = fn()
+                let total = 0;
+                let i = 0;
+                while i < 10 then
+                    total = total + i;
+                    i = i + 1;
+                end
+                return total;
+            end;
+            ",
+        );
+        let config = BenchConfig {
+            iterations: 5,
+            warmup: 1,
+            function: Some("sum".to_owned()),
+        };
+        let result = bench(&compiled, &[], &config).unwrap();
+        assert_eq!(result.iterations(), 5);
+        assert!(result.instructions() > 5 * 10);
+        assert_eq!(result.instructions() % 5, 0);
+        assert!(result.median() <= result.mean() * 5);
+
+        let config = BenchConfig {
+            function: Some("missing".to_owned()),
+            ..BenchConfig::default()
+        };
+        assert!(bench(&compiled, &[], &config).is_err());
+    }
+
+    #[test]
+    fn bench_the_script() {
+        let compiled = compile("var x = 1 + 2;");
+        let result = bench(&compiled, &[], &BenchConfig::default()).unwrap();
+        assert_eq!(result.iterations(), 10);
+        assert!(result.instructions() > 0);
+        assert!(result.to_string().contains("instr/s"));
+    }
+
+    #[test]
+    fn workloads_run() {
+        let config = BenchConfig {
+            iterations: 1,
+            warmup: 0,
+            function: None,
+        };
+        for (name, source) in WORKLOADS.iter() {
+            let compiled = super::compile(source).unwrap();
+            let result = bench(&compiled, &[], &config);
+            assert!(result.is_ok(), "{}: {:?}", name, result);
+            assert_eq!(bench_compile(source, &config).unwrap().iterations(), 1);
+        }
+    }
+}
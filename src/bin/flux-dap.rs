@@ -0,0 +1,6 @@
+fn main() {
+    if let Err(err) = flux_rs::dap::run() {
+        eprintln!("flux-dap: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,6 @@
+fn main() {
+    if let Err(err) = flux_rs::lsp::run() {
+        eprintln!("flux-lsp: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// A standalone executable made by 'flux_rs build' is a copy of the flux_rs binary
+// with the bytecode of a script appended, then the length of the bytecode (u64
+// little endian) and this marker. Binaries ignore bytes past their end
+pub const MARKER: &[u8; 8] = b"FLXBUNDL";
+const TRAILER_LEN: usize = 16;
+
+// The executable running 'payload', with any payload it already had replaced
+pub fn bundle(exe: &[u8], payload: &[u8]) -> Vec<u8> {
+    let exe = match payload_range(exe) {
+        Some((start, _)) => &exe[..start],
+        None => exe,
+    };
+    let mut bytes = Vec::with_capacity(exe.len() + payload.len() + TRAILER_LEN);
+    bytes.extend_from_slice(exe);
+    bytes.extend_from_slice(payload);
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(MARKER);
+    bytes
+}
+
+pub fn payload(exe: &[u8]) -> Option<&[u8]> {
+    payload_range(exe).map(|(start, end)| &exe[start..end])
+}
+
+// Reads only the trailer and the payload, executables are checked on every start
+pub fn read_payload(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < TRAILER_LEN as u64 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let mut trailer = [0; TRAILER_LEN];
+    file.read_exact(&mut trailer)?;
+    let payload_len = match trailer_len(&trailer) {
+        Some(payload_len) if payload_len <= len - TRAILER_LEN as u64 => payload_len,
+        _ => return Ok(None),
+    };
+    file.seek(SeekFrom::End(-((payload_len + TRAILER_LEN as u64) as i64)))?;
+    let mut payload = vec![0; payload_len as usize];
+    file.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn payload_range(exe: &[u8]) -> Option<(usize, usize)> {
+    let end = exe.len().checked_sub(TRAILER_LEN)?;
+    let payload_len = trailer_len(&exe[end..])? as usize;
+    Some((end.checked_sub(payload_len)?, end))
+}
+
+fn trailer_len(trailer: &[u8]) -> Option<u64> {
+    if &trailer[8..] != MARKER {
+        return None;
+    }
+    let mut len = [0; 8];
+    len.copy_from_slice(&trailer[..8]);
+    Some(u64::from_le_bytes(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trips() {
+        let exe = b"\x7fELF binary".to_vec();
+        assert_eq!(payload(&exe), None);
+        let bundled = bundle(&exe, b"bytecode");
+        assert!(bundled.starts_with(&exe));
+        assert_eq!(payload(&bundled), Some(&b"bytecode"[..]));
+        // Building from a bundled executable replaces its script
+        let rebundled = bundle(&bundled, b"other");
+        assert_eq!(rebundled.len(), exe.len() + 5 + TRAILER_LEN);
+        assert_eq!(payload(&rebundled), Some(&b"other"[..]));
+
+        let path = std::env::temp_dir().join("flux_bundle_test");
+        std::fs::write(&path, &bundled).unwrap();
+        assert_eq!(read_payload(&path).unwrap(), Some(b"bytecode".to_vec()));
+        std::fs::write(&path, &exe).unwrap();
+        assert_eq!(read_payload(&path).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,34 @@
+mod bytecode;
 mod chunk;
+mod debug_info;
+mod disassembler;
 mod error;
+pub(crate) mod fold;
 mod instruction;
+#[cfg(feature = "std")]
 mod io;
+mod peephole;
+pub mod symbols;
+mod verifier;
 
+#[cfg(feature = "std")]
 use self::io::absolute_path;
-use crate::parser::{Ast, BinaryOp, BlockExpr, Expr, Literal, Parser, Statement, UnaryOp};
+use crate::parser::{
+    Ast, BinaryOp, BlockExpr, Expr, Literal, Statement, Type, UnaryOp, ITERATOR_NAME,
+    RANGE_START_NAME,
+};
 use crate::sourcefile::{MetaData, SourceFile};
-use crate::vm::{FuncProtoRef, Integer, Value};
+use crate::vm::{FuncProtoRef, Integer, Table, Value};
+use alloc::rc::Rc;
+use alloc::{borrow::ToOwned, boxed::Box, string::String, string::ToString, vec::Vec};
+pub use bytecode::{is_bytecode, BytecodeError, BYTECODE_VERSION, INSTRUCTION_SET_HASH};
 pub use chunk::{Chunk, CompiledSource, FuncProto, JumpCondition};
-pub use error::CompileError;
+use core::convert::TryInto;
+pub use debug_info::{DebugInfo, LocalRange, NodeKind, NodeRange};
+pub use disassembler::disassemble;
+pub use error::{CompileError, IoErrorKind};
 pub use instruction::{BinaryInstr, Instruction, UnaryInstr};
-use std::convert::TryInto;
-use std::rc::Rc;
+pub use verifier::{max_stack, verify, VerifyError};
 
 pub type CompileResult<T> = Result<T, CompileError>;
 
@@ -22,6 +39,14 @@ pub struct Compiler<'a> {
     depth: u8,
     closure_scopes: Vec<ClosureScope>,
     metadata: MetaData,
+    // Line of the last visited node that carries one
+    line: usize,
+    // Echo the values of top level expression statements
+    repl: bool,
+    // Statements and expressions being compiled inside each other
+    nesting: usize,
+    // The variable the function being compiled next is declared as
+    fn_name: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -41,6 +66,12 @@ pub struct Local {
     depth: u8,
     // n means the function in compiler.closure_scopes[n]
     closure: Option<u8>,
+    // First instruction the local is alive in
+    start: usize,
+    // Set when the local is an enum, its fields are folded into constants
+    variants: Option<Vec<String>>,
+    // Values of unfinished expressions below it, like 'a' in 'a + do let b = 1; b end'
+    temps: usize,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -48,14 +79,46 @@ struct ClosureScope {
     depth: u8,
     local_start: usize,
     instructions: Vec<Instruction>,
+    debug_info: DebugInfo,
+    // The loops around the statement being compiled, innermost last
+    loops: Vec<Loop>,
+    // Values left on the stack by the expressions being compiled, see Compiler::above
+    temps: usize,
+}
+
+// A loop that 'break' and 'continue' leave
+#[derive(Clone, Debug, PartialEq)]
+struct Loop {
+    // Scope depth outside the body, the locals above it are popped on leaving
+    depth: u8,
+    // ClosureScope::temps around the body
+    temps: usize,
+    // The condition, None when a step runs before it and 'continue' jumps forward
+    start: Option<usize>,
+    // Placeholders patched to the end of the loop and to its step
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
 }
 
 /**
  * Compiling
  */
 impl<'a> Compiler<'a> {
-    pub fn compile(SourceFile { ast, metadata }: SourceFile) -> CompileResult<CompiledSource> {
+    pub fn compile(source: SourceFile) -> CompileResult<CompiledSource> {
+        Self::compile_source(source, false)
+    }
+
+    // Compiles a repl input, whose top level expression statements print their values
+    pub fn compile_repl(source: SourceFile) -> CompileResult<CompiledSource> {
+        Self::compile_source(source, true)
+    }
+
+    fn compile_source(
+        SourceFile { ast, metadata }: SourceFile,
+        repl: bool,
+    ) -> CompileResult<CompiledSource> {
         let mut compiler = Self::new(metadata);
+        compiler.repl = repl;
         compiler.compile_module(ast)?;
 
         let constants = match compiler.constant_table {
@@ -63,11 +126,12 @@ impl<'a> Compiler<'a> {
             _ => unreachable!(),
         };
         Ok(CompiledSource {
-            chunk: compiler.chunk,
+            chunk: Rc::new(compiler.chunk),
             constant_table: Rc::new(constants),
         })
     }
 
+    #[cfg(feature = "std")]
     fn compile_with_table(
         SourceFile { ast, metadata }: SourceFile,
         table: &'a mut ConstantTableStruct,
@@ -90,9 +154,14 @@ impl<'a> Compiler<'a> {
             depth: 0,
             closure_scopes: Vec::new(),
             metadata,
+            line: 1,
+            repl: false,
+            nesting: 0,
+            fn_name: None,
         }
     }
 
+    #[cfg(feature = "std")]
     fn with_table(metadata: MetaData, table: &'a mut ConstantTableStruct) -> Self {
         let mut compiler = Compiler::new(metadata);
         compiler.constant_table = ConstantTable::Borrowed(table);
@@ -106,15 +175,47 @@ impl<'a> Compiler<'a> {
         self.func_body(body)?;
 
         let closure_scope = self.exit_function()?;
+        self.line = 1;
         self.define_func(closure_scope, 0, false)?;
         self.add_instr(Instruction::Call { args_len: 0 })
     }
 
     fn compile_stmt(&mut self, stmt: Statement) -> CompileResult<()> {
+        let kind = NodeKind::of(&stmt);
+        let start = self.instructions().len();
+        self.descend()?;
+        let compiled = self.compile_stmt_impl(stmt);
+        self.nesting -= 1;
+        compiled?;
+        self.debug_info_mut().push_node(kind, start);
+        Ok(())
+    }
+
+    // Trees deeper than the limit, like ones built by hosts instead of the parser,
+    // are refused before they overflow the stack
+    fn descend(&mut self) -> CompileResult<()> {
+        if self.nesting >= self.metadata.max_depth {
+            return Err(CompileError::TooDeep);
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    fn compile_stmt_impl(&mut self, stmt: Statement) -> CompileResult<()> {
         match stmt {
             Statement::Expr(expr) => self.expr_stmt(expr),
-            Statement::Var { name, value } => self.var_stmt(name, value),
-            Statement::Let { name, value } => self.let_stmt(name, value),
+            Statement::Var {
+                name, value, line, ..
+            } => {
+                self.line = line;
+                self.var_stmt(name, value)
+            }
+            Statement::Let {
+                name, value, line, ..
+            } => {
+                self.line = line;
+                self.let_stmt(name, value)
+            }
             Statement::Set { variable, value } => self.set_stmt(variable, value),
             Statement::Block(statements) => self.block_stmt(statements),
             Statement::If {
@@ -125,7 +226,21 @@ impl<'a> Compiler<'a> {
             Statement::While {
                 condition,
                 then_block,
-            } => self.while_stmt(condition, *then_block),
+                step,
+            } => self.while_stmt(condition, *then_block, step.map(|step| *step)),
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                line,
+                column,
+            } => {
+                self.line = line;
+                self.for_stmt(variable, start, end, *body, column)
+            }
+            Statement::Break { line } => self.break_stmt(line),
+            Statement::Continue { line } => self.continue_stmt(line),
             Statement::Print(expr) => {
                 self.compile_expr(expr)?;
                 self.add_instr(Instruction::Print)
@@ -134,17 +249,37 @@ impl<'a> Compiler<'a> {
                 self.compile_expr(expr)?;
                 self.add_instr(Instruction::Return { return_value: true })
             }
-            Statement::Import { path, name } => self.import_stmt(path, name),
+            Statement::Import {
+                path, name, line, ..
+            } => {
+                self.line = line;
+                self.import_stmt(path, name)
+            }
+            Statement::Enum {
+                name,
+                variants,
+                line,
+                ..
+            } => {
+                self.line = line;
+                self.enum_stmt(name, variants)
+            }
         }
     }
 
     fn expr_stmt(&mut self, expr: Expr) -> CompileResult<()> {
         self.compile_expr(expr)?;
-        self.add_instr(Instruction::Pop)?;
-        Ok(())
+        if self.repl && self.is_top_level() {
+            self.add_instr(Instruction::Echo)
+        } else {
+            self.add_instr(Instruction::Pop)
+        }
     }
 
     fn var_stmt(&mut self, name: String, expr: Expr) -> CompileResult<()> {
+        if let Expr::Function { .. } = expr {
+            self.fn_name = Some(name.clone());
+        }
         let index = self.add_constant(name.into(), false)?;
         self.compile_expr(expr)?;
         self.add_instr(Instruction::SetGlobal { index })
@@ -153,13 +288,13 @@ impl<'a> Compiler<'a> {
     fn let_stmt(&mut self, name: String, value: Expr) -> CompileResult<()> {
         match value {
             Expr::Function { .. } => {
-                self.push_local(name);
+                self.fn_name = Some(name.clone());
+                self.push_local(name)?;
                 self.compile_expr(value)
             }
             _ => {
                 self.compile_expr(value)?;
-                self.push_local(name);
-                Ok(())
+                self.push_local(name)
             }
         }
     }
@@ -167,7 +302,10 @@ impl<'a> Compiler<'a> {
     fn set_stmt(&mut self, variable: Expr, value: Expr) -> CompileResult<()> {
         // TODO: pattern matching for tuple expressions
         match variable {
-            Expr::Identifier(name) => {
+            Expr::Identifier { name, .. } => {
+                if self.resolve_enum(&name).is_some() {
+                    return Err(CompileError::AssignToEnum { name });
+                }
                 let index = self.add_constant(name.clone().into(), false)?;
                 self.compile_expr(value)?;
                 if let Some((index, frame)) = self.resolve_local(name.as_str()) {
@@ -183,12 +321,10 @@ impl<'a> Compiler<'a> {
                 }
             }
             Expr::Access { table, field } => {
-                self.compile_expr(value)?;
-                self.compile_expr(*field)?;
-                self.compile_expr(*table)?;
+                self.operands(vec![value, *field, *table])?;
                 self.add_instr(Instruction::SetField)
             }
-            _ => Err(CompileError::InvalidAssignmentTarget(variable)),
+            _ => Err(CompileError::InvalidAssignmentTarget(Box::new(variable))),
         }?;
         Ok(())
     }
@@ -210,7 +346,9 @@ impl<'a> Compiler<'a> {
         self.compile_expr(condition)?;
 
         let patch_index = self.add_placeholder()?;
+        // Blocks leave a value, a statement must not
         self.compile_expr(then_block)?;
+        self.add_instr(Instruction::Pop)?;
 
         let offset = self.get_offset(patch_index)?;
         if let Some(else_block) = else_block {
@@ -219,6 +357,7 @@ impl<'a> Compiler<'a> {
 
             let patch_index = self.add_placeholder()?;
             self.compile_expr(else_block)?;
+            self.add_instr(Instruction::Pop)?;
             let offset = self.get_offset(patch_index)?;
             self.patch_placeholder(patch_index, offset as i8, JumpCondition::None)?;
         } else {
@@ -227,19 +366,149 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn while_stmt(&mut self, condition: Expr, then_block: Statement) -> CompileResult<()> {
+    fn while_stmt(
+        &mut self,
+        condition: Expr,
+        then_block: Statement,
+        step: Option<Statement>,
+    ) -> CompileResult<()> {
         let start_index = self.instructions().len();
         self.compile_expr(condition)?;
         let patch_index = self.add_placeholder()?;
+        let (depth, temps) = (self.depth, self.closure_scope().temps);
+        self.loops_mut().push(Loop {
+            depth,
+            temps,
+            start: if step.is_none() {
+                Some(start_index)
+            } else {
+                None
+            },
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
         self.compile_stmt(then_block)?;
+        let lp = self
+            .loops_mut()
+            .pop()
+            .expect("Expected the loop being compiled");
+        for index in lp.continues {
+            let offset = self.get_offset(index)?;
+            self.patch_placeholder(index, offset, JumpCondition::None)?;
+        }
+        if let Some(step) = step {
+            self.compile_stmt(step)?;
+        }
         let offset = self.instructions().len() - patch_index + 1;
         self.patch_placeholder(patch_index, offset as i8, JumpCondition::WhenFalse)?;
         self.add_instr(Instruction::Jump {
             offset: -((self.instructions().len() - start_index) as i8),
-        })
+        })?;
+        for index in lp.breaks {
+            let offset = self.get_offset(index)?;
+            self.patch_placeholder(index, offset, JumpCondition::None)?;
+        }
+        Ok(())
+    }
+
+    // The start and then the end are evaluated once into hidden locals, the variable
+    // counts from the start up to the end, excluding it
+    fn for_stmt(
+        &mut self,
+        variable: String,
+        start: Expr,
+        end: Expr,
+        body: Statement,
+        column: usize,
+    ) -> CompileResult<()> {
+        let line = self.line;
+        let counter = || Box::new(Expr::identifier(variable.as_str(), line, column));
+        let condition = Expr::Binary {
+            left: counter(),
+            op: BinaryOp::Less,
+            right: Box::new(Expr::identifier(ITERATOR_NAME, line, column)),
+        };
+        let step = Statement::Set {
+            variable: *counter(),
+            value: Expr::Binary {
+                left: counter(),
+                op: BinaryOp::Plus,
+                right: Box::new(Expr::Literal(Literal::Int(1))),
+            },
+        };
+        self.enter_scope();
+        self.let_stmt(RANGE_START_NAME.to_owned(), start)?;
+        self.let_stmt(ITERATOR_NAME.to_owned(), end)?;
+        // Declared after the end so that the end can't refer to it
+        let first = Expr::identifier(RANGE_START_NAME, line, column);
+        self.let_stmt(variable.clone(), first)?;
+        self.while_stmt(condition, body, Some(step))?;
+        self.exit_scope(false)
+    }
+
+    fn break_stmt(&mut self, line: usize) -> CompileResult<()> {
+        self.leave_loop("break", line)?;
+        let index = self.add_placeholder()?;
+        self.loops_mut().last_mut().unwrap().breaks.push(index);
+        Ok(())
+    }
+
+    // Jumps back to the condition of a while loop, forward to the step of a for loop
+    fn continue_stmt(&mut self, line: usize) -> CompileResult<()> {
+        match self.leave_loop("continue", line)? {
+            Some(start) => {
+                let offset = self.instructions().len() - start;
+                if offset > i8::MAX as usize {
+                    return Err(CompileError::TooLongToJump);
+                }
+                self.add_instr(Instruction::Jump {
+                    offset: -(offset as i8),
+                })
+            }
+            None => {
+                let index = self.add_placeholder()?;
+                self.loops_mut().last_mut().unwrap().continues.push(index);
+                Ok(())
+            }
+        }
+    }
+
+    // Pops the locals declared inside the innermost loop, returns where it starts
+    fn leave_loop(&mut self, keyword: &str, line: usize) -> CompileResult<Option<usize>> {
+        let temps = self.closure_scope().temps;
+        let (depth, start) = match self.loops_mut().last() {
+            Some(lp) if lp.temps != temps => {
+                return Err(CompileError::LeaveExpression {
+                    keyword: keyword.to_owned(),
+                    line,
+                })
+            }
+            Some(lp) => (lp.depth, lp.start),
+            None => {
+                return Err(CompileError::OutsideLoop {
+                    keyword: keyword.to_owned(),
+                    line,
+                })
+            }
+        };
+        let pop = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > depth)
+            .count();
+        if pop > 0 {
+            self.add_instr(Instruction::ExitBlock {
+                pop: pop as u16,
+                return_value: false,
+            })?;
+        }
+        Ok(start)
     }
 
+    #[cfg(feature = "std")]
     fn import_stmt(&mut self, path: Vec<String>, name: String) -> CompileResult<()> {
+        use crate::parser::Parser;
         use std::path::PathBuf;
         // Get source file
         let abs_path = if Self::is_std(&path) {
@@ -264,13 +533,17 @@ impl<'a> Compiler<'a> {
         };
         let source = io::read_file(abs_path.clone())?;
         // Parse and store
-        let ast = Parser::parse_str(source.as_str())?;
+        let ast = Parser::new(source.as_str())
+            .with_max_depth(self.metadata.max_depth)
+            .parse()?;
         debug!("Module {}: {:#?}", &name, &ast);
         let metadata = MetaData {
             dir: abs_path
                 .parent()
                 .expect("Expected a parent directory")
                 .to_owned(),
+            file: Some(abs_path.clone()),
+            max_depth: self.metadata.max_depth,
         };
         // Compile the module
         let chunk = Compiler::compile_with_table(
@@ -283,19 +556,54 @@ impl<'a> Compiler<'a> {
         })?;
         // Add import to table ad push instruction
         let name_index = self.add_constant(name.clone().into(), false)?;
-        self.chunk.add_import(chunk, name, name_index)
+        self.chunk.add_import(chunk, name, name_index, self.line)
+    }
+
+    // Without std there are no files to import
+    #[cfg(not(feature = "std"))]
+    fn import_stmt(&mut self, _path: Vec<String>, _name: String) -> CompileResult<()> {
+        Err(CompileError::IoError(IoErrorKind::Unsupported))
+    }
+
+    // Each variant is tagged 'Name.Variant', a string no variant of another enum
+    // has that prints readably. The table is a frozen constant all evaluations share
+    fn enum_stmt(&mut self, name: String, variants: Vec<String>) -> CompileResult<()> {
+        let mut table = Table::new();
+        for variant in &variants {
+            let tag = format!("{}.{}", name, variant);
+            table.set(variant.clone().into(), tag.into());
+        }
+        table.freeze();
+        let index = self.push_constant(table.into())?;
+        self.add_instr(Instruction::TableTemplate { index })?;
+        self.push_local(name)?;
+        if let Some(local) = self.locals.last_mut() {
+            local.variants = Some(variants);
+        }
+        Ok(())
     }
 
+    #[cfg(feature = "std")]
     #[inline]
     fn is_std(path: &[String]) -> bool {
         &path[0] == "std"
     }
 
     fn compile_expr(&mut self, expr: Expr) -> CompileResult<()> {
+        self.descend()?;
+        let compiled = self.compile_expr_impl(expr);
+        self.nesting -= 1;
+        compiled
+    }
+
+    fn compile_expr_impl(&mut self, expr: Expr) -> CompileResult<()> {
         #[allow(unreachable_patterns)]
         match expr {
             Expr::Literal(lit) => self.literal(lit),
-            Expr::Identifier(name) => self.ident(name),
+            Expr::Identifier { name, line, .. } => {
+                self.line = line;
+                self.ident(name)
+            }
             Expr::Unary { op, expr } => self.unary(*expr, op),
             Expr::Binary { left, op, right } => self.binary(*left, *right, op),
             Expr::Grouping(expr) => self.compile_expr(*expr),
@@ -307,7 +615,16 @@ impl<'a> Compiler<'a> {
                 args,
             } => self.self_access(*table, method, args),
             Expr::TableInit { keys, values } => self.table_init(keys, values),
-            Expr::Function { args, body, env } => self.function_def(args, body, env),
+            Expr::Function {
+                args,
+                body,
+                env,
+                line,
+                ..
+            } => {
+                self.line = line;
+                self.function_def(args, body, env)
+            }
             Expr::Call { func, args } => self.call(*func, args),
             Expr::Block(BlockExpr { stmts, expr }) => self.block_expr(stmts, *expr),
             Expr::If {
@@ -316,8 +633,35 @@ impl<'a> Compiler<'a> {
                 else_block,
             } => self.if_expr(*condition, *then_block, *else_block),
             Expr::Rec => self.add_instr(Instruction::Rec),
-            _ => Err(CompileError::UnimplementedExpr(expr)),
+            Expr::Yield(expr) => self.yield_expr(*expr),
+            Expr::Cast { expr, ty } => self.cast(*expr, ty),
+            _ => Err(CompileError::UnimplementedExpr(Box::new(expr))),
+        }
+    }
+
+    // The type is a constant the vm reads like the argument of assert_type
+    fn cast(&mut self, expr: Expr, ty: Type) -> CompileResult<()> {
+        self.compile_expr(expr)?;
+        let index = self.add_constant_table(Self::type_value(&ty))?;
+        self.add_instr(Instruction::Cast { index })
+    }
+
+    // The name of the type, a tuple of them for a tuple type
+    fn type_value(ty: &Type) -> Value {
+        match ty {
+            Type::Tuple(types) => Value::Tuple(types.iter().map(Self::type_value).collect()),
+            ty => ty.to_string().into(),
+        }
+    }
+
+    // Makes the function a generator, see FuncProto::generator
+    fn yield_expr(&mut self, expr: Expr) -> CompileResult<()> {
+        if self.closure_scopes.len() < 2 {
+            let line = expr.line().unwrap_or(self.line);
+            return Err(CompileError::YieldOutsideFunction { line });
         }
+        self.compile_expr(expr)?;
+        self.add_instr(Instruction::Yield)
     }
 
     fn literal(&mut self, lit: Literal) -> CompileResult<()> {
@@ -328,10 +672,9 @@ impl<'a> Compiler<'a> {
             } else {
                 Instruction::False
             }),
-            Literal::Number(n) => match n.fract() == 0.0 {
-                true => self.int_literal(n as i64),
-                false => self.add_constant(Value::Number(n), true).map(|_| ()),
-            },
+            Literal::Char(c) => self.int_literal(c as Integer),
+            Literal::Int(i) => self.int_literal(i),
+            Literal::Float(n) => self.add_constant(Value::Number(n), true).map(|_| ()),
             Literal::Str(string) => {
                 self.add_constant(string.into(), true)?;
                 Ok(())
@@ -366,6 +709,9 @@ impl<'a> Compiler<'a> {
     }
 
     fn unary(&mut self, expr: Expr, op: UnaryOp) -> CompileResult<()> {
+        if let (UnaryOp::Minus, Some(literal)) = (op, fold::negate(&expr)) {
+            return self.literal(literal);
+        }
         self.compile_expr(expr)?;
         let unary = match op {
             UnaryOp::Minus => UnaryInstr::Negate,
@@ -375,8 +721,24 @@ impl<'a> Compiler<'a> {
     }
 
     fn binary(&mut self, left: Expr, right: Expr, op: BinaryOp) -> CompileResult<()> {
-        self.compile_expr(left)?;
-        self.compile_expr(right)?;
+        let (left, right) = match op {
+            // 'a and b' is 'if a then b else false', 'a or b' is 'if a then true else b'
+            BinaryOp::And => return self.if_expr(left, right, Expr::bool(false)),
+            BinaryOp::Or => return self.if_expr(left, Expr::bool(true), right),
+            BinaryOp::Plus => match fold::concat(left, right) {
+                Expr::Binary { left, right, .. } => (*left, *right),
+                folded => return self.compile_expr(folded),
+            },
+            _ => (left, right),
+        };
+        let literal_type = |expr: &Expr| match expr {
+            Expr::Literal(Literal::Int(_)) | Expr::Literal(Literal::Char(_)) => Some(true),
+            Expr::Literal(Literal::Float(_)) => Some(false),
+            _ => None,
+        };
+        // An operand written as a number is likely to meet another of its type
+        let is_int = literal_type(&right).or_else(|| literal_type(&left));
+        self.operands(vec![left, right])?;
         let binary = match op {
             BinaryOp::Plus => BinaryInstr::Add,
             BinaryOp::Minus => BinaryInstr::Sub,
@@ -391,19 +753,37 @@ impl<'a> Compiler<'a> {
 
             BinaryOp::EqualEqual => BinaryInstr::Eq,
             BinaryOp::BangEqual => BinaryInstr::Ne,
+            BinaryOp::And | BinaryOp::Or => unreachable!(),
         };
-        self.add_instr(Instruction::Bin(binary))
+        self.add_instr(match is_int {
+            Some(true) => Instruction::int_bin(binary),
+            Some(false) => Instruction::float_bin(binary),
+            None => Instruction::Bin(binary),
+        })
     }
 
     fn tuple(&mut self, exprs: Vec<Expr>) -> CompileResult<()> {
         let len = exprs.len() as u8;
-        for expr in exprs {
-            self.compile_expr(expr)?
-        }
+        self.operands(exprs)?;
         self.add_instr(Instruction::Tuple { len })
     }
 
     fn access(&mut self, table: Expr, field: Expr) -> CompileResult<()> {
+        if let (Expr::Identifier { name, .. }, Expr::Literal(Literal::Str(variant))) =
+            (&table, &field)
+        {
+            if let Some(variants) = self.resolve_enum(name) {
+                if !variants.contains(variant) {
+                    return Err(CompileError::UnknownVariant {
+                        name: name.clone(),
+                        variant: variant.clone(),
+                        variants: variants.to_vec(),
+                    });
+                }
+                let tag = format!("{}.{}", name, variant);
+                return self.add_constant(tag.into(), true).map(drop);
+            }
+        }
         self.compile_expr(table)?;
         let access_instr = match field {
             Expr::Literal(lit) => match lit {
@@ -417,7 +797,7 @@ impl<'a> Compiler<'a> {
                 }
             },
             expr => {
-                self.compile_expr(expr)?;
+                self.above(1, expr)?;
                 Instruction::GetField
             }
         };
@@ -429,8 +809,7 @@ impl<'a> Compiler<'a> {
         let table_stack_index: u8 = args.len().try_into().unwrap();
         let args_len = (args.len() + 1).try_into().unwrap();
 
-        self.compile_expr(table)?;
-        self.compile_args(args)?;
+        self.operands(core::iter::once(table).chain(args))?;
         self.add_instr(Instruction::GetMethodImm {
             index,
             table_stack_index,
@@ -439,19 +818,21 @@ impl<'a> Compiler<'a> {
     }
 
     fn table_init(&mut self, keys: Option<Vec<Expr>>, values: Vec<Expr>) -> CompileResult<()> {
+        if self.constant_table.constants().len() < Self::MAX_CONST {
+            if let Some(table) = fold::table_template(keys.as_deref(), &values) {
+                let index = self.push_constant(table.into())?;
+                return self.add_instr(Instruction::TableTemplate { index });
+            }
+        }
         let len = values.len();
         let has_keys = match keys {
             Some(keys) => {
-                for (k, v) in keys.into_iter().zip(values.into_iter()) {
-                    self.compile_expr(k)?;
-                    self.compile_expr(v)?;
-                }
+                let pairs = keys.into_iter().zip(values);
+                self.operands(pairs.flat_map(|(k, v)| vec![k, v]))?;
                 true
             }
             None => {
-                for value in values.into_iter().rev() {
-                    self.compile_expr(value)?;
-                }
+                self.operands(values.into_iter().rev())?;
                 false
             }
         };
@@ -466,6 +847,7 @@ impl<'a> Compiler<'a> {
         body: BlockExpr,
         env: Option<(Vec<Expr>, Vec<Expr>)>,
     ) -> CompileResult<()> {
+        let name = self.fn_name.take();
         // if env is some compile as table literal
         // define function that has env
         let has_env = if let Some((keys, values)) = env {
@@ -476,15 +858,18 @@ impl<'a> Compiler<'a> {
         };
 
         let args_len = args.len() as u8;
+        let line = self.line;
         self.enter_function();
+        self.debug_info_mut().set_definition(name, Some(line));
         for arg in args {
-            self.push_local(arg);
+            self.push_local(arg)?;
         }
 
         self.func_body(body)?;
 
         // Add new func proto
         let closure_scope = self.exit_function()?;
+        self.line = line;
         self.define_func(closure_scope, args_len, has_env)
     }
 
@@ -492,7 +877,13 @@ impl<'a> Compiler<'a> {
         for stmt in body.stmts {
             self.compile_stmt(stmt)?;
         }
+        // The value of the function is a statement of its own for the debugger
+        let start = self.instructions().len();
+        let is_unit = *body.expr == Expr::Literal(Literal::Unit);
         self.compile_expr(*body.expr)?;
+        if !is_unit {
+            self.debug_info_mut().push_node(NodeKind::Expr, start);
+        }
         self.add_instr(Instruction::Return { return_value: true })
     }
 
@@ -503,9 +894,16 @@ impl<'a> Compiler<'a> {
         args_len: u8,
         has_env: bool,
     ) -> CompileResult<()> {
-        let instructions = scope.instructions;
+        let ClosureScope {
+            instructions,
+            debug_info,
+            ..
+        } = scope;
         let args_len = if has_env { args_len - 1 } else { args_len };
-        let proto_index = self.add_proto(args_len, instructions).try_into().unwrap();
+        let proto_index = self
+            .add_proto(args_len, instructions, debug_info)
+            .try_into()
+            .unwrap();
         self.add_instr(Instruction::FuncDef {
             proto_index,
             has_env,
@@ -514,8 +912,7 @@ impl<'a> Compiler<'a> {
 
     fn call(&mut self, func: Expr, args: Vec<Expr>) -> CompileResult<()> {
         let args_len = args.len() as u8;
-        self.compile_args(args)?;
-        self.compile_expr(func)?;
+        self.operands(args.into_iter().chain(core::iter::once(func)))?;
         self.add_instr(Instruction::Call { args_len })
     }
 
@@ -550,12 +947,22 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn compile_args(&mut self, args: Vec<Expr>) -> CompileResult<()> {
-        for arg in args {
-            self.compile_expr(arg)?;
+    // Each expression is compiled above the values of the ones before it
+    fn operands(&mut self, exprs: impl IntoIterator<Item = Expr>) -> CompileResult<()> {
+        for (i, expr) in exprs.into_iter().enumerate() {
+            self.above(i, expr)?;
         }
         Ok(())
     }
+
+    // Compiles the expression while 'count' values of its parent are on the stack, the
+    // locals of the blocks in it go after them
+    fn above(&mut self, count: usize, expr: Expr) -> CompileResult<()> {
+        self.closure_scope_mut().temps += count;
+        let compiled = self.compile_expr(expr);
+        self.closure_scope_mut().temps -= count;
+        compiled
+    }
 }
 
 /**
@@ -564,8 +971,22 @@ impl<'a> Compiler<'a> {
 
 impl<'a> Compiler<'a> {
     fn add_instr(&mut self, instruction: Instruction) -> CompileResult<()> {
-        self.instructions_mut().push(instruction);
-        Ok(())
+        let line = self.line;
+        match self.closure_scopes.last_mut() {
+            Some(closure_scope) => {
+                closure_scope.instructions.push(instruction);
+                closure_scope.debug_info.push_line(line);
+                Ok(())
+            }
+            None => self.chunk.push_instr(instruction, line),
+        }
+    }
+
+    fn debug_info_mut(&mut self) -> &mut DebugInfo {
+        match self.closure_scopes.last_mut() {
+            Some(closure_scope) => &mut closure_scope.debug_info,
+            None => self.chunk.debug_info_mut(),
+        }
     }
 
     fn instructions(&self) -> &[Instruction] {
@@ -575,6 +996,23 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn closure_scope(&self) -> &ClosureScope {
+        self.closure_scopes
+            .last()
+            .expect("Expected code be compiled inside a function")
+    }
+
+    fn closure_scope_mut(&mut self) -> &mut ClosureScope {
+        self.closure_scopes
+            .last_mut()
+            .expect("Expected code be compiled inside a function")
+    }
+
+    // Loops of the function being compiled, a function inside a loop can't leave it
+    fn loops_mut(&mut self) -> &mut Vec<Loop> {
+        &mut self.closure_scope_mut().loops
+    }
+
     fn instructions_mut(&mut self) -> &mut Vec<Instruction> {
         match self.closure_scopes.last_mut() {
             Some(closure_scope) => &mut closure_scope.instructions,
@@ -625,7 +1063,7 @@ impl<'a> Compiler<'a> {
     #[inline]
     fn get_offset(&self, patch_index: usize) -> CompileResult<i8> {
         let offset = self.instructions().len() - patch_index;
-        if offset > std::i8::MAX as usize {
+        if offset > i8::MAX as usize {
             Err(CompileError::TooLongToJump)
         } else {
             Ok(offset as i8)
@@ -650,22 +1088,49 @@ impl<'a> Compiler<'a> {
                     ),
                     None => (0, 0),
                 };
-                Some((i - offset, closure_depth))
+                Some((i - offset + l.temps, closure_depth))
             } else {
                 None
             }
         })
     }
 
-    fn push_local(&mut self, name: String) {
+    // The variants of the enum the name refers to. Enums captured by a closure are
+    // read through its environment and not resolved here
+    fn resolve_enum(&self, name: &str) -> Option<&[String]> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|l| l.name == name)
+            .and_then(|l| l.variants.as_deref())
+    }
+
+    // Shadowing is only allowed from an inner scope, a name can be declared once per scope
+    fn push_local(&mut self, name: String) -> CompileResult<()> {
+        let closure = match self.closure_scopes.len() {
+            0 => None,
+            i => Some(i as u8 - 1),
+        };
+        let is_duplicate = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth == self.depth && l.closure == closure)
+            .any(|l| l.name == name);
+        if is_duplicate {
+            return Err(CompileError::DuplicateLocal { name });
+        }
+        let start = self.instructions().len();
+        let temps = self.closure_scopes.last().map_or(0, |scope| scope.temps);
         self.locals.push(Local {
             name,
             depth: self.depth,
-            closure: match self.closure_scopes.len() {
-                0 => None,
-                i => Some(i as u8 - 1),
-            },
-        })
+            closure,
+            start,
+            variants: None,
+            temps,
+        });
+        Ok(())
     }
 
     fn enter_function(&mut self) {
@@ -674,6 +1139,9 @@ impl<'a> Compiler<'a> {
             depth: self.depth,
             local_start: self.locals.len(),
             instructions: Vec::new(),
+            debug_info: DebugInfo::with_file(self.metadata.file_name()),
+            loops: Vec::new(),
+            temps: 0,
         })
     }
 
@@ -689,6 +1157,12 @@ impl<'a> Compiler<'a> {
         self.scope_incr()
     }
 
+    // In the body of the module, outside of any block or function
+    #[inline]
+    fn is_top_level(&self) -> bool {
+        self.closure_scopes.len() == 1 && self.depth == 1
+    }
+
     fn exit_scope(&mut self, return_value: bool) -> CompileResult<()> {
         let pop = self.scope_decr() as u16;
         self.add_instr(Instruction::ExitBlock { pop, return_value })
@@ -702,7 +1176,13 @@ impl<'a> Compiler<'a> {
         self.depth -= 1;
         let mut pop_count = 0;
         while self.locals.last().is_some() && self.locals.last().unwrap().depth > self.depth {
-            self.locals.pop().unwrap();
+            let local = self.locals.pop().unwrap();
+            let slot = match local.closure {
+                Some(i) => self.locals.len() - self.closure_scopes[i as usize].local_start,
+                None => self.locals.len(),
+            } + local.temps;
+            self.debug_info_mut()
+                .push_local(local.name, slot, local.start);
             pop_count += 1;
         }
         pop_count
@@ -710,30 +1190,41 @@ impl<'a> Compiler<'a> {
 }
 
 impl<'a> Compiler<'a> {
-    const MAX_CONST: usize = std::u8::MAX as usize;
+    const MAX_CONST: usize = core::u8::MAX as usize;
 
-    pub fn add_proto(&mut self, args_len: u8, instructions: Vec<Instruction>) -> usize {
+    pub fn add_proto(
+        &mut self,
+        args_len: u8,
+        mut instructions: Vec<Instruction>,
+        debug_info: DebugInfo,
+    ) -> usize {
+        peephole::fuse(&mut instructions);
         self.constant_table
             .prototypes_mut()
-            .push(Rc::new(FuncProto {
+            .push(Rc::new(FuncProto::new(
                 args_len,
-                instructions: instructions.into_boxed_slice(),
-            }));
+                instructions.into_boxed_slice(),
+                debug_info,
+            )));
         self.constant_table.prototypes().len() - 1
     }
 
     pub fn add_constant_table(&mut self, constant: Value) -> CompileResult<u8> {
+        // The pool is shared by every prototype of the chunk, a name or number used
+        // by many functions is stored once
         let index = match &constant {
-            Value::Str(string) => {
-                if let Some(index) = self.has_string(string) {
-                    Ok(index)
-                } else {
-                    self.push_constant(constant)
-                }
+            Value::Str(string) => self.has_string(string),
+            Value::Int(i) => self.has_constant(|c| matches!(c, Value::Int(j) if i == j)),
+            // Bitwise so 0.0 and -0.0 stay apart
+            Value::Number(n) => {
+                self.has_constant(|c| matches!(c, Value::Number(m) if n.to_bits() == m.to_bits()))
             }
-            _ => self.push_constant(constant),
-        }?;
-        Ok(index)
+            _ => None,
+        };
+        match index {
+            Some(index) => Ok(index),
+            None => self.push_constant(constant),
+        }
     }
 
     #[inline]
@@ -749,6 +1240,14 @@ impl<'a> Compiler<'a> {
         }
     }
 
+    fn has_constant(&self, matches: impl Fn(&Value) -> bool) -> Option<u8> {
+        self.constant_table
+            .constants()
+            .iter()
+            .position(matches)
+            .map(|i| i as u8)
+    }
+
     pub fn has_string(&self, string: &str) -> Option<u8> {
         self.constant_table
             .constants()
@@ -756,7 +1255,7 @@ impl<'a> Compiler<'a> {
             .enumerate()
             .find_map(|(i, s)| match s {
                 Value::Str(s) => {
-                    if **s == string {
+                    if s.as_str() == string {
                         Some(i as u8)
                     } else {
                         None
@@ -818,3 +1317,173 @@ impl Default for ConstantTable<'_> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn compile_syntax(source: &str) -> CompileResult<CompiledSource> {
+        // Skips the analyzer, which already rejects redeclarations
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+    }
+
+    #[test]
+    fn duplicate_local_is_error() {
+        assert_eq!(
+            compile_syntax("let x = 1; let x = 2;"),
+            Err(CompileError::DuplicateLocal {
+                name: "x".to_owned()
+            })
+        );
+        assert_eq!(
+            compile_syntax("fn(a, a) end"),
+            Err(CompileError::DuplicateLocal {
+                name: "a".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let source = format!("let x = {}1;", "-".repeat(30));
+        let error = |max_depth| {
+            Compiler::compile(SourceFile {
+                ast: Parser::new(&source).parse_syntax().unwrap(),
+                metadata: MetaData::default().with_max_depth(max_depth),
+            })
+            .err()
+        };
+        assert_eq!(error(20), Some(CompileError::TooDeep));
+        assert_eq!(error(40), None);
+    }
+
+    fn body_instructions(source: &str) -> Vec<Instruction> {
+        let compiled = compile_syntax(source).unwrap();
+        let body = compiled.constant_table.prototypes.last().unwrap();
+        body.instructions.to_vec()
+    }
+
+    #[test]
+    fn prototypes_share_constants() {
+        let compiled = compile_syntax(
+            "
+            let f = fn(t) t.x + 5000000000 + 2.5 end;
+            let g = fn(t) t.x - 5000000000 - 2.5 end;
+            (f, g, -0.0, 0.0)
+            ",
+        )
+        .unwrap();
+        let constants = &compiled.constant_table.constants;
+        let count = |value: &Value| constants.iter().filter(|c| *c == value).count();
+        assert_eq!(count(&Value::new_str("x")), 1);
+        assert_eq!(count(&Value::Int(5_000_000_000)), 1);
+        assert_eq!(count(&Value::Number(2.5)), 1);
+        // Equal but different constants
+        assert_eq!(count(&Value::Number(0.0)), 2);
+    }
+
+    #[test]
+    fn string_concat_is_folded() {
+        let instructions = body_instructions("\"a\" + \"b\" + \"c\"");
+        assert_eq!(instructions[0], Instruction::Constant { index: 0 });
+        assert!(!instructions.contains(&Instruction::Bin(BinaryInstr::Add)));
+
+        let instructions = body_instructions("let x = nil; x + \"a\" + \"b\"");
+        let adds = instructions
+            .iter()
+            .filter(|i| **i == Instruction::Bin(BinaryInstr::Add))
+            .count();
+        assert_eq!(adds, 1);
+    }
+
+    #[test]
+    fn literal_operands_specialize_the_operator() {
+        let instructions = body_instructions("let x = nil; (x + 1, 2.5 - x, x < 3, x * 2, x + x)");
+        for instr in &[
+            Instruction::AddInt,
+            Instruction::SubFloat,
+            Instruction::LtInt,
+            Instruction::Bin(BinaryInstr::Mul),
+            Instruction::Bin(BinaryInstr::Add),
+        ] {
+            assert!(instructions.contains(instr), "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn negative_literals_are_folded() {
+        let compiled = compile_syntax("-9223372036854775808").unwrap();
+        assert_eq!(compiled.constant_table.constants[0], Value::Int(i64::MIN));
+        assert!(!body_instructions("-9223372036854775808")
+            .contains(&Instruction::Unary(UnaryInstr::Negate)));
+
+        let compiled = compile_syntax("-(2.5)").unwrap();
+        assert_eq!(compiled.constant_table.constants[0], Value::Number(-2.5));
+
+        let instructions = body_instructions("{-1, 2}");
+        assert_eq!(instructions[0], Instruction::TableTemplate { index: 0 });
+    }
+
+    #[test]
+    fn literal_table_is_template() {
+        let compiled = compile_syntax("{1, 2, 3}").unwrap();
+        let body = compiled.constant_table.prototypes.last().unwrap();
+        assert_eq!(
+            body.instructions[0],
+            Instruction::TableTemplate { index: 0 }
+        );
+        assert!(matches!(
+            compiled.constant_table.constants[0],
+            Value::Table(_)
+        ));
+
+        let instructions = body_instructions("let x = 1; {x, 2}");
+        assert!(instructions.contains(&Instruction::InitTable {
+            len: 2,
+            has_keys: false
+        }));
+    }
+
+    #[test]
+    fn shadowing_in_inner_scope_is_allowed() {
+        assert!(compile_syntax("let x = 1; do let x = 2; end").is_ok());
+        assert!(compile_syntax("let x = 1; fn(x) x end").is_ok());
+    }
+
+    #[test]
+    fn repl_echoes_top_level_expressions() {
+        let ast = Parser::new("1 + 2; do 3; end let f = fn() 4; end;")
+            .parse_syntax()
+            .unwrap();
+        let compiled = Compiler::compile_repl(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap();
+        let echoes = |instructions: &[Instruction]| {
+            instructions
+                .iter()
+                .filter(|i| **i == Instruction::Echo)
+                .count()
+        };
+        let prototypes = &compiled.constant_table.prototypes;
+        assert_eq!(echoes(&prototypes.last().unwrap().instructions), 2);
+        assert_eq!(echoes(&prototypes[0].instructions), 0);
+        assert_eq!(echoes(&body_instructions("1 + 2;")), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn files_include_imports() {
+        let compiled = crate::util::load_file("src/vm/tests/import.flux").unwrap();
+        let files = compiled.files();
+        assert_eq!(files.len(), 2);
+        assert!(files.iter().any(|file| file.ends_with("import.flux")));
+        assert!(files.iter().any(|file| file.ends_with("module.flux")));
+    }
+}
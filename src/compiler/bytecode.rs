@@ -0,0 +1,841 @@
+use super::peephole;
+use super::{
+    verify, BinaryInstr, Chunk, CompileError, CompileResult, CompiledSource, ConstantTableStruct,
+    DebugInfo, FuncProto, Instruction, LocalRange, NodeKind, NodeRange, UnaryInstr,
+};
+use crate::vm::{Table, Value};
+use alloc::rc::Rc;
+use alloc::{string::String, vec::Vec};
+use hashbrown::HashMap;
+
+pub const MAGIC: &[u8; 4] = b"FLXC";
+pub const BYTECODE_VERSION: u16 = 7;
+
+// The instructions by tag with the operands written after it. Adding or reordering
+// one changes INSTRUCTION_SET_HASH, so older bytecode is refused instead of read
+// with the wrong opcodes
+const INSTRUCTION_SET: &[&str] = &[
+    "Bin u8",
+    "Unary u8",
+    "Nil",
+    "Unit",
+    "True",
+    "False",
+    "Constant u8",
+    "SetGlobal u8",
+    "GetGlobal u8",
+    "SetLocal u16 u8",
+    "GetLocal u16 u8",
+    "GetMethodImm u8 u8",
+    "GetFieldImm u8",
+    "GetField",
+    "SetFieldImm u8",
+    "SetField",
+    "Pop",
+    "Return bool",
+    "Tuple u8",
+    "InitTable u16 bool",
+    "TableTemplate u8",
+    "JumpIf bool i8",
+    "Jump i8",
+    "Placeholder",
+    "Print",
+    "FuncDef u32 bool",
+    "Call u8",
+    "GetUpval u16",
+    "SetUpval u16",
+    "CloseUpval u8",
+    "Integer i32",
+    "Import u8",
+    "ExitBlock u16 bool",
+    "Rec",
+    "Echo",
+    "AddInt",
+    "SubInt",
+    "LtInt",
+    "AddFloat",
+    "SubFloat",
+    "LtFloat",
+    "Yield",
+    "Cast",
+];
+
+pub const INSTRUCTION_SET_HASH: u32 = hash_names(INSTRUCTION_SET);
+
+// FNV-1a over the names, each followed by a 0
+const fn hash_names(names: &[&str]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < names.len() {
+        let bytes = names[i].as_bytes();
+        let mut j = 0;
+        while j <= bytes.len() {
+            let byte = if j < bytes.len() { bytes[j] } else { 0 };
+            hash = (hash ^ byte as u32).wrapping_mul(0x0100_0193);
+            j += 1;
+        }
+        i += 1;
+    }
+    hash
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    // Written by a flux_rs with other instructions under the same version
+    InstructionSetMismatch(u32),
+    UnexpectedEnd,
+    InvalidTag { what: &'static str, tag: u8 },
+    InvalidUtf8,
+}
+
+impl From<BytecodeError> for CompileError {
+    fn from(error: BytecodeError) -> Self {
+        CompileError::Bytecode(error)
+    }
+}
+
+type Result<T> = core::result::Result<T, BytecodeError>;
+
+/*
+ * Layout, all integers little endian:
+ *   magic "FLXC", version u16, INSTRUCTION_SET_HASH u32
+ *   constants: u32 count, tagged values
+ *   prototypes: u32 count, args_len u8, instructions, debug info
+ *   chunk: instructions, debug info, u32 count of (name, chunk) imports
+ * Superinstructions are written as the first instruction they run and fused again
+ * when read, the format doesn't depend on the peephole pass
+ */
+impl CompiledSource {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.bytes(MAGIC);
+        writer.u16(BYTECODE_VERSION);
+        writer.u32(INSTRUCTION_SET_HASH);
+
+        let table = &self.constant_table;
+        writer.len(table.constants.len());
+        for constant in table.constants.iter() {
+            writer.value(constant);
+        }
+        writer.len(table.prototypes.len());
+        for proto in table.prototypes.iter() {
+            writer.u8(proto.args_len);
+            writer.instructions(&proto.instructions);
+            writer.debug_info(&proto.debug_info);
+        }
+        writer.chunk(&self.chunk);
+        writer.buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> CompileResult<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(BytecodeError::BadMagic.into());
+        }
+        let version = reader.u16()?;
+        if version != BYTECODE_VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version).into());
+        }
+        let instruction_set = reader.u32()?;
+        if instruction_set != INSTRUCTION_SET_HASH {
+            return Err(BytecodeError::InstructionSetMismatch(instruction_set).into());
+        }
+
+        let mut constants = Vec::new();
+        for _ in 0..reader.len()? {
+            constants.push(reader.value()?);
+        }
+        let mut prototypes = Vec::new();
+        for _ in 0..reader.len()? {
+            let args_len = reader.u8()?;
+            let instructions = reader.instructions()?.into_boxed_slice();
+            let debug_info = reader.debug_info()?;
+            prototypes.push(Rc::new(FuncProto::new(args_len, instructions, debug_info)));
+        }
+        let chunk = reader.chunk()?;
+        let compiled = CompiledSource {
+            chunk: Rc::new(chunk),
+            constant_table: Rc::new(ConstantTableStruct {
+                constants,
+                prototypes,
+            }),
+        };
+        verify(&compiled)?;
+        Ok(compiled)
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes)
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value)
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32)
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8)
+    }
+
+    fn str(&mut self, string: &str) {
+        self.len(string.len());
+        self.bytes(string.as_bytes())
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Nil => self.u8(0),
+            Value::Bool(b) => {
+                self.u8(1);
+                self.bool(*b)
+            }
+            Value::Int(i) => {
+                self.u8(2);
+                self.bytes(&i.to_le_bytes())
+            }
+            Value::Number(n) => {
+                self.u8(3);
+                self.bytes(&n.to_le_bytes())
+            }
+            Value::Str(string) => {
+                self.u8(4);
+                self.str(string)
+            }
+            Value::Embedded(string) => {
+                self.u8(4);
+                self.str(string)
+            }
+            Value::Unit => self.u8(5),
+            Value::Table(table) => {
+                let table = table.borrow();
+                self.u8(if table.is_frozen() { 8 } else { 6 });
+                self.len(table.array_part().len());
+                for (_, value) in table.array_part() {
+                    self.value(value)
+                }
+                self.len(table.hash_len());
+                for (key, value) in table.hash_part() {
+                    self.value(key);
+                    self.value(value)
+                }
+            }
+            Value::Tuple(values) => {
+                self.u8(7);
+                self.len(values.len());
+                for value in values.iter() {
+                    self.value(value)
+                }
+            }
+            // The compiler never stores functions in the constant table
+            Value::Function(_) => panic!("functions can't be serialized"),
+        }
+    }
+
+    fn instructions(&mut self, instructions: &[Instruction]) {
+        self.len(instructions.len());
+        for instr in instructions {
+            self.instruction(*instr)
+        }
+    }
+
+    fn instruction(&mut self, instr: Instruction) {
+        use Instruction::*;
+        match instr {
+            BinLocals { .. } | BinLocalInt { .. } | CallGlobal { .. } | CallFieldImm { .. } => {
+                let code = peephole::unfuse(instr).expect("Expected a superinstruction");
+                self.instruction(code[0])
+            }
+            Bin(op) => {
+                self.u8(0);
+                self.u8(op as u8)
+            }
+            Unary(op) => {
+                self.u8(1);
+                self.u8(op as u8)
+            }
+            Nil => self.u8(2),
+            Unit => self.u8(3),
+            True => self.u8(4),
+            False => self.u8(5),
+            Constant { index } => {
+                self.u8(6);
+                self.u8(index)
+            }
+            SetGlobal { index } => {
+                self.u8(7);
+                self.u8(index)
+            }
+            GetGlobal { index } => {
+                self.u8(8);
+                self.u8(index)
+            }
+            SetLocal { index, frame } => {
+                self.u8(9);
+                self.u16(index);
+                self.u8(frame)
+            }
+            GetLocal { index, frame } => {
+                self.u8(10);
+                self.u16(index);
+                self.u8(frame)
+            }
+            GetMethodImm {
+                index,
+                table_stack_index,
+            } => {
+                self.u8(11);
+                self.u8(index);
+                self.u8(table_stack_index)
+            }
+            GetFieldImm { index } => {
+                self.u8(12);
+                self.u8(index)
+            }
+            GetField => self.u8(13),
+            SetFieldImm { index } => {
+                self.u8(14);
+                self.u8(index)
+            }
+            SetField => self.u8(15),
+            Pop => self.u8(16),
+            Return { return_value } => {
+                self.u8(17);
+                self.bool(return_value)
+            }
+            Tuple { len } => {
+                self.u8(18);
+                self.u8(len)
+            }
+            InitTable { len, has_keys } => {
+                self.u8(19);
+                self.u16(len);
+                self.bool(has_keys)
+            }
+            TableTemplate { index } => {
+                self.u8(20);
+                self.u8(index)
+            }
+            JumpIf { when_true, offset } => {
+                self.u8(21);
+                self.bool(when_true);
+                self.u8(offset as u8)
+            }
+            Jump { offset } => {
+                self.u8(22);
+                self.u8(offset as u8)
+            }
+            Placeholder => self.u8(23),
+            Print => self.u8(24),
+            FuncDef {
+                proto_index,
+                has_env,
+            } => {
+                self.u8(25);
+                self.u32(proto_index);
+                self.bool(has_env)
+            }
+            Call { args_len } => {
+                self.u8(26);
+                self.u8(args_len)
+            }
+            GetUpval { index } => {
+                self.u8(27);
+                self.u16(index)
+            }
+            SetUpval { index } => {
+                self.u8(28);
+                self.u16(index)
+            }
+            CloseUpval { index } => {
+                self.u8(29);
+                self.u8(index)
+            }
+            Integer(value) => {
+                self.u8(30);
+                self.bytes(&value.to_le_bytes())
+            }
+            Import { name_index } => {
+                self.u8(31);
+                self.u8(name_index)
+            }
+            ExitBlock { pop, return_value } => {
+                self.u8(32);
+                self.u16(pop);
+                self.bool(return_value)
+            }
+            Rec => self.u8(33),
+            Echo => self.u8(34),
+            AddInt => self.u8(35),
+            SubInt => self.u8(36),
+            LtInt => self.u8(37),
+            AddFloat => self.u8(38),
+            SubFloat => self.u8(39),
+            LtFloat => self.u8(40),
+            Yield => self.u8(41),
+            Cast { index } => {
+                self.u8(42);
+                self.u8(index)
+            }
+        }
+    }
+
+    fn debug_info(&mut self, debug_info: &DebugInfo) {
+        self.bool(debug_info.file().is_some());
+        if let Some(file) = debug_info.file() {
+            self.str(file);
+        }
+        self.bool(debug_info.name().is_some());
+        if let Some(name) = debug_info.name() {
+            self.str(name);
+        }
+        // 0 when the function has no 'fn' line, lines start from 1
+        self.len(debug_info.start_line.unwrap_or(0));
+        self.len(debug_info.lines().len());
+        for line in debug_info.lines() {
+            self.len(*line)
+        }
+        self.len(debug_info.nodes().len());
+        for node in debug_info.nodes() {
+            self.len(node.start);
+            self.len(node.end);
+            self.len(node.line);
+            self.u8(node.kind as u8)
+        }
+        self.len(debug_info.locals().len());
+        for local in debug_info.locals() {
+            self.str(&local.name);
+            self.len(local.slot);
+            self.len(local.start);
+            self.len(local.end);
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        self.instructions(chunk.instructions());
+        self.debug_info(chunk.debug_info());
+        let mut imports: Vec<_> = chunk.imported().collect();
+        // Keep the output stable between runs
+        imports.sort_by_key(|(name, _)| *name);
+        self.len(imports.len());
+        for (name, import) in imports {
+            self.str(name);
+            self.chunk(import)
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(BytecodeError::InvalidTag { what: "bool", tag }),
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidUtf8)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        Ok(match self.u8()? {
+            0 => Value::Nil,
+            1 => Value::Bool(self.bool()?),
+            2 => Value::Int(i64::from_le_bytes(self.array()?)),
+            3 => Value::Number(f64::from_le_bytes(self.array()?)),
+            4 => Value::new_str(self.string()?),
+            5 => Value::Unit,
+            // Enum tables are written as 8
+            tag @ (6 | 8) => {
+                let mut array = Vec::new();
+                for i in 0..self.len()? {
+                    array.push((Value::Int(i as i64), self.value()?));
+                }
+                let mut table = Table::from_array(array);
+                for _ in 0..self.len()? {
+                    let key = self.value()?;
+                    let value = self.value()?;
+                    table.set(key, value);
+                }
+                if tag == 8 {
+                    table.freeze()
+                }
+                table.into()
+            }
+            7 => {
+                let mut values = Vec::new();
+                for _ in 0..self.len()? {
+                    values.push(self.value()?);
+                }
+                Value::Tuple(values.into())
+            }
+            tag => return Err(BytecodeError::InvalidTag { what: "value", tag }),
+        })
+    }
+
+    fn instructions(&mut self) -> Result<Vec<Instruction>> {
+        let len = self.len()?;
+        let mut instructions = Vec::new();
+        for _ in 0..len {
+            instructions.push(self.instruction()?);
+        }
+        peephole::fuse(&mut instructions);
+        Ok(instructions)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction> {
+        use Instruction::*;
+        Ok(match self.u8()? {
+            0 => Bin(self.binary()?),
+            1 => Unary(match self.u8()? {
+                0 => UnaryInstr::Negate,
+                1 => UnaryInstr::Not,
+                tag => return Err(BytecodeError::InvalidTag { what: "unary", tag }),
+            }),
+            2 => Nil,
+            3 => Unit,
+            4 => True,
+            5 => False,
+            6 => Constant { index: self.u8()? },
+            7 => SetGlobal { index: self.u8()? },
+            8 => GetGlobal { index: self.u8()? },
+            9 => SetLocal {
+                index: self.u16()?,
+                frame: self.u8()?,
+            },
+            10 => GetLocal {
+                index: self.u16()?,
+                frame: self.u8()?,
+            },
+            11 => GetMethodImm {
+                index: self.u8()?,
+                table_stack_index: self.u8()?,
+            },
+            12 => GetFieldImm { index: self.u8()? },
+            13 => GetField,
+            14 => SetFieldImm { index: self.u8()? },
+            15 => SetField,
+            16 => Pop,
+            17 => Return {
+                return_value: self.bool()?,
+            },
+            18 => Tuple { len: self.u8()? },
+            19 => InitTable {
+                len: self.u16()?,
+                has_keys: self.bool()?,
+            },
+            20 => TableTemplate { index: self.u8()? },
+            21 => JumpIf {
+                when_true: self.bool()?,
+                offset: self.u8()? as i8,
+            },
+            22 => Jump {
+                offset: self.u8()? as i8,
+            },
+            23 => Placeholder,
+            24 => Print,
+            25 => FuncDef {
+                proto_index: self.u32()?,
+                has_env: self.bool()?,
+            },
+            26 => Call {
+                args_len: self.u8()?,
+            },
+            27 => GetUpval { index: self.u16()? },
+            28 => SetUpval { index: self.u16()? },
+            29 => CloseUpval { index: self.u8()? },
+            30 => Integer(i32::from_le_bytes(self.array()?)),
+            31 => Import {
+                name_index: self.u8()?,
+            },
+            32 => ExitBlock {
+                pop: self.u16()?,
+                return_value: self.bool()?,
+            },
+            33 => Rec,
+            34 => Echo,
+            35 => AddInt,
+            36 => SubInt,
+            37 => LtInt,
+            38 => AddFloat,
+            39 => SubFloat,
+            40 => LtFloat,
+            41 => Yield,
+            42 => Cast { index: self.u8()? },
+            tag => {
+                return Err(BytecodeError::InvalidTag {
+                    what: "instruction",
+                    tag,
+                })
+            }
+        })
+    }
+
+    fn binary(&mut self) -> Result<BinaryInstr> {
+        use BinaryInstr::*;
+        Ok(match self.u8()? {
+            0 => Add,
+            1 => Sub,
+            2 => Mul,
+            3 => Div,
+            4 => Gt,
+            5 => Lt,
+            6 => Ge,
+            7 => Le,
+            8 => Eq,
+            9 => Ne,
+            10 => Rem,
+            tag => {
+                return Err(BytecodeError::InvalidTag {
+                    what: "binary",
+                    tag,
+                })
+            }
+        })
+    }
+
+    fn debug_info(&mut self) -> Result<DebugInfo> {
+        let file = if self.bool()? {
+            Some(self.string()?)
+        } else {
+            None
+        };
+        let name = if self.bool()? {
+            Some(self.string()?)
+        } else {
+            None
+        };
+        let start_line = Some(self.len()?).filter(|line| *line != 0);
+        let mut lines = Vec::new();
+        for _ in 0..self.len()? {
+            lines.push(self.len()?);
+        }
+        let mut nodes = Vec::new();
+        for _ in 0..self.len()? {
+            let start = self.len()?;
+            let end = self.len()?;
+            let line = self.len()?;
+            let kind = self.node_kind()?;
+            nodes.push(NodeRange {
+                start,
+                end,
+                line,
+                kind,
+            });
+        }
+        let mut locals = Vec::new();
+        for _ in 0..self.len()? {
+            let name = self.string()?;
+            let slot = self.len()?;
+            let start = self.len()?;
+            let end = self.len()?;
+            locals.push(LocalRange {
+                name,
+                slot,
+                start,
+                end,
+            });
+        }
+        let mut debug_info = DebugInfo::from_parts(file, lines, nodes, locals);
+        debug_info.set_definition(name, start_line);
+        Ok(debug_info)
+    }
+
+    fn node_kind(&mut self) -> Result<NodeKind> {
+        use NodeKind::*;
+        Ok(match self.u8()? {
+            0 => Expr,
+            1 => Let,
+            2 => Var,
+            3 => Set,
+            4 => Block,
+            5 => If,
+            6 => While,
+            7 => Print,
+            8 => Return,
+            9 => Import,
+            10 => Break,
+            11 => Continue,
+            12 => For,
+            tag => return Err(BytecodeError::InvalidTag { what: "node", tag }),
+        })
+    }
+
+    fn chunk(&mut self) -> Result<Chunk> {
+        let instructions = self.instructions()?;
+        let debug_info = self.debug_info()?;
+        let mut imports = HashMap::new();
+        for _ in 0..self.len()? {
+            let name = self.string()?;
+            let chunk = self.chunk()?;
+            imports.insert(name, Rc::new(chunk));
+        }
+        Ok(Chunk::from_parts(instructions, imports, debug_info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+    use crate::vm::Vm;
+
+    fn compile(source: &str) -> CompiledSource {
+        let ast = Parser::parse_str(source).unwrap();
+        super::super::Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "
+            let add = fn(a, b) a + b end;
+            let t = { \"x\" = 1.5, \"y\" = 2 };
+            let list = {1, 2, 3};
+            add(t.y, list[2])
+        ";
+        let compiled = compile(source);
+        let loaded = CompiledSource::from_bytes(&compiled.to_bytes()).unwrap();
+        assert_eq!(loaded.chunk, compiled.chunk);
+        assert_eq!(
+            loaded.constant_table.prototypes,
+            compiled.constant_table.prototypes
+        );
+        assert_eq!(Vm::new().run(&loaded), Ok(Value::Int(5)));
+    }
+
+    #[test]
+    fn round_trip_loop_control() {
+        let source = "
+            var sum = 0;
+            for i in 0..10 do
+                if i == 6 then break; end
+                if i % 2 == 0 then continue; end
+                sum = sum + i;
+            end
+            sum
+        ";
+        let compiled = compile(source);
+        let loaded = CompiledSource::from_bytes(&compiled.to_bytes()).unwrap();
+        assert_eq!(loaded.chunk, compiled.chunk);
+        assert_eq!(Vm::new().run(&loaded), Ok(Value::Int(9)));
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert_eq!(
+            CompiledSource::from_bytes(b"FLUX\x01\x00"),
+            Err(CompileError::Bytecode(BytecodeError::BadMagic))
+        );
+        assert_eq!(
+            CompiledSource::from_bytes(b"FLXC\x01\x00"),
+            Err(CompileError::Bytecode(BytecodeError::UnsupportedVersion(1)))
+        );
+        let bytes = compile("1 + 2").to_bytes();
+        assert_eq!(
+            CompiledSource::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(CompileError::Bytecode(BytecodeError::UnexpectedEnd))
+        );
+
+        let mut other_set = bytes.clone();
+        other_set[6..10].copy_from_slice(&0xdead_beef_u32.to_le_bytes());
+        assert_eq!(
+            CompiledSource::from_bytes(&other_set),
+            Err(CompileError::Bytecode(
+                BytecodeError::InstructionSetMismatch(0xdead_beef)
+            ))
+        );
+    }
+
+    #[test]
+    fn instruction_set_lists_every_tag() {
+        let last = INSTRUCTION_SET.len() as u8 - 1;
+        // Followed by its operand
+        let mut reader = Reader {
+            bytes: &[last, 0],
+            pos: 0,
+        };
+        assert!(reader.instruction().is_ok());
+        let mut reader = Reader {
+            bytes: &[last + 1],
+            pos: 0,
+        };
+        assert_eq!(
+            reader.instruction(),
+            Err(BytecodeError::InvalidTag {
+                what: "instruction",
+                tag: last + 1
+            })
+        );
+    }
+}
@@ -1,23 +1,40 @@
-use super::{CompileError, CompileResult, ConstantTableStruct, Instruction};
-use std::collections::HashMap;
-use std::rc::Rc;
+use super::{max_stack, CompileError, CompileResult, ConstantTableStruct, DebugInfo, Instruction};
+#[cfg(feature = "jit")]
+use crate::vm::Jit;
+use crate::vm::Quickened;
+use alloc::collections::BTreeSet;
+use alloc::rc::Rc;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use hashbrown::HashMap;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct CompiledSource {
-    pub chunk: Chunk,
+    // Shared, so running a source doesn't copy it
+    pub chunk: Rc<Chunk>,
     pub constant_table: Rc<ConstantTableStruct>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Chunk {
     instructions: Vec<Instruction>,
-    imports: HashMap<String, Chunk>,
+    imports: HashMap<String, Rc<Chunk>>,
+    debug_info: DebugInfo,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct FuncProto {
     pub args_len: u8,
     pub instructions: Box<[Instruction]>,
+    pub debug_info: DebugInfo,
+    // Values pushed above the arguments and env at most, see compiler::max_stack
+    pub max_stack: usize,
+    // What the vm runs, see vm::quicken
+    pub(crate) quickened: Quickened,
+    // Counts the calls and holds the native code, see vm::jit
+    #[cfg(feature = "jit")]
+    pub(crate) jit: Jit,
+    // Has a yield, calling it returns the iterator resuming its body
+    pub generator: bool,
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -27,30 +44,77 @@ pub enum JumpCondition {
     WhenFalse,
 }
 
+impl FuncProto {
+    pub fn new(args_len: u8, instructions: Box<[Instruction]>, debug_info: DebugInfo) -> Self {
+        FuncProto {
+            args_len,
+            max_stack: max_stack(&instructions),
+            quickened: Quickened::new(&instructions),
+            #[cfg(feature = "jit")]
+            jit: Jit::default(),
+            generator: instructions.contains(&Instruction::Yield),
+            instructions,
+            debug_info,
+        }
+    }
+}
+
+impl CompiledSource {
+    // The files the source was compiled from, the script and the modules it imports
+    pub fn files(&self) -> Vec<&str> {
+        let files: BTreeSet<&str> = self
+            .constant_table
+            .prototypes
+            .iter()
+            .filter_map(|proto| proto.debug_info.file())
+            .collect();
+        files.into_iter().collect()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn from_parts(
+        instructions: Vec<Instruction>,
+        imports: HashMap<String, Rc<Chunk>>,
+        debug_info: DebugInfo,
+    ) -> Self {
+        Chunk {
+            instructions,
+            imports,
+            debug_info,
+        }
+    }
+
     #[inline]
-    fn push_instr(&mut self, instr: Instruction) -> CompileResult<()> {
+    pub fn push_instr(&mut self, instr: Instruction, line: usize) -> CompileResult<()> {
         self.instructions.push(instr);
+        self.debug_info.push_line(line);
         Ok(())
     }
 
-    pub fn add_import(&mut self, import: Chunk, name: String, name_index: u8) -> CompileResult<()> {
+    pub fn add_import(
+        &mut self,
+        import: Chunk,
+        name: String,
+        name_index: u8,
+        line: usize,
+    ) -> CompileResult<()> {
         if self.imports.contains_key(&name) {
             panic!("module '{}' is already imported", &name);
         }
         // let name_index = self.add_constant(name.clone().into())?;
-        self.push_instr(Instruction::Import { name_index })?;
-        self.imports.insert(name, import);
+        self.push_instr(Instruction::Import { name_index }, line)?;
+        self.imports.insert(name, Rc::new(import));
         Ok(())
     }
 
-    pub fn push_placeholder(&mut self) -> CompileResult<usize> {
+    pub fn push_placeholder(&mut self, line: usize) -> CompileResult<usize> {
         let index = self.instructions.len();
-        self.push_instr(Instruction::Placeholder)?;
+        self.push_instr(Instruction::Placeholder, line)?;
         Ok(index)
     }
 
@@ -89,12 +153,26 @@ impl Chunk {
         &mut self.instructions
     }
 
-    pub fn take_imports(&mut self) -> HashMap<String, Chunk> {
-        std::mem::replace(&mut self.imports, HashMap::new())
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    pub fn debug_info_mut(&mut self) -> &mut DebugInfo {
+        &mut self.debug_info
+    }
+
+    pub fn take_imports(&mut self) -> HashMap<String, Rc<Chunk>> {
+        core::mem::replace(&mut self.imports, HashMap::new())
+    }
+
+    pub fn imported(&self) -> impl Iterator<Item = (&String, &Chunk)> {
+        self.imports
+            .iter()
+            .map(|(name, chunk)| (name, chunk.as_ref()))
     }
 
-    pub fn imports(&mut self) -> &mut HashMap<String, Chunk> {
-        &mut self.imports
+    pub fn imports(&self) -> &HashMap<String, Rc<Chunk>> {
+        &self.imports
     }
 }
 
@@ -103,6 +181,7 @@ impl Default for Chunk {
         Chunk {
             instructions: Vec::new(),
             imports: HashMap::new(),
+            debug_info: DebugInfo::new(),
         }
     }
 }
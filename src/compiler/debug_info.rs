@@ -0,0 +1,216 @@
+use crate::parser::Statement;
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+/// Maps the instructions of a function prototype or chunk back to the source.
+///
+/// Every instruction has a line, every compiled statement records the
+/// range of instructions it produced, and every named local the range of
+/// instructions it lives in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    // Source the instructions were compiled from
+    file: Option<String>,
+    // The variable a function was declared as and the line of its 'fn', chunks
+    // and anonymous functions have no name
+    name: Option<String>,
+    pub(super) start_line: Option<usize>,
+    lines: Vec<usize>,
+    nodes: Vec<NodeRange>,
+    locals: Vec<LocalRange>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NodeRange {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub kind: NodeKind,
+}
+
+// 'slot' is the offset of the local from the stack top of its call frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalRange {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NodeKind {
+    Expr,
+    Let,
+    Var,
+    Set,
+    Block,
+    If,
+    While,
+    Print,
+    Return,
+    Import,
+    Break,
+    Continue,
+    For,
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(file: Option<String>) -> Self {
+        DebugInfo {
+            file,
+            ..Self::default()
+        }
+    }
+
+    pub fn from_parts(
+        file: Option<String>,
+        lines: Vec<usize>,
+        nodes: Vec<NodeRange>,
+        locals: Vec<LocalRange>,
+    ) -> Self {
+        DebugInfo {
+            file,
+            lines,
+            nodes,
+            locals,
+            ..Self::default()
+        }
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // The line of the 'fn', or of the first instruction for module bodies
+    pub fn start_line(&self) -> Option<usize> {
+        self.start_line.or_else(|| self.line(0))
+    }
+
+    pub(super) fn set_definition(&mut self, name: Option<String>, start_line: Option<usize>) {
+        self.name = name;
+        self.start_line = start_line;
+    }
+
+    pub fn lines(&self) -> &[usize] {
+        &self.lines
+    }
+
+    pub fn line(&self, index: usize) -> Option<usize> {
+        self.lines.get(index).copied()
+    }
+
+    pub fn nodes(&self) -> &[NodeRange] {
+        &self.nodes
+    }
+
+    pub fn locals(&self) -> &[LocalRange] {
+        &self.locals
+    }
+
+    // Locals alive at the instruction, by slot
+    pub fn locals_at(&self, index: usize) -> impl Iterator<Item = &LocalRange> {
+        self.locals
+            .iter()
+            .filter(move |local| local.start <= index && index < local.end)
+    }
+
+    // Innermost statement that produced the instruction
+    #[allow(dead_code)]
+    pub fn node_at(&self, index: usize) -> Option<&NodeRange> {
+        self.nodes
+            .iter()
+            .filter(|node| node.start <= index && index < node.end)
+            .min_by_key(|node| node.end - node.start)
+    }
+
+    // First instruction of the first statement on the line, where a breakpoint would stop
+    #[allow(dead_code)]
+    pub fn first_instruction(&self, line: usize) -> Option<usize> {
+        self.nodes
+            .iter()
+            .filter(|node| node.line == line)
+            .map(|node| node.start)
+            .min()
+    }
+
+    pub(super) fn push_line(&mut self, line: usize) {
+        self.lines.push(line);
+    }
+
+    // Locals are pushed when they go out of scope
+    pub(super) fn push_local(&mut self, name: String, slot: usize, start: usize) {
+        let end = self.lines.len();
+        if start < end {
+            self.locals.push(LocalRange {
+                name,
+                slot,
+                start,
+                end,
+            });
+        }
+    }
+
+    // Statements are pushed after compilation so nested statements come first
+    pub(super) fn push_node(&mut self, kind: NodeKind, start: usize) {
+        let end = self.lines.len();
+        if start == end {
+            return;
+        }
+        let line = self.lines[start..end].iter().copied().min().unwrap();
+        self.nodes.push(NodeRange {
+            start,
+            end,
+            line,
+            kind,
+        });
+    }
+}
+
+impl NodeKind {
+    pub fn of(stmt: &Statement) -> Self {
+        match stmt {
+            Statement::Expr(_) => NodeKind::Expr,
+            Statement::Let { .. } | Statement::Enum { .. } => NodeKind::Let,
+            Statement::Var { .. } => NodeKind::Var,
+            Statement::Set { .. } => NodeKind::Set,
+            Statement::Block(_) => NodeKind::Block,
+            Statement::If { .. } => NodeKind::If,
+            Statement::While { .. } => NodeKind::While,
+            Statement::For { .. } => NodeKind::For,
+            Statement::Print(_) => NodeKind::Print,
+            Statement::Return(_) => NodeKind::Return,
+            Statement::Import { .. } => NodeKind::Import,
+            Statement::Break { .. } => NodeKind::Break,
+            Statement::Continue { .. } => NodeKind::Continue,
+        }
+    }
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NodeKind::Expr => "expr",
+            NodeKind::Let => "let",
+            NodeKind::Var => "var",
+            NodeKind::Set => "set",
+            NodeKind::Block => "block",
+            NodeKind::If => "if",
+            NodeKind::While => "while",
+            NodeKind::For => "for",
+            NodeKind::Print => "print",
+            NodeKind::Return => "return",
+            NodeKind::Import => "import",
+            NodeKind::Break => "break",
+            NodeKind::Continue => "continue",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,94 @@
+use super::{Chunk, CompiledSource, DebugInfo, Instruction};
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+/// Renders the compiled prototypes and chunks, interleaving the lines of
+/// `source` (the root module) with the instructions they produced.
+pub fn disassemble(compiled: &CompiledSource, source: Option<&str>) -> String {
+    let lines: Vec<&str> = source.map(|s| s.lines().collect()).unwrap_or_default();
+    let mut out = String::new();
+    for (i, proto) in compiled.constant_table.prototypes.iter().enumerate() {
+        writeln!(out, "== fn #{} (args: {}) ==", i, proto.args_len).unwrap();
+        disassemble_code(&mut out, &proto.instructions, &proto.debug_info, &lines);
+    }
+    disassemble_chunk(&mut out, "main", &compiled.chunk, &lines);
+    out
+}
+
+fn disassemble_chunk(out: &mut String, name: &str, chunk: &Chunk, lines: &[&str]) {
+    writeln!(out, "== {} ==", name).unwrap();
+    disassemble_code(out, chunk.instructions(), chunk.debug_info(), lines);
+    for (name, import) in chunk.imported() {
+        // Imported modules come from other files
+        disassemble_chunk(out, name, import, &[]);
+    }
+}
+
+fn disassemble_code(
+    out: &mut String,
+    instructions: &[Instruction],
+    debug_info: &DebugInfo,
+    source: &[&str],
+) {
+    let mut last_line = None;
+    for (i, instr) in instructions.iter().enumerate() {
+        let line = debug_info.line(i).unwrap_or(0);
+        if last_line != Some(line) {
+            let text = source.get(line.wrapping_sub(1)).map(|text| text.trim());
+            // Outermost statement starting here
+            let node = debug_info
+                .nodes()
+                .iter()
+                .filter(|node| node.start == i)
+                .max_by_key(|node| node.end);
+            match (text, node) {
+                (Some(text), _) => writeln!(out, "{:>6} | {}", line, text).unwrap(),
+                (None, Some(node)) => writeln!(out, "{:>6} | <{}>", line, node.kind).unwrap(),
+                (None, None) => (),
+            }
+            last_line = Some(line);
+        }
+        writeln!(out, "{:04} {:>5}  {:?}", i, line, instr).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+
+    fn compile(source: &str) -> CompiledSource {
+        let ast = Parser::parse_str(source).unwrap();
+        Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn statements_map_to_lines() {
+        let source = "let x = 1;\nlet y = 2;\nprintln(x + y);\n";
+        let compiled = compile(source);
+        let body = &compiled.constant_table.prototypes[0].debug_info;
+        let lines: Vec<usize> = body.nodes().iter().map(|node| node.line).collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+        assert_eq!(body.first_instruction(2), Some(1));
+        assert_eq!(
+            body.node_at(1).unwrap().kind,
+            crate::compiler::NodeKind::Let
+        );
+    }
+
+    #[test]
+    fn disassembly_interleaves_source() {
+        let source = "let x = 1;\nprintln(x);\n";
+        let text = disassemble(&compile(source), Some(source));
+        let first = text.find("     1 | let x = 1;").unwrap();
+        let second = text.find("     2 | println(x);").unwrap();
+        assert!(first < second);
+        assert!(text.contains("0000     1  Integer(1)"));
+    }
+}
@@ -1,23 +1,64 @@
-use crate::compiler::Instruction;
+use crate::compiler::{BytecodeError, Instruction, VerifyError};
 use crate::parser::{Expr, ParserError};
-use std::io;
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+// Why a file could not be read or written
+#[cfg(feature = "std")]
+pub use std::io::ErrorKind as IoErrorKind;
+
+// Without std there are no files
+#[cfg(not(feature = "std"))]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IoErrorKind {
+    Unsupported,
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum CompileError {
     TooManyConstants,
-    UnimplementedExpr(Expr),
+    UnimplementedExpr(Box<Expr>),
     UndefinedVariable {
         name: String,
     },
-    InvalidAssignmentTarget(Expr),
+    DuplicateLocal {
+        name: String,
+    },
+    InvalidAssignmentTarget(Box<Expr>),
     WrongPatch(Instruction),
     TooLongToJump,
     Parse(ParserError),
-    IoError(io::ErrorKind),
+    IoError(IoErrorKind),
+    Bytecode(BytecodeError),
+    Verify(VerifyError),
     ModuleError {
         name: String,
         error: Box<CompileError>,
     },
+    // The tree nests deeper than the limit in its metadata
+    TooDeep,
+    // Only the body of a function can be resumed
+    YieldOutsideFunction {
+        line: usize,
+    },
+    // 'break' or 'continue' without a loop around it in the same function
+    OutsideLoop {
+        keyword: String,
+        line: usize,
+    },
+    // The values an expression keeps on the stack would stay after the jump
+    LeaveExpression {
+        keyword: String,
+        line: usize,
+    },
+    // Enums are constant, see Compiler::enum_stmt
+    AssignToEnum {
+        name: String,
+    },
+    UnknownVariant {
+        name: String,
+        variant: String,
+        variants: Vec<String>,
+    },
 }
 
 impl From<ParserError> for CompileError {
@@ -25,3 +66,9 @@ impl From<ParserError> for CompileError {
         CompileError::Parse(pe)
     }
 }
+
+impl From<VerifyError> for CompileError {
+    fn from(error: VerifyError) -> Self {
+        CompileError::Verify(error)
+    }
+}
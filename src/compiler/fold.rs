@@ -0,0 +1,132 @@
+use crate::parser::{BinaryOp, Expr, Literal, UnaryOp};
+use crate::vm::{Integer, Table, Value};
+use alloc::{boxed::Box, vec::Vec};
+
+/// Merges the string literals of a `+` chain, `"a" + "b" + x` becomes `"ab" + x`.
+pub fn concat(left: Expr, right: Expr) -> Expr {
+    let left = fold_concat(left);
+    let right = fold_concat(right);
+    match (left, right) {
+        (Expr::Literal(Literal::Str(a)), Expr::Literal(Literal::Str(b))) => {
+            Expr::Literal(Literal::Str(a + &b))
+        }
+        // (x + "a") + "b" fails for the same values as x + "ab"
+        (
+            Expr::Binary {
+                left,
+                op: BinaryOp::Plus,
+                right: inner,
+            },
+            Expr::Literal(Literal::Str(b)),
+        ) if is_str(&inner) => match *inner {
+            Expr::Literal(Literal::Str(a)) => Expr::Binary {
+                left,
+                op: BinaryOp::Plus,
+                right: Box::new(Expr::Literal(Literal::Str(a + &b))),
+            },
+            _ => unreachable!(),
+        },
+        (left, right) => Expr::Binary {
+            left: Box::new(left),
+            op: BinaryOp::Plus,
+            right: Box::new(right),
+        },
+    }
+}
+
+fn fold_concat(expr: Expr) -> Expr {
+    match expr {
+        Expr::Binary {
+            left,
+            op: BinaryOp::Plus,
+            right,
+        } => concat(*left, *right),
+        expr => expr,
+    }
+}
+
+#[inline]
+fn is_str(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(Literal::Str(_)))
+}
+
+/// Builds the table of an initializer made of scalar literals only, so it can be
+/// stored once as a constant and cloned at runtime.
+pub fn table_template(keys: Option<&[Expr]>, values: &[Expr]) -> Option<Table> {
+    if values.is_empty() {
+        return None;
+    }
+    let values = values
+        .iter()
+        .map(literal_value)
+        .collect::<Option<Vec<Value>>>()?;
+    match keys {
+        Some(keys) => {
+            let keys = keys
+                .iter()
+                .map(literal_value)
+                .collect::<Option<Vec<Value>>>()?;
+            let mut table = Table::new();
+            // Same order as InitTable, which pops the pairs from the last one
+            for (key, value) in keys.into_iter().zip(values).rev() {
+                table.set(key, value);
+            }
+            Some(table)
+        }
+        None => Some(Table::from_array(
+            values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| (Value::Int(i as Integer), value))
+                .collect(),
+        )),
+    }
+}
+
+/// The literal `-expr` evaluates to when `expr` is a number literal in parentheses,
+/// so `-(5)` is the constant -5 rather than 5 negated at runtime. The parser already
+/// reads a minus right before a number as part of the literal.
+pub fn negate(expr: &Expr) -> Option<Literal> {
+    match expr {
+        Expr::Literal(Literal::Int(i)) => i.checked_neg().map(Literal::Int),
+        Expr::Literal(Literal::Float(n)) => Some(Literal::Float(-n)),
+        Expr::Grouping(expr) => negate(expr),
+        _ => None,
+    }
+}
+
+/// The value of an expression made of literals only, after folding string
+/// concatenations and negations, like a table key `"a" + "b"` or `-1`.
+pub fn constant(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Binary {
+            left,
+            op: BinaryOp::Plus,
+            right,
+        } => match concat(*left.clone(), *right.clone()) {
+            folded @ Expr::Literal(_) => literal_value(&folded),
+            _ => None,
+        },
+        Expr::Grouping(expr) => constant(expr),
+        expr => literal_value(expr),
+    }
+}
+
+fn literal_value(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Unary {
+            op: UnaryOp::Minus,
+            expr,
+        } => literal_value(&Expr::Literal(negate(expr)?)),
+        Expr::Literal(lit) => Some(match lit {
+            Literal::Nil => Value::Nil,
+            Literal::Unit => Value::Unit,
+            Literal::Bool(b) => Value::Bool(*b),
+            Literal::Char(c) => Value::Int(*c as Integer),
+            Literal::Int(i) => Value::Int(*i),
+            Literal::Float(n) => Value::Number(*n),
+            Literal::Str(string) => Value::new_str(string.as_str()),
+        }),
+        _ => None,
+    }
+}
@@ -70,6 +70,10 @@ pub enum Instruction {
         len: u16,
         has_keys: bool,
     },
+    /* Push a copy of the constant table built from a literal initializer */
+    TableTemplate {
+        index: u8,
+    },
     /* Pop value if truth value matches with 'when_true' then branch */
     JumpIf {
         when_true: bool,
@@ -82,6 +86,8 @@ pub enum Instruction {
     /* Placeholder for patching jumps */
     Placeholder,
     Print,
+    /* Pop value and print it unless it is unit, for expression statements in the repl */
+    Echo,
     FuncDef {
         // TODO: make u32
         proto_index: u32,
@@ -110,6 +116,77 @@ pub enum Instruction {
     },
     // rercursive call
     Rec,
+    // Pop the value and hand it to the caller resuming the generator
+    Yield,
+    // 'value as ty', the constant is the type, see Value::cast
+    Cast {
+        index: u8,
+    },
+    /* Superinstructions, see peephole::fuse. They run the instructions following them too */
+    /* GetLocal a, GetLocal b, Bin(op) */
+    BinLocals {
+        op: BinaryInstr,
+        a: u16,
+        b: u16,
+        frame: u8,
+    },
+    /* GetLocal index, Integer(value), Bin(op) */
+    BinLocalInt {
+        op: BinaryInstr,
+        index: u16,
+        frame: u8,
+        value: i16,
+    },
+    /* GetGlobal index, Call args_len */
+    CallGlobal {
+        index: u8,
+        args_len: u8,
+    },
+    /* GetFieldImm index, Call args_len */
+    CallFieldImm {
+        index: u8,
+        args_len: u8,
+    },
+    /* Bin(op) when an operand is a literal of the type, falls back to Bin(op) for others */
+    AddInt,
+    SubInt,
+    LtInt,
+    AddFloat,
+    SubFloat,
+    LtFloat,
+}
+
+impl Instruction {
+    /* The operator of Bin and of the instructions specialized from it */
+    pub fn bin_op(self) -> Option<BinaryInstr> {
+        use Instruction::*;
+        match self {
+            Bin(op) => Some(op),
+            AddInt | AddFloat => Some(BinaryInstr::Add),
+            SubInt | SubFloat => Some(BinaryInstr::Sub),
+            LtInt | LtFloat => Some(BinaryInstr::Lt),
+            _ => None,
+        }
+    }
+
+    /* Bin(op) specialized for integer operands if there is an instruction for it */
+    pub fn int_bin(op: BinaryInstr) -> Instruction {
+        match op {
+            BinaryInstr::Add => Instruction::AddInt,
+            BinaryInstr::Sub => Instruction::SubInt,
+            BinaryInstr::Lt => Instruction::LtInt,
+            op => Instruction::Bin(op),
+        }
+    }
+
+    pub fn float_bin(op: BinaryInstr) -> Instruction {
+        match op {
+            BinaryInstr::Add => Instruction::AddFloat,
+            BinaryInstr::Sub => Instruction::SubFloat,
+            BinaryInstr::Lt => Instruction::LtFloat,
+            op => Instruction::Bin(op),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
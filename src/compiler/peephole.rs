@@ -0,0 +1,126 @@
+use super::Instruction;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+/// Fuses common instruction sequences into superinstructions.
+///
+/// Only the first instruction of a sequence is replaced, the rest stay in place
+/// and the vm skips over them. Jumps and debug info keep pointing at the same
+/// instructions, and a jump into the middle of a sequence still runs the rest of it.
+pub fn fuse(instructions: &mut [Instruction]) {
+    let mut i = 0;
+    while i < instructions.len() {
+        match superinstruction(&instructions[i..]) {
+            Some((fused, len)) => {
+                instructions[i] = fused;
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+}
+
+// The superinstruction starting the code and the number of instructions it runs
+fn superinstruction(code: &[Instruction]) -> Option<(Instruction, usize)> {
+    use Instruction::*;
+    match *code {
+        [GetLocal { index: a, frame }, GetLocal {
+            index: b,
+            frame: frame_b,
+        }, Bin(op), ..]
+            if frame == frame_b =>
+        {
+            Some((BinLocals { op, a, b, frame }, 3))
+        }
+        // The compiler specializes the operator for the integer operand
+        [GetLocal { index, frame }, Integer(value), bin, ..]
+            if bin.bin_op().map(Instruction::int_bin) == Some(bin) =>
+        {
+            let op = bin.bin_op()?;
+            let value = i16::try_from(value).ok()?;
+            Some((
+                BinLocalInt {
+                    op,
+                    index,
+                    frame,
+                    value,
+                },
+                3,
+            ))
+        }
+        [GetGlobal { index }, Call { args_len }, ..] => Some((CallGlobal { index, args_len }, 2)),
+        [GetFieldImm { index }, Call { args_len }, ..] => {
+            Some((CallFieldImm { index, args_len }, 2))
+        }
+        _ => None,
+    }
+}
+
+/// The instructions a superinstruction stands for, None for the others.
+pub fn unfuse(instr: Instruction) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+    Some(match instr {
+        BinLocals { op, a, b, frame } => vec![
+            GetLocal { index: a, frame },
+            GetLocal { index: b, frame },
+            Bin(op),
+        ],
+        BinLocalInt {
+            op,
+            index,
+            frame,
+            value,
+        } => vec![
+            GetLocal { index, frame },
+            Integer(value as i32),
+            Instruction::int_bin(op),
+        ],
+        CallGlobal { index, args_len } => vec![GetGlobal { index }, Call { args_len }],
+        CallFieldImm { index, args_len } => vec![GetFieldImm { index }, Call { args_len }],
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::BinaryInstr;
+
+    #[test]
+    fn fuses_only_the_first_instruction() {
+        use Instruction::*;
+        let original = vec![
+            GetLocal { index: 0, frame: 1 },
+            GetLocal { index: 1, frame: 1 },
+            Bin(BinaryInstr::Add),
+            GetLocal { index: 0, frame: 1 },
+            Integer(100_000),
+            LtInt,
+            GetGlobal { index: 2 },
+            Call { args_len: 1 },
+        ];
+        let mut code = original.clone();
+        fuse(&mut code);
+        assert_eq!(
+            code[0],
+            BinLocals {
+                op: BinaryInstr::Add,
+                a: 0,
+                b: 1,
+                frame: 1
+            }
+        );
+        // Too big for the fused operand
+        assert_eq!(code[3], original[3]);
+        assert_eq!(
+            code[6],
+            CallGlobal {
+                index: 2,
+                args_len: 1
+            }
+        );
+        assert_eq!(code[1..3], original[1..3]);
+        assert_eq!(unfuse(code[0]).unwrap(), original[0..3]);
+        assert_eq!(unfuse(code[6]).unwrap(), original[6..8]);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::parser::{Ast, BlockExpr, Expr, Statement, ITERATOR_NAME, RANGE_START_NAME};
+use crate::vm::{PREDEFINED_CONSTANTS, PREDEFINED_GLOBALS};
+use alloc::{string::String, string::ToString, vec::Vec};
+use hashbrown::HashMap;
+use serde::Serialize;
+
+// Name resolution for tooling. It mirrors the analyzer and the compiler so that every
+// identifier gets the binding it will have at runtime. Expects the ast before analysis
+// (see Parser::parse_syntax) since the analyzer rewrites captured variables.
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum Binding {
+    // Stack slot relative to the frame of the function
+    Local { slot: u16 },
+    // Captured by the closure through its env table
+    Upvalue,
+    Global,
+    // The function refers to itself
+    Recursive,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum DeclarationKind {
+    Let,
+    Var,
+    Argument,
+    Import,
+    Enum,
+    Native,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Declaration {
+    pub name: String,
+    pub line: usize,
+    // Columns of the name like in scanner::Span, the end is exclusive. Natives have 0
+    pub column: usize,
+    pub end_column: usize,
+    pub kind: DeclarationKind,
+    pub binding: Binding,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Reference {
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+    pub end_column: usize,
+    pub binding: Binding,
+    // Index to SymbolTable::declarations, None if the name is never declared
+    pub declaration: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct SymbolTable {
+    pub declarations: Vec<Declaration>,
+    pub references: Vec<Reference>,
+}
+
+impl SymbolTable {
+    #[allow(dead_code)]
+    pub fn definition(&self, reference: &Reference) -> Option<&Declaration> {
+        reference.declaration.map(|i| &self.declarations[i])
+    }
+
+    #[allow(dead_code)]
+    pub fn references_to(&self, declaration: usize) -> impl Iterator<Item = &Reference> {
+        self.references
+            .iter()
+            .filter(move |r| r.declaration == Some(declaration))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("SymbolTable is always serializable")
+    }
+}
+
+pub fn resolve(ast: &Ast) -> SymbolTable {
+    let mut resolver = Resolver::new();
+    resolver.top_level(ast.expr());
+    resolver.table
+}
+
+struct Local {
+    name: String,
+    depth: u8,
+    declaration: usize,
+}
+
+struct FunctionScope {
+    // Name of the function if it is bound by a let, used for recursion
+    name: Option<String>,
+    local_start: usize,
+    args_len: usize,
+    captures: bool,
+    // Values left on the stack by the expressions being resolved, see Compiler::above
+    temps: usize,
+    // Declarations and references that are slots of this function
+    slots: Vec<Slot>,
+}
+
+enum Slot {
+    Declaration(usize),
+    Reference(usize),
+}
+
+struct Resolver {
+    table: SymbolTable,
+    locals: Vec<Local>,
+    functions: Vec<FunctionScope>,
+    globals: HashMap<String, usize>,
+    // Natives and imports are globals that closures capture like locals of the top level
+    predefined: HashMap<String, usize>,
+    depth: u8,
+}
+
+impl Resolver {
+    fn new() -> Self {
+        let mut resolver = Resolver {
+            table: SymbolTable::default(),
+            locals: Vec::new(),
+            functions: Vec::new(),
+            globals: HashMap::new(),
+            predefined: HashMap::new(),
+            depth: 0,
+        };
+        for (name, _) in PREDEFINED_CONSTANTS.iter() {
+            let index = resolver.declare(name, (0, 0), DeclarationKind::Native, Binding::Global);
+            resolver.predefined.insert(name.to_string(), index);
+        }
+        for name in PREDEFINED_GLOBALS.iter() {
+            let index = resolver.declare(name, (0, 0), DeclarationKind::Native, Binding::Global);
+            resolver.globals.insert(name.to_string(), index);
+        }
+        resolver
+    }
+
+    fn top_level(&mut self, body: &BlockExpr) {
+        self.enter_function(None, &[], &[]);
+        self.block_body(body);
+        self.exit_function();
+    }
+
+    fn block_body(&mut self, block: &BlockExpr) {
+        for stmt in block.stmts.iter() {
+            self.stmt(stmt);
+        }
+        self.expr(&block.expr, None);
+    }
+
+    fn stmt(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Expr(expr) | Statement::Print(expr) | Statement::Return(expr) => {
+                self.expr(expr, None)
+            }
+            Statement::Let {
+                name,
+                value,
+                line,
+                column,
+                ..
+            } => match value {
+                Expr::Function { .. } => {
+                    self.add_local(name, (*line, *column), DeclarationKind::Let);
+                    self.expr(value, Some(name.clone()));
+                }
+                _ => {
+                    self.expr(value, None);
+                    self.add_local(name, (*line, *column), DeclarationKind::Let);
+                }
+            },
+            Statement::Var {
+                name,
+                value,
+                line,
+                column,
+                ..
+            } => {
+                self.expr(value, None);
+                let position = (*line, *column);
+                let index = self.declare(name, position, DeclarationKind::Var, Binding::Global);
+                self.globals.insert(name.clone(), index);
+            }
+            Statement::Set { variable, value } => match variable {
+                Expr::Access { table, field } => self.operands([value, &**field, &**table]),
+                _ => {
+                    self.expr(value, None);
+                    self.expr(variable, None);
+                }
+            },
+            Statement::Block(stmts) => {
+                self.depth += 1;
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.exit_scope();
+            }
+            Statement::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition, None);
+                self.expr(then_block, None);
+                if let Some(else_block) = else_block {
+                    self.expr(else_block, None);
+                }
+            }
+            Statement::While {
+                condition,
+                then_block,
+                step,
+            } => {
+                self.expr(condition, None);
+                self.stmt(then_block);
+                if let Some(step) = step {
+                    self.stmt(step);
+                }
+            }
+            // The hidden locals of the start and the end take the slots before the variable
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                line,
+                column,
+            } => {
+                self.depth += 1;
+                let position = (*line, *column);
+                self.expr(start, None);
+                self.add_local(RANGE_START_NAME, position, DeclarationKind::Let);
+                self.expr(end, None);
+                self.add_local(ITERATOR_NAME, position, DeclarationKind::Let);
+                self.add_local(variable, position, DeclarationKind::Let);
+                self.stmt(body);
+                self.exit_scope();
+            }
+            Statement::Break { .. } | Statement::Continue { .. } => (),
+            Statement::Import {
+                name, line, column, ..
+            } => {
+                let position = (*line, *column);
+                let index = self.declare(name, position, DeclarationKind::Import, Binding::Global);
+                self.predefined.insert(name.clone(), index);
+            }
+            Statement::Enum {
+                name, line, column, ..
+            } => self.add_local(name, (*line, *column), DeclarationKind::Enum),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, func_name: Option<String>) {
+        match expr {
+            Expr::Literal(_) | Expr::Rec => (),
+            Expr::Identifier { name, line, column } => self.reference(name, (*line, *column)),
+            Expr::Unary { expr, .. }
+            | Expr::Grouping(expr)
+            | Expr::Yield(expr)
+            | Expr::Cast { expr, .. } => self.expr(expr, None),
+            Expr::Binary { left, right, .. } => self.operands([&**left, &**right]),
+            Expr::Tuple(exprs) => self.operands(exprs),
+            Expr::Access { table, field } => {
+                self.expr(table, None);
+                self.above(1, field);
+            }
+            Expr::SelfAccess { table, args, .. } => {
+                self.operands(core::iter::once(&**table).chain(args))
+            }
+            Expr::TableInit { keys, values } => match keys {
+                Some(keys) => {
+                    let pairs = keys.iter().zip(values);
+                    self.operands(pairs.flat_map(|(k, v)| [k, v]))
+                }
+                None => self.operands(values.iter().rev()),
+            },
+            Expr::Function {
+                args,
+                arg_positions,
+                body,
+                ..
+            } => {
+                self.enter_function(func_name, args, arg_positions);
+                self.block_body(body);
+                self.exit_function();
+            }
+            Expr::Call { func, args } => {
+                self.operands(args.iter().chain(core::iter::once(&**func)))
+            }
+            Expr::Block(block) => {
+                self.depth += 1;
+                self.block_body(block);
+                self.exit_scope();
+            }
+            Expr::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition, None);
+                self.expr(then_block, None);
+                self.expr(else_block, None);
+            }
+        }
+    }
+
+    // Each expression is resolved above the values of the ones before it, like
+    // Compiler::operands
+    fn operands<'e>(&mut self, exprs: impl IntoIterator<Item = &'e Expr>) {
+        for (i, expr) in exprs.into_iter().enumerate() {
+            self.above(i, expr);
+        }
+    }
+
+    fn above(&mut self, count: usize, expr: &Expr) {
+        self.current_function().temps += count;
+        self.expr(expr, None);
+        self.current_function().temps -= count;
+    }
+
+    // Positions are the line and column of the name token
+    fn reference(&mut self, name: &str, (line, column): (usize, usize)) {
+        let is_rec = self.functions.last().and_then(|f| f.name.as_deref()) == Some(name);
+        let local = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, l)| l.name == name)
+            .map(|(i, l)| (i, l.declaration));
+        let (binding, declaration) = if is_rec {
+            (Binding::Recursive, local.map(|(_, d)| d))
+        } else if let Some((index, declaration)) = local {
+            let owner = self.owner_of(index);
+            if owner == self.functions.len() - 1 {
+                (
+                    self.table.declarations[declaration].binding,
+                    Some(declaration),
+                )
+            } else {
+                for function in self.functions.iter_mut().skip(owner + 1) {
+                    function.captures = true;
+                }
+                (Binding::Upvalue, Some(declaration))
+            }
+        } else if let Some(&declaration) = self.predefined.get(name) {
+            if self.functions.len() > 1 {
+                for function in self.functions.iter_mut().skip(1) {
+                    function.captures = true;
+                }
+                (Binding::Upvalue, Some(declaration))
+            } else {
+                (Binding::Global, Some(declaration))
+            }
+        } else {
+            (Binding::Global, self.globals.get(name).copied())
+        };
+        self.table.references.push(Reference {
+            name: name.to_string(),
+            line,
+            column,
+            end_column: end_column(name, column),
+            binding,
+            declaration,
+        });
+        if let Binding::Local { .. } = binding {
+            let index = self.table.references.len() - 1;
+            self.current_function().slots.push(Slot::Reference(index));
+        }
+    }
+
+    // Index of the function that the local belongs to
+    fn owner_of(&self, local: usize) -> usize {
+        self.functions
+            .iter()
+            .rposition(|f| f.local_start <= local)
+            .unwrap_or(0)
+    }
+
+    fn add_local(&mut self, name: &str, position: (usize, usize), kind: DeclarationKind) {
+        let len = self.locals.len();
+        let function = self.current_function();
+        let slot = (len - function.local_start + function.temps) as u16;
+        let index = self.declare(name, position, kind, Binding::Local { slot });
+        self.current_function().slots.push(Slot::Declaration(index));
+        self.locals.push(Local {
+            name: name.to_string(),
+            depth: self.depth,
+            declaration: index,
+        });
+    }
+
+    fn declare(
+        &mut self,
+        name: &str,
+        (line, column): (usize, usize),
+        kind: DeclarationKind,
+        binding: Binding,
+    ) -> usize {
+        self.table.declarations.push(Declaration {
+            name: name.to_string(),
+            line,
+            column,
+            end_column: end_column(name, column),
+            kind,
+            binding,
+        });
+        self.table.declarations.len() - 1
+    }
+
+    fn enter_function(
+        &mut self,
+        name: Option<String>,
+        args: &[String],
+        positions: &[(usize, usize)],
+    ) {
+        self.depth += 1;
+        self.functions.push(FunctionScope {
+            name,
+            local_start: self.locals.len(),
+            args_len: args.len(),
+            captures: false,
+            temps: 0,
+            slots: Vec::new(),
+        });
+        for (arg, position) in args.iter().zip(positions) {
+            self.add_local(arg, *position, DeclarationKind::Argument);
+        }
+    }
+
+    fn exit_function(&mut self) {
+        self.exit_scope();
+        let function = self.functions.pop().expect("Expected a function scope");
+        // Env table is passed after the arguments so it shifts the rest of the locals
+        if function.captures {
+            for slot in function.slots {
+                let binding = match slot {
+                    Slot::Declaration(i) => &mut self.table.declarations[i].binding,
+                    Slot::Reference(i) => &mut self.table.references[i].binding,
+                };
+                if let Binding::Local { slot } = binding {
+                    if *slot as usize >= function.args_len {
+                        *slot += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn exit_scope(&mut self) {
+        self.depth -= 1;
+        while self.locals.last().is_some_and(|l| l.depth > self.depth) {
+            self.locals.pop();
+        }
+    }
+
+    fn current_function(&mut self) -> &mut FunctionScope {
+        self.functions
+            .last_mut()
+            .expect("Expected a function scope")
+    }
+}
+
+fn end_column(name: &str, column: usize) -> usize {
+    if column == 0 {
+        0
+    } else {
+        column + name.chars().count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn symbols(source: &str) -> SymbolTable {
+        let mut parser = Parser::new(source);
+        resolve(&parser.parse_syntax().unwrap())
+    }
+
+    fn reference<'a>(table: &'a SymbolTable, name: &str) -> &'a Reference {
+        table.references.iter().find(|r| r.name == name).unwrap()
+    }
+
+    #[test]
+    fn locals_and_globals() {
+        let table = symbols(
+            "
+            let a = 1;
+            var b = 2;
+            let c = a + b;
+            println(c);
+            ",
+        );
+        let a = reference(&table, "a");
+        assert_eq!(a.binding, Binding::Local { slot: 0 });
+        assert_eq!(table.definition(a).unwrap().line, 2);
+        assert_eq!(reference(&table, "b").binding, Binding::Global);
+        assert_eq!(reference(&table, "c").binding, Binding::Local { slot: 1 });
+        let println = reference(&table, "println");
+        assert_eq!(
+            table.definition(println).unwrap().kind,
+            DeclarationKind::Native
+        );
+    }
+
+    #[test]
+    fn upvalues_shift_locals() {
+        let table = symbols(
+            "
+            let x = 1;
+            let f = fn(y)
+                let z = x + y;
+                z
+            end;
+            ",
+        );
+        assert_eq!(reference(&table, "x").binding, Binding::Upvalue);
+        assert_eq!(reference(&table, "y").binding, Binding::Local { slot: 0 });
+        // Slot 1 is the env table
+        assert_eq!(reference(&table, "z").binding, Binding::Local { slot: 2 });
+    }
+
+    #[test]
+    fn block_operands_shift_locals() {
+        let table = symbols(
+            "
+            let a = 10;
+            let sum = a + do let c = 2; c * 3 end;
+            let t = { 1, do let d = 4; d end };
+            ",
+        );
+        // 'a' is on the stack below 'c'
+        assert_eq!(reference(&table, "c").binding, Binding::Local { slot: 2 });
+        // The list is compiled last to first so the block is the first operand
+        assert_eq!(reference(&table, "d").binding, Binding::Local { slot: 2 });
+        assert_eq!(reference(&table, "a").binding, Binding::Local { slot: 0 });
+    }
+
+    #[test]
+    fn recursion_and_scopes() {
+        let table = symbols(
+            "
+            let fib = fn(n)
+                if n <= 1 then n else fib(n - 1) end
+            end;
+            do
+                let fib = 5;
+                fib
+            end
+            ",
+        );
+        let recursive: Vec<_> = table
+            .references
+            .iter()
+            .filter(|r| r.name == "fib")
+            .map(|r| (r.binding, r.declaration))
+            .collect();
+        assert_eq!(
+            recursive,
+            vec![
+                (Binding::Recursive, Some(table.declarations.len() - 3)),
+                (
+                    Binding::Local { slot: 1 },
+                    Some(table.declarations.len() - 1)
+                ),
+            ]
+        );
+        assert_eq!(table.references_to(table.declarations.len() - 1).count(), 1);
+    }
+
+    #[test]
+    fn for_loops_declare_their_variable() {
+        let table = symbols("let n = 3; for i in 0..n do println(i); end");
+        let i = reference(&table, "i");
+        // After 'n' and the hidden start and end
+        assert_eq!(i.binding, Binding::Local { slot: 3 });
+        let declaration = table.definition(i).unwrap();
+        assert_eq!((declaration.line, declaration.column), (1, 16));
+        assert_eq!(table.references.iter().filter(|r| r.name == "i").count(), 1);
+    }
+
+    #[test]
+    fn columns_come_from_tokens() {
+        let table = symbols("let x = 1; let f = fn(x) x end; f(x)");
+        let columns: Vec<_> = table
+            .references
+            .iter()
+            .map(|r| (r.name.as_str(), r.column, r.end_column))
+            .collect();
+        assert_eq!(columns, vec![("x", 26, 27), ("x", 35, 36), ("f", 33, 34)]);
+        let argument = table.definition(&table.references[0]).unwrap();
+        assert_eq!(argument.kind, DeclarationKind::Argument);
+        assert_eq!((argument.column, argument.end_column), (23, 24));
+        let x = table.definition(&table.references[1]).unwrap();
+        assert_eq!((x.line, x.column, x.end_column), (1, 5, 6));
+    }
+}
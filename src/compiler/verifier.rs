@@ -0,0 +1,523 @@
+use super::{peephole, Chunk, CompiledSource, Instruction};
+use crate::vm::{FuncProtoRef, Value};
+use alloc::vec::Vec;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    pub kind: VerifyErrorKind,
+    // Prototype index, None for a chunk
+    pub function: Option<usize>,
+    pub index: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyErrorKind {
+    JumpOutOfBounds { target: isize },
+    ConstantOutOfBounds { index: u8 },
+    ExpectedTableConstant { index: u8 },
+    PrototypeOutOfBounds { index: u32 },
+    LocalOutOfBounds { index: u16 },
+    // A local of another frame than the one running the code
+    FrameOutOfBounds { frame: u8 },
+    UnknownImport,
+    UnsupportedInstruction(Instruction),
+    // A superinstruction not followed by the rest of the instructions it runs
+    BrokenSuperinstruction,
+    StackUnderflow,
+    // Two paths reach the same instruction with different stack depths
+    StackMismatch { expected: usize, found: usize },
+    MissingReturn,
+}
+
+/// Checks that a compiled source can be executed without indexing out of
+/// bounds, before handing it to the vm.
+pub fn verify(compiled: &CompiledSource) -> Result<(), VerifyError> {
+    let table = &compiled.constant_table;
+    for (i, proto) in table.prototypes.iter().enumerate() {
+        let locals = proto.args_len as usize + has_env(compiled, i) as usize;
+        Verifier::new(compiled, Some(i), &proto.instructions, locals).verify()?;
+    }
+    verify_chunk(compiled, &compiled.chunk)
+}
+
+fn verify_chunk(compiled: &CompiledSource, chunk: &Chunk) -> Result<(), VerifyError> {
+    Verifier::new(compiled, None, chunk.instructions(), 0).verify()?;
+    for (name, import) in chunk.imported() {
+        let imported = chunk.instructions().iter().any(|instr| match instr {
+            Instruction::Import { name_index } => {
+                compiled.constant_table.constants.get(*name_index as usize)
+                    == Some(&Value::new_str(name.as_str()))
+            }
+            _ => false,
+        });
+        if imported {
+            verify_chunk(compiled, import)?;
+        }
+    }
+    Ok(())
+}
+
+// Env tables are passed as an extra local after the arguments
+fn has_env(compiled: &CompiledSource, proto_index: usize) -> bool {
+    let is_env_def = |instr: &Instruction| match instr {
+        Instruction::FuncDef {
+            proto_index: index,
+            has_env,
+        } => *has_env && *index as usize == proto_index,
+        _ => false,
+    };
+    let table = &compiled.constant_table;
+    table
+        .prototypes
+        .iter()
+        .any(|proto: &FuncProtoRef| proto.instructions.iter().any(is_env_def))
+        || compiled.chunk.instructions().iter().any(is_env_def)
+}
+
+/// The most values the code keeps on the stack above its locals, along any path.
+/// Malformed code is measured as far as it can be, `verify` rejects it.
+pub fn max_stack(instructions: &[Instruction]) -> usize {
+    let mut seen = vec![false; instructions.len()];
+    let mut pending = vec![(0, 0usize)];
+    let mut max = 0;
+    while let Some((i, depth)) = pending.pop() {
+        if i >= instructions.len() || seen[i] {
+            continue;
+        }
+        seen[i] = true;
+        let instr = peephole::unfuse(instructions[i]).map_or(instructions[i], |code| code[0]);
+        let (pops, pushes) = Verifier::stack_effect(instr);
+        let depth = depth.saturating_sub(pops) + pushes;
+        max = max.max(depth);
+        match instr {
+            Instruction::Return { .. } => (),
+            Instruction::Jump { offset } => {
+                pending.push(((i as isize + offset as isize) as usize, depth))
+            }
+            Instruction::JumpIf { offset, .. } => {
+                pending.push(((i as isize + offset as isize) as usize, depth));
+                pending.push((i + 1, depth));
+            }
+            _ => pending.push((i + 1, depth)),
+        }
+    }
+    max
+}
+
+struct Verifier<'a> {
+    compiled: &'a CompiledSource,
+    function: Option<usize>,
+    instructions: &'a [Instruction],
+    locals: usize,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(
+        compiled: &'a CompiledSource,
+        function: Option<usize>,
+        instructions: &'a [Instruction],
+        locals: usize,
+    ) -> Self {
+        Verifier {
+            compiled,
+            function,
+            instructions,
+            locals,
+        }
+    }
+
+    fn error(&self, kind: VerifyErrorKind, index: usize) -> VerifyError {
+        VerifyError {
+            kind,
+            function: self.function,
+            index,
+        }
+    }
+
+    fn verify(&self) -> Result<(), VerifyError> {
+        for (i, instr) in self.instructions.iter().enumerate() {
+            self.verify_operands(i, *instr)?;
+        }
+        self.verify_stack()
+    }
+
+    fn verify_operands(&self, i: usize, instr: Instruction) -> Result<(), VerifyError> {
+        use Instruction::*;
+        let constants = &self.compiled.constant_table.constants;
+        let prototypes = &self.compiled.constant_table.prototypes;
+        let error = match instr {
+            Constant { index }
+            | SetGlobal { index }
+            | GetGlobal { index }
+            | GetFieldImm { index }
+            | SetFieldImm { index }
+            | GetMethodImm { index, .. }
+            | CallGlobal { index, .. }
+            | CallFieldImm { index, .. }
+            | Cast { index }
+                if index as usize >= constants.len() =>
+            {
+                Some(VerifyErrorKind::ConstantOutOfBounds { index })
+            }
+            TableTemplate { index } => match constants.get(index as usize) {
+                Some(Value::Table(_)) => None,
+                Some(_) => Some(VerifyErrorKind::ExpectedTableConstant { index }),
+                None => Some(VerifyErrorKind::ConstantOutOfBounds { index }),
+            },
+            Import { name_index } => match constants.get(name_index as usize) {
+                Some(Value::Str(_)) => None,
+                Some(_) => Some(VerifyErrorKind::UnknownImport),
+                None => Some(VerifyErrorKind::ConstantOutOfBounds { index: name_index }),
+            },
+            FuncDef { proto_index, .. } if proto_index as usize >= prototypes.len() => {
+                Some(VerifyErrorKind::PrototypeOutOfBounds { index: proto_index })
+            }
+            Jump { offset } | JumpIf { offset, .. } => {
+                let target = i as isize + offset as isize;
+                if target < 0 || target as usize >= self.instructions.len() {
+                    Some(VerifyErrorKind::JumpOutOfBounds { target })
+                } else {
+                    None
+                }
+            }
+            Placeholder | GetUpval { .. } | SetUpval { .. } | CloseUpval { .. } => {
+                Some(VerifyErrorKind::UnsupportedInstruction(instr))
+            }
+            _ => match peephole::unfuse(instr) {
+                Some(code) if self.instructions.get(i + 1..i + code.len()) != Some(&code[1..]) => {
+                    Some(VerifyErrorKind::BrokenSuperinstruction)
+                }
+                _ => None,
+            },
+        };
+        match error {
+            Some(kind) => Err(self.error(kind, i)),
+            None => Ok(()),
+        }
+    }
+
+    // Follows every path from the first instruction, tracking the stack depth above the locals
+    fn verify_stack(&self) -> Result<(), VerifyError> {
+        let len = self.instructions.len();
+        let mut depths: Vec<Option<usize>> = vec![None; len];
+        let mut pending = vec![(0, 0)];
+        while let Some((i, depth)) = pending.pop() {
+            if i >= len {
+                return Err(self.error(VerifyErrorKind::MissingReturn, len));
+            }
+            match depths[i] {
+                Some(expected) if expected == depth => continue,
+                Some(expected) => {
+                    return Err(self.error(
+                        VerifyErrorKind::StackMismatch {
+                            expected,
+                            found: depth,
+                        },
+                        i,
+                    ))
+                }
+                None => depths[i] = Some(depth),
+            }
+
+            // The rest of a superinstruction follows it and is checked on its own
+            let instr = self.instructions[i];
+            let instr = peephole::unfuse(instr).map_or(instr, |code| code[0]);
+            self.verify_locals(i, instr, depth)?;
+            let (pops, pushes) = Self::stack_effect(instr);
+            if pops > depth {
+                return Err(self.error(VerifyErrorKind::StackUnderflow, i));
+            }
+            let depth = depth - pops + pushes;
+            match instr {
+                Instruction::Return { .. } => (),
+                Instruction::Jump { offset } => {
+                    pending.push(((i as isize + offset as isize) as usize, depth))
+                }
+                Instruction::JumpIf { offset, .. } => {
+                    pending.push(((i as isize + offset as isize) as usize, depth));
+                    pending.push((i + 1, depth));
+                }
+                _ => pending.push((i + 1, depth)),
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_locals(&self, i: usize, instr: Instruction, depth: usize) -> Result<(), VerifyError> {
+        // Captured locals are read through env tables, so functions only use their
+        // own frame (offset 1) and the chunk the bottom one (0)
+        let own_frame = self.function.is_some() as u8;
+        let error = match instr {
+            Instruction::GetLocal { frame, .. } | Instruction::SetLocal { frame, .. }
+                if frame != own_frame =>
+            {
+                Some(VerifyErrorKind::FrameOutOfBounds { frame })
+            }
+            Instruction::GetLocal { index, .. } | Instruction::SetLocal { index, .. }
+                if index as usize >= self.locals + depth =>
+            {
+                Some(VerifyErrorKind::LocalOutOfBounds { index })
+            }
+            Instruction::GetMethodImm {
+                table_stack_index, ..
+            } if table_stack_index as usize >= depth => Some(VerifyErrorKind::StackUnderflow),
+            _ => None,
+        };
+        match error {
+            Some(kind) => Err(self.error(kind, i)),
+            None => Ok(()),
+        }
+    }
+
+    // Values popped and pushed by the instruction
+    fn stack_effect(instr: Instruction) -> (usize, usize) {
+        use Instruction::*;
+        match instr {
+            Nil
+            | Unit
+            | True
+            | False
+            | Constant { .. }
+            | Integer(_)
+            | GetGlobal { .. }
+            | GetLocal { .. }
+            | Rec
+            | TableTemplate { .. }
+            | GetMethodImm { .. }
+            | BinLocals { .. }
+            | BinLocalInt { .. }
+            | CallGlobal { .. } => (0, 1),
+            Pop | SetGlobal { .. } | SetLocal { .. } | Print | Echo | JumpIf { .. } => (1, 0),
+            Bin(_) | GetField => (2, 1),
+            AddInt | SubInt | LtInt | AddFloat | SubFloat | LtFloat => (2, 1),
+            Unary(_) | GetFieldImm { .. } | CallFieldImm { .. } | Yield | Cast { .. } => (1, 1),
+            SetField => (3, 0),
+            SetFieldImm { .. } => (2, 0),
+            Tuple { len } => (len as usize, 1),
+            InitTable { len, has_keys } => (len as usize * if has_keys { 2 } else { 1 }, 1),
+            FuncDef { has_env, .. } => (has_env as usize, 1),
+            Call { args_len } => (args_len as usize + 1, 1),
+            ExitBlock { pop, return_value } => {
+                (pop as usize + return_value as usize, return_value as usize)
+            }
+            Return { return_value } => (return_value as usize, 0),
+            Jump { .. } | Import { .. } => (0, 0),
+            Placeholder | GetUpval { .. } | SetUpval { .. } | CloseUpval { .. } => (0, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{Compiler, FuncProto};
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+    use crate::vm::Vm;
+    use alloc::rc::Rc;
+
+    fn compile(source: &str) -> CompiledSource {
+        let ast = Parser::parse_str(source).unwrap();
+        Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap()
+    }
+
+    // Replaces the body of the last prototype, the module function
+    fn with_body(compiled: &CompiledSource, instructions: Vec<Instruction>) -> CompiledSource {
+        let mut table = (*compiled.constant_table).clone();
+        let body = table.prototypes.pop().unwrap();
+        table.prototypes.push(Rc::new(FuncProto::new(
+            body.args_len,
+            instructions.into_boxed_slice(),
+            body.debug_info.clone(),
+        )));
+        CompiledSource {
+            chunk: compiled.chunk.clone(),
+            constant_table: Rc::new(table),
+        }
+    }
+
+    // Replaces the instructions of the chunk, which runs in the bottom frame
+    fn with_chunk(compiled: &CompiledSource, instructions: Vec<Instruction>) -> CompiledSource {
+        let mut chunk = Chunk::new();
+        for instr in instructions {
+            chunk.push_instr(instr, 1).unwrap();
+        }
+        CompiledSource {
+            chunk: Rc::new(chunk),
+            constant_table: Rc::clone(&compiled.constant_table),
+        }
+    }
+
+    fn kind(compiled: &CompiledSource) -> VerifyErrorKind {
+        verify(compiled).unwrap_err().kind
+    }
+
+    #[test]
+    fn compiled_code_is_valid() {
+        let compiled = compile(
+            "
+            let add = fn(a, b) a + b end;
+            let counter = fn()
+                let i = 0;
+                fn()
+                    i = i + 1;
+                    i
+                end
+            end;
+            let t = { \"x\" = 1 };
+            let x = 0;
+            while x < 3 then
+                if x > 1 then t.x = add(t.x, x); end
+                x = x + 1;
+            end
+            for i in 0..5 do
+                let sq = i * i;
+                if sq > 4 then break; end
+                if i == 1 then continue; end
+                x = x + do let half = sq / 2; half + 1 end;
+            end
+            let obj = { \"get\" = fn(self) self.x end };
+            obj:get()
+            ",
+        );
+        assert_eq!(verify(&compiled), Ok(()));
+    }
+
+    #[test]
+    fn max_stack_follows_every_path() {
+        let compiled = compile(
+            "
+            let add = fn(a, b) a + b end;
+            let pick = fn(x) if x then (1, 2, 3) else 0 end end;
+            add(1, 2)
+            ",
+        );
+        let max: Vec<usize> = compiled
+            .constant_table
+            .prototypes
+            .iter()
+            .map(|proto| proto.max_stack)
+            .collect();
+        // The module keeps both functions as locals below the call of 'add'
+        assert_eq!(max, vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn malformed_code_is_rejected() {
+        use Instruction::*;
+        let compiled = compile("1");
+        let ret = Return { return_value: true };
+        assert_eq!(
+            kind(&with_body(&compiled, vec![Jump { offset: 5 }, ret])),
+            VerifyErrorKind::JumpOutOfBounds { target: 5 }
+        );
+        assert_eq!(
+            kind(&with_body(&compiled, vec![Constant { index: 200 }, ret])),
+            VerifyErrorKind::ConstantOutOfBounds { index: 200 }
+        );
+        assert_eq!(
+            kind(&with_body(&compiled, vec![Pop, Unit, ret])),
+            VerifyErrorKind::StackUnderflow
+        );
+        assert_eq!(
+            kind(&with_body(&compiled, vec![Unit])),
+            VerifyErrorKind::MissingReturn
+        );
+        assert_eq!(
+            kind(&with_body(
+                &compiled,
+                vec![
+                    True,
+                    JumpIf {
+                        when_true: true,
+                        offset: 2
+                    },
+                    Unit,
+                    Unit,
+                    ret
+                ]
+            )),
+            VerifyErrorKind::StackMismatch {
+                expected: 1,
+                found: 0
+            }
+        );
+        assert_eq!(
+            kind(&with_body(
+                &compiled,
+                vec![GetLocal { index: 0, frame: 1 }, ret]
+            )),
+            VerifyErrorKind::LocalOutOfBounds { index: 0 }
+        );
+        assert_eq!(
+            kind(&with_body(
+                &compiled,
+                vec![
+                    BinLocalInt {
+                        op: crate::compiler::BinaryInstr::Add,
+                        index: 0,
+                        frame: 0,
+                        value: 1
+                    },
+                    Pop,
+                    Unit,
+                    ret
+                ]
+            )),
+            VerifyErrorKind::BrokenSuperinstruction
+        );
+    }
+
+    #[test]
+    fn locals_of_other_frames_are_rejected() {
+        use crate::compiler::BinaryInstr;
+        use Instruction::*;
+        let compiled = compile("1");
+        let ret = Return { return_value: true };
+        let local = |frame| {
+            with_chunk(
+                &compiled,
+                vec![Integer(7), GetLocal { index: 0, frame }, ret],
+            )
+        };
+        assert_eq!(verify(&local(0)), Ok(()));
+        assert_eq!(Vm::new().run(&local(0)).unwrap(), Value::Int(7));
+        // The vm counts the frame back from the top one, past the only frame it has
+        assert_eq!(
+            kind(&local(2)),
+            VerifyErrorKind::FrameOutOfBounds { frame: 2 }
+        );
+        assert!(CompiledSource::from_bytes(&local(2).to_bytes()).is_err());
+        assert_eq!(
+            kind(&local(1)),
+            VerifyErrorKind::FrameOutOfBounds { frame: 1 }
+        );
+        let fused = vec![
+            Integer(7),
+            BinLocals {
+                op: BinaryInstr::Add,
+                a: 0,
+                b: 0,
+                frame: 3,
+            },
+            GetLocal { index: 0, frame: 3 },
+            Bin(BinaryInstr::Add),
+            ret,
+        ];
+        assert_eq!(
+            kind(&with_chunk(&compiled, fused)),
+            VerifyErrorKind::FrameOutOfBounds { frame: 3 }
+        );
+        assert_eq!(
+            kind(&with_body(
+                &compiled,
+                vec![Integer(7), GetLocal { index: 0, frame: 0 }, ret]
+            )),
+            VerifyErrorKind::FrameOutOfBounds { frame: 0 }
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use flux_rs::compiler::symbols;
+use flux_rs::parser::Parser;
+use flux_rs::vm::{Value, Vm};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+// Tab completion for the repl. Names come from the globals of the vm and the
+// declarations of the lines already entered for the current input, and after a
+// '.' the keys of the table bound to the receiver are offered.
+#[derive(Default)]
+pub struct ReplHelper {
+    globals: Vec<(String, Value)>,
+    locals: Vec<String>,
+}
+
+impl ReplHelper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called before reading a line. Tables are shared so their fields stay up to date
+    pub fn refresh(&mut self, vm: &Vm, pending: &str) {
+        self.globals = vm
+            .globals()
+            .filter_map(|(key, value)| Some((key_name(key)?.to_owned(), value.clone())))
+            .collect();
+        self.locals = match Parser::new(pending).parse_syntax() {
+            Ok(ast) => symbols::resolve(&ast)
+                .declarations
+                .into_iter()
+                .map(|declaration| declaration.name)
+                .collect(),
+            // Incomplete input has no declarations yet
+            _ => Vec::new(),
+        };
+    }
+
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let before = &line[..pos];
+        let start = word_start(before);
+        let prefix = &before[start..];
+        let mut names: Vec<String> = match before[..start].strip_suffix('.') {
+            Some(receiver) => {
+                let path = &receiver[path_start(receiver)..];
+                match self.resolve(path) {
+                    Some(Value::Table(table)) => table
+                        .borrow()
+                        .pairs()
+                        .filter_map(|(key, _)| key_name(key).map(str::to_owned))
+                        .collect(),
+                    _ => Vec::new(),
+                }
+            }
+            None => self
+                .globals
+                .iter()
+                .map(|(name, _)| name)
+                .chain(self.locals.iter())
+                .cloned()
+                .collect(),
+        };
+        names.retain(|name| name.starts_with(prefix));
+        names.sort();
+        names.dedup();
+        (start, names)
+    }
+
+    // Follows a path like 'a.b.c' starting from the globals
+    fn resolve(&self, path: &str) -> Option<Value> {
+        let mut parts = path.split('.');
+        let first = parts.next()?;
+        let mut value = self
+            .globals
+            .iter()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value.clone())?;
+        for part in parts {
+            value = match value {
+                Value::Table(table) => table.borrow().get(&Value::new_str(part)).clone(),
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+fn word_start(text: &str) -> usize {
+    text.rfind(|c| !is_identifier_char(c))
+        .map(|i| i + 1)
+        .unwrap_or(0)
+}
+
+fn path_start(text: &str) -> usize {
+    text.rfind(|c| !is_identifier_char(c) && c != '.')
+        .map(|i| i + 1)
+        .unwrap_or(0)
+}
+
+fn key_name(key: &Value) -> Option<&str> {
+    match key {
+        Value::Str(s) => Some(s.as_str()),
+        Value::Embedded(s) => Some(s),
+        _ => None,
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flux_rs::vm::Table;
+
+    fn helper(pending: &str) -> ReplHelper {
+        let mut helper = ReplHelper::new();
+        helper.refresh(&Vm::new(), pending);
+        let mut inner = Table::new();
+        inner.set(Value::new_str("count"), Value::Int(1));
+        let mut outer = Table::new();
+        outer.set(Value::new_str("inner"), inner.into());
+        outer.set(Value::new_str("init"), Value::Nil);
+        helper.globals.push(("config".to_owned(), outer.into()));
+        helper
+    }
+
+    #[test]
+    fn completes_globals_and_locals() {
+        let helper = helper("let counter = 0;\n");
+        assert_eq!(
+            helper.candidates("println(co", 10),
+            (8, vec!["config".to_owned(), "counter".to_owned()])
+        );
+        assert!(helper.candidates("ar", 2).1.contains(&"args".to_owned()));
+    }
+
+    #[test]
+    fn completes_table_fields() {
+        let helper = helper("");
+        assert_eq!(
+            helper.candidates("config.in", 9),
+            (7, vec!["init".to_owned(), "inner".to_owned()])
+        );
+        assert_eq!(
+            helper.candidates("x = config.inner.", 17),
+            (17, vec!["count".to_owned()])
+        );
+        assert_eq!(helper.candidates("missing.", 8), (8, Vec::new()));
+    }
+}
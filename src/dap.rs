@@ -0,0 +1,391 @@
+use crate::error::FluxError;
+use crate::protocol::{read_message, write_message};
+use crate::util::load_file;
+use crate::vm::{Function, Resume, RunState, Value as FluxValue, Vm};
+use hashbrown::HashMap;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+// A debug adapter over stdin and stdout. The script runs in the adapter, its output
+// is sent back as output events. Lines are 1-based like in the rest of the crate,
+// there is a single thread and frames are numbered from the innermost one
+
+const THREAD_ID: i64 = 1;
+// Variables of a frame are referenced by the frame id plus one, globals come after
+const GLOBALS_REFERENCE: i64 = i32::MAX as i64;
+
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    Adapter::default().serve(&mut stdin.lock(), &mut stdout.lock())
+}
+
+// Collects what the script prints
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<String>>);
+
+impl fmt::Write for Captured {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.borrow_mut().push_str(s);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Adapter {
+    seq: i64,
+    program: String,
+    vm: Option<Vm>,
+    stop_on_entry: bool,
+    configured: bool,
+    // Lines by source path, kept to set them when the script is launched
+    breakpoints: HashMap<String, Vec<usize>>,
+    output: Captured,
+    // Sent after the response of the current request
+    events: Vec<Value>,
+}
+
+impl Adapter {
+    pub fn serve(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        while let Some(message) = read_message(input)? {
+            if message["type"] != "request" {
+                continue;
+            }
+            let command = message["command"].as_str().unwrap_or_default();
+            let mut response = json!({
+                "type": "response",
+                "request_seq": message["seq"],
+                "command": command,
+            });
+            match self.request(command, &message["arguments"]) {
+                Ok(body) => {
+                    response["success"] = true.into();
+                    response["body"] = body;
+                }
+                Err(error) => {
+                    response["success"] = false.into();
+                    response["message"] = error.into();
+                }
+            }
+            self.send(output, response)?;
+            for event in std::mem::take(&mut self.events) {
+                self.send(output, event)?;
+            }
+            if command == "disconnect" {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, output: &mut impl Write, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = self.seq.into();
+        write_message(output, &message)
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.events
+            .push(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn request(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                self.event("initialized", json!({}));
+                Ok(json!({ "supportsConfigurationDoneRequest": true }))
+            }
+            "launch" => {
+                self.launch(args)?;
+                Ok(json!({}))
+            }
+            "setBreakpoints" => {
+                let lines: Vec<usize> = args["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| {
+                        breakpoints
+                            .iter()
+                            .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                            .map(|line| line as usize)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let path = args["source"]["path"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned();
+                if let Some(vm) = &mut self.vm {
+                    vm.clear_breakpoints(&path);
+                    for line in &lines {
+                        vm.set_breakpoint(&path, *line);
+                    }
+                }
+                let breakpoints: Vec<Value> = lines
+                    .iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect();
+                self.breakpoints.insert(path, lines);
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.start();
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => {
+                let vm = self.vm.as_ref().ok_or("The script is not running")?;
+                let frames: Vec<Value> = vm
+                    .call_stack()
+                    .iter()
+                    .enumerate()
+                    .map(|(id, frame)| {
+                        json!({
+                            "id": id,
+                            "name": frame.name,
+                            "line": frame.line.unwrap_or(0),
+                            "column": 1,
+                            "source": { "path": frame.file.as_ref().unwrap_or(&self.program) },
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+            }
+            "scopes" => {
+                let frame = args["frameId"].as_i64().unwrap_or(0);
+                Ok(json!({
+                    "scopes": [
+                        { "name": "Locals", "variablesReference": frame + 1, "expensive": false },
+                        { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+                    ]
+                }))
+            }
+            "variables" => {
+                let vm = self.vm.as_ref().ok_or("The script is not running")?;
+                let reference = args["variablesReference"].as_i64().unwrap_or(0);
+                let variables = if reference == GLOBALS_REFERENCE {
+                    let mut globals: Vec<(String, &FluxValue)> = vm
+                        .globals()
+                        .filter(|(_, value)| {
+                            !matches!(value, FluxValue::Function(Function::Native(_)))
+                        })
+                        .map(|(name, value)| (name.to_string(), value))
+                        .collect();
+                    globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    globals
+                        .into_iter()
+                        .map(|(name, value)| variable(&name, value))
+                        .collect()
+                } else {
+                    let call_stack = vm.call_stack();
+                    let frame = (reference as usize)
+                        .checked_sub(1)
+                        .and_then(|frame| call_stack.get(frame))
+                        .ok_or("Unknown variables reference")?;
+                    frame
+                        .locals
+                        .iter()
+                        .map(|(name, value)| variable(name, value))
+                        .collect::<Vec<_>>()
+                };
+                Ok(json!({ "variables": variables }))
+            }
+            "continue" => {
+                self.resume(Resume::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.resume(Resume::StepOver);
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                self.resume(Resume::StepIn);
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                self.resume(Resume::StepOut);
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.vm = None;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported command {}", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("Missing the program to launch")?;
+        let compiled = load_file(program).map_err(|err| err.to_string())?;
+        let mut vm = Vm::new();
+        vm.set_args(&[program.to_owned()]);
+        vm.set_output(self.output.clone());
+        for (path, lines) in &self.breakpoints {
+            for line in lines {
+                vm.set_breakpoint(path, *line);
+            }
+        }
+        vm.start(&compiled);
+        self.program = program.to_owned();
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.vm = Some(vm);
+        self.start();
+        Ok(())
+    }
+
+    // Runs once the script is launched and the breakpoints are configured
+    fn start(&mut self) {
+        if self.vm.is_some() && self.configured {
+            if self.stop_on_entry {
+                self.resume(Resume::StepIn)
+            } else {
+                self.resume(Resume::Continue)
+            }
+        }
+    }
+
+    fn resume(&mut self, resume: Resume) {
+        let state = match &mut self.vm {
+            Some(vm) => vm.resume(resume),
+            None => return,
+        };
+        let output = self.output.0.borrow().clone();
+        self.output.0.borrow_mut().clear();
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }));
+        }
+        let exit_code = match state {
+            Ok(RunState::Paused { .. }) => {
+                let reason = match resume {
+                    _ if self.stop_on_entry => "entry",
+                    Resume::Continue => "breakpoint",
+                    _ => "step",
+                };
+                self.stop_on_entry = false;
+                self.event(
+                    "stopped",
+                    json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+                );
+                return;
+            }
+            Ok(RunState::Finished(_)) => 0,
+            Err(err) => {
+                let output = format!("Error: {}\n", FluxError::from(err));
+                self.event("output", json!({ "category": "stderr", "output": output }));
+                1
+            }
+        };
+        self.vm = None;
+        self.event("exited", json!({ "exitCode": exit_code }));
+        self.event("terminated", json!({}));
+    }
+}
+
+fn variable(name: &str, value: &FluxValue) -> Value {
+    json!({ "name": name, "value": value.to_string(), "variablesReference": 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    fn request(seq: i64, command: &str, arguments: Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let message = json!({
+            "seq": seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        protocol::write_message(&mut bytes, &message).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn session() {
+        let path = std::env::temp_dir().join("flux_dap_session.flux");
+        std::fs::write(
+            &path,
+            "let x = 1;\nprintln(x);\nlet y = x + 1;\nprintln(y);",
+        )
+        .unwrap();
+        let program = path.to_string_lossy().into_owned();
+
+        let mut input = Vec::new();
+        input.extend(request(1, "initialize", json!({})));
+        input.extend(request(2, "launch", json!({ "program": program })));
+        input.extend(request(
+            3,
+            "setBreakpoints",
+            json!({ "source": { "path": program }, "breakpoints": [{ "line": 3 }] }),
+        ));
+        input.extend(request(4, "configurationDone", json!({})));
+        input.extend(request(5, "stackTrace", json!({ "threadId": 1 })));
+        input.extend(request(6, "variables", json!({ "variablesReference": 1 })));
+        input.extend(request(7, "next", json!({ "threadId": 1 })));
+        input.extend(request(8, "continue", json!({ "threadId": 1 })));
+        input.extend(request(9, "disconnect", json!({})));
+
+        let mut output = Vec::new();
+        Adapter::default()
+            .serve(&mut io::Cursor::new(input), &mut output)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut output = io::Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = protocol::read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        let kinds: Vec<String> = messages
+            .iter()
+            .map(|message| {
+                let name = &message[if message["type"] == "event" {
+                    "event"
+                } else {
+                    "command"
+                }];
+                format!(
+                    "{}:{}",
+                    message["type"].as_str().unwrap(),
+                    name.as_str().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "response:initialize",
+                "event:initialized",
+                "response:launch",
+                "response:setBreakpoints",
+                "response:configurationDone",
+                "event:output",
+                "event:stopped",
+                "response:stackTrace",
+                "response:variables",
+                "response:next",
+                "event:stopped",
+                "response:continue",
+                "event:output",
+                "event:exited",
+                "event:terminated",
+                "response:disconnect",
+            ]
+        );
+        assert_eq!(messages[5]["body"]["output"], "1 \n");
+        assert_eq!(messages[6]["body"]["reason"], "breakpoint");
+        assert_eq!(messages[7]["body"]["stackFrames"][0]["line"], 3);
+        assert_eq!(
+            messages[8]["body"]["variables"],
+            json!([{ "name": "x", "value": "1", "variablesReference": 0 }])
+        );
+        assert_eq!(messages[10]["body"]["reason"], "step");
+        assert!(messages.iter().all(|message| message["success"] != false));
+    }
+}
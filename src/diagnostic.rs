@@ -0,0 +1,547 @@
+use crate::compiler::{BytecodeError, CompileError, BYTECODE_VERSION};
+use crate::error::FluxError;
+use crate::parser::{ParserError, ParserErrorKind};
+use crate::scanner::{LexError, LexErrorKind, Span, TokenType};
+use crate::vm::RuntimeError;
+use alloc::{borrow::ToOwned, string::String, string::ToString, vec::Vec};
+use core::fmt::Write;
+#[cfg(feature = "std")]
+use std::io::IsTerminal;
+
+// Renders errors for people: the message, the source line it points at underlined,
+// and a note on how to fix it when there is a common one
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    // 1-based, None when the error doesn't know where it happened
+    pub line: Option<usize>,
+    // Narrows the underline to the token, the whole line is underlined without it
+    pub span: Option<Span>,
+    pub help: Option<String>,
+    // Details of the error, like the instruction a runtime error happened at
+    pub note: Option<String>,
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, line: Option<usize>) -> Self {
+        Diagnostic {
+            level: Level::Error,
+            message: message.into(),
+            line,
+            span: None,
+            help: None,
+            note: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+
+    // A syntax error gives one diagnostic for each error recovered from
+    pub fn from_error(error: &FluxError) -> Vec<Diagnostic> {
+        match error {
+            FluxError::Lex(error) => vec![lex(error)],
+            FluxError::Parse(error) => vec![parse(error)],
+            FluxError::Syntax(errors) => errors.iter().map(parse).collect(),
+            FluxError::Compile(error) => vec![compile(error)],
+            FluxError::Runtime(error) => vec![runtime(error)],
+        }
+    }
+
+    // 'source' is the text of 'path', the line the diagnostic points at is taken from it
+    pub fn render(&self, source: &str, path: Option<&str>, color: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("{}{}{}", style, text, RESET)
+            } else {
+                text.to_owned()
+            }
+        };
+        let (name, style) = match self.level {
+            Level::Error => ("error", RED),
+            Level::Warning => ("warning", YELLOW),
+        };
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{}{}",
+            paint(style, name),
+            paint(BOLD, &format!(": {}", self.message))
+        )
+        .unwrap();
+        let text = self
+            .line
+            .and_then(|line| source.lines().nth(line.checked_sub(1)?));
+        let gutter = self.line.map_or(0, |line| line.to_string().len());
+        let pad = " ".repeat(gutter);
+        let span = self.span.filter(|span| Some(span.line) == self.line);
+        let location = match (self.line, span) {
+            (Some(_), Some(span)) => format!("{}:{}", span.line, span.column),
+            (Some(line), None) => line.to_string(),
+            (None, _) => String::new(),
+        };
+        match (path, self.line) {
+            (Some(path), Some(_)) => {
+                writeln!(out, "{}{} {}:{}", pad, paint(BLUE, "-->"), path, location).unwrap()
+            }
+            (Some(path), None) => writeln!(out, "{} {}", paint(BLUE, "-->"), path).unwrap(),
+            (None, Some(_)) => {
+                writeln!(out, "{}{} line {}", pad, paint(BLUE, "-->"), location).unwrap()
+            }
+            (None, None) => (),
+        }
+        if let (Some(line), Some(text)) = (self.line, text) {
+            let bar = paint(BLUE, "|");
+            let (indent, width) = match span {
+                // The token may go on past the line, like an unterminated string
+                Some(span) => {
+                    let rest = text.chars().skip(span.column - 1).count();
+                    let width = source
+                        .get(span.start..span.end)
+                        .map_or(1, |token| token.chars().count());
+                    (span.column - 1, width.min(rest))
+                }
+                None => {
+                    let trimmed = text.trim_start();
+                    (
+                        text.len() - trimmed.len(),
+                        trimmed.trim_end().chars().count(),
+                    )
+                }
+            };
+            let underline = "^".repeat(width.max(1));
+            writeln!(out, "{} {}", pad, bar).unwrap();
+            writeln!(out, "{} {} {}", paint(BLUE, &line.to_string()), bar, text).unwrap();
+            writeln!(
+                out,
+                "{} {} {}{}",
+                pad,
+                bar,
+                " ".repeat(indent),
+                paint(style, &underline)
+            )
+            .unwrap();
+        }
+        let notes = [("note:", &self.note), ("help:", &self.help)];
+        for (name, text) in notes
+            .iter()
+            .filter_map(|(name, text)| Some((name, text.as_ref()?)))
+        {
+            writeln!(
+                out,
+                "{} {} {} {}",
+                pad,
+                paint(BLUE, "="),
+                paint(BOLD, name),
+                text
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+// Renders every diagnostic of the error, see Diagnostic::render
+pub fn render_error(error: &FluxError, source: &str, path: Option<&str>, color: bool) -> String {
+    Diagnostic::from_error(error)
+        .iter()
+        .map(|diagnostic| diagnostic.render(source, path, color))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Colors are used when stdout is a terminal, unless NO_COLOR is set
+#[cfg(feature = "std")]
+pub fn use_color() -> bool {
+    std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+fn lex(error: &LexError) -> Diagnostic {
+    Diagnostic {
+        span: Some(error.span),
+        ..lex_kind(&error.kind, Some(error.span.line))
+    }
+}
+
+fn lex_kind(kind: &LexErrorKind, line: Option<usize>) -> Diagnostic {
+    match kind {
+        LexErrorKind::UnexpectedChar(c) => {
+            Diagnostic::error(format!("unexpected character '{}'", c), line)
+        }
+        LexErrorKind::InvalidChar(c) => {
+            Diagnostic::error(format!("invalid character '{}'", c), line)
+        }
+        LexErrorKind::TooShort | LexErrorKind::Eof => {
+            Diagnostic::error("the source ends unexpectedly", line)
+        }
+        LexErrorKind::UnterminatedString => Diagnostic::error("unterminated string", line)
+            .with_help("add the closing quote, strings end at the next quote of their kind"),
+        LexErrorKind::InvalidEscape(c) => Diagnostic::error(
+            format!("unknown escape sequence '\\{}'", c),
+            line,
+        )
+        .with_help(
+            "the escapes are \\n, \\t, \\r, \\0, \\\\, \\\", \\', \\x41 and \\u{1F600}, write \\\\ for a backslash",
+        ),
+        LexErrorKind::InvalidHexEscape => Diagnostic::error("invalid '\\x' escape", line)
+            .with_help("\\x takes two hex digits up to 7F, like \\x41; use \\u{..} for other chars"),
+        LexErrorKind::InvalidUnicodeEscape => {
+            Diagnostic::error("invalid '\\u' escape", line)
+                .with_help("write one to six hex digits in braces, like \\u{1F600}")
+        }
+        LexErrorKind::InvalidCodepoint(codepoint) => Diagnostic::error(
+            format!("'{:X}' is not a unicode codepoint", codepoint),
+            line,
+        )
+        .with_help("codepoints go up to 10FFFF, and D800 to DFFF are reserved for surrogates"),
+        LexErrorKind::InvalidNumber => Diagnostic::error("invalid number literal", line)
+            .with_help("underscores only go between digits, like in 1_000 or 0xFF_FF"),
+        LexErrorKind::InvalidCharLiteral => {
+            Diagnostic::error("char literal must hold exactly one char", line)
+                .with_help("use a string for more chars, like 'ab'")
+        }
+    }
+}
+
+fn parse(error: &ParserError) -> Diagnostic {
+    Diagnostic {
+        span: error.span,
+        ..parse_kind(error)
+    }
+}
+
+fn parse_kind(error: &ParserError) -> Diagnostic {
+    let line = Some(error.line);
+    match &error.kind {
+        ParserErrorKind::ExpectedToken => Diagnostic::error("expected more input", line),
+        ParserErrorKind::UnexpectedToken { token } => {
+            Diagnostic::error(format!("unexpected '{}'", token.text()), line)
+        }
+        ParserErrorKind::NotMatched {
+            typ: TokenType::Semicolon,
+        } => Diagnostic::error("expected Semicolon", line).with_help(
+            "statements end with a semicolon, only those closed by `end` may leave it out",
+        ),
+        ParserErrorKind::NotMatched { typ } => {
+            Diagnostic::error(format!("expected {:?}", typ), line)
+        }
+        ParserErrorKind::Unclosed { delimiter } => {
+            let closing = match delimiter.get_type() {
+                TokenType::LeftParen => ")",
+                TokenType::LeftBracket => "]",
+                TokenType::LeftCurly => "}",
+                _ => "end",
+            };
+            Diagnostic::error(format!("unclosed '{}'", delimiter.text()), line)
+                .with_note("the source ends before it is closed")
+                .with_help(format!("add the '{}' closing it", closing))
+        }
+        ParserErrorKind::TooDeep => Diagnostic::error("the code nests too deeply", line)
+            .with_help("move parts of it into variables or functions"),
+        ParserErrorKind::IntegerTooLarge => Diagnostic::error("integer literal is too large", line)
+            .with_help("integers hold 64 bits, add '.0' to make it a float"),
+        ParserErrorKind::UnknownType { name } => {
+            Diagnostic::error(format!("unknown type '{}'", name), line).with_help(
+                "the types are int, number, str, bool, nil, table, fn, any and tuples of them",
+            )
+        }
+        ParserErrorKind::MissingElse => {
+            Diagnostic::error("an if used as a value needs an else branch", line)
+                .with_help("add 'else nil' or make the if a statement")
+        }
+        ParserErrorKind::InitError => {
+            Diagnostic::error("a table mixes values with and without keys", line)
+                .with_help("give every value a key, like { \"a\" = 1 }, or none of them")
+        }
+        ParserErrorKind::UnexpectedExpr(_) => Diagnostic::error("unexpected expression", line),
+        ParserErrorKind::Lex(kind) => lex_kind(kind, line),
+        ParserErrorKind::ReservedIdentifier(name) => {
+            Diagnostic::error(format!("'{}' is a reserved name", name), line)
+        }
+        ParserErrorKind::Redeclaration { name } => {
+            Diagnostic::error(format!("'{}' is already declared", name), line).with_help(format!(
+                "assign it with '{} = ...' or pick another name",
+                name
+            ))
+        }
+        ParserErrorKind::Undeclared { name, suggestion } => {
+            let error = Diagnostic::error(format!("'{}' is not declared", name), line);
+            match suggestion {
+                Some(suggestion) => error.with_help(format!("did you mean '{}'?", suggestion)),
+                None => error.with_help(format!(
+                    "declare it first with 'let {} = ...' or 'var {} = ...'",
+                    name, name
+                )),
+            }
+        }
+        ParserErrorKind::InnerVarDeclaration { name } => Diagnostic::error(
+            format!("'var {}' is only allowed at the top level", name),
+            line,
+        )
+        .with_help("use 'let' inside blocks and functions"),
+    }
+}
+
+fn compile(error: &CompileError) -> Diagnostic {
+    match error {
+        CompileError::Parse(error) => parse(error),
+        CompileError::TooManyConstants => Diagnostic::error("too many constants", None)
+            .with_help("a script can have at most 256 different constants and names"),
+        CompileError::UndefinedVariable { name } => {
+            Diagnostic::error(format!("'{}' is not defined", name), None)
+        }
+        CompileError::DuplicateLocal { name } => Diagnostic::error(
+            format!("'{}' is declared twice in the same scope", name),
+            None,
+        ),
+        CompileError::InvalidAssignmentTarget(_) => {
+            Diagnostic::error("this can't be assigned to", None)
+                .with_help("only variables and table fields can be assigned")
+        }
+        CompileError::TooLongToJump => Diagnostic::error("a block is too long to jump over", None)
+            .with_help("move some of its code into functions"),
+        CompileError::TooDeep => Diagnostic::error("the code nests too deeply", None)
+            .with_help("move parts of it into variables or functions"),
+        CompileError::YieldOutsideFunction { line } => {
+            Diagnostic::error("'yield' is only allowed inside functions", Some(*line))
+                .with_help("use 'yield()' to let the other tasks run")
+        }
+        CompileError::OutsideLoop { keyword, line } => Diagnostic::error(
+            format!("'{}' is only allowed inside loops", keyword),
+            Some(*line),
+        )
+        .with_help("a function inside a loop can't leave it, return from it instead"),
+        CompileError::LeaveExpression { keyword, line } => Diagnostic::error(
+            format!("'{}' can't leave the expression it is in", keyword),
+            Some(*line),
+        )
+        .with_help("move it into a statement of the loop"),
+        CompileError::AssignToEnum { name } => {
+            Diagnostic::error(format!("cannot assign to the enum '{}'", name), None)
+                .with_help("declare a variable with another name")
+        }
+        CompileError::UnknownVariant {
+            name,
+            variant,
+            variants,
+        } => Diagnostic::error(
+            format!("the enum '{}' has no variant '{}'", name, variant),
+            None,
+        )
+        .with_help(format!("its variants are {}", variants.join(", "))),
+        CompileError::IoError(kind) => {
+            Diagnostic::error(format!("could not read the file: {:?}", kind), None)
+        }
+        CompileError::Bytecode(BytecodeError::BadMagic) => {
+            Diagnostic::error("the file is not flux bytecode", None)
+        }
+        CompileError::Bytecode(BytecodeError::UnsupportedVersion(version)) => Diagnostic::error(
+            format!(
+                "the bytecode has format version {}, this flux_rs reads version {}",
+                version, BYTECODE_VERSION
+            ),
+            None,
+        )
+        .with_help("compile the script again with 'flux_rs compile'"),
+        CompileError::Bytecode(BytecodeError::InstructionSetMismatch(_)) => {
+            Diagnostic::error("the bytecode was compiled for other instructions", None)
+                .with_help("compile the script again with 'flux_rs compile'")
+        }
+        // The lines are the ones of the module, not of the source being rendered
+        CompileError::ModuleError { name, error } => {
+            let mut diagnostic = compile(error);
+            diagnostic.message = format!("in module '{}': {}", name, diagnostic.message);
+            if let Some(line) = diagnostic.line.take() {
+                diagnostic.message.push_str(&format!(" (line {})", line));
+            }
+            diagnostic
+        }
+        error => Diagnostic::error(format!("{:?}", error), None),
+    }
+}
+
+fn runtime(error: &RuntimeError) -> Diagnostic {
+    match error {
+        RuntimeError::TypeError => Diagnostic::error("a value has the wrong type", None),
+        RuntimeError::UndefinedVariable { name, suggestion } => {
+            let error = Diagnostic::error(format!("'{}' is not defined", name), None);
+            match suggestion {
+                Some(suggestion) => error.with_help(format!("did you mean '{}'?", suggestion)),
+                None => error,
+            }
+        }
+        RuntimeError::WrongNumberOfArgs { expected, found } => Diagnostic::error(
+            format!("expected {} arguments, found {}", expected, found),
+            None,
+        ),
+        RuntimeError::UnsupportedBinary { value, op } => {
+            Diagnostic::error(format!("cannot apply {:?} to {}", op, value), None)
+        }
+        RuntimeError::DivideByZero => Diagnostic::error("division by zero", None),
+        RuntimeError::IndexOutOfRange { index, len } => Diagnostic::error(
+            format!(
+                "index {} is out of range for a tuple of {} elements",
+                index, len
+            ),
+            None,
+        ),
+        RuntimeError::FrozenTable { key } => {
+            Diagnostic::error(format!("cannot set '{}' of a frozen table", key), None)
+                .with_help("enum tables can't be changed, copy them with clone first")
+        }
+        RuntimeError::WrongType { expected, found } => {
+            Diagnostic::error(format!("expected {}, found {}", expected, found), None)
+        }
+        RuntimeError::UnknownType(name) => {
+            Diagnostic::error(format!("unknown type '{}'", name), None).with_help(
+                "the types are int, number, str, bool, nil, table, fn, any and tuples of them",
+            )
+        }
+        RuntimeError::AssertionFailed(value) => {
+            Diagnostic::error(format!("assertion failed on {}", value), None)
+        }
+        RuntimeError::Native(message) => Diagnostic::error(message.clone(), None),
+        RuntimeError::Deadlock => Diagnostic::error("every task waits for a value", None)
+            .with_help("a task waits on a channel no other task sends to"),
+        RuntimeError::GeneratorRunning => {
+            Diagnostic::error("the generator is already running", None)
+                .with_help("a generator can't ask itself for its next value")
+        }
+        RuntimeError::Sandboxed(native) => {
+            Diagnostic::error(format!("'{}' is not allowed in the sandbox", native), None)
+                .with_help("the host runs the script with Vm::set_sandboxed(true)")
+        }
+        RuntimeError::Interrupted(signal) => {
+            Diagnostic::error(format!("interrupted by the '{}' signal", signal), None)
+        }
+        RuntimeError::OutOfMemory { limit } => Diagnostic::error(
+            format!("the script allocated more than {} bytes", limit),
+            None,
+        ),
+        RuntimeError::MemoryUntracked => {
+            Diagnostic::error("the memory limit needs the TrackingAllocator", None)
+                .with_help("install it with #[global_allocator] in the host")
+        }
+        RuntimeError::Timeout { limit } => {
+            Diagnostic::error(format!("the script ran longer than {:?}", limit), None)
+        }
+        RuntimeError::ImportError { error, module } => {
+            let mut diagnostic = Diagnostic::from_error(error).remove(0);
+            diagnostic.message = format!("in module '{}': {}", module, diagnostic.message);
+            diagnostic.line = None;
+            diagnostic
+        }
+        RuntimeError::At {
+            pc,
+            instr,
+            frame,
+            source,
+        } => Diagnostic {
+            note: Some(format!(
+                "at instruction {} ({:?}) of call frame {}",
+                pc, instr, frame
+            )),
+            ..runtime(source)
+        },
+        error => Diagnostic::error(format!("{:?}", error), None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn renders_the_line_and_help() {
+        let source = "let x = 1;\n  println(y);\n";
+        let error: FluxError = Parser::new(source).parse_all().unwrap_err().into();
+        let text = render_error(&error, source, Some("main.flux"), false);
+        assert_eq!(
+            text,
+            "error: 'y' is not declared
+ --> main.flux:2
+  |
+2 |   println(y);
+  |   ^^^^^^^^^^^
+  = help: declare it first with 'let y = ...' or 'var y = ...'
+"
+        );
+        assert!(render_error(&error, source, None, true).contains(RED));
+    }
+
+    #[test]
+    fn underlines_the_token() {
+        let source = "let t = {1, 2};\nlet u = {\"a\" = 1, 2};\n";
+        let error: FluxError = Parser::new(source).parse_all().unwrap_err().into();
+        assert_eq!(
+            render_error(&error, source, Some("main.flux"), false),
+            "error: a table mixes values with and without keys
+ --> main.flux:2:20
+  |
+2 | let u = {\"a\" = 1, 2};
+  |                    ^
+  = help: give every value a key, like { \"a\" = 1 }, or none of them
+"
+        );
+
+        let error: FluxError = Parser::new("let s = \"abc").parse_all().unwrap_err().into();
+        let text = render_error(&error, "let s = \"abc", None, false);
+        assert!(text.contains(" --> line 1:13\n"), "{}", text);
+    }
+
+    #[test]
+    fn points_at_unclosed_delimiter() {
+        let source = "let t = {\n    \"a\" = print(1, 2),\n";
+        let error: FluxError = Parser::new(source).parse_all().unwrap_err().into();
+        assert_eq!(
+            render_error(&error, source, Some("main.flux"), false),
+            "error: unclosed '{'
+ --> main.flux:1:9
+  |
+1 | let t = {
+  |         ^
+  = note: the source ends before it is closed
+  = help: add the '}' closing it
+"
+        );
+    }
+
+    #[test]
+    fn errors_without_a_line() {
+        let error: FluxError = RuntimeError::DivideByZero.into();
+        assert_eq!(
+            render_error(&error, "", Some("main.flux"), false),
+            "error: division by zero\n--> main.flux\n"
+        );
+
+        let error: FluxError = crate::macros::eval("let f = fn() nil + 1; end; f();").unwrap_err();
+        assert_eq!(
+            render_error(&error, "", None, false),
+            "error: cannot apply Add to Nil
+ = note: at instruction 2 (AddInt) of call frame 2
+"
+        );
+    }
+}
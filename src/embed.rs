@@ -0,0 +1,66 @@
+// Scripts compiled by a build script and embedded in the binary, so it ships no
+// sources and doesn't parse them when it starts. The build script calls
+// compile_script("scripts/main.flux") and the crate loads the bytecode with
+// include_chunk!("main.flux")
+use crate::compiler::CompileError;
+use crate::error::FluxResult;
+use crate::util::load_file;
+use std::path::{Path, PathBuf};
+
+// Returns the CompileResult<CompiledSource> of the script compile_script compiled,
+// named by its file name. The bytecode is checked again when it is read
+#[macro_export]
+macro_rules! include_chunk {
+    ($file:expr) => {
+        $crate::compiler::CompiledSource::from_bytes(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/",
+            $file,
+            "c"
+        )))
+    };
+}
+
+// Compiles the script into OUT_DIR for include_chunk!, and tells cargo to run the
+// build script again when the script or a module it imports changes. Only build
+// scripts have an OUT_DIR
+pub fn compile_script(path: impl AsRef<Path>) -> FluxResult<PathBuf> {
+    let out_dir = std::env::var_os("OUT_DIR").expect("OUT_DIR is set by cargo for build scripts");
+    compile_script_into(path.as_ref(), Path::new(&out_dir))
+}
+
+// Writes the bytecode of the script to 'dir' as its file name with a 'c' added,
+// 'main.flux' becoming 'main.fluxc'
+pub fn compile_script_into(path: &Path, dir: &Path) -> FluxResult<PathBuf> {
+    let compiled = load_file(&path.to_string_lossy())?;
+    println!("cargo:rerun-if-changed={}", path.display());
+    for file in compiled.files() {
+        println!("cargo:rerun-if-changed={}", file);
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push("c");
+    let output = dir.join(name);
+    std::fs::write(&output, compiled.to_bytes()).map_err(|e| CompileError::IoError(e.kind()))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompiledSource;
+    use crate::vm::{Value, Vm};
+
+    #[test]
+    fn compiled_scripts_run_from_bytes() {
+        let dir = std::env::temp_dir().join("flux_embed_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.flux");
+        std::fs::write(&path, "fn double(x) x * 2 end; double(21)").unwrap();
+
+        let output = compile_script_into(&path, &dir).unwrap();
+        assert_eq!(output, dir.join("main.fluxc"));
+        let compiled = CompiledSource::from_bytes(&std::fs::read(&output).unwrap()).unwrap();
+        assert_eq!(Vm::new().run(&compiled), Ok(Value::Int(42)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,18 +2,31 @@ use super::compiler::CompileError;
 use super::parser::ParserError;
 use super::scanner::LexError;
 use super::vm::RuntimeError;
-use std::fmt::{Display, Formatter};
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt::{Display, Formatter};
 
-pub type FluxResult<T> = std::result::Result<T, FluxError>;
+pub type FluxResult<T> = core::result::Result<T, FluxError>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum FluxError {
     Lex(LexError),
     Parse(ParserError),
+    // Multiple errors recovered from a single parse
+    Syntax(Vec<ParserError>),
     Compile(CompileError),
     Runtime(Box<RuntimeError>),
 }
 
+impl FluxError {
+    // Runtime errors lose the instruction they happened at, see RuntimeError::cause
+    pub fn without_location(self) -> Self {
+        match self {
+            FluxError::Runtime(error) => FluxError::Runtime(Box::new(error.into_cause())),
+            error => error,
+        }
+    }
+}
+
 impl From<LexError> for FluxError {
     fn from(error: LexError) -> Self {
         FluxError::Lex(error)
@@ -26,6 +39,16 @@ impl From<ParserError> for FluxError {
     }
 }
 
+impl From<Vec<ParserError>> for FluxError {
+    fn from(mut errors: Vec<ParserError>) -> Self {
+        if errors.len() == 1 {
+            FluxError::Parse(errors.remove(0))
+        } else {
+            FluxError::Syntax(errors)
+        }
+    }
+}
+
 impl From<CompileError> for FluxError {
     fn from(error: CompileError) -> Self {
         FluxError::Compile(error)
@@ -39,13 +62,22 @@ impl From<RuntimeError> for FluxError {
 }
 
 impl Display for FluxError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         match self {
             // TODO: format
             FluxError::Lex(l) => write!(f, "{}", l),
             FluxError::Compile(c) => write!(f, "{:?}", c),
             FluxError::Runtime(r) => write!(f, "{:?}", r),
             FluxError::Parse(c) => write!(f, "{}", c),
+            FluxError::Syntax(errors) => {
+                for (i, error) in errors.iter().enumerate() {
+                    if i != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
         }
     }
 }
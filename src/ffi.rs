@@ -0,0 +1,320 @@
+// A C API for embedding the interpreter, build the shared library with
+// `cargo rustc --release --features cdylib --crate-type cdylib`. Values cross the
+// boundary boxed: every FluxValue pointer returned is owned by the caller and
+// freed with flux_value_free, strings returned with flux_string_free. Pointers
+// passed in must be valid and not null unless stated otherwise, strings NUL
+// terminated UTF-8.
+#![allow(clippy::missing_safety_doc)]
+
+#[cfg(feature = "plugins")]
+pub mod plugin;
+
+use crate::compiler::{CompiledSource, Compiler};
+use crate::diagnostic::render_error;
+use crate::error::{FluxError, FluxResult};
+use crate::parser::Parser;
+use crate::sourcefile::{MetaData, SourceFile};
+use crate::vm::{ArgsLen, Function, NativeFunction, RuntimeError, Value, Vm};
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+pub struct FluxVm {
+    vm: Vm,
+    // The rendered error of the last flux_eval that failed
+    error: Option<CString>,
+}
+
+pub type FluxValue = Value;
+
+// Gets the arguments in order, returns a new value or null to fail the call
+pub type FluxNative = unsafe extern "C" fn(
+    user_data: *mut c_void,
+    args: *const *const FluxValue,
+    len: usize,
+) -> *mut FluxValue;
+
+pub const FLUX_NIL: c_int = 0;
+pub const FLUX_BOOL: c_int = 1;
+pub const FLUX_INT: c_int = 2;
+pub const FLUX_NUMBER: c_int = 3;
+pub const FLUX_STR: c_int = 4;
+pub const FLUX_TABLE: c_int = 5;
+pub const FLUX_FUNCTION: c_int = 6;
+// Tuples and the unit value
+pub const FLUX_OTHER: c_int = 7;
+
+fn boxed(value: Value) -> *mut FluxValue {
+    Box::into_raw(Box::new(value))
+}
+
+unsafe fn str_arg<'a>(string: *const c_char) -> Option<&'a str> {
+    CStr::from_ptr(string).to_str().ok()
+}
+
+fn compile(vm: &Vm, source: &str) -> FluxResult<CompiledSource> {
+    let ast = Parser::new(source)
+        .with_globals(vm.global_names())
+        .parse_all()?;
+    Ok(Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })?)
+}
+
+#[no_mangle]
+pub extern "C" fn flux_vm_new() -> *mut FluxVm {
+    Box::into_raw(Box::new(FluxVm {
+        vm: Vm::new(),
+        error: None,
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn flux_vm_free(vm: *mut FluxVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+// Runs the source in the vm, the globals it defines stay for the next ones. Returns
+// its value, or null when it failed to compile or run, see flux_last_error
+#[no_mangle]
+pub unsafe extern "C" fn flux_eval(vm: *mut FluxVm, source: *const c_char) -> *mut FluxValue {
+    let vm = &mut *vm;
+    let source = match str_arg(source) {
+        Some(source) => source,
+        None => {
+            vm.error = CString::new("the source is not valid UTF-8").ok();
+            return ptr::null_mut();
+        }
+    };
+    let result = match compile(&vm.vm, source) {
+        Ok(compiled) => vm.vm.run(&compiled).map_err(FluxError::from),
+        Err(error) => Err(error),
+    };
+    match result {
+        Ok(value) => {
+            vm.error = None;
+            boxed(value)
+        }
+        Err(error) => {
+            let text = render_error(&error, source, None, false);
+            vm.error = CString::new(text.replace('\0', "")).ok();
+            ptr::null_mut()
+        }
+    }
+}
+
+// The error of the last flux_eval, null if it succeeded. Owned by the vm
+#[no_mangle]
+pub unsafe extern "C" fn flux_last_error(vm: *const FluxVm) -> *const c_char {
+    match &(*vm).error {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+// Null if the global isn't defined
+#[no_mangle]
+pub unsafe extern "C" fn flux_get_global(vm: *const FluxVm, name: *const c_char) -> *mut FluxValue {
+    match str_arg(name).and_then(|name| (*vm).vm.global(name)) {
+        Some(value) => boxed(value.clone()),
+        None => ptr::null_mut(),
+    }
+}
+
+// Defines the global 'name' as a native calling 'callback' with 'user_data', a
+// negative 'args_len' takes any number of arguments. Returns 0 if the name isn't
+// valid UTF-8 or args_len is over 255
+#[no_mangle]
+pub unsafe extern "C" fn flux_register_native(
+    vm: *mut FluxVm,
+    name: *const c_char,
+    args_len: c_int,
+    callback: FluxNative,
+    user_data: *mut c_void,
+) -> c_int {
+    let name = match str_arg(name) {
+        Some(name) => name,
+        None => return 0,
+    };
+    match c_native(name, args_len, callback, user_data) {
+        Some(native) => {
+            (*vm)
+                .vm
+                .set_global(name, Value::Function(Function::Native(native)));
+            1
+        }
+        None => 0,
+    }
+}
+
+// Calls 'callback', the natives of flux_register_native and of plugins
+fn c_native(
+    name: &str,
+    args_len: c_int,
+    callback: FluxNative,
+    user_data: *mut c_void,
+) -> Option<NativeFunction> {
+    let args_len = match args_len {
+        len if len < 0 => ArgsLen::Variadic,
+        len if len <= u8::MAX as c_int => ArgsLen::Exact(len as u8),
+        _ => return None,
+    };
+    let failed = format!("native '{}' failed", name);
+    Some(NativeFunction::host(args_len, move |_, args| {
+        let pointers: Vec<*const FluxValue> = args.iter().map(|arg| arg as *const _).collect();
+        let result = unsafe { callback(user_data, pointers.as_ptr(), pointers.len()) };
+        if result.is_null() {
+            Err(RuntimeError::Native(failed.clone()))
+        } else {
+            Ok(*unsafe { Box::from_raw(result) })
+        }
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_free(value: *mut FluxValue) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_type(value: *const FluxValue) -> c_int {
+    match &*value {
+        Value::Nil => FLUX_NIL,
+        Value::Bool(_) => FLUX_BOOL,
+        Value::Int(_) => FLUX_INT,
+        Value::Number(_) => FLUX_NUMBER,
+        Value::Str(_) | Value::Embedded(_) => FLUX_STR,
+        Value::Table(_) => FLUX_TABLE,
+        Value::Function(_) => FLUX_FUNCTION,
+        Value::Tuple(_) | Value::Unit => FLUX_OTHER,
+    }
+}
+
+// The value as the script's conditions see it
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_as_bool(value: *const FluxValue) -> bool {
+    (*value).as_bool()
+}
+
+// 0 unless the value is an integer or a number without a fraction
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_as_int(value: *const FluxValue) -> i64 {
+    (*value).convert_int().unwrap_or(0)
+}
+
+// 0 unless the value is a number or an integer
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_as_number(value: *const FluxValue) -> f64 {
+    match &*value {
+        Value::Number(n) => *n,
+        Value::Int(i) => *i as f64,
+        _ => 0.0,
+    }
+}
+
+// The value printed like println does, free it with flux_string_free
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_to_string(value: *const FluxValue) -> *mut c_char {
+    let text = match &*value {
+        Value::Str(string) => string.as_str().to_owned(),
+        value => value.to_string(),
+    };
+    CString::new(text.replace('\0', ""))
+        .map(CString::into_raw)
+        .unwrap_or(ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn flux_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn flux_value_nil() -> *mut FluxValue {
+    boxed(Value::Nil)
+}
+
+#[no_mangle]
+pub extern "C" fn flux_value_bool(b: bool) -> *mut FluxValue {
+    boxed(Value::Bool(b))
+}
+
+#[no_mangle]
+pub extern "C" fn flux_value_int(i: i64) -> *mut FluxValue {
+    boxed(Value::Int(i))
+}
+
+#[no_mangle]
+pub extern "C" fn flux_value_number(n: f64) -> *mut FluxValue {
+    boxed(Value::Number(n))
+}
+
+// Null if the string isn't valid UTF-8
+#[no_mangle]
+pub unsafe extern "C" fn flux_value_str(string: *const c_char) -> *mut FluxValue {
+    match str_arg(string) {
+        Some(string) => boxed(Value::new_str(string)),
+        None => ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn scale(
+        user_data: *mut c_void,
+        args: *const *const FluxValue,
+        len: usize,
+    ) -> *mut FluxValue {
+        let factor = *(user_data as *const i64);
+        let args = std::slice::from_raw_parts(args, len);
+        match flux_value_type(args[0]) {
+            FLUX_INT => flux_value_int(flux_value_as_int(args[0]) * factor),
+            _ => ptr::null_mut(),
+        }
+    }
+
+    #[test]
+    fn embeds_the_vm() {
+        unsafe {
+            let vm = flux_vm_new();
+            let mut factor: i64 = 3;
+            let name = CString::new("scale").unwrap();
+            let user_data = &mut factor as *mut i64 as *mut c_void;
+            assert_eq!(
+                flux_register_native(vm, name.as_ptr(), 1, scale, user_data),
+                1
+            );
+
+            let source = CString::new("var x = scale(14); var s = \"a\" + \"b\"; x").unwrap();
+            let value = flux_eval(vm, source.as_ptr());
+            assert_eq!(flux_value_type(value), FLUX_INT);
+            assert_eq!(flux_value_as_int(value), 42);
+            flux_value_free(value);
+
+            let name = CString::new("s").unwrap();
+            let value = flux_get_global(vm, name.as_ptr());
+            let text = flux_value_to_string(value);
+            assert_eq!(CStr::from_ptr(text).to_str(), Ok("ab"));
+            flux_string_free(text);
+            flux_value_free(value);
+
+            let name = CString::new("missing").unwrap();
+            assert!(flux_get_global(vm, name.as_ptr()).is_null());
+
+            let source = CString::new("scale(\"a\")").unwrap();
+            assert!(flux_eval(vm, source.as_ptr()).is_null());
+            let error = CStr::from_ptr(flux_last_error(vm)).to_str().unwrap();
+            assert!(error.contains("native 'scale' failed"));
+            flux_vm_free(vm);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// Native modules loaded at runtime with require_native. A plugin is a shared
+// library exporting 'flux_open_<name>', <name> being its file name without the
+// 'lib' prefix and the extension. It gets the functions to work with values, so it
+// doesn't link against the interpreter, and returns the natives it defines
+use super::*;
+use crate::vm::{RuntimeResult, Table};
+
+// Raised when the layout of the structs below changes
+pub const FLUX_ABI_VERSION: u32 = 1;
+
+#[repr(C)]
+pub struct FluxApi {
+    pub abi_version: u32,
+    pub value_free: unsafe extern "C" fn(*mut FluxValue),
+    pub value_type: unsafe extern "C" fn(*const FluxValue) -> c_int,
+    pub value_as_bool: unsafe extern "C" fn(*const FluxValue) -> bool,
+    pub value_as_int: unsafe extern "C" fn(*const FluxValue) -> i64,
+    pub value_as_number: unsafe extern "C" fn(*const FluxValue) -> f64,
+    pub value_to_string: unsafe extern "C" fn(*const FluxValue) -> *mut c_char,
+    pub string_free: unsafe extern "C" fn(*mut c_char),
+    pub value_nil: extern "C" fn() -> *mut FluxValue,
+    pub value_bool: extern "C" fn(bool) -> *mut FluxValue,
+    pub value_int: extern "C" fn(i64) -> *mut FluxValue,
+    pub value_number: extern "C" fn(f64) -> *mut FluxValue,
+    pub value_str: unsafe extern "C" fn(*const c_char) -> *mut FluxValue,
+}
+
+pub static FLUX_API: FluxApi = FluxApi {
+    abi_version: FLUX_ABI_VERSION,
+    value_free: flux_value_free,
+    value_type: flux_value_type,
+    value_as_bool: flux_value_as_bool,
+    value_as_int: flux_value_as_int,
+    value_as_number: flux_value_as_number,
+    value_to_string: flux_value_to_string,
+    string_free: flux_string_free,
+    value_nil: flux_value_nil,
+    value_bool: flux_value_bool,
+    value_int: flux_value_int,
+    value_number: flux_value_number,
+    value_str: flux_value_str,
+};
+
+// A native of the module, like the arguments of flux_register_native
+#[repr(C)]
+pub struct FluxNativeEntry {
+    pub name: *const c_char,
+    pub args_len: c_int,
+    pub function: FluxNative,
+    pub user_data: *mut c_void,
+}
+
+// Has to stay valid as long as the process runs, a static in the plugin
+#[repr(C)]
+pub struct FluxModule {
+    pub abi_version: u32,
+    pub len: usize,
+    pub natives: *const FluxNativeEntry,
+}
+
+pub type FluxOpen = unsafe extern "C" fn(api: *const FluxApi) -> *const FluxModule;
+
+// The table of the natives 'open' returns
+pub fn open_module(open: FluxOpen) -> RuntimeResult<Table> {
+    let module = unsafe { open(&FLUX_API) };
+    let module = match unsafe { module.as_ref() } {
+        Some(module) => module,
+        None => return Err(RuntimeError::Native("the module failed to open".to_owned())),
+    };
+    if module.abi_version != FLUX_ABI_VERSION {
+        return Err(RuntimeError::Native(format!(
+            "the module was built for version {} of the plugin ABI, not {}",
+            module.abi_version, FLUX_ABI_VERSION
+        )));
+    }
+    let entries = match module.len {
+        0 => &[],
+        len => unsafe { std::slice::from_raw_parts(module.natives, len) },
+    };
+    let mut table = Table::new();
+    for entry in entries {
+        let name = unsafe { str_arg(entry.name) }
+            .ok_or_else(|| RuntimeError::Native("a native name is not UTF-8".to_owned()))?;
+        let native = c_native(name, entry.args_len, entry.function, entry.user_data)
+            .ok_or_else(|| RuntimeError::Native(format!("'{}' takes too many arguments", name)))?;
+        table.set(
+            Value::new_str(name),
+            Value::Function(Function::Native(native)),
+        );
+    }
+    Ok(table)
+}
+
+// Loads the library at 'path', the platform's extension is added when it has none.
+// The library stays loaded, its natives can be called until the process exits
+#[cfg(unix)]
+pub fn require_native(path: &str) -> RuntimeResult<Table> {
+    use std::path::Path;
+
+    let mut file = Path::new(path).to_path_buf();
+    if file.extension().is_none() {
+        file.set_extension(std::env::consts::DLL_EXTENSION);
+    }
+    let stem = file
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let symbol = format!("flux_open_{}", stem.strip_prefix("lib").unwrap_or(stem));
+    let error = || {
+        let message = unsafe { libc::dlerror() };
+        let message = match message.is_null() {
+            true => "unknown error".into(),
+            false => unsafe { CStr::from_ptr(message) }.to_string_lossy(),
+        };
+        RuntimeError::Native(format!("could not load '{}': {}", path, message))
+    };
+
+    let file =
+        CString::new(file.to_string_lossy().into_owned()).map_err(|_| RuntimeError::TypeError)?;
+    let handle = unsafe { libc::dlopen(file.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(error());
+    }
+    let symbol = CString::new(symbol).map_err(|_| RuntimeError::TypeError)?;
+    let open = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+    if open.is_null() {
+        return Err(error());
+    }
+    open_module(unsafe { std::mem::transmute::<*mut c_void, FluxOpen>(open) })
+}
+
+#[cfg(not(unix))]
+pub fn require_native(_path: &str) -> RuntimeResult<Table> {
+    Err(RuntimeError::Native(
+        "native modules are only supported on unix".to_owned(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn add(
+        _: *mut c_void,
+        args: *const *const FluxValue,
+        len: usize,
+    ) -> *mut FluxValue {
+        let args = std::slice::from_raw_parts(args, len);
+        flux_value_int(flux_value_as_int(args[0]) + flux_value_as_int(args[1]))
+    }
+
+    struct Natives([FluxNativeEntry; 1]);
+
+    // The entries only hold static data
+    unsafe impl Sync for Natives {}
+
+    static NATIVES: Natives = Natives([FluxNativeEntry {
+        name: b"add\0".as_ptr() as *const c_char,
+        args_len: 2,
+        function: add,
+        user_data: std::ptr::null_mut(),
+    }]);
+
+    unsafe extern "C" fn open(api: *const FluxApi) -> *const FluxModule {
+        struct Module(FluxModule);
+        unsafe impl Sync for Module {}
+        static MODULE: Module = Module(FluxModule {
+            abi_version: FLUX_ABI_VERSION,
+            len: 1,
+            natives: NATIVES.0.as_ptr(),
+        });
+        assert_eq!((*api).abi_version, FLUX_ABI_VERSION);
+        &MODULE.0
+    }
+
+    unsafe extern "C" fn open_old(_: *const FluxApi) -> *const FluxModule {
+        struct Module(FluxModule);
+        unsafe impl Sync for Module {}
+        static MODULE: Module = Module(FluxModule {
+            abi_version: 0,
+            len: 0,
+            natives: std::ptr::null(),
+        });
+        &MODULE.0
+    }
+
+    #[test]
+    fn modules_are_tables_of_natives() {
+        let mut vm = Vm::new();
+        vm.set_global("demo", open_module(open).unwrap().into());
+        let compiled = compile(&vm, "demo.add(40, 2)").unwrap();
+        assert_eq!(vm.run(&compiled), Ok(Value::Int(42)));
+
+        assert!(open_module(open_old).is_err());
+        assert!(require_native("/nonexistent/libfoo").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::error::FluxResult;
+use crate::parser::pretty;
+use crate::parser::{Comments, Parser};
+use alloc::string::String;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FormatConfig {
+    pub indent_width: usize,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        FormatConfig { indent_width: 4 }
+    }
+}
+
+// Canonical layout of the source. Comments are kept on their own lines before the
+// statement that follows them
+pub fn format(source: &str, config: &FormatConfig) -> FluxResult<String> {
+    let mut parser = Parser::new(source);
+    let ast = parser.parse_syntax()?;
+    Ok(pretty::print_source(
+        ast.expr(),
+        config.indent_width,
+        Comments::of(source),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_with_indent_width() {
+        let source = "let f = fn(x)\nif x then 1 else 2 end end;";
+        let config = FormatConfig { indent_width: 2 };
+        assert_eq!(
+            format(source, &config).unwrap(),
+            "fn f(x)\n  if x then\n    1\n  else\n    2\n  end\nend\n"
+        );
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let source = "\
+#!/usr/bin/env flux
+// Header
+let x = 1; // one
+let s = \"// not a comment\";
+let t = 'a\\' // still not'; // but this is
+let u = \"\"\"
+    \"// nor this\"
+    \"\"\"; // this is
+
+
+
+let f = fn()
+        // Inside
+    x
+end;
+";
+        let expected = "\
+#!/usr/bin/env flux
+// Header
+let x = 1; // one
+let s = \"// not a comment\";
+let t = \"a' // still not\"; // but this is
+let u = '\"// nor this\"';
+// this is
+
+fn f()
+    // Inside
+    x
+end
+";
+        let formatted = format(source, &FormatConfig::default()).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(
+            format(&formatted, &FormatConfig::default()).unwrap(),
+            formatted
+        );
+    }
+}
@@ -0,0 +1,47 @@
+// The test harness needs std, the tests of the no_std build still link it
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+// Without std, Vec, String and Box come from alloc and so do format! and vec!
+#[macro_use]
+extern crate alloc;
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+mod macros;
+#[cfg(feature = "std")]
+pub mod bench;
+#[cfg(feature = "std")]
+pub mod bundle;
+pub mod compiler;
+#[cfg(feature = "dap")]
+pub mod dap;
+pub mod diagnostic;
+#[cfg(feature = "std")]
+pub mod embed;
+pub mod error;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+pub mod formatter;
+pub mod lint;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+pub mod parser;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(any(feature = "lsp", feature = "dap"))]
+mod protocol;
+pub mod scanner;
+pub mod session;
+pub mod sourcefile;
+pub mod suggest;
+#[cfg(feature = "std")]
+pub mod test_runner;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod typecheck;
+#[cfg(feature = "std")]
+pub mod util;
+pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,667 @@
+use crate::compiler::fold;
+use crate::error::FluxError;
+use crate::parser::{BinaryOp, BlockExpr, Expr, Literal, Parser, Statement};
+use crate::vm::Value;
+use alloc::{borrow::ToOwned, boxed::Box, string::String, string::ToString, vec::Vec};
+use core::fmt::{self, Display, Formatter};
+use hashbrown::HashMap;
+
+// Style checks over the syntax tree (see Parser::parse_syntax). Every rule has an id that
+// the config table uses to change its severity, like { "empty-block" = "off" }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    ShadowedVariable,
+    SelfComparison,
+    EmptyBlock,
+    AssignmentInCondition,
+    UnusedParameter,
+    DuplicateKey,
+    UselessExpression,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Off,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lint {
+    pub rule: Rule,
+    pub severity: Severity,
+    // None when the tree doesn't record the line of the node
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LintConfigError {
+    Syntax(Box<FluxError>),
+    // The config must be a table of rule ids to severities
+    NotATable,
+    UnknownRule(String),
+    UnknownSeverity(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LintConfig {
+    severities: HashMap<Rule, Severity>,
+}
+
+const RULES: [Rule; 7] = [
+    Rule::ShadowedVariable,
+    Rule::SelfComparison,
+    Rule::EmptyBlock,
+    Rule::AssignmentInCondition,
+    Rule::UnusedParameter,
+    Rule::DuplicateKey,
+    Rule::UselessExpression,
+];
+
+impl Rule {
+    pub fn id(self) -> &'static str {
+        match self {
+            Rule::ShadowedVariable => "shadowed-variable",
+            Rule::SelfComparison => "self-comparison",
+            Rule::EmptyBlock => "empty-block",
+            Rule::AssignmentInCondition => "assignment-in-condition",
+            Rule::UnusedParameter => "unused-parameter",
+            Rule::DuplicateKey => "duplicate-key",
+            Rule::UselessExpression => "useless-expression",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Rule> {
+        RULES.iter().copied().find(|rule| rule.id() == id)
+    }
+}
+
+impl Severity {
+    pub fn from_name(name: &str) -> Option<Severity> {
+        match name {
+            "off" => Some(Severity::Off),
+            "warning" => Some(Severity::Warning),
+            "error" => Some(Severity::Error),
+            _ => None,
+        }
+    }
+}
+
+impl LintConfig {
+    // Parses a source consisting of the config table
+    pub fn from_source(source: &str) -> Result<Self, LintConfigError> {
+        let ast = Parser::new(source)
+            .parse_syntax()
+            .map_err(|errors| LintConfigError::Syntax(Box::new(errors.into())))?;
+        let block = ast.expr();
+        let (keys, values) = match (block.stmts.as_slice(), block.expr.as_ref()) {
+            ([], Expr::TableInit { keys, values }) => (keys.as_deref().unwrap_or(&[]), values),
+            ([Statement::Expr(Expr::TableInit { keys, values })], Expr::Literal(Literal::Unit)) => {
+                (keys.as_deref().unwrap_or(&[]), values)
+            }
+            _ => return Err(LintConfigError::NotATable),
+        };
+        if keys.len() != values.len() {
+            return Err(LintConfigError::NotATable);
+        }
+        let mut config = LintConfig::default();
+        for (key, value) in keys.iter().zip(values) {
+            let (id, name) = match (key, value) {
+                (Expr::Literal(Literal::Str(id)), Expr::Literal(Literal::Str(name))) => (id, name),
+                _ => return Err(LintConfigError::NotATable),
+            };
+            let rule = Rule::from_id(id).ok_or_else(|| LintConfigError::UnknownRule(id.clone()))?;
+            let severity = Severity::from_name(name)
+                .ok_or_else(|| LintConfigError::UnknownSeverity(name.clone()))?;
+            config.severities.insert(rule, severity);
+        }
+        Ok(config)
+    }
+
+    pub fn severity(&self, rule: Rule) -> Severity {
+        self.severities
+            .get(&rule)
+            .copied()
+            .unwrap_or(Severity::Warning)
+    }
+}
+
+pub fn lint(block: &BlockExpr, config: &LintConfig) -> Vec<Lint> {
+    let mut linter = Linter {
+        config,
+        scopes: vec![Vec::new()],
+        lints: Vec::new(),
+    };
+    linter.block(block);
+    linter.lints
+}
+
+struct Linter<'a> {
+    config: &'a LintConfig,
+    // Names declared in each enclosing block and function
+    scopes: Vec<Vec<String>>,
+    lints: Vec<Lint>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, rule: Rule, line: Option<usize>, message: String) {
+        let severity = self.config.severity(rule);
+        if severity != Severity::Off {
+            self.lints.push(Lint {
+                rule,
+                severity,
+                line,
+                message,
+            });
+        }
+    }
+
+    fn scoped(&mut self, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(Vec::new());
+        f(self);
+        self.scopes.pop();
+    }
+
+    fn declare(&mut self, name: &str, line: Option<usize>) {
+        let (current, outer) = self.scopes.split_last_mut().unwrap();
+        // The hidden locals of for loops are declared by every loop
+        if !Parser::is_hidden(name) && outer.iter().any(|scope| scope.iter().any(|n| n == name)) {
+            let message = format!("'{}' shadows a variable of an outer scope", name);
+            current.push(name.to_owned());
+            return self.report(Rule::ShadowedVariable, line, message);
+        }
+        current.push(name.to_owned());
+    }
+
+    fn block(&mut self, block: &BlockExpr) {
+        self.scoped(|linter| {
+            block.stmts.iter().for_each(|stmt| linter.statement(stmt));
+            linter.expr(&block.expr);
+        })
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Expr(Expr::Block(block))
+                if block.stmts.is_empty() && is_unit(&block.expr) =>
+            {
+                self.report(Rule::EmptyBlock, None, "Empty block".to_owned())
+            }
+            Statement::Expr(expr) => {
+                self.useless_expression(expr);
+                self.expr(expr)
+            }
+            Statement::Print(expr) | Statement::Return(expr) => self.expr(expr),
+            Statement::Let {
+                name, value, line, ..
+            }
+            | Statement::Var {
+                name, value, line, ..
+            } => {
+                // Functions can refer to themselves
+                self.declare(name, Some(*line));
+                self.expr(value);
+            }
+            Statement::Set { variable, value } => {
+                self.expr(variable);
+                self.expr(value);
+            }
+            Statement::Block(stmts) => {
+                self.scoped(|linter| stmts.iter().for_each(|stmt| linter.statement(stmt)));
+            }
+            Statement::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.condition(condition);
+                self.branch(then_block, condition.line());
+                if let Some(else_block) = else_block {
+                    self.branch(else_block, condition.line());
+                }
+            }
+            Statement::While {
+                condition,
+                then_block,
+                step,
+            } => {
+                self.condition(condition);
+                match then_block.as_ref() {
+                    // The body of a for loop may be empty, the step still runs
+                    Statement::Block(stmts) if stmts.is_empty() && step.is_none() => self.report(
+                        Rule::EmptyBlock,
+                        condition.line(),
+                        "Empty while loop".to_owned(),
+                    ),
+                    _ => (),
+                }
+                match then_block.as_ref() {
+                    Statement::Block(stmts) => {
+                        self.scoped(|linter| stmts.iter().for_each(|stmt| linter.statement(stmt)))
+                    }
+                    stmt => self.statement(stmt),
+                }
+                if let Some(step) = step {
+                    self.statement(step)
+                }
+            }
+            // The body may be empty, the loop still counts
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                line,
+                ..
+            } => {
+                self.expr(start);
+                self.expr(end);
+                self.scoped(|linter| {
+                    linter.declare(variable, Some(*line));
+                    match body.as_ref() {
+                        Statement::Block(stmts) => {
+                            stmts.iter().for_each(|stmt| linter.statement(stmt))
+                        }
+                        stmt => linter.statement(stmt),
+                    }
+                })
+            }
+            Statement::Import { name, line, .. } | Statement::Enum { name, line, .. } => {
+                self.declare(name, Some(*line))
+            }
+            Statement::Break { .. } | Statement::Continue { .. } => (),
+        }
+    }
+
+    // Then and else blocks of an if
+    fn branch(&mut self, expr: &Expr, line: Option<usize>) {
+        match expr {
+            Expr::Block(block) if block.stmts.is_empty() && is_unit(&block.expr) => {
+                self.report(Rule::EmptyBlock, line, "Empty branch of if".to_owned())
+            }
+            Expr::Block(block) => self.block(block),
+            expr => self.expr(expr),
+        }
+    }
+
+    // Conditions are expressions, an assignment can only hide in a block expression
+    fn condition(&mut self, condition: &Expr) {
+        if let Expr::Block(block) = condition {
+            if block
+                .stmts
+                .iter()
+                .any(|stmt| matches!(stmt, Statement::Set { .. }))
+            {
+                self.report(
+                    Rule::AssignmentInCondition,
+                    condition.line(),
+                    "Assignment in the condition".to_owned(),
+                );
+            }
+        }
+        self.expr(condition);
+    }
+
+    // Only the last value of a key given twice is kept. Keys are compared after
+    // folding, so "ab" and "a" + "b" are the same
+    fn duplicate_keys(&mut self, keys: &[Expr], line: Option<usize>) {
+        let mut seen = Vec::new();
+        for key in keys.iter().filter_map(fold::constant) {
+            let name = match &key {
+                Value::Str(s) => format!("\"{}\"", s),
+                Value::Int(i) => i.to_string(),
+                _ => continue,
+            };
+            if seen.contains(&key) {
+                let message = format!("Key {} is given more than once", name);
+                self.report(Rule::DuplicateKey, line, message);
+            } else {
+                seen.push(key);
+            }
+        }
+    }
+
+    // A statement that computes a value only to drop it, usually a typo for an assignment
+    fn useless_expression(&mut self, expr: &Expr) {
+        if !has_no_effect(expr) {
+            return;
+        }
+        let message = match expr {
+            Expr::Binary {
+                op: BinaryOp::EqualEqual,
+                ..
+            } => "Result of the comparison is unused, did you mean '='?",
+            _ => "Expression has no effect",
+        };
+        self.report(Rule::UselessExpression, expr.line(), message.to_owned());
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal(_) | Expr::Identifier { .. } | Expr::Rec => (),
+            Expr::Unary { expr, .. }
+            | Expr::Grouping(expr)
+            | Expr::Yield(expr)
+            | Expr::Cast { expr, .. } => self.expr(expr),
+            Expr::Binary { left, op, right } => {
+                if is_comparison(*op) && same_value(left, right) {
+                    self.report(
+                        Rule::SelfComparison,
+                        expr.line(),
+                        "Value is compared with itself".to_owned(),
+                    );
+                }
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Tuple(exprs) => exprs.iter().for_each(|expr| self.expr(expr)),
+            Expr::Access { table, field } => {
+                self.expr(table);
+                self.expr(field);
+            }
+            Expr::SelfAccess { table, args, .. } => {
+                self.expr(table);
+                args.iter().for_each(|arg| self.expr(arg));
+            }
+            Expr::TableInit { keys, values } => {
+                if let Some(keys) = keys {
+                    self.duplicate_keys(keys, expr.line());
+                }
+                keys.iter()
+                    .flatten()
+                    .chain(values)
+                    .for_each(|expr| self.expr(expr))
+            }
+            Expr::Function {
+                args, body, line, ..
+            } => {
+                for arg in args {
+                    if !arg.starts_with('_') && !block_uses(body, arg) {
+                        self.report(
+                            Rule::UnusedParameter,
+                            Some(*line),
+                            format!("Parameter '{}' is never used", arg),
+                        );
+                    }
+                }
+                self.scoped(|linter| {
+                    args.iter().for_each(|arg| linter.declare(arg, Some(*line)));
+                    linter.block(body);
+                });
+            }
+            Expr::Call { func, args } => {
+                self.expr(func);
+                args.iter().for_each(|arg| self.expr(arg));
+            }
+            Expr::Block(block) => self.block(block),
+            Expr::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.condition(condition);
+                self.branch(then_block, condition.line());
+                self.branch(else_block, condition.line());
+            }
+        }
+    }
+}
+
+fn is_unit(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(Literal::Unit))
+}
+
+// Calls and blocks may do anything, the rest only computes a value. Unit is left out
+// as the parser stands it in for missing expressions
+fn has_no_effect(expr: &Expr) -> bool {
+    match expr {
+        Expr::Literal(Literal::Unit) => false,
+        Expr::Literal(_) | Expr::Identifier { .. } | Expr::Function { .. } | Expr::Rec => true,
+        Expr::Unary { expr, .. } | Expr::Grouping(expr) => has_no_effect(expr),
+        Expr::Binary { left, right, .. } => has_no_effect(left) && has_no_effect(right),
+        Expr::Tuple(exprs) => exprs.iter().all(has_no_effect),
+        Expr::Access { table, field } => has_no_effect(table) && has_no_effect(field),
+        Expr::TableInit { keys, values } => keys.iter().flatten().chain(values).all(has_no_effect),
+        Expr::SelfAccess { .. } | Expr::Call { .. } | Expr::Block(_) | Expr::If { .. } => false,
+        // A cast stops the script when the type is wrong
+        Expr::Yield(_) | Expr::Cast { .. } => false,
+    }
+}
+
+fn is_comparison(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::EqualEqual
+            | BinaryOp::BangEqual
+            | BinaryOp::Less
+            | BinaryOp::Greater
+            | BinaryOp::LessEqual
+            | BinaryOp::GreaterEqual
+    )
+}
+
+// Same variable or field on both sides, calls may return different values
+fn same_value(left: &Expr, right: &Expr) -> bool {
+    match (left, right) {
+        (Expr::Identifier { name: a, .. }, Expr::Identifier { name: b, .. }) => a == b,
+        (
+            Expr::Access {
+                table: a,
+                field: field_a,
+            },
+            Expr::Access {
+                table: b,
+                field: field_b,
+            },
+        ) => same_value(a, b) && (field_a == field_b || same_value(field_a, field_b)),
+        (Expr::Grouping(a), b) | (b, Expr::Grouping(a)) => same_value(a, b),
+        _ => false,
+    }
+}
+
+// Any reference counts, even when an inner declaration shadows the name
+fn block_uses(block: &BlockExpr, name: &str) -> bool {
+    block.stmts.iter().any(|stmt| stmt_uses(stmt, name)) || expr_uses(&block.expr, name)
+}
+
+fn stmt_uses(stmt: &Statement, name: &str) -> bool {
+    match stmt {
+        Statement::Expr(expr)
+        | Statement::Print(expr)
+        | Statement::Return(expr)
+        | Statement::Let { value: expr, .. }
+        | Statement::Var { value: expr, .. } => expr_uses(expr, name),
+        Statement::Set { variable, value } => expr_uses(variable, name) || expr_uses(value, name),
+        Statement::Block(stmts) => stmts.iter().any(|stmt| stmt_uses(stmt, name)),
+        Statement::If {
+            condition,
+            then_block,
+            else_block,
+        } => {
+            expr_uses(condition, name)
+                || expr_uses(then_block, name)
+                || else_block
+                    .as_ref()
+                    .is_some_and(|block| expr_uses(block, name))
+        }
+        Statement::While {
+            condition,
+            then_block,
+            step,
+        } => {
+            expr_uses(condition, name)
+                || stmt_uses(then_block, name)
+                || step.as_ref().is_some_and(|step| stmt_uses(step, name))
+        }
+        Statement::For {
+            start, end, body, ..
+        } => expr_uses(start, name) || expr_uses(end, name) || stmt_uses(body, name),
+        Statement::Import { .. }
+        | Statement::Enum { .. }
+        | Statement::Break { .. }
+        | Statement::Continue { .. } => false,
+    }
+}
+
+fn expr_uses(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::Identifier { name: n, .. } => n == name,
+        Expr::Literal(_) | Expr::Rec => false,
+        Expr::Unary { expr, .. }
+        | Expr::Grouping(expr)
+        | Expr::Yield(expr)
+        | Expr::Cast { expr, .. } => expr_uses(expr, name),
+        Expr::Binary { left, right, .. } => expr_uses(left, name) || expr_uses(right, name),
+        Expr::Tuple(exprs) => exprs.iter().any(|expr| expr_uses(expr, name)),
+        Expr::Access { table, field } => expr_uses(table, name) || expr_uses(field, name),
+        Expr::SelfAccess { table, args, .. } => {
+            expr_uses(table, name) || args.iter().any(|arg| expr_uses(arg, name))
+        }
+        Expr::TableInit { keys, values } => keys
+            .iter()
+            .flatten()
+            .chain(values)
+            .any(|expr| expr_uses(expr, name)),
+        Expr::Function { body, .. } => block_uses(body, name),
+        Expr::Call { func, args } => {
+            expr_uses(func, name) || args.iter().any(|arg| expr_uses(arg, name))
+        }
+        Expr::Block(block) => block_uses(block, name),
+        Expr::If {
+            condition,
+            then_block,
+            else_block,
+        } => {
+            expr_uses(condition, name) || expr_uses(then_block, name) || expr_uses(else_block, name)
+        }
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Off => "off",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "{}: ", line)?;
+        }
+        write!(f, "{}[{}]: {}", self.severity, self.rule.id(), self.message)
+    }
+}
+
+impl Display for LintConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LintConfigError::Syntax(err) => write!(f, "{}", err),
+            LintConfigError::NotATable => write!(
+                f,
+                "Lint config must be a table of rule ids to \"off\", \"warning\" or \"error\""
+            ),
+            LintConfigError::UnknownRule(id) => write!(f, "Unknown lint rule '{}'", id),
+            LintConfigError::UnknownSeverity(name) => write!(f, "Unknown severity '{}'", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(source: &str, config: &LintConfig) -> Vec<Rule> {
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        lint(ast.expr(), config)
+            .into_iter()
+            .map(|lint| lint.rule)
+            .collect()
+    }
+
+    #[test]
+    fn reports_each_rule() {
+        let config = LintConfig::default();
+        assert_eq!(
+            rules("let x = 1; do let x = 2; end", &config),
+            vec![Rule::ShadowedVariable]
+        );
+        assert_eq!(
+            rules("let t = {}; t.x == (t.x)", &config),
+            vec![Rule::SelfComparison]
+        );
+        assert_eq!(rules("let f = fn() 1 end; f() == f()", &config), vec![]);
+        assert_eq!(
+            rules(
+                "let x = 1; if x then end while x then end do end x",
+                &config
+            ),
+            vec![Rule::EmptyBlock, Rule::EmptyBlock, Rule::EmptyBlock]
+        );
+        assert_eq!(
+            rules("var x = 1; if do x = 2; x end then 1 else 2 end", &config),
+            vec![Rule::AssignmentInCondition]
+        );
+        assert_eq!(
+            rules("fn f(a, b, _c) a end", &config),
+            vec![Rule::UnusedParameter]
+        );
+        assert_eq!(rules("fn f(a) fn() a end end", &config), vec![]);
+        assert_eq!(
+            rules(
+                "{\"ab\" = 1, 2 = 2, \"a\" + \"b\" = 3, c'a' = 4, 97 = 5}",
+                &config
+            ),
+            vec![Rule::DuplicateKey, Rule::DuplicateKey]
+        );
+        assert_eq!(
+            rules("let a = 1; {a = 1, a = 2, -1 = 3, 1 = 4}", &config),
+            vec![]
+        );
+        assert_eq!(
+            rules("var a = 1; a == 2; -a; {x = a}; print(a); a", &config),
+            vec![
+                Rule::UselessExpression,
+                Rule::UselessExpression,
+                Rule::UselessExpression
+            ]
+        );
+        assert_eq!(
+            rules("let t = {f = fn(x) x end}; t.f(1); t:f(); t", &config),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn config_table_sets_severity() {
+        let config = LintConfig::from_source(
+            "{ \"unused-parameter\" = \"error\", \"shadowed-variable\" = \"off\" }",
+        )
+        .unwrap();
+        assert_eq!(config.severity(Rule::UnusedParameter), Severity::Error);
+        assert_eq!(config.severity(Rule::EmptyBlock), Severity::Warning);
+        assert_eq!(
+            rules("let x = 1; fn f(x) 1 end", &config),
+            vec![Rule::UnusedParameter]
+        );
+
+        assert_eq!(
+            LintConfig::from_source("{ \"no-such-rule\" = \"off\" }"),
+            Err(LintConfigError::UnknownRule("no-such-rule".to_owned()))
+        );
+        assert_eq!(
+            LintConfig::from_source("{ \"empty-block\" = \"loud\" }"),
+            Err(LintConfigError::UnknownSeverity("loud".to_owned()))
+        );
+        assert_eq!(
+            LintConfig::from_source("1"),
+            Err(LintConfigError::NotATable)
+        );
+    }
+}
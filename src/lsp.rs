@@ -0,0 +1,268 @@
+mod document;
+
+use crate::compiler::symbols::DeclarationKind;
+use crate::lint::Severity;
+use crate::protocol::{read_message, write_message};
+pub use document::{Diagnostic, Document, Span, Symbol};
+use hashbrown::HashMap;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+// A language server over stdin and stdout. Documents are synced in full and analyzed
+// again on every change, positions of the protocol are 0-based
+
+const METHOD_NOT_FOUND: i64 = -32601;
+
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    Server::default().serve(&mut stdin.lock(), &mut stdout.lock())
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    pub fn serve(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        while let Some(message) = read_message(input)? {
+            let method = message["method"].as_str().unwrap_or_default();
+            if method == "exit" {
+                break;
+            }
+            let params = &message["params"];
+            match message.get("id") {
+                Some(id) => {
+                    let response = match self.request(method, params) {
+                        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error }),
+                    };
+                    write_message(output, &response)?;
+                }
+                None => {
+                    for notification in self.notification(method, params) {
+                        write_message(output, &notification)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, Value> {
+        let position = || {
+            let position = &params["position"];
+            (
+                position["line"].as_u64().unwrap_or(0) as usize + 1,
+                position["character"].as_u64().unwrap_or(0) as usize,
+            )
+        };
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let document = self.documents.get(uri);
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "definitionProvider": true,
+                },
+                "serverInfo": { "name": "flux-lsp" },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/hover" => {
+                let (line, character) = position();
+                Ok(document
+                    .and_then(|document| document.hover(line, character))
+                    .map_or(
+                        Value::Null,
+                        |text| json!({ "contents": { "kind": "markdown", "value": text } }),
+                    ))
+            }
+            "textDocument/definition" => {
+                let (line, character) = position();
+                Ok(document
+                    .and_then(|document| document.definition(line, character))
+                    .map_or(
+                        Value::Null,
+                        |span| json!({ "uri": uri, "range": range(span) }),
+                    ))
+            }
+            "textDocument/documentSymbol" => Ok(document.map_or(json!([]), |document| {
+                document
+                    .symbols()
+                    .iter()
+                    .map(|symbol| symbol_information(uri, symbol))
+                    .collect()
+            })),
+            _ => Err(json!({
+                "code": METHOD_NOT_FOUND,
+                "message": format!("Unsupported method {}", method),
+            })),
+        }
+    }
+
+    // Returns the notifications to send back
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // Full sync, the last change holds the whole text
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, &[])];
+            }
+            _ => None,
+        };
+        match text {
+            Some(text) => {
+                let document = Document::new(text.to_owned());
+                let notification = publish_diagnostics(&uri, document.diagnostics());
+                self.documents.insert(uri, document);
+                vec![notification]
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+fn range(span: Span) -> Value {
+    json!({
+        "start": { "line": span.line - 1, "character": span.start },
+        "end": { "line": span.line - 1, "character": span.end },
+    })
+}
+
+// Diagnostics without a span cover their whole line
+fn publish_diagnostics(uri: &str, diagnostics: &[Diagnostic]) -> Value {
+    let diagnostics: Vec<Value> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let line = diagnostic.line.saturating_sub(1);
+            let range = match diagnostic.span {
+                Some(span) => range(span),
+                None => json!({
+                    "start": { "line": line, "character": 0 },
+                    "end": { "line": line + 1, "character": 0 },
+                }),
+            };
+            json!({
+                "range": range,
+                "severity": if diagnostic.severity == Severity::Error { 1 } else { 2 },
+                "source": "flux",
+                "message": diagnostic.message,
+            })
+        })
+        .collect();
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+fn symbol_information(uri: &str, symbol: &Symbol) -> Value {
+    // SymbolKind of the protocol
+    let kind = match symbol.kind {
+        DeclarationKind::Import => 2,
+        DeclarationKind::Enum => 10,
+        _ if symbol.is_function => 12,
+        _ => 13,
+    };
+    let line = symbol.line - 1;
+    json!({
+        "name": symbol.name,
+        "kind": kind,
+        "location": {
+            "uri": uri,
+            "range": {
+                "start": { "line": line, "character": 0 },
+                "end": { "line": line + 1, "character": 0 },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol;
+
+    fn message(value: Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        protocol::write_message(&mut bytes, &value).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn session() {
+        let uri = "file:///main.flux";
+        let mut input = Vec::new();
+        input.extend(message(
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+        ));
+        input.extend(message(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": uri, "text": "let x = 1;\nprintln(x);\nlet y = ;" } },
+        })));
+        input.extend(message(json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "textDocument/definition",
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 1, "character": 8 } },
+        })));
+        input.extend(message(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": uri },
+                "contentChanges": [{ "text": "let x = 1;\nprintln(x);" }],
+            },
+        })));
+        input.extend(message(json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "textDocument/definition",
+            "params": { "textDocument": { "uri": uri }, "position": { "line": 1, "character": 8 } },
+        })));
+        input.extend(message(
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "unknown" }),
+        ));
+        input.extend(message(json!({ "jsonrpc": "2.0", "method": "exit" })));
+
+        let mut output = Vec::new();
+        Server::default()
+            .serve(&mut io::Cursor::new(input), &mut output)
+            .unwrap();
+        let mut output = io::Cursor::new(output);
+        let mut responses = Vec::new();
+        while let Some(message) = protocol::read_message(&mut output).unwrap() {
+            responses.push(message);
+        }
+
+        assert_eq!(responses.len(), 6);
+        assert_eq!(
+            responses[0]["result"]["capabilities"]["hoverProvider"],
+            true
+        );
+        let diagnostics = &responses[1]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 2);
+        // The document could not be parsed
+        assert_eq!(responses[2]["result"], Value::Null);
+        assert_eq!(responses[3]["params"]["diagnostics"], json!([]));
+        assert_eq!(
+            responses[4]["result"]["range"]["start"],
+            json!({ "line": 0, "character": 4 })
+        );
+        assert_eq!(responses[5]["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
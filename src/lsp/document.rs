@@ -0,0 +1,417 @@
+use crate::compiler::symbols::{self, Binding, Declaration, DeclarationKind, SymbolTable};
+use crate::diagnostic;
+use crate::error::FluxError;
+use crate::lint::{self, LintConfig, Severity};
+use crate::parser::{pretty, Ast, BlockExpr, Expr, Parser, Statement};
+use crate::vm::{ArgsLen, Function, Value, PREDEFINED_CONSTANTS};
+use hashbrown::HashMap;
+
+// The analysis of an open file. Lines are 1-based like in the rest of the crate,
+// the server converts them to the 0-based positions of the protocol
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    // The token the error is about, the whole line is meant without it
+    pub span: Option<Span>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: DeclarationKind,
+    pub is_function: bool,
+    pub line: usize,
+}
+
+// Range of a name on a line, in characters
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+pub struct Document {
+    text: String,
+    diagnostics: Vec<Diagnostic>,
+    symbols: SymbolTable,
+    // Short description of the value bound by each let and var, by declaration line and name
+    values: HashMap<(usize, String), String>,
+}
+
+impl Document {
+    pub fn new(text: String) -> Self {
+        let mut document = Document {
+            text,
+            diagnostics: Vec::new(),
+            symbols: SymbolTable::default(),
+            values: HashMap::new(),
+        };
+        match Self::parse(&document.text) {
+            Ok(ast) => document.analyze(&ast),
+            Err(diagnostics) => document.diagnostics = diagnostics,
+        }
+        document
+    }
+
+    fn parse(text: &str) -> Result<Ast, Vec<Diagnostic>> {
+        Parser::new(text)
+            .parse_syntax()
+            .map_err(|errors| errors_of(text, &FluxError::Syntax(errors)))
+    }
+
+    fn analyze(&mut self, ast: &Ast) {
+        self.symbols = symbols::resolve(ast);
+        describe_block(ast.expr(), &mut self.values);
+        // The analyzer reports undeclared names and redeclarations
+        if let Err(err) = Parser::new(&self.text).parse() {
+            let errors = errors_of(&self.text, &FluxError::Parse(err));
+            self.diagnostics.extend(errors);
+        }
+        for lint in lint::lint(ast.expr(), &LintConfig::default()) {
+            self.diagnostics.push(Diagnostic {
+                line: lint.line.unwrap_or(1),
+                span: None,
+                severity: lint.severity,
+                message: format!("{} [{}]", lint.message, lint.rule.id()),
+            });
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // Declarations of the file, natives and arguments are left out
+    pub fn symbols(&self) -> Vec<Symbol> {
+        self.symbols
+            .declarations
+            .iter()
+            .filter(|declaration| {
+                !Parser::is_hidden(&declaration.name)
+                    && matches!(
+                        declaration.kind,
+                        DeclarationKind::Let
+                            | DeclarationKind::Var
+                            | DeclarationKind::Import
+                            | DeclarationKind::Enum
+                    )
+            })
+            .map(|declaration| Symbol {
+                name: declaration.name.clone(),
+                kind: declaration.kind,
+                is_function: self
+                    .value(declaration)
+                    .is_some_and(|value| value.starts_with("fn ")),
+                line: declaration.line,
+            })
+            .collect()
+    }
+
+    pub fn hover(&self, line: usize, character: usize) -> Option<String> {
+        let declaration = self.declaration_at(line, character)?;
+        let binding = match declaration.binding {
+            Binding::Local { .. } => "local",
+            Binding::Upvalue => "captured",
+            Binding::Global => "global",
+            Binding::Recursive => "recursive",
+        };
+        let text = match declaration.kind {
+            DeclarationKind::Native => native_signature(&declaration.name)
+                .unwrap_or_else(|| format!("{} (host global)", declaration.name)),
+            DeclarationKind::Argument => format!("argument {}", declaration.name),
+            _ => self
+                .value(declaration)
+                .cloned()
+                .unwrap_or_else(|| declaration.name.clone()),
+        };
+        Some(format!("```flux\n{}\n```\n{}", text, binding))
+    }
+
+    pub fn definition(&self, line: usize, character: usize) -> Option<Span> {
+        let declaration = self.declaration_at(line, character)?;
+        if declaration.kind == DeclarationKind::Native {
+            return None;
+        }
+        Some(Span {
+            line: declaration.line,
+            start: declaration.column.checked_sub(1)?,
+            end: declaration.end_column - 1,
+        })
+    }
+
+    fn value(&self, declaration: &Declaration) -> Option<&String> {
+        self.values
+            .get(&(declaration.line, declaration.name.clone()))
+    }
+
+    // The declaration of the name under the cursor, or the declaration itself
+    fn declaration_at(&self, line: usize, character: usize) -> Option<&Declaration> {
+        // Columns start from 1, the cursor may also be right after the name
+        let column = character + 1;
+        let under =
+            |at: usize, start: usize, end: usize| at == line && start <= column && column <= end;
+        let reference = self
+            .symbols
+            .references
+            .iter()
+            .find(|r| under(r.line, r.column, r.end_column));
+        match reference {
+            Some(reference) => self.symbols.definition(reference),
+            None => self
+                .symbols
+                .declarations
+                .iter()
+                .find(|d| d.column > 0 && under(d.line, d.column, d.end_column)),
+        }
+    }
+}
+
+// The messages the command line prints, with the help appended
+fn errors_of(text: &str, error: &FluxError) -> Vec<Diagnostic> {
+    diagnostic::Diagnostic::from_error(error)
+        .into_iter()
+        .map(|diagnostic| {
+            let line = diagnostic.line.unwrap_or(1);
+            let span = diagnostic
+                .span
+                .filter(|span| span.line == line)
+                .map(|span| {
+                    // A token going on past its line, like an unterminated string, is cut at it
+                    let width = text
+                        .get(span.start..span.end)
+                        .unwrap_or_default()
+                        .chars()
+                        .take_while(|c| *c != '\n')
+                        .count();
+                    let start = span.column - 1;
+                    Span {
+                        line,
+                        start,
+                        end: start + width.max(1),
+                    }
+                });
+            let message = match diagnostic.help {
+                Some(help) => format!("{}\nhelp: {}", diagnostic.message, help),
+                None => diagnostic.message,
+            };
+            Diagnostic {
+                line,
+                span,
+                severity: Severity::Error,
+                message,
+            }
+        })
+        .collect()
+}
+
+fn native_signature(name: &str) -> Option<String> {
+    let constants = PREDEFINED_CONSTANTS;
+    let (_, value) = constants.iter().find(|(n, _)| *n == name)?;
+    match value {
+        Value::Function(Function::Native(native)) => Some(match native.args_len {
+            ArgsLen::Variadic => format!("native fn {}(...)", name),
+            ArgsLen::Exact(1) => format!("native fn {}(1 argument)", name),
+            ArgsLen::Exact(len) => format!("native fn {}({} arguments)", name, len),
+        }),
+        _ => None,
+    }
+}
+
+fn describe_block(block: &BlockExpr, values: &mut HashMap<(usize, String), String>) {
+    block
+        .stmts
+        .iter()
+        .for_each(|stmt| describe_stmt(stmt, values));
+    describe_expr(&block.expr, values);
+}
+
+fn describe_stmt(stmt: &Statement, values: &mut HashMap<(usize, String), String>) {
+    match stmt {
+        Statement::Let {
+            name, value, line, ..
+        }
+        | Statement::Var {
+            name, value, line, ..
+        } => {
+            let text = match value {
+                Expr::Function { args, .. } => format!("fn {}({})", name, args.join(", ")),
+                _ => pretty::print(std::slice::from_ref(stmt))
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned(),
+            };
+            values.insert((*line, name.clone()), text);
+            describe_expr(value, values);
+        }
+        Statement::Import {
+            path, name, line, ..
+        } => {
+            let text = format!("import {} as {}", path.join("."), name);
+            values.insert((*line, name.clone()), text);
+        }
+        Statement::Enum {
+            name,
+            variants,
+            line,
+            ..
+        } => {
+            let text = format!("enum {} {{ {} }}", name, variants.join(", "));
+            values.insert((*line, name.clone()), text);
+        }
+        Statement::Expr(expr) | Statement::Print(expr) | Statement::Return(expr) => {
+            describe_expr(expr, values)
+        }
+        Statement::Set { value, .. } => describe_expr(value, values),
+        Statement::Block(stmts) => stmts.iter().for_each(|stmt| describe_stmt(stmt, values)),
+        Statement::If {
+            then_block,
+            else_block,
+            ..
+        } => {
+            describe_expr(then_block, values);
+            if let Some(else_block) = else_block {
+                describe_expr(else_block, values);
+            }
+        }
+        Statement::While { then_block, .. }
+        | Statement::For {
+            body: then_block, ..
+        } => describe_stmt(then_block, values),
+        Statement::Break { .. } | Statement::Continue { .. } => (),
+    }
+}
+
+// Only expressions that can hold statements are visited
+fn describe_expr(expr: &Expr, values: &mut HashMap<(usize, String), String>) {
+    match expr {
+        Expr::Function { body: block, .. } | Expr::Block(block) => describe_block(block, values),
+        Expr::If {
+            then_block,
+            else_block,
+            ..
+        } => {
+            describe_expr(then_block, values);
+            describe_expr(else_block, values);
+        }
+        Expr::Call { func, args } => {
+            describe_expr(func, values);
+            args.iter().for_each(|arg| describe_expr(arg, values));
+        }
+        Expr::SelfAccess { args: exprs, .. } | Expr::Tuple(exprs) => {
+            exprs.iter().for_each(|expr| describe_expr(expr, values))
+        }
+        Expr::TableInit { values: exprs, .. } => {
+            exprs.iter().for_each(|expr| describe_expr(expr, values))
+        }
+        Expr::Unary { expr, .. }
+        | Expr::Grouping(expr)
+        | Expr::Yield(expr)
+        | Expr::Cast { expr, .. } => describe_expr(expr, values),
+        Expr::Binary { left, right, .. } => {
+            describe_expr(left, values);
+            describe_expr(right, values);
+        }
+        Expr::Literal(_) | Expr::Identifier { .. } | Expr::Access { .. } | Expr::Rec => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+fn add(a, b)
+    a + b
+end
+let total = add(1, 2);
+println(total);
+";
+
+    #[test]
+    fn reports_syntax_errors_and_lints() {
+        let document = Document::new("let x = ;\nlet y = 2;".to_owned());
+        assert_eq!(document.diagnostics()[0].line, 1);
+        assert_eq!(document.diagnostics()[0].severity, Severity::Error);
+        assert_eq!(document.diagnostics()[0].message, "unexpected ';'");
+        assert_eq!(
+            document.diagnostics()[0].span,
+            Some(Span {
+                line: 1,
+                start: 8,
+                end: 9
+            })
+        );
+
+        let document = Document::new("let x = 1;\nlet y = z + x;".to_owned());
+        let undeclared = &document.diagnostics()[0];
+        assert!(
+            undeclared
+                .message
+                .starts_with("'z' is not declared\nhelp: "),
+            "{}",
+            undeclared.message
+        );
+        assert_eq!(undeclared.line, 2);
+
+        let document = Document::new("fn f(a) 1 end".to_owned());
+        assert_eq!(document.diagnostics().len(), 1);
+        assert_eq!(document.diagnostics()[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn hover_shows_signatures() {
+        let document = Document::new(SOURCE.to_owned());
+        let hover = document.hover(4, 14).unwrap();
+        assert!(hover.contains("fn add(a, b)"), "{}", hover);
+        let hover = document.hover(5, 2).unwrap();
+        assert!(hover.contains("native fn println(...)"), "{}", hover);
+        let hover = document.hover(5, 10).unwrap();
+        assert!(hover.contains("let total = add(1, 2);"), "{}", hover);
+        assert_eq!(document.hover(4, 3), None);
+    }
+
+    #[test]
+    fn definition_and_symbols() {
+        let document = Document::new(SOURCE.to_owned());
+        assert_eq!(
+            document.definition(5, 9),
+            Some(Span {
+                line: 4,
+                start: 4,
+                end: 9
+            })
+        );
+        assert_eq!(
+            document.definition(2, 4),
+            Some(Span {
+                line: 1,
+                start: 7,
+                end: 8
+            })
+        );
+        let symbols: Vec<(String, bool)> = document
+            .symbols()
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.is_function))
+            .collect();
+        assert_eq!(
+            symbols,
+            vec![("add".to_owned(), true), ("total".to_owned(), false)]
+        );
+    }
+
+    #[test]
+    fn definition_uses_the_columns_of_names() {
+        let document = Document::new("let y = 1;\nlet x = y; let y = x;\ny".to_owned());
+        let span = |line, start, end| Some(Span { line, start, end });
+        assert_eq!(document.definition(3, 0), span(2, 15, 16));
+        assert_eq!(document.definition(2, 8), span(1, 4, 5));
+        assert_eq!(document.definition(2, 19), span(2, 4, 5));
+        assert_eq!(document.definition(2, 10), None);
+    }
+}
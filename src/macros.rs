@@ -1,20 +1,78 @@
+#[cfg(feature = "std")]
 #[allow(unused_macros)]
 macro_rules! debug {
     ($($arg:tt)*) => {
         if cfg!(debug_assertions) {
-            println!($($arg)*)
+            eprintln!($($arg)*)
         }
     };
 }
 
+// Without std there is no stderr, the arguments are only checked
+#[cfg(not(feature = "std"))]
+#[allow(unused_macros)]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
+    };
+}
+
 #[allow(unused_macros)]
 macro_rules! unit_test {
     ($name:ident, $source:expr, $expected:expr) => {
         #[test]
         fn $name() {
-            use crate::util::eval;
+            use crate::error::FluxError;
+            use crate::macros::eval;
 
-            assert_eq!(eval($source, ""), $expected);
+            // The tests are about the kind of the errors, not where they happened
+            assert_eq!(
+                eval($source).map_err(FluxError::without_location),
+                $expected
+            );
         }
     };
 }
+
+// Compiles and runs the source in a new vm. Unlike util::eval it doesn't need the
+// std feature, so the tests run without it too
+#[cfg(test)]
+pub fn eval(source: &str) -> crate::error::FluxResult<crate::vm::Value> {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+
+    let ast = Parser::new(source).parse_all()?;
+    let compiled = Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })?;
+    let interpreted = crate::vm::Vm::new().run(&compiled);
+    // With the jit the source runs again compiling every function on its first
+    // call, both backends have to agree
+    #[cfg(feature = "jit")]
+    {
+        let mut vm = crate::vm::Vm::new();
+        vm.set_jit_threshold(Some(0));
+        let compiled = Compiler::compile(SourceFile {
+            ast: Parser::new(source).parse_all()?,
+            metadata: MetaData::default(),
+        })?;
+        assert_eq!(vm.run(&compiled), interpreted, "the jit disagrees");
+    }
+    Ok(interpreted?)
+}
+
+#[cfg(test)]
+pub fn compile(source: &str) -> crate::compiler::CompiledSource {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+
+    let ast = Parser::new(source).parse_all().unwrap();
+    Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })
+    .unwrap()
+}
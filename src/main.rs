@@ -1,73 +1,406 @@
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate maplit;
 extern crate dirs;
 
-#[macro_use]
-mod macros;
-mod compiler;
-pub mod error;
-mod parser;
-mod scanner;
-mod sourcefile;
-mod util;
-mod vm;
-
-use compiler::Compiler;
-use parser::Parser;
-use sourcefile::{MetaData, SourceFile};
-use std::io::Write;
-use util::run_file;
-use vm::Vm;
+mod completion;
+
+use completion::ReplHelper;
+use flux_rs::bench::BenchConfig;
+use flux_rs::compiler::symbols;
+use flux_rs::diagnostic::{render_error, use_color};
+use flux_rs::error;
+use flux_rs::formatter::FormatConfig;
+use flux_rs::lint::{LintConfig, Severity};
+use flux_rs::parser::{pretty, Parser};
+use flux_rs::session::Session;
+use flux_rs::test_runner::{discover, run_test_file};
+use flux_rs::util::{
+    self, bench_file, build_file, check_file, compile_file, coverage_file, disassemble_file,
+    format_file, lint_file, load_file, parse_file, parse_syntax_file, profile_file, run_embedded,
+    run_file, trace_file,
+};
+use flux_rs::vm::{self, Vm};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
+    // Executables made by 'flux_rs build' run their script with all the arguments
+    if let Some(result) = run_embedded(&args) {
+        if let Err(err) = result {
+            print!("{}", render_error(&err, "", Some(&args[0]), use_color()));
+            std::process::exit(1);
+        }
+    } else if args.len() < 2 {
         match repl() {
             Ok(()) => (),
             Err(err) => println!("{:?}", err),
         }
+    } else if args[1] == "--emit" {
+        match (args.get(2).map(String::as_str), args.get(3)) {
+            (Some("ast"), Some(path)) => match parse_file(path) {
+                Ok(ast) => println!("{}", ast.to_json()),
+                Err(err) => report(&err, path),
+            },
+            (Some("source"), Some(path)) => match parse_syntax_file(path) {
+                Ok(ast) => print!("{}", pretty::print_block(ast.expr())),
+                Err(err) => report(&err, path),
+            },
+            (Some("symbols"), Some(path)) => match parse_syntax_file(path) {
+                Ok(ast) => println!("{}", symbols::resolve(&ast).to_json()),
+                Err(err) => report(&err, path),
+            },
+            (Some("bytecode"), Some(path)) => match disassemble_file(path) {
+                Ok(text) => print!("{}", text),
+                Err(err) => report(&err, path),
+            },
+            _ => println!("Usage: flux_rs --emit [ast|source|symbols|bytecode] [file_path]"),
+        }
+    } else if args[1] == "--trace" {
+        match args.get(2) {
+            Some(path) => match trace_file(path, &args[3..], std::io::stderr()) {
+                Ok(value) => println!("Exited program. Evaluated: {}", value),
+                Err(err) => report(&err, path),
+            },
+            None => println!("Usage: flux_rs --trace [file_path] [args]"),
+        }
+    } else if args[1] == "compile" {
+        match (args.get(2), args.get(3).map(String::as_str), args.get(4)) {
+            (Some(path), None, None) => {
+                let output = Path::new(path).with_extension("fluxc");
+                compile(path, &output.to_string_lossy())
+            }
+            (Some(path), Some("-o"), Some(output)) => compile(path, output),
+            _ => println!("Usage: flux_rs compile [file_path] -o [output_path]"),
+        }
+    } else if args[1] == "build" {
+        match (args.get(2), args.get(3).map(String::as_str), args.get(4)) {
+            // Next to the script, without its extension
+            (Some(path), None, None) if Path::new(path).extension().is_some() => {
+                let output = Path::new(path).with_extension(std::env::consts::EXE_EXTENSION);
+                build(path, &output)
+            }
+            (Some(path), Some("-o"), Some(output)) => build(path, Path::new(output)),
+            _ => println!("Usage: flux_rs build [file_path] -o [output_path]"),
+        }
+    } else if args[1] == "fmt" {
+        let indent = match args.get(3).map(String::as_str) {
+            Some("--indent") => args.get(4).and_then(|width| width.parse().ok()),
+            None => Some(FormatConfig::default().indent_width),
+            _ => None,
+        };
+        match (args.get(2), indent) {
+            (Some(path), Some(indent_width)) => {
+                match format_file(path, &FormatConfig { indent_width }) {
+                    Ok(true) => println!("Formatted {}", path),
+                    Ok(false) => println!("{} is already formatted", path),
+                    Err(err) => report(&err, path),
+                }
+            }
+            _ => println!("Usage: flux_rs fmt [file_path] --indent [width]"),
+        }
+    } else if args[1] == "lint" {
+        match (args.get(2), args.get(3).map(String::as_str), args.get(4)) {
+            (Some(path), None, None) => lint(path, LintConfig::default()),
+            (Some(path), Some("--config"), Some(config)) => {
+                let source = std::fs::read_to_string(config).unwrap();
+                match LintConfig::from_source(&source) {
+                    Ok(config) => lint(path, config),
+                    Err(err) => println!("Error in {}: {}", config, err),
+                }
+            }
+            _ => println!("Usage: flux_rs lint [file_path] --config [config_path]"),
+        }
+    } else if args[1] == "check" {
+        match args.get(2) {
+            Some(path) => check(path),
+            None => println!("Usage: flux_rs check [file_path]"),
+        }
+    } else if args[1] == "profile" {
+        match args.get(2) {
+            Some(path) => match profile_file(path, &args[3..]) {
+                Ok((value, profile)) => {
+                    println!("Exited program. Evaluated: {}", value);
+                    print!("{}", profile)
+                }
+                Err(err) => report(&err, path),
+            },
+            None => println!("Usage: flux_rs profile [file_path] [args]"),
+        }
+    } else if args[1] == "coverage" {
+        match (args.get(2), args.get(3).map(String::as_str), args.get(4)) {
+            (Some(path), None, None) => {
+                let output = Path::new(path).with_extension("lcov");
+                coverage(path, &output.to_string_lossy())
+            }
+            (Some(path), Some("-o"), Some(output)) => coverage(path, output),
+            _ => println!("Usage: flux_rs coverage [file_path] -o [output_path]"),
+        }
+    } else if args[1] == "bench" {
+        match (args.get(2), bench_config(&args[3..])) {
+            (Some(path), Some(config)) => match bench_file(path, &[], &config) {
+                Ok(bench) => print!("{}", bench),
+                Err(err) => report(&err, path),
+            },
+            _ => println!(
+                "Usage: flux_rs bench [file_path] -n [iterations] --warmup [iterations] --fn [name]"
+            ),
+        }
+    } else if args[1] == "test" {
+        match args.get(2) {
+            Some(path) => test(path),
+            None => println!("Usage: flux_rs test [file_or_directory]"),
+        }
+    } else if args[1] == "watch" {
+        match args.get(2) {
+            Some(path) => watch(path, &args[3..]),
+            None => println!("Usage: flux_rs watch [file_path] [args]"),
+        }
     } else {
         let path = &args[1];
         /* let mut file = File::open(path).unwrap();
         let mut buffer = String::new();
         file.read_to_string(&mut buffer).unwrap(); */
 
-        let value = run_file(path);
+        let value = run_file(path, &args[2..]);
         match value {
             Ok(value) => println!("Exited program. Evaluated: {}", value),
-            Err(err) => println!("Error: {}", err),
+            Err(err) => report(&err, path),
         }
     }
 }
 
-fn repl() -> Result<(), error::FluxError> {
-    let stdin = std::io::stdin();
-    let mut line = String::new();
-    let mut vm = Vm::new();
+// Prints the error with the lines of 'path' it points at
+fn report(err: &error::FluxError, path: &str) {
+    // The source is only used to show lines, a file that can't be read shows none
+    let source = std::fs::read_to_string(path).unwrap_or_default();
+    print!("{}", render_error(err, &source, Some(path), use_color()));
+}
+
+fn compile(path: &str, output: &str) {
+    match compile_file(path, output) {
+        Ok(()) => println!("Compiled {} to {}", path, output),
+        Err(err) => report(&err, path),
+    }
+}
+
+fn build(path: &str, output: &Path) {
+    match build_file(path, &output.to_string_lossy()) {
+        Ok(()) => println!("Built {} into {}", path, output.display()),
+        Err(err) => report(&err, path),
+    }
+}
+
+fn coverage(path: &str, output: &str) {
+    match coverage_file(path, &[]) {
+        Ok((_, coverage)) => {
+            std::fs::write(output, coverage.to_lcov(path)).unwrap();
+            println!(
+                "Covered {} of {} lines, wrote {}",
+                coverage.lines_hit(),
+                coverage.lines_found(),
+                output
+            )
+        }
+        Err(err) => report(&err, path),
+    }
+}
+
+fn bench_config(options: &[String]) -> Option<BenchConfig> {
+    let mut config = BenchConfig::default();
+    for option in options.chunks(2) {
+        match (option[0].as_str(), option.get(1)) {
+            ("-n", Some(value)) => config.iterations = value.parse().ok()?,
+            ("--warmup", Some(value)) => config.warmup = value.parse().ok()?,
+            ("--fn", Some(name)) => config.function = Some(name.clone()),
+            _ => return None,
+        }
+    }
+    Some(config)
+}
+
+// Runs the test files under 'path', exits with an error code if any test fails
+fn test(path: &str) {
+    let (mut passed, mut failed) = (0, 0);
+    for file in discover(Path::new(path)) {
+        let file = file.to_string_lossy();
+        println!("{}", file);
+        match run_test_file(&file) {
+            Ok(results) => {
+                for result in results {
+                    match result.failure {
+                        None => {
+                            passed += 1;
+                            println!("  ok      {}", result.name)
+                        }
+                        Some(failure) => {
+                            failed += 1;
+                            println!("  FAILED  {}", result.name);
+                            for line in failure.to_string().lines() {
+                                println!("    {}", line);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                failed += 1;
+                report(&err, &file)
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+// Reruns the script whenever it or a module it imports changes, runs until interrupted
+fn watch(path: &str, args: &[String]) {
+    let mut script_args = vec![path.to_owned()];
+    script_args.extend_from_slice(args);
+    // The imports are only known once the script compiles
+    let mut files = vec![PathBuf::from(path)];
     loop {
-        print!("> ");
-        std::io::stdout().flush().unwrap();
-        stdin.read_line(&mut line).unwrap();
-        let mut parser = Parser::new(&line)?;
-        let ast = parser.parse()?;
-        dbg!(&ast);
-        match Compiler::compile(SourceFile {
-            ast,
-            metadata: MetaData::default(),
-        }) {
+        let mut modified = modified_times(&files);
+        match load_file(path) {
             Ok(compiled) => {
-                dbg!(&compiled.chunk);
-                match vm.run(compiled) {
-                    Ok(value) => println!("{}", value),
-                    Err(error) => println!("{:?}", error),
+                files = compiled.files().into_iter().map(PathBuf::from).collect();
+                modified = modified_times(&files);
+                let mut vm = Vm::new();
+                vm.set_args(&script_args);
+                match vm.run(&compiled) {
+                    Ok(value) => println!("Exited program. Evaluated: {}", value),
+                    Err(err) => report(&err.into(), path),
                 }
             }
-            Err(err) => {
-                println!("{:?}", err);
+            Err(err) => report(&err, path),
+        }
+        println!("Watching {} file(s) for changes", files.len());
+        while modified_times(&files) == modified {
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        println!();
+    }
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// Exits with an error code if the annotations don't match
+fn check(path: &str) {
+    match check_file(path) {
+        Ok(errors) => {
+            for error in &errors {
+                println!("{}:{}", path, error);
             }
+            if !errors.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => report(&err, path),
+    }
+}
+
+// Exits with an error code if any lint has the error severity
+fn lint(path: &str, config: LintConfig) {
+    match lint_file(path, &config) {
+        Ok(lints) => {
+            for lint in &lints {
+                println!("{}:{}", path, lint);
+            }
+            if lints.iter().any(|lint| lint.severity == Severity::Error) {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => report(&err, path),
+    }
+}
+
+const HISTORY_FILE: &str = ".flux_history";
+
+fn repl() -> rustyline::Result<()> {
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ReplHelper::new()));
+    let history = dirs::home_dir().map(|home| home.join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // There is no history on the first run
+        let _ = editor.load_history(path);
+    }
+    let mut source = String::new();
+    let mut vm = Vm::new();
+    let mut session = Session::new();
+    loop {
+        let prompt = if source.is_empty() { "> " } else { ".. " };
+        if let Some(helper) = editor.helper_mut() {
+            helper.refresh(&vm, &source);
+        }
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C drops the current input, Ctrl-D exits
+            Err(ReadlineError::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
         };
-        line.clear();
+        if source.is_empty() && line.trim_start().starts_with(':') {
+            editor.add_history_entry(line.trim())?;
+            repl_command(&mut vm, &mut session, line.trim());
+            continue;
+        }
+        // An empty line ends the input even if it is incomplete
+        let force = !source.is_empty() && line.trim().is_empty();
+        source.push_str(&line);
+        source.push('\n');
+        if !force && Parser::is_incomplete(&source) {
+            continue;
+        }
+        editor.add_history_entry(source.trim_end())?;
+        match eval_repl(&mut vm, &source) {
+            Ok(()) => session.record(&vm, &source),
+            Err(err) => print!("{}", render_error(&err, &source, None, use_color())),
+        }
+        source.clear();
+    }
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            println!("Could not save the history: {}", err);
+        }
+    }
+    Ok(())
+}
+
+// ':save path' writes the globals of the session as a script, ':load path' runs one
+fn repl_command(vm: &mut Vm, session: &mut Session, command: &str) {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(":save"), Some(path), None) => match std::fs::write(path, session.save(vm)) {
+            Ok(()) => println!("Saved the session to {}", path),
+            Err(err) => println!("Error: {}", err),
+        },
+        (Some(":load"), Some(path), None) => match load_file(path) {
+            Ok(compiled) => match vm.run(&compiled) {
+                // The file was just read
+                Ok(_) => session.record(vm, &std::fs::read_to_string(path).unwrap()),
+                Err(err) => report(&err.into(), path),
+            },
+            Err(err) => report(&err, path),
+        },
+        _ => println!("Commands: :save [file_path], :load [file_path]"),
+    }
+}
+
+fn eval_repl(vm: &mut Vm, source: &str) -> Result<(), error::FluxError> {
+    match util::eval_repl(vm, source)? {
+        vm::Value::Unit => (),
+        value => println!("{}", value),
     }
+    Ok(())
 }
@@ -1,46 +1,126 @@
 mod analyzer;
+mod comments;
 mod error;
 mod expr;
 mod lookahead;
+mod precedence;
+pub mod pretty;
 mod statement;
+mod types;
 
 pub use super::scanner::{Token, TokenType};
-use crate::scanner::Scanner;
+use crate::scanner::{LexErrorKind, Scanner, Span};
+use crate::vm::lib::ITERATE;
+use alloc::{boxed::Box, string::String, string::ToString, vec::Vec};
+pub use comments::{Comment, Comments};
+use core::convert::TryFrom;
+use core::ops::{Deref, DerefMut};
 pub use error::{ParserError, ParserErrorKind};
 pub use expr::{BinaryOp, BlockExpr, Expr, Literal, UnaryOp};
 use lookahead::LookAhead;
+pub use precedence::{Assoc, Infix, Precedence};
+use serde::Serialize;
 pub use statement::Statement;
-use std::ops::{Deref, DerefMut};
+pub use types::{Signature, Type};
 
-type Result<T> = std::result::Result<T, ParserError>;
+type Result<T> = core::result::Result<T, ParserError>;
 
-#[derive(Debug, Clone, PartialEq)]
+// How deep expressions and blocks may nest by default, deeper source would overflow
+// the stack of the recursive passes over the tree
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Ast(BlockExpr);
 
 impl Ast {
     pub fn get_expr(self) -> BlockExpr {
         self.0
     }
+
+    pub fn expr(&self) -> &BlockExpr {
+        &self.0
+    }
+
+    // Structured view of the parse for external tools
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Ast is always serializable")
+    }
 }
 
+// Hidden locals of for loops, names that can't be written in the source
+pub(crate) const ITERATOR_NAME: &str = "";
+// Holds the start of a range loop while its end is evaluated
+pub(crate) const RANGE_START_NAME: &str = "..";
+
 pub struct Parser<I>
 where
     I: Iterator<Item = Token>,
 {
     lookahead: LookAhead<I>,
+    // Errors recovered from while parsing
+    errors: Vec<ParserError>,
+    // The first error was found at the end of the source, more input could complete it
+    incomplete: bool,
+    // Tokens opening the delimiters and blocks being parsed, innermost last
+    delimiters: Vec<Token>,
+    // Nesting of the tree being built, see 'descend'
+    depth: usize,
+    max_depth: usize,
+    // Defined by the host before the script runs, see with_globals
+    globals: Vec<String>,
 }
 
-impl Parser<std::vec::IntoIter<Token>> {
-    pub fn new(source: &str) -> Result<Self> {
+impl Parser<alloc::vec::IntoIter<Token>> {
+    // Lexical errors are reported by the parse functions along with the syntax errors,
+    // the tokens around them are still parsed
+    pub fn new(source: &str) -> Self {
         let mut scanner = Scanner::new(source);
-        scanner.scan()?;
+        scanner.scan();
+        let errors: Vec<ParserError> = scanner.errors().iter().map(|&err| err.into()).collect();
+        // More lines could close the string
+        let incomplete = matches!(
+            errors.first().map(|err| &err.kind),
+            Some(ParserErrorKind::Lex(LexErrorKind::UnterminatedString))
+        );
         let lookahead = LookAhead::new(scanner.extract_tokens().into_iter());
-        Ok(Parser { lookahead })
+        Parser {
+            lookahead,
+            errors,
+            incomplete,
+            delimiters: Vec::new(),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            globals: Vec::new(),
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    // Lets the script use globals it doesn't declare itself, like the natives
+    // registered with Vm::register_native
+    pub fn with_globals<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.globals.extend(names.into_iter().map(Into::into));
+        self
     }
 
     pub fn parse_str(source: &str) -> Result<Ast> {
-        let mut parser = Parser::new(source)?;
-        parser.parse()
+        Parser::new(source).parse()
+    }
+
+    // Whether the name is one of the hidden locals of for loops, which
+    // can't be written in the source
+    pub fn is_hidden(name: &str) -> bool {
+        name == ITERATOR_NAME || name == RANGE_START_NAME
+    }
+
+    // Whether the source only fails because it ends too early, like an unclosed block or a
+    // trailing operator, so that the repl can ask for more lines
+    pub fn is_incomplete(source: &str) -> bool {
+        let mut parser = Parser::new(source);
+        parser.parse_syntax().is_err() && parser.incomplete
     }
 }
 
@@ -49,29 +129,77 @@ where
     I: Iterator<Item = Token>,
 {
     pub fn parse(&mut self) -> Result<Ast> {
+        self.parse_all().map_err(|mut errors| errors.remove(0))
+    }
+
+    // Parses the whole source, recovering from syntax errors at statement boundaries
+    // so that every error in the source is reported at once
+    pub fn parse_all(&mut self) -> core::result::Result<Ast, Vec<ParserError>> {
         use analyzer::Analyzer;
-        let block = self.block_expr(TokenType::Eof)?;
-        // Ok(Ast(block))
-        Analyzer::analyze(Ast(block), self)
+        let ast = self.parse_syntax()?;
+        Analyzer::analyze(ast, self).map_err(|err| vec![err])
+    }
+
+    // Parses without running the analyzer so the tree is kept as it is written in the source
+    pub fn parse_syntax(&mut self) -> core::result::Result<Ast, Vec<ParserError>> {
+        let block = match self.block_expr(TokenType::Eof) {
+            Ok(block) => Some(block),
+            Err(err) => {
+                self.push_error(err);
+                None
+            }
+        };
+        match block {
+            Some(block) if self.errors.is_empty() => Ok(Ast(block)),
+            _ => {
+                // The lexical errors come first, they are put in place among the others
+                let mut errors = core::mem::take(&mut self.errors);
+                errors.sort_by_key(|err| err.line);
+                Err(errors)
+            }
+        }
     }
 
     pub fn statement(&mut self) -> Result<Statement> {
+        let depth = self.descend()?;
+        let stmt = self.statement_impl();
+        self.depth = depth;
+        stmt
+    }
+
+    fn statement_impl(&mut self) -> Result<Statement> {
         if self.match_token(TokenType::Let).is_ok() {
             self.let_stmt()
         } else if self.match_token(TokenType::Var).is_ok() {
             self.var_stmt()
         } else if self.match_token(TokenType::If).is_ok() {
-            self.if_stmt()
+            self.open();
+            self.if_stmt().map(|stmt| self.skip_semicolon(stmt))
         } else if self.match_token(TokenType::While).is_ok() {
-            self.while_stmt()
+            self.open();
+            self.while_stmt().map(|stmt| self.skip_semicolon(stmt))
         } else if self.match_token(TokenType::For).is_ok() {
-            self.for_stmt()
+            self.open();
+            self.for_stmt().map(|stmt| self.skip_semicolon(stmt))
         } else if self.match_token(TokenType::Return).is_ok() {
             self.return_stmt()
+        } else if let Ok(token) = self.match_token(TokenType::Break) {
+            self.match_token(TokenType::Semicolon)?;
+            Ok(Statement::Break {
+                line: token.get_line(),
+            })
+        } else if let Ok(token) = self.match_token(TokenType::Continue) {
+            self.match_token(TokenType::Semicolon)?;
+            Ok(Statement::Continue {
+                line: token.get_line(),
+            })
         } else if self.match_token(TokenType::Fn).is_ok() {
-            self.fn_stmt()
+            self.open();
+            self.fn_stmt().map(|stmt| self.skip_semicolon(stmt))
         } else if self.match_token(TokenType::Import).is_ok() {
             self.import_stmt()
+        } else if self.match_token(TokenType::Enum).is_ok() {
+            self.enum_stmt().map(|stmt| self.skip_semicolon(stmt))
         } else {
             let expr = self.expression()?;
             if self.match_token(TokenType::Equal).is_ok() {
@@ -81,32 +209,94 @@ where
             } else {
                 match expr {
                     Expr::Block(..) => Ok(Statement::Expr(expr)),
-                    _ => Err(self.make_error(ParserErrorKind::UnexpectedExpr(expr))?),
+                    _ => Err(self.make_error(ParserErrorKind::UnexpectedExpr(Box::new(expr)))?),
                 }
             }
         }
     }
 
+    // Statements closed by 'end' need no semicolon, one is still allowed after them
+    fn skip_semicolon(&mut self, stmt: Statement) -> Statement {
+        let _ = self.match_token(TokenType::Semicolon);
+        stmt
+    }
+
     fn let_stmt(&mut self) -> Result<Statement> {
         let token = self.match_token(TokenType::Identifier)?;
         let name = token.text();
+        let ty = self.annotation(TokenType::Colon)?;
         self.match_token(TokenType::Equal)?;
         let value = self.expression()?;
         self.match_token(TokenType::Semicolon)?;
         Ok(Statement::Let {
             name: name.to_string(),
+            ty,
             value,
+            line: token.get_line(),
+            column: token.span().column,
         })
     }
 
     fn var_stmt(&mut self) -> Result<Statement> {
-        let name = self.match_token(TokenType::Identifier)?.text().to_string();
+        let token = self.match_token(TokenType::Identifier)?;
+        let Span { line, column, .. } = token.span();
+        let name = token.extract_text();
+        let ty = self.annotation(TokenType::Colon)?;
 
         self.match_token(TokenType::Equal)?;
         let value = self.expression()?;
         // Maybe optional
         self.match_token(TokenType::Semicolon)?;
-        Ok(Statement::Var { name, value })
+        Ok(Statement::Var {
+            name,
+            ty,
+            value,
+            line,
+            column,
+        })
+    }
+
+    // The type after 'prefix', ': int' or '-> int', None without the prefix
+    fn annotation(&mut self, prefix: TokenType) -> Result<Option<Type>> {
+        match self.match_token(prefix) {
+            Ok(_) => self.type_annotation().map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // int, number, str, bool, nil, table, fn, any, () or a tuple like (int, str)
+    fn type_annotation(&mut self) -> Result<Type> {
+        if self.match_token(TokenType::Fn).is_ok() {
+            return Ok(Type::Function);
+        } else if self.match_token(TokenType::Nil).is_ok() {
+            return Ok(Type::Nil);
+        } else if self.match_token(TokenType::LeftParen).is_ok() {
+            self.open();
+            let mut types = Vec::new();
+            while self.close(TokenType::RightParen).is_err() {
+                types.push(self.type_annotation()?);
+                if self.match_token(TokenType::Comma).is_err() {
+                    self.close(TokenType::RightParen)?;
+                    break;
+                }
+            }
+            return Ok(match types.len() {
+                0 => Type::Unit,
+                1 => types.pop().unwrap(),
+                _ => Type::Tuple(types),
+            });
+        }
+        let token = self.match_token(TokenType::Identifier)?;
+        match Type::from_name(token.text()) {
+            Some(ty) => Ok(ty),
+            None => Err(ParserError {
+                line: token.get_line(),
+                span: Some(token.span()),
+                kind: ParserErrorKind::UnknownType {
+                    name: token.extract_text(),
+                },
+            }),
+        }
     }
 
     fn if_stmt(&mut self) -> Result<Statement> {
@@ -116,6 +306,7 @@ where
         let then_block = self.block_expr_impl()?;
         if self.match_token(TokenType::Else).is_ok() {
             let else_block = if self.match_token(TokenType::If).is_ok() {
+                self.descend()?;
                 let if_stmt = self.if_stmt()?;
                 if if_stmt.can_convert_expr() {
                     Some(Box::new(if_stmt.into_expr().unwrap()))
@@ -135,7 +326,7 @@ where
                 else_block,
             })
         } else {
-            self.match_token(TokenType::End)?;
+            self.close(TokenType::End)?;
             Ok(Statement::If {
                 condition,
                 then_block: Box::new(then_block.into()),
@@ -146,11 +337,14 @@ where
 
     fn block_stmt(&mut self) -> Result<Vec<Statement>> {
         let mut stmts = Vec::new();
-        while self.current()?.get_type() != TokenType::End
-            && self.current()?.get_type() != TokenType::Else
-        {
-            let stmt = self.statement()?;
-            stmts.push(stmt)
+        while !Self::BLOCK_ENDING.contains(&self.current()?.get_type()) {
+            let start = self.position();
+            let depth = self.delimiters.len();
+            match self.statement() {
+                Ok(stmt) => stmts.push(stmt),
+                Err(err) => self.recover(err, start)?,
+            }
+            self.delimiters.truncate(depth);
         }
         Ok(stmts)
     }
@@ -159,61 +353,93 @@ where
         let condition = self.expression()?;
         self.match_token(TokenType::Then)?;
         let then_block = Statement::Block(self.block_stmt()?);
-        self.match_token(TokenType::End)?;
+        self.close(TokenType::End)?;
         Ok(Statement::While {
             condition,
             then_block: Box::new(then_block),
+            step: None,
         })
     }
 
-    const ITERATOR_NAME: &'static str = "";
-
-    // Desugar for to a while inside a block
+    // Desugar for to a while inside a block, the iterable goes through the 'for'
+    // native which returns the function called for each value
     fn for_stmt(&mut self) -> Result<Statement> {
         let variable = self.match_token(TokenType::Identifier)?;
-        if variable.text() == Self::ITERATOR_NAME {
+        if variable.text() == ITERATOR_NAME {
             return Err(self.make_error(ParserErrorKind::ReservedIdentifier(
-                Self::ITERATOR_NAME.to_string(),
+                ITERATOR_NAME.to_string(),
             ))?);
         }
         self.match_token(TokenType::In)?;
         let iter = self.expression()?;
+        if self.match_token(TokenType::DotDot).is_ok() {
+            let end = self.expression()?;
+            return self.range_loop(variable, iter, end);
+        }
 
         // body
         self.match_token(TokenType::Do)?;
 
-        let mut for_block = self.block_stmt()?;
-        for_block.push(Statement::Set {
-            variable: Expr::Identifier(variable.text().to_string()),
+        let Span { line, column, .. } = variable.span();
+        let for_block = self.block_stmt()?;
+        let step = Statement::Set {
+            variable: Expr::identifier(variable.text(), line, column),
             value: Expr::Call {
-                func: Box::new(Expr::Identifier(Self::ITERATOR_NAME.to_string())),
+                func: Box::new(Expr::identifier(ITERATOR_NAME, line, column)),
                 args: vec![],
             },
-        });
-        self.match_token(TokenType::End)?;
+        };
+        self.close(TokenType::End)?;
         Ok(Statement::Block(vec![
             Statement::Let {
-                name: Self::ITERATOR_NAME.to_string(),
-                value: iter.clone(),
+                name: ITERATOR_NAME.to_string(),
+                ty: None,
+                value: Expr::Call {
+                    func: Box::new(Expr::identifier(ITERATE, line, column)),
+                    args: vec![iter],
+                },
+                line,
+                column,
             },
             Statement::Let {
                 name: variable.text().to_string(),
+                ty: None,
                 value: Expr::Call {
-                    func: Box::new(Expr::Identifier(Self::ITERATOR_NAME.to_string())),
+                    func: Box::new(Expr::identifier(ITERATOR_NAME, line, column)),
                     args: vec![],
                 },
+                line,
+                column,
             },
             Statement::While {
                 condition: Expr::Binary {
-                    left: Box::new(Expr::Identifier(variable.text().to_string())),
+                    left: Box::new(Expr::identifier(variable.text(), line, column)),
                     op: BinaryOp::BangEqual,
                     right: Box::new(Expr::nil()),
                 },
                 then_block: Box::new(Statement::Block(for_block)),
+                step: Some(Box::new(step)),
             },
         ]))
     }
 
+    // 'for i in start..end do', lowered by the compiler
+    fn range_loop(&mut self, variable: Token, start: Expr, end: Expr) -> Result<Statement> {
+        self.match_token(TokenType::Do)?;
+
+        let Span { line, column, .. } = variable.span();
+        let body = self.block_stmt()?;
+        self.close(TokenType::End)?;
+        Ok(Statement::For {
+            variable: variable.extract_text(),
+            start,
+            end,
+            body: Box::new(Statement::Block(body)),
+            line,
+            column,
+        })
+    }
+
     #[allow(dead_code)]
     fn print_stmt(&mut self) -> Result<Statement> {
         let expr = self.expression()?;
@@ -221,16 +447,31 @@ where
     }
 
     fn return_stmt(&mut self) -> Result<Statement> {
-        let expr = self.expression().unwrap_or(Expr::Literal(Literal::Unit));
+        let start = self.position();
+        let expr = match self.expression() {
+            Ok(expr) => expr,
+            // No expression follows, nothing is returned
+            Err(err) if self.position() == start && err.kind != ParserErrorKind::TooDeep => {
+                Expr::Literal(Literal::Unit)
+            }
+            Err(err) => return Err(err),
+        };
         let _ = self.match_token(TokenType::Semicolon);
         Ok(Statement::Return(expr))
     }
 
     fn fn_stmt(&mut self) -> Result<Statement> {
         if let Ok(token) = self.match_token(TokenType::Identifier) {
+            let Span { line, column, .. } = token.span();
             let name = token.extract_text();
             let value = self.function()?;
-            Ok(Statement::Let { name, value })
+            Ok(Statement::Let {
+                name,
+                ty: None,
+                value,
+                line,
+                column,
+            })
         } else {
             let func = self.function()?;
             Ok(Statement::Expr(func))
@@ -240,15 +481,49 @@ where
     fn import_stmt(&mut self) -> Result<Statement> {
         let mut path = Vec::new();
         let module = self.match_token(TokenType::Identifier)?;
+        let line = module.get_line();
         path.push(module.extract_text());
         while self.match_token(TokenType::Dot).is_ok() {
             let module = self.match_token(TokenType::Identifier)?;
             path.push(module.extract_text());
         }
         self.match_token(TokenType::As)?;
-        let name = self.match_token(TokenType::Identifier)?.extract_text();
+        let name = self.match_token(TokenType::Identifier)?;
+        let column = name.span().column;
         let _ = self.match_token(TokenType::Semicolon)?;
-        Ok(Statement::Import { path, name })
+        Ok(Statement::Import {
+            path,
+            name: name.extract_text(),
+            line,
+            column,
+        })
+    }
+
+    // enum Name { A, B, C }, a comma may follow the last variant
+    fn enum_stmt(&mut self) -> Result<Statement> {
+        let token = self.match_token(TokenType::Identifier)?;
+        let Span { line, column, .. } = token.span();
+        let name = token.extract_text();
+        self.match_token(TokenType::LeftCurly)?;
+        self.open();
+        let mut variants: Vec<String> = Vec::new();
+        while self.close(TokenType::RightCurly).is_err() {
+            let variant = self.match_token(TokenType::Identifier)?.extract_text();
+            if variants.contains(&variant) {
+                return Err(self.make_error(ParserErrorKind::Redeclaration { name: variant })?);
+            }
+            variants.push(variant);
+            if self.match_token(TokenType::Comma).is_err() {
+                self.close(TokenType::RightCurly)?;
+                break;
+            }
+        }
+        Ok(Statement::Enum {
+            name,
+            variants,
+            line,
+            column,
+        })
     }
 
     fn assign_stmt(&mut self, variable: Expr) -> Result<Statement> {
@@ -258,112 +533,122 @@ where
     }
 
     pub(self) fn expression(&mut self) -> Result<Expr> {
-        self.binary()
+        self.binary(Precedence::Lowest)
     }
 
-    fn binary(&mut self) -> Result<Expr> {
-        self.comparasion()
+    // Parses operators binding at least as tight as 'min', see precedence::infix
+    fn binary(&mut self, min: Precedence) -> Result<Expr> {
+        let depth = self.depth;
+        let left = self.unary()?;
+        let expr = self.infix(left, min);
+        self.depth = depth;
+        expr
     }
 
-    fn comparasion(&mut self) -> Result<Expr> {
-        let mut left = self.addition()?;
-        while let Some(token) = [
-            TokenType::Less,
-            TokenType::Greater,
-            TokenType::LessEqual,
-            TokenType::GreaterEqual,
-            TokenType::EqualEqual,
-            TokenType::BangEqual,
-        ]
-        .iter()
-        .find_map(|t| self.match_token(*t).ok())
+    // The operators following an operand already parsed
+    fn infix(&mut self, mut left: Expr, min: Precedence) -> Result<Expr> {
+        while let Some(infix) = self
+            .current()
+            .ok()
+            .and_then(|token| precedence::infix(token.get_type()))
+            .filter(|infix| infix.precedence >= min)
         {
-            let binop: BinaryOp = token.get_type().into();
-            let right = self.addition()?;
+            self.advance()?;
+            // Each operator nests the operands before it one level deeper
+            self.descend()?;
+            let right = self.binary(infix.right_precedence())?;
             left = Expr::Binary {
                 left: Box::new(left),
-                op: binop,
+                op: infix.op,
                 right: Box::new(right),
             }
         }
         Ok(left)
     }
 
-    fn addition(&mut self) -> Result<Expr> {
-        let mut left = self.multiplication()?;
-        while let Ok(token) = self
-            .match_token(TokenType::Plus)
-            .or_else(|_| self.match_token(TokenType::Minus))
+    fn unary(&mut self) -> Result<Expr> {
+        let depth = self.descend()?;
+        let expr = if let Some(token) = [
+            TokenType::Plus,
+            TokenType::Minus,
+            TokenType::Bang,
+            TokenType::Not,
+        ]
+        .iter()
+        .find_map(|t| self.match_token(*t).ok())
         {
-            let binop: BinaryOp = token.get_type().into();
-            let right = self.multiplication()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op: binop,
-                right: Box::new(right),
+            let unop = UnaryOp::try_from(&token)?;
+            // The minus is part of a number literal, '-9223372036854775808' is in range
+            // although its digits alone are not
+            let negative = unop == UnaryOp::Minus
+                && self
+                    .current()
+                    .is_ok_and(|token| token.get_type() == TokenType::Number);
+            if negative {
+                let token = self.match_token(TokenType::Number)?;
+                let literal = self.number(&token, true)?;
+                self.operand(literal)?
+            } else {
+                let expr = self.binary(precedence::prefix_operand(token.get_type()))?;
+                Expr::Unary {
+                    op: unop,
+                    expr: Box::new(expr),
+                }
             }
-        }
-        Ok(left)
+        } else {
+            self.access()?
+        };
+        self.depth = depth;
+        Ok(expr)
     }
 
-    fn multiplication(&mut self) -> Result<Expr> {
-        let mut left = self.unary()?;
-        while let Ok(token) = self
-            .match_token(TokenType::Star)
-            .or_else(|_| self.match_token(TokenType::Slash))
-            .or_else(|_| self.match_token(TokenType::Rem))
-        {
-            let binop: BinaryOp = token.get_type().into();
-            let right = self.unary()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op: binop,
-                right: Box::new(right),
-            }
-        }
-        Ok(left)
+    fn access(&mut self) -> Result<Expr> {
+        let expr = self.primary()?;
+        self.operand(expr)
     }
 
-    fn unary(&mut self) -> Result<Expr> {
-        if let Ok(token) = self
-            .match_token(TokenType::Plus)
-            .or_else(|_| self.match_token(TokenType::Minus))
-            .or_else(|_| self.match_token(TokenType::Bang))
-        {
-            let unop: UnaryOp = token.get_type().into();
-            let expr = self.unary()?;
-            Ok(Expr::Unary {
-                op: unop,
+    // The calls, accesses and casts after a primary expression
+    fn operand(&mut self, expr: Expr) -> Result<Expr> {
+        let depth = self.depth;
+        let expr = match self.postfix(expr) {
+            Ok(expr) => self.cast(expr),
+            Err(err) => Err(err),
+        };
+        self.depth = depth;
+        expr
+    }
+
+    // 'expr as int', binds tighter than the binary operators like in Rust
+    fn cast(&mut self, mut expr: Expr) -> Result<Expr> {
+        while self.match_token(TokenType::As).is_ok() {
+            self.descend()?;
+            let ty = self.type_annotation()?;
+            expr = Expr::Cast {
                 expr: Box::new(expr),
-            })
-        } else {
-            self.access()
+                ty,
+            };
         }
+        Ok(expr)
     }
 
-    // TODO: test this
-    fn access(&mut self) -> Result<Expr> {
-        let mut expr = self.primary()?;
-        while let Ok(token) = self
+    // The fields, methods, indexes and calls following an expression
+    fn postfix(&mut self, mut expr: Expr) -> Result<Expr> {
+        while let Some(token) = self
             .match_token(TokenType::Dot)
             .or_else(|_| self.match_token(TokenType::Colon))
-            .or_else(|_| self.match_token(TokenType::LeftBracket))
-            .or_else(|_| self.match_token(TokenType::LeftParen))
+            .ok()
+            .or_else(|| self.match_on_same_line(TokenType::LeftBracket))
+            .or_else(|| self.match_on_same_line(TokenType::LeftParen))
         {
+            self.descend()?;
             match token.get_type() {
-                TokenType::Dot => {
-                    let token = self.match_token(TokenType::Identifier)?;
-                    let name = token.text().to_string();
-                    expr = Expr::Access {
-                        table: Box::new(expr),
-                        field: Box::new(Expr::string(name)),
-                    };
-                }
+                TokenType::Dot => expr = self.field(expr)?,
                 TokenType::Colon => {
                     let token = self.match_token(TokenType::Identifier)?;
                     let method = token.text().to_string();
                     // TODO convert error to expected method
                     self.match_token(TokenType::LeftParen)?;
+                    self.open();
                     let args = self.call_args()?;
                     expr = Expr::SelfAccess {
                         table: Box::new(expr),
@@ -372,14 +657,16 @@ where
                     }
                 }
                 TokenType::LeftBracket => {
+                    self.open();
                     let access_expr = self.expression()?;
                     expr = Expr::Access {
                         table: Box::new(expr),
                         field: Box::new(access_expr),
                     };
-                    self.match_token(TokenType::RightBracket)?;
+                    self.close(TokenType::RightBracket)?;
                 }
                 TokenType::LeftParen => {
+                    self.open();
                     let args = self.call_args()?;
                     expr = Expr::Call {
                         func: Box::new(expr),
@@ -392,29 +679,86 @@ where
         Ok(expr)
     }
 
+    // The name after a '.', or the index of a tuple element like in 't.0'
+    fn field(&mut self, table: Expr) -> Result<Expr> {
+        let token = match self.match_token(TokenType::Number) {
+            Ok(token) => token,
+            Err(_) => {
+                let token = self.match_token(TokenType::Identifier)?;
+                return Ok(Expr::Access {
+                    table: Box::new(table),
+                    field: Box::new(Expr::string(token.text().to_string())),
+                });
+            }
+        };
+        // 't.0.1' is scanned as the number '0.1', and 't.0.x' as '0.' before 'x'
+        let mut expr = table;
+        for part in token.text().split('.') {
+            if part.is_empty() {
+                return self.field(expr);
+            }
+            let index = match number_value(part, false) {
+                Some(Literal::Int(index)) => index,
+                _ => {
+                    return Err(self.make_error(ParserErrorKind::UnexpectedToken {
+                        token: Box::new(token.clone()),
+                    })?)
+                }
+            };
+            expr = Expr::Access {
+                table: Box::new(expr),
+                field: Box::new(Expr::integer(index)),
+            };
+        }
+        Ok(expr)
+    }
+
+    // A '(' or '[' starting a line doesn't call or index the expression before it, it
+    // starts the next statement like in 'do ... end' followed by '(f)(x);'
+    fn match_on_same_line(&mut self, typ: TokenType) -> Option<Token> {
+        if self.current().ok()?.get_line() != self.previous().get_line() {
+            return None;
+        }
+        self.match_token(typ).ok()
+    }
+
     fn call_args(&mut self) -> Result<Vec<Expr>> {
         let mut args = Vec::new();
-        if self.match_token(TokenType::RightParen).is_err() {
+        if self.close(TokenType::RightParen).is_err() {
             args.push(self.expression()?);
             while self.match_token(TokenType::Comma).is_ok() {
                 args.push(self.expression()?);
             }
-            self.match_token(TokenType::RightParen)?;
+            self.close(TokenType::RightParen)?;
         }
         Ok(args)
     }
 
+    fn number(&self, token: &Token, negative: bool) -> Result<Expr> {
+        match number_value(token.text(), negative) {
+            Some(number) => Ok(Expr::Literal(number)),
+            None => Err(ParserError {
+                kind: ParserErrorKind::IntegerTooLarge,
+                line: token.get_line(),
+                span: Some(token.span()),
+            }),
+        }
+    }
+
     fn primary(&mut self) -> Result<Expr> {
         // println!("primary: {}", self.current()?.text());
         if let Ok(token) = self.match_token(TokenType::String) {
             let string = token.text().to_string();
             Ok(Expr::Literal(Literal::Str(string)))
+        } else if let Ok(token) = self.match_token(TokenType::Char) {
+            // Malformed ones are reported by the scanner
+            let c = token.text().chars().next().unwrap_or('\0');
+            Ok(Expr::Literal(Literal::Char(c)))
         } else if let Ok(token) = self.match_token(TokenType::Number) {
-            let number: f64 = token.text().parse().unwrap();
-            Ok(Expr::Literal(Literal::Number(number)))
+            self.number(&token, false)
         } else if let Ok(token) = self.match_token(TokenType::Identifier) {
-            let name = token.text();
-            Ok(Expr::Identifier(name.to_string()))
+            let Span { line, column, .. } = token.span();
+            Ok(Expr::identifier(token.extract_text(), line, column))
         } else if self.match_token(TokenType::True).is_ok() {
             Ok(Expr::Literal(Literal::Bool(true)))
         } else if self.match_token(TokenType::False).is_ok() {
@@ -422,29 +766,61 @@ where
         } else if self.match_token(TokenType::Nil).is_ok() {
             Ok(Expr::Literal(Literal::Nil))
         } else if self.match_token(TokenType::LeftParen).is_ok() {
+            self.open();
             self.grouping()
         } else if self.match_token(TokenType::LeftCurly).is_ok() {
+            self.open();
             self.table_init()
         } else if self.match_token(TokenType::Fn).is_ok() {
+            self.open();
             self.function()
         } else if self.match_token(TokenType::Do).is_ok() {
+            self.open();
             self.block_expr(TokenType::End).map(BlockExpr::into)
         } else if self.match_token(TokenType::If).is_ok() {
+            self.open();
             self.if_expr()
+        } else if let Ok(token) = self.match_token(TokenType::Yield) {
+            self.yield_expr(token.span())
         } else {
             Err(self.make_error(ParserErrorKind::UnexpectedToken {
-                token: self.current()?,
+                token: Box::new(self.current()?),
             })?)
         }
     }
 
+    // 'yield value' hands the value to the caller of the generator, while 'yield()'
+    // still calls the native letting the other tasks run
+    fn yield_expr(&mut self, span: Span) -> Result<Expr> {
+        let value = match self.match_on_same_line(TokenType::LeftParen) {
+            Some(_) => {
+                self.open();
+                let mut args = self.call_args()?;
+                let value = match args.len() {
+                    0 => {
+                        return Ok(Expr::Call {
+                            func: Box::new(Expr::identifier("yield", span.line, span.column)),
+                            args,
+                        })
+                    }
+                    1 => Expr::Grouping(Box::new(args.remove(0))),
+                    _ => Expr::Tuple(args),
+                };
+                let value = self.postfix(value)?;
+                self.infix(value, Precedence::Lowest)?
+            }
+            None => self.expression()?,
+        };
+        Ok(Expr::Yield(Box::new(value)))
+    }
+
     #[inline]
     fn grouping(&mut self) -> Result<Expr> {
         let expr = self.expression()?;
         if self.match_token(TokenType::Comma).is_ok() {
             self.tuple(expr)
         } else {
-            self.match_token(TokenType::RightParen)?;
+            self.close(TokenType::RightParen)?;
             Ok(Expr::Grouping(Box::new(expr)))
         }
     }
@@ -456,12 +832,12 @@ where
             let expr = self.expression()?;
             elems.push(expr);
         }
-        self.match_token(TokenType::RightParen)?;
+        self.close(TokenType::RightParen)?;
         Ok(Expr::Tuple(elems))
     }
 
     fn table_init(&mut self) -> Result<Expr> {
-        if self.match_token(TokenType::RightCurly).is_ok() {
+        if self.close(TokenType::RightCurly).is_ok() {
             Ok(Expr::TableInit {
                 values: Vec::new(),
                 keys: None,
@@ -501,36 +877,55 @@ where
                     }
                 }
             }
-            self.match_token(TokenType::RightCurly)?;
+            self.close(TokenType::RightCurly)?;
             Ok(Expr::TableInit { keys, values })
         }
     }
 
     fn function(&mut self) -> Result<Expr> {
         let mut args = Vec::new();
-        self.match_token(TokenType::LeftParen)?;
+        let mut arg_positions = Vec::new();
+        let mut signature = Signature::default();
+        let line = self.match_token(TokenType::LeftParen)?.get_line();
         if let Ok(token) = self.match_token(TokenType::Identifier) {
+            arg_positions.push((token.get_line(), token.span().column));
             args.push(token.extract_text());
+            signature.args.push(self.annotation(TokenType::Colon)?);
             while self.match_token(TokenType::RightParen).is_err() {
                 self.match_token(TokenType::Comma)?;
                 let name = self.match_token(TokenType::Identifier)?;
+                arg_positions.push((name.get_line(), name.span().column));
                 args.push(name.extract_text());
+                signature.args.push(self.annotation(TokenType::Colon)?);
             }
         } else {
             self.match_token(TokenType::RightParen)?;
         }
+        signature.ret = self.annotation(TokenType::ThinArrow)?;
         let body = self.block_expr(TokenType::End)?;
+        let annotated = signature.ret.is_some() || signature.args.iter().any(Option::is_some);
         Ok(Expr::Function {
             args,
+            arg_positions,
             body,
             env: None,
+            line,
+            signature: if annotated {
+                Some(Box::new(signature))
+            } else {
+                None
+            },
         })
     }
 
+    // Blocks ending with 'end' close the keyword that opened them
     fn block_expr(&mut self, terminating_token: TokenType) -> Result<BlockExpr> {
-        //...
         let expr = self.block_expr_impl()?;
-        self.match_token(terminating_token)?;
+        if terminating_token == TokenType::End {
+            self.close(terminating_token)?;
+        } else {
+            self.match_token(terminating_token)?;
+        }
         Ok(expr)
     }
 
@@ -539,6 +934,8 @@ where
     fn block_expr_impl(&mut self) -> Result<BlockExpr> {
         let mut stmts = Vec::new();
         let expr = loop {
+            let start = self.position();
+            let depth = self.delimiters.len();
             match self.statement() {
                 Ok(stmt) => stmts.push(stmt),
                 Err(err) => {
@@ -546,12 +943,12 @@ where
                         ParserError {
                             kind: ParserErrorKind::UnexpectedExpr(expr),
                             ..
-                        } => break expr,
+                        } => break *expr,
                         // TODO: check if matched with terminating token if so push literal expr
                         err => {
                             let typ = self.current()?.get_type();
                             // We check if it ends with block terminating token so we don't omit any real error
-                            if Self::BLOCK_ENDING.iter().any(|&t| t == typ) {
+                            if self.position() == start && Self::BLOCK_ENDING.contains(&typ) {
                                 // Check if last statement can be converted to expr
                                 let last_stmt = stmts.last();
                                 break {
@@ -561,12 +958,14 @@ where
                                     }
                                 };
                             } else {
-                                return Err(err);
+                                self.recover(err, start)?;
                             }
                         }
                     }
                 }
             }
+            // Delimiters left open by a failed statement are given up with it
+            self.delimiters.truncate(depth);
         };
         Ok(BlockExpr {
             stmts,
@@ -574,11 +973,103 @@ where
         })
     }
 
+    const STATEMENT_START: [TokenType; 11] = [
+        TokenType::Let,
+        TokenType::Var,
+        TokenType::If,
+        TokenType::While,
+        TokenType::For,
+        TokenType::Return,
+        TokenType::Break,
+        TokenType::Continue,
+        TokenType::Fn,
+        TokenType::Import,
+        TokenType::Enum,
+    ];
+
+    // Records the error then skips to the next statement boundary. A statement that failed
+    // without consuming any tokens is skipped entirely so that parsing always makes progress.
+    // Running out of source inside a delimiter is reported where the delimiter was
+    // opened, the end of the source says little about what is missing
+    fn push_error(&mut self, err: ParserError) {
+        let at_end = self
+            .current()
+            .map_or(true, |token| token.get_type() == TokenType::Eof);
+        if self.errors.is_empty() {
+            self.incomplete = at_end;
+        }
+        let err = match self.delimiters.last() {
+            Some(delimiter) if at_end => ParserError {
+                line: delimiter.get_line(),
+                span: Some(delimiter.span()),
+                kind: ParserErrorKind::Unclosed {
+                    delimiter: Box::new(delimiter.clone()),
+                },
+            },
+            _ => err,
+        };
+        // The blocks around an unclosed one fail at the end too, each is reported once
+        if self.errors.last() != Some(&err) {
+            self.errors.push(err);
+        }
+    }
+
+    // Goes one level deeper into the tree being built, failing past the limit.
+    // Returns the depth to go back to, errors leave it to the statement around them
+    fn descend(&mut self) -> Result<usize> {
+        if self.depth >= self.max_depth {
+            return Err(self.make_error(ParserErrorKind::TooDeep)?);
+        }
+        self.depth += 1;
+        Ok(self.depth - 1)
+    }
+
+    // Pushes the token just matched as an opening delimiter
+    fn open(&mut self) {
+        let token = self.previous().clone();
+        self.delimiters.push(token);
+    }
+
+    // Matches the closing token of the innermost delimiter
+    fn close(&mut self, typ: TokenType) -> Result<Token> {
+        let token = self.match_token(typ)?;
+        self.delimiters.pop();
+        Ok(token)
+    }
+
+    fn recover(&mut self, err: ParserError, start: usize) -> Result<()> {
+        self.push_error(err);
+        if self.position() == start && self.current()?.get_type() != TokenType::Eof {
+            self.advance()?;
+        }
+        loop {
+            let typ = self.current()?.get_type();
+            if typ == TokenType::Eof
+                || Self::BLOCK_ENDING.contains(&typ)
+                || Self::STATEMENT_START.contains(&typ)
+            {
+                return Ok(());
+            }
+            self.advance()?;
+            if typ == TokenType::Semicolon {
+                return Ok(());
+            }
+        }
+    }
+
     fn if_expr(&mut self) -> Result<Expr> {
         let condition = self.expression()?;
         self.match_token(TokenType::Then)?;
-        let then_block = self.block_expr(TokenType::Else)?;
+        let then_block = self.block_expr_impl()?;
+        // Without the else branch there is no value when the condition is false
+        if self.current()?.get_type() == TokenType::End {
+            let err = self.make_error(ParserErrorKind::MissingElse)?;
+            self.close(TokenType::End)?;
+            return Err(err);
+        }
+        self.match_token(TokenType::Else)?;
         let else_block = if self.match_token(TokenType::If).is_ok() {
+            self.descend()?;
             self.if_expr()?
         } else {
             self.block_expr(TokenType::End)?.into()
@@ -612,52 +1103,126 @@ where
     }
 }
 
+// The value of a number token, an integer unless it has a fraction. Its digit
+// separators are dropped, the scanner reported the misplaced ones. None when an
+// integer doesn't fit in 64 bits
+// 'negative' when the literal follows a minus, the sign is parsed with the digits
+fn number_value(text: &str, negative: bool) -> Option<Literal> {
+    let digits = text.replace('_', "");
+    let sign = if negative { "-" } else { "" };
+    match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some("") => Some(Literal::Int(0)),
+        Some(hex) => i64::from_str_radix(&format!("{}{}", sign, hex), 16)
+            .ok()
+            .map(Literal::Int),
+        None if digits.contains('.') => {
+            let n: f64 = digits.parse().unwrap();
+            Some(Literal::Float(if negative { -n } else { n }))
+        }
+        None => format!("{}{}", sign, digits).parse().ok().map(Literal::Int),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scanner::Span;
 
     #[test]
     fn binary_works() {
         let source = "3 + 4 * 2 < 20 - 4";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Binary {
                 left: Box::new(Expr::Binary {
-                    left: Box::new(Expr::Literal(Literal::Number(3.0))),
+                    left: Box::new(Expr::Literal(Literal::Int(3))),
                     op: BinaryOp::Plus,
                     right: Box::new(Expr::Binary {
-                        left: Box::new(Expr::Literal(Literal::Number(4.0))),
+                        left: Box::new(Expr::Literal(Literal::Int(4))),
                         op: BinaryOp::Star,
-                        right: Box::new(Expr::Literal(Literal::Number(2.0)))
+                        right: Box::new(Expr::Literal(Literal::Int(2)))
                     }),
                 }),
                 op: BinaryOp::Less,
                 right: Box::new(Expr::Binary {
-                    left: Box::new(Expr::Literal(Literal::Number(20.0))),
+                    left: Box::new(Expr::Literal(Literal::Int(20))),
                     op: BinaryOp::Minus,
-                    right: Box::new(Expr::Literal(Literal::Number(4.0)))
+                    right: Box::new(Expr::Literal(Literal::Int(4)))
                 })
             }
         )
     }
 
+    #[test]
+    fn operators_follow_the_precedence_table() {
+        let num = |n| Box::new(Expr::Literal(Literal::Int(n)));
+        let binary = |left, op, right| Box::new(Expr::Binary { left, op, right });
+        let parse = |source| Box::new(Parser::new(source).expression().unwrap());
+        assert_eq!(
+            parse("1 - 2 - 3"),
+            binary(
+                binary(num(1), BinaryOp::Minus, num(2)),
+                BinaryOp::Minus,
+                num(3)
+            )
+        );
+        assert_eq!(
+            parse("1 < 2 == 3 % 4"),
+            binary(
+                binary(num(1), BinaryOp::Less, num(2)),
+                BinaryOp::EqualEqual,
+                binary(num(3), BinaryOp::Rem, num(4))
+            )
+        );
+        // Prefix operators bind tighter than any binary one
+        let negated = Box::new(Expr::Unary {
+            op: UnaryOp::Minus,
+            expr: Box::new(Expr::identifier("a", 1, 2)),
+        });
+        assert_eq!(parse("-a * 2"), binary(negated, BinaryOp::Star, num(2)));
+        // A minus before a number is part of the literal
+        assert_eq!(parse("-1 * 2"), binary(num(-1), BinaryOp::Star, num(2)));
+        // Unlike '!', 'not' takes a whole comparison
+        let not = |expr| {
+            Box::new(Expr::Unary {
+                op: UnaryOp::Bang,
+                expr,
+            })
+        };
+        assert_eq!(
+            parse("1 or not 2 == 3 and 4"),
+            binary(
+                num(1),
+                BinaryOp::Or,
+                binary(
+                    not(binary(num(2), BinaryOp::EqualEqual, num(3))),
+                    BinaryOp::And,
+                    num(4)
+                )
+            )
+        );
+    }
+
     #[test]
     fn grouping_works() {
         let source = "(3 + 4) * 2";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Binary {
                 left: Box::new(Expr::Grouping(Box::new(Expr::Binary {
-                    left: Box::new(Expr::Literal(Literal::Number(3.0))),
+                    left: Box::new(Expr::Literal(Literal::Int(3))),
                     op: BinaryOp::Plus,
-                    right: Box::new(Expr::Literal(Literal::Number(4.0)))
+                    right: Box::new(Expr::Literal(Literal::Int(4)))
                 }))),
                 op: BinaryOp::Star,
-                right: Box::new(Expr::Literal(Literal::Number(2.0)))
+                right: Box::new(Expr::Literal(Literal::Int(2)))
             }
         );
     }
@@ -665,26 +1230,26 @@ mod tests {
     #[test]
     fn tuple_works() {
         let source = "(3, \"hello\")";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Tuple(vec![
-                Expr::Literal(Literal::Number(3.0)),
+                Expr::Literal(Literal::Int(3)),
                 Expr::Literal(Literal::Str("hello".to_string()))
             ])
         );
 
         let source = "((3 + 2, \"hello\", !false, (nil)))";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Grouping(Box::new(Expr::Tuple(vec![
                 Expr::Binary {
-                    left: Box::new(Expr::Literal(Literal::Number(3.0))),
+                    left: Box::new(Expr::Literal(Literal::Int(3))),
                     op: BinaryOp::Plus,
-                    right: Box::new(Expr::Literal(Literal::Number(2.0)))
+                    right: Box::new(Expr::Literal(Literal::Int(2)))
                 },
                 Expr::Literal(Literal::Str("hello".to_string())),
                 Expr::Unary {
@@ -699,41 +1264,47 @@ mod tests {
     #[test]
     fn table_init_works() {
         let source = "{3 = 6, \"foo\" = bar, \"xd\" = 5 + 3}";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::TableInit {
                 keys: Some(vec![
-                    Expr::Literal(Literal::Number(3.0)),
+                    Expr::Literal(Literal::Int(3)),
                     Expr::Literal(Literal::Str("foo".to_string())),
                     Expr::Literal(Literal::Str("xd".to_string())),
                 ]),
                 values: vec![
-                    Expr::Literal(Literal::Number(6.0)),
-                    Expr::Identifier("bar".to_string()),
+                    Expr::Literal(Literal::Int(6)),
+                    Expr::identifier("bar", 1, 17),
                     Expr::Binary {
-                        left: Box::new(Expr::Literal(Literal::Number(5.0))),
+                        left: Box::new(Expr::Literal(Literal::Int(5))),
                         op: BinaryOp::Plus,
-                        right: Box::new(Expr::Literal(Literal::Number(3.0)))
+                        right: Box::new(Expr::Literal(Literal::Int(3)))
                     },
                 ]
             }
         );
 
         let source = "{3 = 6, \"foo\", \"xd\" = 5 + 3}";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression();
         assert_eq!(
             parsed,
             Err(ParserError {
                 kind: ParserErrorKind::InitError,
                 line: 1,
+                span: Some(Span {
+                    start: 13,
+                    end: 14,
+                    line: 1,
+                    column: 14,
+                }),
             })
         );
 
         let source = "{}";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression();
         assert_eq!(
             parsed,
@@ -747,34 +1318,34 @@ mod tests {
     #[test]
     fn call_works() {
         let source = "foo(5 + 2, bar[\"foo\"])";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Call {
-                func: Box::new(Expr::Identifier("foo".to_string())),
+                func: Box::new(Expr::identifier("foo", 1, 1)),
                 args: vec![
                     Expr::Binary {
-                        left: Box::new(Expr::Literal(Literal::Number(5.0))),
+                        left: Box::new(Expr::Literal(Literal::Int(5))),
                         op: BinaryOp::Plus,
-                        right: Box::new(Expr::Literal(Literal::Number(2.0))),
+                        right: Box::new(Expr::Literal(Literal::Int(2))),
                     },
                     Expr::Access {
-                        table: Box::new(Expr::Identifier("bar".to_string())),
+                        table: Box::new(Expr::identifier("bar", 1, 12)),
                         field: Box::new(Expr::Literal(Literal::Str("foo".to_string()))),
                     }
                 ]
             }
         );
         let source = "bar[\"foo\"].hello()";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Call {
                 func: Box::new(Expr::Access {
                     table: Box::new(Expr::Access {
-                        table: Box::new(Expr::Identifier("bar".to_string())),
+                        table: Box::new(Expr::identifier("bar", 1, 1)),
                         field: Box::new(Expr::Literal(Literal::Str("foo".to_string()))),
                     }),
                     field: Box::new(Expr::Literal(Literal::Str("hello".to_string())))
@@ -787,23 +1358,58 @@ mod tests {
     #[test]
     fn fn_stmt_works() {
         let source = "fn foo() end";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.statement().unwrap();
         assert_eq!(
             parsed,
             Statement::Let {
                 name: "foo".to_string(),
+                ty: None,
                 value: Expr::Function {
                     args: vec![],
+                    arg_positions: vec![],
                     body: BlockExpr {
                         stmts: vec![],
                         expr: Box::new(Expr::Literal(Literal::Unit))
                     },
-                    env: None
-                }
+                    env: None,
+                    line: 1,
+                    signature: None,
+                },
+                line: 1,
+                column: 4,
             }
         )
     }
+
+    #[test]
+    fn annotations_are_parsed() {
+        let mut parser = Parser::new("let t: (int, str) = nil; let f = fn(a: fn, b) -> () end;");
+        let ast = parser.parse_syntax().unwrap();
+        match &ast.expr().stmts[..] {
+            [Statement::Let { ty, .. }, Statement::Let {
+                value: Expr::Function { signature, .. },
+                ..
+            }] => {
+                assert_eq!(ty, &Some(Type::Tuple(vec![Type::Int, Type::Str])));
+                assert_eq!(
+                    signature.as_deref(),
+                    Some(&Signature {
+                        args: vec![Some(Type::Function), None],
+                        ret: Some(Type::Unit),
+                    })
+                );
+            }
+            stmts => panic!("Unexpected statements {:?}", stmts),
+        }
+        let err = Parser::new("let x: integer = 1;").parse().unwrap_err();
+        assert_eq!(
+            err.kind,
+            ParserErrorKind::UnknownType {
+                name: "integer".to_owned()
+            }
+        );
+    }
     // WTF??
     /* #[test]
     fn block_expr_works() {
@@ -813,7 +1419,7 @@ mod tests {
                 5
             end;
         ";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.parse().unwrap();
         assert_eq!(
             parsed,
@@ -823,9 +1429,9 @@ mod tests {
                     value: Expr::Block(BlockExpr {
                         stmts: vec![Statement::Let {
                             name: "bar".to_string(),
-                            value: Expr::Identifier("foo".to_string()),
+                            value: Expr::identifier("foo", 1),
                         }],
-                        expr: Box::new(Expr::Literal(Literal::Number(5.0)))
+                        expr: Box::new(Expr::Literal(Literal::Int(5)))
                     })
                 }],
                 expr: Box::new(Expr::unit())
@@ -836,16 +1442,16 @@ mod tests {
             x * x
         end
         ";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.expression().unwrap();
         assert_eq!(
             parsed,
             Expr::Block(BlockExpr {
                 stmts: vec![],
                 expr: Box::new(Expr::Binary {
-                    left: Box::new(Expr::Identifier("x".to_string())),
+                    left: Box::new(Expr::identifier("x", 1)),
                     op: BinaryOp::Star,
-                    right: Box::new(Expr::Identifier("x".to_string()))
+                    right: Box::new(Expr::identifier("x", 1))
                 })
             })
         );
@@ -854,17 +1460,249 @@ mod tests {
     #[test]
     fn assignment_stmt() {
         let source = "let x = foo = bar;";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.parse();
         println!("{:?}", parsed);
         assert!(parsed.is_err());
 
         let source = "let foo = fn() end; foo(x = 5)";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.parse();
         assert!(parsed.is_err());
     }
 
+    #[test]
+    fn paren_on_next_line_starts_statement() {
+        let source = "do f end\n(g)(1);";
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        assert_eq!(ast.expr().stmts.len(), 2);
+        // On the same line it calls the block's value
+        let source = "do f end (g)(1);";
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        assert_eq!(ast.expr().stmts.len(), 1);
+        // Without a semicolon 'x = f' can't go on into the next line
+        let source = "x = f\n(g)(1);";
+        let errors = Parser::new(source).parse_syntax().unwrap_err();
+        assert_eq!(
+            errors[0].kind,
+            ParserErrorKind::NotMatched {
+                typ: TokenType::Semicolon
+            }
+        );
+        assert_eq!(errors[0].line, 2);
+    }
+
+    #[test]
+    fn semicolon_after_end_is_optional() {
+        let source = "
+        if x then y(); end;
+        while x then y(); end
+        for i in x do y(); end;
+        fn f() end;
+        do y(); end;
+        x = 1;
+        ";
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        assert_eq!(ast.expr().stmts.len(), 6);
+    }
+
+    #[test]
+    fn if_expression_needs_else() {
+        for source in &[
+            "let x = if c then 1 end; x",
+            "f(if c then 1 else if d then 2 end);",
+        ] {
+            let errors = Parser::new(source).parse_syntax().unwrap_err();
+            assert_eq!(errors.len(), 1);
+            assert_eq!(errors[0].kind, ParserErrorKind::MissingElse);
+        }
+        // Statements don't need one
+        assert!(Parser::new("if c then f(); end").parse_syntax().is_ok());
+    }
+
+    #[test]
+    fn non_operator_is_error() {
+        // There is no unary plus, the token used to panic when taken for an operator
+        let errors = Parser::new("let x = +1;").parse_syntax().unwrap_err();
+        match &errors[0].kind {
+            ParserErrorKind::UnexpectedToken { token } => {
+                assert_eq!(token.get_type(), TokenType::Plus)
+            }
+            kind => panic!("unexpected error {:?}", kind),
+        }
+        assert_eq!(errors[0].span.map(|span| span.column), Some(9));
+    }
+
+    #[test]
+    fn reports_where_unclosed_delimiters_open() {
+        let unclosed = |source: &str| -> Vec<(usize, String)> {
+            let errors = Parser::new(source).parse_syntax().unwrap_err();
+            errors
+                .into_iter()
+                .map(|err| match err.kind {
+                    ParserErrorKind::Unclosed { delimiter } => (err.line, delimiter.extract_text()),
+                    kind => panic!("unexpected error {:?}", kind),
+                })
+                .collect()
+        };
+        assert_eq!(unclosed("let x = (1 +\n2"), vec![(1, "(".to_string())]);
+        assert_eq!(unclosed("print(t[1\n"), vec![(1, "[".to_string())]);
+        assert_eq!(
+            unclosed("fn f()\n    let t = {1, 2};\n    if x then\n        y(\n"),
+            vec![
+                (1, "fn".to_string()),
+                (3, "if".to_string()),
+                (4, "(".to_string())
+            ]
+        );
+        // Closed delimiters are not blamed for a later error
+        assert_eq!(
+            unclosed("do f((1), {2});\nwhile x then\n    y();\nend"),
+            vec![(1, "do".to_string())]
+        );
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let too_deep = |parser: &mut Parser<_>| match parser.parse_syntax() {
+            Err(errors) => errors[0].kind == ParserErrorKind::TooDeep,
+            Ok(_) => false,
+        };
+        let source = format!("let x = {}1{};", "(".repeat(100), ")".repeat(100));
+        assert!(too_deep(&mut Parser::new(&source).with_max_depth(50)));
+        let source = format!("let x = {}1;", "-".repeat(100));
+        assert!(too_deep(&mut Parser::new(&source).with_max_depth(50)));
+        // Chained operators nest the tree without recursing in the parser
+        let source = format!("let x = 1{};", " + 1".repeat(100));
+        assert!(too_deep(&mut Parser::new(&source).with_max_depth(50)));
+        let source = format!("return {}1{};", "(".repeat(100), ")".repeat(100));
+        assert!(too_deep(&mut Parser::new(&source).with_max_depth(50)));
+        // Siblings don't add up
+        let source = format!("f({});", vec!["1 + 1 + 1"; 300].join(", "));
+        assert!(Parser::new(&source)
+            .with_max_depth(10)
+            .parse_syntax()
+            .is_ok());
+    }
+
+    #[test]
+    fn default_depth_fits_the_main_thread() {
+        // Debug builds need the 8MB of the main thread, tests run with less
+        let parse = std::thread::Builder::new().stack_size(8 << 20).spawn(|| {
+            // Way past the limit, this used to overflow the stack
+            let source = format!("let x = {}1{};", "(".repeat(10_000), ")".repeat(10_000));
+            let errors = Parser::new(&source).parse_syntax().unwrap_err();
+            assert_eq!(errors[0].kind, ParserErrorKind::TooDeep);
+            let source = format!("let x = {}1{};", "(-".repeat(95), ")".repeat(95));
+            assert!(Parser::new(&source).parse_all().is_ok());
+        });
+        parse.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn recovers_multiple_errors() {
+        let source = "
+        let x = 1;
+        let y = ;
+        let z = 3
+        println(x);
+        fn foo()
+            let w = );
+        end
+        ";
+        let mut parser = Parser::new(source);
+        let errors = parser.parse_all().unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 5, 7]);
+    }
+
+    #[test]
+    fn reports_lexical_errors_with_syntax_errors() {
+        let source = "let x = #1;\nlet y = ;\nlet s = \"abc\nlet z = 2;";
+        let errors = Parser::new(source).parse_all().unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        // The string is left out, 'let s =' misses its value on the next line
+        assert_eq!(lines, vec![1, 2, 3, 4]);
+        assert_eq!(
+            errors[0].kind,
+            ParserErrorKind::Lex(LexErrorKind::InvalidChar('#'))
+        );
+        assert_eq!(
+            errors[2].kind,
+            ParserErrorKind::Lex(LexErrorKind::UnterminatedString)
+        );
+    }
+
+    #[test]
+    fn incomplete_input() {
+        for source in &[
+            "let f = fn(a)",
+            "foo(1,",
+            "let x = 1 +",
+            "let t = {1, 2",
+            "do let x = 1;",
+            "let s = \"abc",
+        ] {
+            assert!(Parser::is_incomplete(source), "{}", source);
+        }
+        for source in &["1 + 2", "let x = );", "let x = ) + 1", "fn() end"] {
+            assert!(!Parser::is_incomplete(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn unfinished_statement_is_error() {
+        let source = "println(1";
+        let mut parser = Parser::new(source);
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn ast_to_json() {
+        let ast = Parser::parse_str("let x = 1; let y = -x;").unwrap();
+        let json: serde_json::Value = serde_json::from_str(&ast.to_json()).unwrap();
+        let stmt = &json["stmts"][1]["Let"];
+        assert_eq!(stmt["name"], "y");
+        assert_eq!(stmt["value"]["Unary"]["op"], "Minus");
+        assert_eq!(stmt["value"]["Unary"]["expr"]["Identifier"]["name"], "x");
+    }
+
+    #[test]
+    fn number_literals_keep_their_form() {
+        let literal = |source| match Parser::new(source).expression() {
+            Ok(Expr::Literal(literal)) => Ok(literal),
+            Ok(expr) => panic!("{:?}", expr),
+            Err(err) => Err(err.kind),
+        };
+        assert_eq!(literal("2.0"), Ok(Literal::Float(2.0)));
+        assert_eq!(literal("2."), Ok(Literal::Float(2.0)));
+        assert_eq!(literal("0xFF"), Ok(Literal::Int(255)));
+        assert_eq!(
+            literal("9_007_199_254_740_993"),
+            Ok(Literal::Int(9_007_199_254_740_993))
+        );
+        assert_eq!(
+            literal("9223372036854775808"),
+            Err(ParserErrorKind::IntegerTooLarge)
+        );
+        assert_eq!(
+            literal("0x1_0000_0000_0000_0000"),
+            Err(ParserErrorKind::IntegerTooLarge)
+        );
+        assert_eq!(
+            literal("-9_223_372_036_854_775_808"),
+            Ok(Literal::Int(i64::MIN))
+        );
+        assert_eq!(
+            literal("-0x8000_0000_0000_0000"),
+            Ok(Literal::Int(i64::MIN))
+        );
+        assert_eq!(
+            literal("-9223372036854775809"),
+            Err(ParserErrorKind::IntegerTooLarge)
+        );
+    }
+
     #[test]
     fn if_without_else() {
         let source = "
@@ -873,7 +1711,7 @@ mod tests {
         else if true then
             print(\"here\");
         end";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let parsed = parser.parse();
         assert!(parsed.is_ok());
     }
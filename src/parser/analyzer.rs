@@ -1,6 +1,8 @@
-use super::{Ast, BlockExpr, Expr, Parser, ParserErrorKind, Result, Statement, Token};
-use crate::vm::lib::PREDEFINED_CONSTANTS;
-use std::collections::HashSet;
+use super::{Ast, BlockExpr, Expr, Parser, ParserError, ParserErrorKind, Result, Statement, Token};
+use crate::suggest::did_you_mean;
+use crate::vm::lib::{PREDEFINED_CONSTANTS, PREDEFINED_GLOBALS};
+use alloc::{borrow::ToOwned, boxed::Box, string::String, string::ToString, vec::Vec};
+use hashbrown::HashSet;
 
 pub struct Analyzer<'a, I>
 where
@@ -15,6 +17,8 @@ struct Scope {
     name: Option<String>, // Function name for recursion
     locals: HashSet<String>,
     environment: Option<HashSet<String>>,
+    // The block of a loop, 'var' is still at the top level inside it
+    statement: bool,
 }
 
 const ENV_NAME: &str = "env";
@@ -25,6 +29,14 @@ impl Scope {
             name: None,
             locals: HashSet::new(),
             environment: None,
+            statement: false,
+        }
+    }
+
+    fn statement() -> Self {
+        Scope {
+            statement: true,
+            ..Scope::block()
         }
     }
 
@@ -33,6 +45,7 @@ impl Scope {
             name,
             locals: HashSet::new(),
             environment: Some(HashSet::new()),
+            statement: false,
         }
     }
 
@@ -45,6 +58,7 @@ impl Scope {
             name: None,
             locals,
             environment: None,
+            statement: false,
         }
     }
 }
@@ -62,10 +76,20 @@ where
         Analyzer {
             parser,
             scopes: vec![Scope::global()],
-            globals: HashSet::new(),
+            globals: PREDEFINED_GLOBALS
+                .iter()
+                .map(|s| s.to_string())
+                .chain(parser.globals.iter().cloned())
+                .collect(),
         }
     }
 
+    // The visible name closest to an undeclared one
+    fn suggest(&self, name: &str) -> Option<String> {
+        let locals = self.scopes.iter().flat_map(|scope| scope.locals.iter());
+        did_you_mean(name, locals.chain(&self.globals).map(String::as_str))
+    }
+
     fn visit_ast(&mut self, Ast(mut block_expr): Ast) -> Result<Ast> {
         self.visit_block_expr(&mut block_expr)?;
         Ok(Ast(block_expr))
@@ -78,22 +102,31 @@ where
         self.visit_expr(block_expr.expr.as_mut(), None)
     }
 
+    fn visit_stmts(&mut self, stmts: &mut [Statement]) -> Result<()> {
+        for stmt in stmts.iter_mut() {
+            self.visit_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
     fn visit_stmt(&mut self, stmt: &mut Statement) -> Result<()> {
         match stmt {
-            Statement::Let { name, value } => {
+            Statement::Let {
+                name, value, line, ..
+            } => {
                 match value {
                     // Also block?
                     Expr::Function { .. } => {
-                        self.add_local(&*name)?;
+                        self.add_local(&*name, *line)?;
                         self.visit_expr(value, Some(name.clone()))
                     }
                     _ => {
                         self.visit_expr(value, None)?;
-                        self.add_local(&*name)
+                        self.add_local(&*name, *line)
                     }
                 }
             }
-            Statement::Var { name, value } => {
+            Statement::Var { name, value, .. } => {
                 if self.is_top_level() {
                     self.visit_expr(value, None)?;
                     self.globals.insert(name.to_string());
@@ -110,9 +143,13 @@ where
                 self.visit_expr(variable, None)?;
                 self.visit_expr(value, None)
             }
-            Statement::Block(stmts) => stmts
-                .into_iter()
-                .fold(Ok(()), |result, stmt| result.and(self.visit_stmt(stmt))),
+            // Scoped like the compiler does, so the locals of two for loops don't clash
+            Statement::Block(stmts) => {
+                self.scopes.push(Scope::statement());
+                let result = self.visit_stmts(stmts);
+                self.exit_scope();
+                result
+            }
             Statement::If {
                 condition,
                 then_block,
@@ -128,13 +165,37 @@ where
             Statement::While {
                 condition,
                 then_block,
+                step,
             } => {
                 self.visit_expr(condition, None)?;
-                self.visit_stmt(then_block.as_mut())
+                self.visit_stmt(then_block.as_mut())?;
+                match step {
+                    Some(step) => self.visit_stmt(step.as_mut()),
+                    None => Ok(()),
+                }
+            }
+            // The end can't refer to the variable, see Compiler::for_stmt
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                line,
+                ..
+            } => {
+                self.scopes.push(Scope::statement());
+                let result = self
+                    .visit_expr(start, None)
+                    .and_then(|_| self.visit_expr(end, None))
+                    .and_then(|_| self.add_local(variable, *line))
+                    .and_then(|_| self.visit_stmt(body.as_mut()));
+                self.exit_scope();
+                result
             }
+            Statement::Break { .. } | Statement::Continue { .. } => Ok(()),
             Statement::Return(expr) => self.visit_expr(expr, None),
-            Statement::Import { name, .. } => {
-                self.add_local(name)?;
+            Statement::Import { name, line, .. } | Statement::Enum { name, line, .. } => {
+                self.add_local(name, *line)?;
                 Ok(())
             }
             Statement::Expr(expr) => self.visit_expr(expr, None),
@@ -145,7 +206,7 @@ where
     fn visit_expr(&mut self, expr: &mut Expr, func_name: Option<String>) -> Result<()> {
         use Expr::*;
         match expr {
-            Identifier(name) => {
+            Identifier { name, line, column } => {
                 // TODO: seems like not the best way to do it
 
                 let is_rec = {
@@ -179,7 +240,7 @@ where
                     if is_local_somewhere {
                         if !self.has_local(name) {
                             *expr = Expr::Access {
-                                table: Box::new(Expr::Identifier("env".to_owned())),
+                                table: Box::new(Expr::identifier(ENV_NAME, *line, *column)),
                                 field: Box::new(Expr::string(name.to_owned())),
                             }
                         }
@@ -187,9 +248,14 @@ where
                     } else {
                         let is_global = self.globals.contains(name);
                         if !is_global {
-                            Err(self.parser.make_error(ParserErrorKind::Undeclared {
-                                name: name.to_string(),
-                            })?)
+                            Err(ParserError {
+                                kind: ParserErrorKind::Undeclared {
+                                    name: name.to_string(),
+                                    suggestion: self.suggest(name),
+                                },
+                                line: *line,
+                                span: None,
+                            })
                         } else {
                             Ok(())
                         }
@@ -238,7 +304,9 @@ where
             Binary { left, right, .. } => self
                 .visit_expr(left.as_mut(), None)
                 .and(self.visit_expr(right.as_mut(), None)),
-            Grouping(expr) => self.visit_expr(expr.as_mut(), None),
+            Grouping(expr) | Yield(expr) | Cast { expr, .. } => {
+                self.visit_expr(expr.as_mut(), None)
+            }
             Tuple(exprs) => exprs
                 .into_iter()
                 .fold(Ok(()), |acc, e| acc.and(self.visit_expr(e, None))),
@@ -260,11 +328,18 @@ where
                     .into_iter()
                     .fold(Ok(()), |res, value| res.and(self.visit_expr(value, None)))
             }
-            Function { body, args, env } => {
+            Function {
+                body,
+                args,
+                arg_positions,
+                env,
+                line,
+                ..
+            } => {
                 self.enter_env(func_name);
 
                 for arg in args.iter() {
-                    self.add_local(arg)?;
+                    self.add_local(arg, *line)?;
                 }
 
                 self.visit_block_expr(body)?;
@@ -274,14 +349,17 @@ where
                     0 => (),
                     _ => {
                         let keys = env_vars.iter().map(|v| Expr::string(v.clone())).collect();
-                        let mut values: Vec<Expr> =
-                            env_vars.iter().map(|v| Identifier(v.clone())).collect();
+                        let mut values: Vec<Expr> = env_vars
+                            .iter()
+                            .map(|v| Expr::identifier(v, *line, 0))
+                            .collect();
                         for value in values.iter_mut() {
                             self.visit_expr(value, None)?;
                         }
                         *env = Some((keys, values));
 
                         args.push(ENV_NAME.to_owned());
+                        arg_positions.push((*line, 0));
                     }
                 }
                 Ok(())
@@ -311,7 +389,7 @@ where
         }
     }
 
-    fn add_local(&mut self, name: &str) -> Result<()> {
+    fn add_local(&mut self, name: &str, line: usize) -> Result<()> {
         let inserted = self
             .scopes
             .last_mut()
@@ -319,9 +397,13 @@ where
             .locals
             .insert(name.to_owned());
         if !inserted {
-            Err(self.parser.make_error(ParserErrorKind::Redeclaration {
-                name: name.to_owned(),
-            })?)
+            Err(ParserError {
+                kind: ParserErrorKind::Redeclaration {
+                    name: name.to_owned(),
+                },
+                line,
+                span: None,
+            })
         } else {
             Ok(())
         }
@@ -358,7 +440,7 @@ where
 
     #[inline]
     fn is_top_level(&self) -> bool {
-        self.scopes.len() == 1
+        self.scopes[1..].iter().all(|scope| scope.statement)
     }
 }
 
@@ -375,7 +457,7 @@ mod tests {
                 end
             end
         ";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let ast = parser.parse().unwrap();
         let desugared = Analyzer::analyze(ast, &parser).unwrap();
         // Complete this
@@ -413,7 +495,7 @@ mod tests {
                 end
             end;
         ";
-        let mut parser = Parser::new(source).unwrap();
+        let mut parser = Parser::new(source);
         let ast = parser.parse().unwrap();
         println!("{:#?}", ast);
     }
@@ -0,0 +1,109 @@
+use crate::scanner::{Scanner, TriviaKind};
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+
+// A '//' comment. Empty text stands for a blank line, several of them are printed as one
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub line: usize,
+    pub text: String,
+    // Follows code on its line instead of having the line to itself
+    pub trailing: bool,
+}
+
+// The comments of a source, read from the trivia of its tokens. The tree doesn't hold
+// them, they are found by the line of the statement they are about (Statement::line)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Comments(Vec<Comment>);
+
+impl Comments {
+    pub fn of(source: &str) -> Self {
+        let mut scanner = Scanner::with_trivia(source);
+        scanner.scan();
+        let mut comments = Vec::new();
+        // Nothing but whitespace since the line started
+        let mut blank = true;
+        for token in scanner.extract_lossless() {
+            for trivia in &token.leading {
+                match trivia.kind {
+                    TriviaKind::Newline => {
+                        if blank {
+                            comments.push(Comment {
+                                line: trivia.span.line,
+                                text: String::new(),
+                                trailing: false,
+                            });
+                        }
+                        blank = true;
+                    }
+                    TriviaKind::Comment => {
+                        comments.push(Comment {
+                            line: trivia.span.line,
+                            text: trivia.text(source).trim_end().to_owned(),
+                            trailing: !blank,
+                        });
+                        blank = false;
+                    }
+                    TriviaKind::Skipped => blank = false,
+                    TriviaKind::Whitespace => (),
+                }
+            }
+            blank = false;
+        }
+        Comments(comments)
+    }
+
+    // The comment lines right above 'line', like the documentation of a declaration
+    // starting there. A blank line or code ends them
+    pub fn leading(&self, line: usize) -> Vec<&str> {
+        let above = self
+            .0
+            .iter()
+            .rev()
+            .skip_while(|comment| comment.line >= line);
+        let mut leading: Vec<&str> = above
+            .zip((1..line).rev())
+            .take_while(|(comment, expected)| {
+                comment.line == *expected && !comment.trailing && !comment.text.is_empty()
+            })
+            .map(|(comment, _)| comment.text.as_str())
+            .collect();
+        leading.reverse();
+        leading
+    }
+
+    // The comment after the code on 'line'
+    pub fn trailing(&self, line: usize) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|comment| comment.line == line && comment.trailing)
+            .map(|comment| comment.text.as_str())
+    }
+
+    pub fn into_vec(self) -> Vec<Comment> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attaches_to_lines() {
+        let source = "\
+// Adds one
+// to x
+fn inc(x) x + 1 end // inc
+
+// Not about y
+
+let y = 1; // one
+";
+        let comments = Comments::of(source);
+        assert_eq!(comments.leading(3), vec!["// Adds one", "// to x"]);
+        assert_eq!(comments.trailing(3), Some("// inc"));
+        assert!(comments.leading(7).is_empty());
+        assert_eq!(comments.trailing(7), Some("// one"));
+        assert_eq!(comments.trailing(5), None);
+    }
+}
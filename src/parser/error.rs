@@ -1,6 +1,7 @@
 use super::Expr;
-use crate::scanner::{LexError, LexErrorKind, Token, TokenType};
-use std::fmt::{Display, Formatter};
+use crate::scanner::{LexError, LexErrorKind, Span, Token, TokenType};
+use alloc::{boxed::Box, string::String};
+use core::fmt::{Display, Formatter};
 
 // TODO: ParserErrorKind and ParserError
 
@@ -8,28 +9,51 @@ use std::fmt::{Display, Formatter};
 pub struct ParserError {
     pub kind: ParserErrorKind,
     pub line: usize,
+    // The token the error is at, None for the errors found after parsing
+    pub span: Option<Span>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum ParserErrorKind {
     ExpectedToken,
-    UnexpectedToken { token: Token },
+    UnexpectedToken { token: Box<Token> },
     NotMatched { typ: TokenType },
     // mixing array and table initialization
     // Ex: let t = { 3, foo = 5 }
     InitError,
-    UnexpectedExpr(Expr),
+    UnexpectedExpr(Box<Expr>),
     Lex(LexErrorKind),
     ReservedIdentifier(String),
     Redeclaration { name: String },
-    Undeclared { name: String },
+    // 'suggestion' is a declared name it may be a typo of
+    Undeclared {
+        name: String,
+        suggestion: Option<String>,
+    },
     // var declaration is only allowed at top level
     InnerVarDeclaration { name: String },
+    // The source ends before the delimiter or block opened by the token is closed
+    Unclosed { delimiter: Box<Token> },
+    // Expressions or blocks nest deeper than the parser's limit
+    TooDeep,
+    // An integer literal that doesn't fit in 64 bits
+    IntegerTooLarge,
+    // An annotation names no type, see Type::from_name
+    UnknownType { name: String },
+    // An if used as a value ends without an else branch
+    MissingElse,
 }
 
 impl Display for ParserError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[line {}] Parsing Error: {:?}", self.line, self.kind)
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.span {
+            Some(span) => write!(
+                f,
+                "[line {}:{}] Parsing Error: {:?}",
+                span.line, span.column, self.kind
+            ),
+            None => write!(f, "[line {}] Parsing Error: {:?}", self.line, self.kind),
+        }
     }
 }
 
@@ -37,7 +61,21 @@ impl From<LexError> for ParserError {
     fn from(lex_error: LexError) -> Self {
         ParserError {
             kind: ParserErrorKind::Lex(lex_error.kind),
-            line: lex_error.line,
+            line: lex_error.span.line,
+            span: Some(lex_error.span),
+        }
+    }
+}
+
+impl From<&Token> for ParserError {
+    // The token can't be where it is, like a non operator token taken for an operator
+    fn from(token: &Token) -> Self {
+        ParserError {
+            line: token.get_line(),
+            span: Some(token.span()),
+            kind: ParserErrorKind::UnexpectedToken {
+                token: Box::new(token.clone()),
+            },
         }
     }
 }
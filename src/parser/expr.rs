@@ -1,10 +1,18 @@
-use super::Statement;
-use crate::scanner::TokenType;
+use super::precedence::infix;
+use super::{ParserError, Signature, Statement, Type};
+use crate::scanner::{Token, TokenType};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::convert::TryFrom;
+use serde::Serialize;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Expr {
     Literal(Literal),
-    Identifier(String),
+    Identifier {
+        name: String,
+        line: usize,
+        column: usize,
+    },
     Unary {
         op: UnaryOp,
         expr: Box<Expr>,
@@ -31,9 +39,14 @@ pub enum Expr {
     },
     Function {
         args: Vec<String>,
+        // Line and column of each argument name
+        arg_positions: Vec<(usize, usize)>,
         body: BlockExpr,
         /** (keys, values) */
         env: Option<(Vec<Expr>, Vec<Expr>)>,
+        line: usize,
+        // None when neither the arguments nor the result are annotated
+        signature: Option<Box<Signature>>,
     },
     Call {
         func: Box<Expr>,
@@ -47,16 +60,31 @@ pub enum Expr {
         else_block: Box<Expr>,
     },
     Rec, // Function calls itself
+    // Hands the value to the caller of the generator and waits to be resumed
+    Yield(Box<Expr>),
+    // 'expr as ty' checks the type at runtime, see Value::cast
+    Cast {
+        expr: Box<Expr>,
+        ty: Type,
+    },
 }
 
 // TODO: use these function instead of manually wrapping
 impl Expr {
+    pub fn identifier(name: impl Into<String>, line: usize, column: usize) -> Self {
+        Expr::Identifier {
+            name: name.into(),
+            line,
+            column,
+        }
+    }
+
     pub fn string(s: String) -> Self {
         Expr::Literal(Literal::Str(s))
     }
 
-    pub fn integer(i: f64) -> Self {
-        Expr::Literal(Literal::Number(i))
+    pub fn integer(i: i64) -> Self {
+        Expr::Literal(Literal::Int(i))
     }
 
     pub fn bool(b: bool) -> Self {
@@ -70,9 +98,34 @@ impl Expr {
     pub fn nil() -> Self {
         Expr::Literal(Literal::Nil)
     }
+
+    // Line of the leftmost token that records one
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Expr::Identifier { line, .. } | Expr::Function { line, .. } => Some(*line),
+            Expr::Literal(_) | Expr::Rec => None,
+            Expr::Unary { expr, .. }
+            | Expr::Grouping(expr)
+            | Expr::Yield(expr)
+            | Expr::Cast { expr, .. } => expr.line(),
+            Expr::Binary { left, right, .. } => left.line().or_else(|| right.line()),
+            Expr::Tuple(exprs) => exprs.iter().find_map(Expr::line),
+            Expr::Access { table, .. } | Expr::SelfAccess { table, .. } => table.line(),
+            Expr::TableInit { keys, values } => {
+                keys.iter().flatten().chain(values).find_map(Expr::line)
+            }
+            Expr::Call { func, args } => func.line().or_else(|| args.iter().find_map(Expr::line)),
+            Expr::Block(block) => block
+                .stmts
+                .first()
+                .and_then(Statement::line)
+                .or_else(|| block.expr.line()),
+            Expr::If { condition, .. } => condition.line(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct BlockExpr {
     pub stmts: Vec<Statement>,
     pub expr: Box<Expr>,
@@ -84,32 +137,38 @@ impl Into<Expr> for BlockExpr {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Literal {
     Str(String),
-    Number(f64),
+    // Compiles to the codepoint
+    Char(char),
+    // Numbers written without a fraction, like 2 or 0xFF
+    Int(i64),
+    Float(f64),
     Bool(bool),
     Unit,
     Nil,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
 pub enum UnaryOp {
     Minus,
     Bang,
 }
 
-impl From<TokenType> for UnaryOp {
-    fn from(typ: TokenType) -> UnaryOp {
-        match typ {
-            TokenType::Minus => UnaryOp::Minus,
-            TokenType::Bang => UnaryOp::Bang,
-            _ => panic!("Unexpected type to convert to UnaryOp: {:?}", typ),
+impl TryFrom<&Token> for UnaryOp {
+    type Error = ParserError;
+
+    fn try_from(token: &Token) -> Result<UnaryOp, ParserError> {
+        match token.get_type() {
+            TokenType::Minus => Ok(UnaryOp::Minus),
+            TokenType::Bang | TokenType::Not => Ok(UnaryOp::Bang),
+            _ => Err(token.into()),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
 pub enum BinaryOp {
     Plus,
     Minus,
@@ -122,23 +181,18 @@ pub enum BinaryOp {
     GreaterEqual,
     LessEqual,
     Rem,
+    // Short-circuit, only evaluate the right side when the left doesn't decide
+    And,
+    Or,
 }
 
-impl From<TokenType> for BinaryOp {
-    fn from(typ: TokenType) -> BinaryOp {
-        match typ {
-            TokenType::Plus => BinaryOp::Plus,
-            TokenType::Minus => BinaryOp::Minus,
-            TokenType::Star => BinaryOp::Star,
-            TokenType::Slash => BinaryOp::Slash,
-            TokenType::Greater => BinaryOp::Greater,
-            TokenType::Less => BinaryOp::Less,
-            TokenType::GreaterEqual => BinaryOp::GreaterEqual,
-            TokenType::LessEqual => BinaryOp::LessEqual,
-            TokenType::EqualEqual => BinaryOp::EqualEqual,
-            TokenType::BangEqual => BinaryOp::BangEqual,
-            TokenType::Rem => BinaryOp::Rem,
-            _ => panic!("Unexpected type to convert to BinaryOp: {:?}", typ),
+impl TryFrom<&Token> for BinaryOp {
+    type Error = ParserError;
+
+    fn try_from(token: &Token) -> Result<BinaryOp, ParserError> {
+        match infix(token.get_type()) {
+            Some(infix) => Ok(infix.op),
+            None => Err(token.into()),
         }
     }
 }
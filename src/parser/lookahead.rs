@@ -1,5 +1,6 @@
 use super::Result;
 use super::{ParserError, ParserErrorKind, Token, TokenType};
+use alloc::boxed::Box;
 
 const LOOKAHEAD_SIZE: usize = 3;
 
@@ -11,6 +12,8 @@ where
     token_buffer: I,
     lookahead: [Token; LOOKAHEAD_SIZE],
     lookahead_index: usize,
+    // Number of tokens consumed so far
+    position: usize,
 }
 
 impl<I> LookAhead<I>
@@ -24,6 +27,7 @@ where
             token_buffer: buffer,
             lookahead,
             lookahead_index: 0,
+            position: 0,
         }
     }
 
@@ -31,6 +35,7 @@ where
         match self.token_buffer.next() {
             Some(token) => {
                 self.lookahead_insert(token.clone());
+                self.position += 1;
                 self.current()
             }
             None => Err(self.make_error(ParserErrorKind::ExpectedToken)?),
@@ -49,6 +54,10 @@ where
         }
     }
 
+    pub(super) fn position(&self) -> usize {
+        self.position
+    }
+
     fn lookahead_insert(&mut self, token: Token) {
         let next_index = (self.lookahead_index + 1) % LOOKAHEAD_SIZE;
         self.lookahead[next_index] = token;
@@ -58,11 +67,11 @@ where
     pub(super) fn current(&self) -> Result<Token> {
         let i = self.lookahead_index;
         let token = self.lookahead[i].clone();
-        let line = token.get_line();
         if token.is_invalid() {
             Err(ParserError {
-                kind: ParserErrorKind::UnexpectedToken { token },
-                line,
+                line: token.get_line(),
+                span: Some(token.span()),
+                kind: ParserErrorKind::UnexpectedToken { token: Box::new(token) },
             })
         } else {
             // println!("current token: {:?}", token);
@@ -70,22 +79,29 @@ where
         }
     }
 
+    // The last token consumed, the tokens before it are kept in the buffer
+    pub(super) fn previous(&self) -> &Token {
+        &self.lookahead[(self.lookahead_index + LOOKAHEAD_SIZE - 1) % LOOKAHEAD_SIZE]
+    }
+
     #[allow(dead_code)]
     pub(super) fn peek_first(&self) -> Result<Token> {
         let i = (self.lookahead_index + 1) % LOOKAHEAD_SIZE;
         let token = self.lookahead[i].clone();
-        println!("peek: {:?}", token);
+        debug!("peek: {:?}", token);
         if token.is_invalid() {
-            Err(self.make_error(ParserErrorKind::UnexpectedToken { token })?)
+            Err(self.make_error(ParserErrorKind::UnexpectedToken { token: Box::new(token) })?)
         } else {
             Ok(token)
         }
     }
 
     pub(super) fn make_error(&self, kind: ParserErrorKind) -> Result<ParserError> {
+        let token = self.current()?;
         Ok(ParserError {
             kind,
-            line: self.current()?.get_line(),
+            line: token.get_line(),
+            span: Some(token.span()),
         })
     }
 }
@@ -0,0 +1,89 @@
+use super::BinaryOp;
+use crate::scanner::TokenType;
+
+// How tightly an operator holds its operands, later levels bind first
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precedence {
+    Lowest,
+    Or,
+    And,
+    Comparison,
+    Term,
+    Factor,
+    // The operand of a prefix operator, only tighter binary operators reach into it
+    Unary,
+}
+
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::Lowest => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor | Precedence::Unary => Precedence::Unary,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Assoc {
+    // 'a - b - c' is '(a - b) - c'
+    Left,
+    // 'a ^ b ^ c' would be 'a ^ (b ^ c)'
+    Right,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Infix {
+    pub op: BinaryOp,
+    pub precedence: Precedence,
+    pub assoc: Assoc,
+}
+
+impl Infix {
+    // The least precedence of the operators that may appear in its right operand
+    pub fn right_precedence(&self) -> Precedence {
+        match self.assoc {
+            Assoc::Left => self.precedence.next(),
+            Assoc::Right => self.precedence,
+        }
+    }
+}
+
+// The binary operators, a new one only needs an arm here and a BinaryOp to compile to
+pub fn infix(typ: TokenType) -> Option<Infix> {
+    use Assoc::*;
+    use Precedence::*;
+    let (op, precedence, assoc) = match typ {
+        TokenType::Or => (BinaryOp::Or, Or, Left),
+        TokenType::And => (BinaryOp::And, And, Left),
+        TokenType::Less => (BinaryOp::Less, Comparison, Left),
+        TokenType::Greater => (BinaryOp::Greater, Comparison, Left),
+        TokenType::LessEqual => (BinaryOp::LessEqual, Comparison, Left),
+        TokenType::GreaterEqual => (BinaryOp::GreaterEqual, Comparison, Left),
+        TokenType::EqualEqual => (BinaryOp::EqualEqual, Comparison, Left),
+        TokenType::BangEqual => (BinaryOp::BangEqual, Comparison, Left),
+        TokenType::Plus => (BinaryOp::Plus, Term, Left),
+        TokenType::Minus => (BinaryOp::Minus, Term, Left),
+        TokenType::Star => (BinaryOp::Star, Factor, Left),
+        TokenType::Slash => (BinaryOp::Slash, Factor, Left),
+        TokenType::Rem => (BinaryOp::Rem, Factor, Left),
+        _ => return None,
+    };
+    Some(Infix {
+        op,
+        precedence,
+        assoc,
+    })
+}
+
+// The least precedence of the operators in the operand of a prefix operator. Like in
+// Python 'not a == b' is 'not (a == b)', while '!a == b' negates only 'a'
+pub fn prefix_operand(typ: TokenType) -> Precedence {
+    match typ {
+        TokenType::Not => Precedence::Comparison,
+        _ => Precedence::Unary,
+    }
+}
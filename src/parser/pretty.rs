@@ -0,0 +1,602 @@
+use super::{
+    BinaryOp, BlockExpr, Comment, Comments, Expr, Literal, Signature, Statement, Type, UnaryOp,
+};
+use crate::scanner::is_identifier;
+use crate::vm::lib::ITERATE;
+use alloc::rc::Rc;
+use alloc::{borrow::ToOwned, string::String, string::ToString, vec::Vec};
+use core::cell::RefCell;
+
+// Renders the ast back into indented source. Groupings are kept in the tree so the
+// output parses to the same ast, up to the lines.
+
+const INDENT_WIDTH: usize = 4;
+
+pub fn print(stmts: &[Statement]) -> String {
+    let mut printer = Printer::default();
+    for stmt in stmts {
+        printer.statement(stmt);
+    }
+    printer.out
+}
+
+// Prints the statements and the trailing expression of a block, like the body of a module
+pub fn print_block(block: &BlockExpr) -> String {
+    let mut out = print(&block.stmts);
+    if !matches!(block.expr.as_ref(), Expr::Literal(Literal::Unit)) {
+        out.push_str(&Printer::default().expr(&block.expr));
+        out.push('\n');
+    }
+    out
+}
+
+// Like print_block, with comments placed before the first statement after them. A
+// comment trailing a statement that fits on one line stays at its end
+pub fn print_source(block: &BlockExpr, indent_width: usize, comments: Comments) -> String {
+    // Popped from the back in source order
+    let mut comments = comments.into_vec();
+    comments.reverse();
+    let mut printer = Printer {
+        indent: " ".repeat(indent_width),
+        comments: Rc::new(RefCell::new(comments)),
+        ..Printer::default()
+    };
+    printer.block_body(block);
+    printer.comments_before(usize::MAX);
+    let len = printer.out.trim_end().len();
+    printer.out.truncate(len);
+    printer.out.push('\n');
+    printer.out
+}
+
+struct Printer {
+    out: String,
+    depth: usize,
+    indent: String,
+    // Comments not printed yet, shared with the printers of nested blocks
+    comments: Rc<RefCell<Vec<Comment>>>,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer {
+            out: String::new(),
+            depth: 0,
+            indent: " ".repeat(INDENT_WIDTH),
+            comments: Rc::default(),
+        }
+    }
+}
+
+impl Printer {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str(&self.indent);
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    // Comments on the line of a statement stay after it
+    fn comments_before(&mut self, line: usize) {
+        loop {
+            let next = self.comments.borrow().last().map(|comment| comment.line);
+            match next {
+                Some(next) if next < line => {
+                    let comment = self.comments.borrow_mut().pop().unwrap();
+                    if !comment.text.is_empty() {
+                        self.line(&comment.text)
+                    } else if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+                        self.out.push('\n')
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn indented(&mut self, f: impl FnOnce(&mut Self)) {
+        self.depth += 1;
+        f(self);
+        self.depth -= 1;
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        if let Some(line) = stmt.line() {
+            self.comments_before(line);
+        }
+        let start = self.out.len();
+        self.statement_impl(stmt);
+        if let Some(line) = stmt.line() {
+            self.trailing_comment(line, start);
+        }
+    }
+
+    // Appends the comment trailing 'line' when the output since 'start' is that line
+    fn trailing_comment(&mut self, line: usize, start: usize) {
+        let trailing = matches!(
+            self.comments.borrow().last(),
+            Some(Comment { line: next, trailing: true, .. }) if *next == line
+        );
+        if trailing && self.out[start..].matches('\n').count() == 1 {
+            let comment = self.comments.borrow_mut().pop().unwrap();
+            self.out.pop();
+            self.out.push(' ');
+            self.out.push_str(&comment.text);
+            self.out.push('\n');
+        }
+    }
+
+    fn statement_impl(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Expr(expr) => {
+                let text = self.expr(expr);
+                self.line(&format!("{};", text))
+            }
+            // Same tree as 'let name = fn(...) ... end;'
+            Statement::Let {
+                name,
+                ty: None,
+                value:
+                    Expr::Function {
+                        args,
+                        body,
+                        signature,
+                        ..
+                    },
+                ..
+            } => {
+                let text = self.function(args, body, signature.as_deref());
+                self.line(&format!("fn {}{}", name, text))
+            }
+            Statement::Let {
+                name, ty, value, ..
+            } => {
+                let text = self.expr(value);
+                self.line(&format!("let {}{} = {};", name, annotation(": ", ty), text))
+            }
+            Statement::Var {
+                name, ty, value, ..
+            } => {
+                let text = self.expr(value);
+                self.line(&format!("var {}{} = {};", name, annotation(": ", ty), text))
+            }
+            Statement::Set { variable, value } => {
+                let text = format!("{} = {};", self.expr(variable), self.expr(value));
+                self.line(&text)
+            }
+            Statement::Block(stmts) => {
+                if let Some((variable, iter, body)) = for_loop(stmts) {
+                    let text = format!("for {} in {} do", variable, self.expr(iter));
+                    self.line(&text);
+                    self.indented(|p| body.iter().for_each(|stmt| p.statement(stmt)));
+                    return self.line("end");
+                }
+                self.line("do");
+                self.indented(|p| stmts.iter().for_each(|stmt| p.statement(stmt)));
+                self.line("end")
+            }
+            Statement::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let text = self.if_chain(condition, then_block, else_block.as_deref());
+                self.line(&text)
+            }
+            Statement::While {
+                condition,
+                then_block,
+                step,
+            } => {
+                let text = format!("while {} then", self.expr(condition));
+                self.line(&text);
+                self.indented(|p| {
+                    match then_block.as_ref() {
+                        Statement::Block(stmts) => stmts.iter().for_each(|stmt| p.statement(stmt)),
+                        stmt => p.statement(stmt),
+                    }
+                    if let Some(step) = step {
+                        p.statement(step)
+                    }
+                });
+                self.line("end")
+            }
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                let text = format!(
+                    "for {} in {}..{} do",
+                    variable,
+                    self.expr(start),
+                    self.expr(end)
+                );
+                self.line(&text);
+                self.indented(|p| match body.as_ref() {
+                    Statement::Block(stmts) => stmts.iter().for_each(|stmt| p.statement(stmt)),
+                    stmt => p.statement(stmt),
+                });
+                self.line("end")
+            }
+            Statement::Break { .. } => self.line("break;"),
+            Statement::Continue { .. } => self.line("continue;"),
+            Statement::Print(expr) => {
+                let text = self.expr(expr);
+                self.line(&format!("print {};", text))
+            }
+            Statement::Return(expr) => {
+                let text = match expr {
+                    Expr::Literal(Literal::Unit) => "return;".to_owned(),
+                    expr => format!("return {};", self.expr(expr)),
+                };
+                self.line(&text)
+            }
+            Statement::Import { path, name, .. } => {
+                self.line(&format!("import {} as {};", path.join("."), name))
+            }
+            Statement::Enum { name, variants, .. } => {
+                self.line(&format!("enum {} {{ {} }}", name, variants.join(", ")))
+            }
+        }
+    }
+
+    fn block_body(&mut self, block: &BlockExpr) {
+        for stmt in &block.stmts {
+            self.statement(stmt);
+        }
+        if !matches!(block.expr.as_ref(), Expr::Literal(Literal::Unit)) {
+            if let Some(line) = block.expr.line() {
+                self.comments_before(line);
+            }
+            let text = self.expr(&block.expr);
+            self.line(&text);
+        }
+    }
+
+    // Block bodies are rendered on their own lines, indented one level deeper than the
+    // line the expression starts on
+    fn nested(&self, f: impl FnOnce(&mut Self)) -> String {
+        let mut printer = Printer {
+            out: String::new(),
+            depth: self.depth + 1,
+            indent: self.indent.clone(),
+            comments: Rc::clone(&self.comments),
+        };
+        f(&mut printer);
+        printer.out
+    }
+
+    fn closing(&self, keyword: &str) -> String {
+        format!("{}{}", self.indent.repeat(self.depth), keyword)
+    }
+
+    // From 'if' to the final 'end', 'else if' chains are kept flat
+    fn if_chain(&self, condition: &Expr, then_block: &Expr, else_block: Option<&Expr>) -> String {
+        let mut text = format!("if {} then\n", self.expr(condition));
+        text.push_str(&self.nested(|p| p.block_expr(then_block)));
+        match else_block.map(|block| (block, else_if(block))) {
+            Some((_, Some((condition, then_block, else_block)))) => {
+                text.push_str(&self.closing("else "));
+                text.push_str(&self.if_chain(condition, then_block, else_block));
+                return text;
+            }
+            Some((else_block, None)) => {
+                text.push_str(&self.closing("else\n"));
+                text.push_str(&self.nested(|p| p.block_expr(else_block)));
+            }
+            None => (),
+        }
+        text.push_str(&self.closing("end"));
+        text
+    }
+
+    fn block_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Block(block) => self.block_body(block),
+            expr => {
+                let text = self.expr(expr);
+                self.line(&text)
+            }
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Literal(literal) => literal_text(literal),
+            Expr::Identifier { name, .. } => name.clone(),
+            Expr::Unary { op, expr } => format!("{}{}", unary_text(*op), self.expr(expr)),
+            Expr::Binary { left, op, right } => format!(
+                "{} {} {}",
+                self.expr(left),
+                binary_text(*op),
+                self.expr(right)
+            ),
+            Expr::Grouping(expr) => format!("({})", self.expr(expr)),
+            Expr::Tuple(exprs) => format!("({})", self.list(exprs)),
+            Expr::Access { table, field } => match field.as_ref() {
+                Expr::Literal(Literal::Str(name)) if is_identifier(name) => {
+                    format!("{}.{}", self.expr(table), name)
+                }
+                field => format!("{}[{}]", self.expr(table), self.expr(field)),
+            },
+            Expr::SelfAccess {
+                table,
+                method,
+                args,
+            } => format!("{}:{}({})", self.expr(table), method, self.list(args)),
+            Expr::TableInit { keys: None, values } => format!("{{{}}}", self.list(values)),
+            Expr::TableInit {
+                keys: Some(keys),
+                values,
+            } => {
+                let pairs: Vec<String> = keys
+                    .iter()
+                    .zip(values)
+                    .map(|(key, value)| format!("{} = {}", self.expr(key), self.expr(value)))
+                    .collect();
+                format!("{{{}}}", pairs.join(", "))
+            }
+            Expr::Function {
+                args,
+                body,
+                signature,
+                ..
+            } => format!("fn{}", self.function(args, body, signature.as_deref())),
+            Expr::Call { func, args } => format!("{}({})", self.expr(func), self.list(args)),
+            Expr::Block(block) => {
+                let body = self.nested(|p| p.block_body(block));
+                format!("do\n{}{}", body, self.closing("end"))
+            }
+            Expr::If {
+                condition,
+                then_block,
+                else_block,
+            } => self.if_chain(condition, then_block, Some(else_block)),
+            Expr::Rec => "rec".to_owned(),
+            Expr::Yield(expr) => format!("yield {}", self.expr(expr)),
+            Expr::Cast { expr, ty } => format!("{} as {}", self.expr(expr), ty),
+        }
+    }
+
+    // From the arguments to 'end'
+    fn function(&self, args: &[String], body: &BlockExpr, signature: Option<&Signature>) -> String {
+        let body = self.nested(|p| p.block_body(body));
+        let (args, ret) = match signature {
+            Some(signature) => {
+                let args: Vec<String> = args
+                    .iter()
+                    .zip(&signature.args)
+                    .map(|(arg, ty)| format!("{}{}", arg, annotation(": ", ty)))
+                    .collect();
+                (args.join(", "), annotation(" -> ", &signature.ret))
+            }
+            None => (args.join(", "), String::new()),
+        };
+        format!("({}){}\n{}{}", args, ret, body, self.closing("end"))
+    }
+
+    fn list(&self, exprs: &[Expr]) -> String {
+        let texts: Vec<String> = exprs.iter().map(|expr| self.expr(expr)).collect();
+        texts.join(", ")
+    }
+}
+
+// ': int' after a variable, ' -> int' after the arguments of a function
+fn annotation(prefix: &str, ty: &Option<Type>) -> String {
+    match ty {
+        Some(ty) => format!("{}{}", prefix, ty),
+        None => String::new(),
+    }
+}
+
+// Variable, iterable and body of the block a for loop is desugared into. The iterator is
+// hidden behind an empty name, which can't be written in the source, and made by
+// calling the 'for' native on the iterable
+fn for_loop(stmts: &[Statement]) -> Option<(&str, &Expr, &[Statement])> {
+    match stmts {
+        [Statement::Let {
+            name: iterator,
+            value: Expr::Call { func, args },
+            ..
+        }, Statement::Let { name, .. }, Statement::While {
+            then_block,
+            step: Some(_),
+            ..
+        }] if iterator.is_empty()
+            && matches!(func.as_ref(), Expr::Identifier { name, .. } if name == ITERATE)
+            && args.len() == 1 =>
+        {
+            let iter = &args[0];
+            match then_block.as_ref() {
+                Statement::Block(body) => Some((name, iter, body)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Parts of the 'if' that an else block consists of
+fn else_if(block: &Expr) -> Option<(&Expr, &Expr, Option<&Expr>)> {
+    match block {
+        Expr::If {
+            condition,
+            then_block,
+            else_block,
+        } => Some((condition, then_block, Some(else_block))),
+        // 'else if' statements are parsed into a block holding only the if
+        Expr::Block(BlockExpr { stmts, expr })
+            if stmts.len() == 1 && matches!(expr.as_ref(), Expr::Literal(Literal::Unit)) =>
+        {
+            match &stmts[0] {
+                Statement::If {
+                    condition,
+                    then_block,
+                    else_block,
+                } => Some((condition, then_block, else_block.as_deref())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Double quotes unless single ones save escaping
+fn quote(s: &str) -> String {
+    let quote = if s.contains('"') && !s.contains('\'') {
+        '\''
+    } else {
+        '"'
+    };
+    quote_with(s, quote)
+}
+
+fn quote_with(s: &str, quote: char) -> String {
+    let mut text = String::with_capacity(s.len() + 2);
+    text.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => text.push_str("\\\\"),
+            '\n' => text.push_str("\\n"),
+            '\t' => text.push_str("\\t"),
+            '\r' => text.push_str("\\r"),
+            '\0' => text.push_str("\\0"),
+            c if c == quote => {
+                text.push('\\');
+                text.push(c);
+            }
+            c => text.push(c),
+        }
+    }
+    text.push(quote);
+    text
+}
+
+fn literal_text(literal: &Literal) -> String {
+    match literal {
+        Literal::Str(s) => quote(s),
+        Literal::Char(c) => format!("c{}", quote_with(&c.to_string(), '\'')),
+        Literal::Int(i) => i.to_string(),
+        // Display never uses an exponent, only the point may be missing
+        Literal::Float(n) => match n.to_string() {
+            text if text.contains('.') => text,
+            text => text + ".0",
+        },
+        Literal::Bool(b) => b.to_string(),
+        Literal::Unit => "()".to_owned(),
+        Literal::Nil => "nil".to_owned(),
+    }
+}
+
+fn unary_text(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Minus => "-",
+        UnaryOp::Bang => "!",
+    }
+}
+
+fn binary_text(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Plus => "+",
+        BinaryOp::Minus => "-",
+        BinaryOp::Star => "*",
+        BinaryOp::Slash => "/",
+        BinaryOp::Greater => ">",
+        BinaryOp::Less => "<",
+        BinaryOp::EqualEqual => "==",
+        BinaryOp::BangEqual => "!=",
+        BinaryOp::GreaterEqual => ">=",
+        BinaryOp::LessEqual => "<=",
+        BinaryOp::Rem => "%",
+        BinaryOp::And => "and",
+        BinaryOp::Or => "or",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn pretty(source: &str) -> String {
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        print_block(ast.expr())
+    }
+
+    #[test]
+    fn prints_indented_source() {
+        let source = "
+            let add = fn(a,b) a+b end;
+            import lib.math as m;
+            var t = {\"x\"=1, \"a b\"=(2 * 3)};
+            t.x = -t[\"a b\"];
+            while t.x<10 then t.x = t.x+1; end
+            if t.x > 5 then print(t:get(1)); else if t.x == 0 then return; end
+            let y = if true then 1 else 2 end;
+            for i in iter do println(i); end
+            for i in 0..len(t) do println(i); end
+            add(1, 2)
+        ";
+        let expected = "\
+fn add(a, b)
+    a + b
+end
+import lib.math as m;
+var t = {\"x\" = 1, \"a b\" = (2 * 3)};
+t.x = -t[\"a b\"];
+while t.x < 10 then
+    t.x = t.x + 1;
+end
+if t.x > 5 then
+    print(t:get(1));
+else if t.x == 0 then
+    return;
+end
+let y = if true then
+    1
+else
+    2
+end;
+for i in iter do
+    println(i);
+end
+for i in 0..len(t) do
+    println(i);
+end
+add(1, 2)
+";
+        assert_eq!(pretty(source), expected);
+    }
+
+    #[test]
+    fn output_parses_to_the_same_tree() {
+        let source = "
+            let f = fn(n)
+                let i = 0;
+                do let j = (i, n); end
+                if n > 1 then n * rec(n - 1) else if n == 1 then 1 else { 1, 2 }[0] end
+            end;
+            for x in f(2) do println(x, c'\\'', c'\\n'); end
+            for i in 0..3 do if i == 1 then continue; end while true then break; end end
+            f(3)
+        ";
+        let once = pretty(source);
+        assert_eq!(pretty(&once), once);
+    }
+
+    #[test]
+    fn quotes_strings() {
+        let source = r#"let a = 'it said "hi"'; let b = "don't"; let c = "\t'\"\\\n";"#;
+        assert_eq!(
+            pretty(source),
+            r#"let a = 'it said "hi"';
+let b = "don't";
+let c = "\t'\"\\\n";
+"#
+        );
+    }
+}
@@ -1,16 +1,26 @@
-use super::Expr;
+use super::{Expr, Type};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use serde::Serialize;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Statement {
     Expr(Expr),
     Let {
         name: String, // TODO: pattern matching with tuples
+        // 'let name: ty = value'
+        ty: Option<Type>,
         value: Expr,
+        line: usize,
+        // Column of the name
+        column: usize,
     },
     // Declaration
     Var {
         name: String,
+        ty: Option<Type>,
         value: Expr,
+        line: usize,
+        column: usize,
     },
     // Set declaration
     Set {
@@ -26,12 +36,41 @@ pub enum Statement {
     While {
         condition: Expr,
         then_block: Box<Statement>,
+        // Run after the body and on 'continue', advances the variable of a for loop
+        step: Option<Box<Statement>>,
+    },
+    // 'for variable in start..end do body end', see Compiler::for_stmt
+    For {
+        variable: String,
+        start: Expr,
+        end: Expr,
+        body: Box<Statement>,
+        line: usize,
+        // Column of the variable
+        column: usize,
+    },
+    // Leave the innermost loop
+    Break {
+        line: usize,
+    },
+    // Go on with the next iteration of the innermost loop
+    Continue {
+        line: usize,
     },
     Print(Expr),
     Return(Expr),
     Import {
         path: Vec<String>,
         name: String,
+        line: usize,
+        column: usize,
+    },
+    // A table of a distinct value for each variant, see Compiler::enum_stmt
+    Enum {
+        name: String,
+        variants: Vec<String>,
+        line: usize,
+        column: usize,
     },
 }
 
@@ -47,6 +86,25 @@ impl Statement {
         }
     }
 
+    // Line of the first token of the statement, when the tree records it
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Statement::Let { line, .. }
+            | Statement::Var { line, .. }
+            | Statement::Import { line, .. }
+            | Statement::Enum { line, .. }
+            | Statement::For { line, .. }
+            | Statement::Break { line }
+            | Statement::Continue { line } => Some(*line),
+            Statement::Expr(expr) | Statement::Print(expr) | Statement::Return(expr) => expr.line(),
+            Statement::Set { variable, .. } => variable.line(),
+            Statement::Block(stmts) => stmts.first().and_then(Statement::line),
+            Statement::If { condition, .. } | Statement::While { condition, .. } => {
+                condition.line()
+            }
+        }
+    }
+
     pub fn into_expr(self) -> Option<Expr> {
         match self {
             Statement::Expr(expr) => Some(expr),
@@ -0,0 +1,81 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+use serde::Serialize;
+
+// Annotations like 'let x: int = 1'. Only typecheck::check reads them, the compiler
+// leaves the values dynamic
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Type {
+    Int,
+    Number,
+    Str,
+    Bool,
+    Nil,
+    Unit,
+    Table,
+    Function,
+    // Any value, like the variables without an annotation
+    Any,
+    Tuple(Vec<Type>),
+}
+
+// The annotations of a function, None for the ones left out
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Signature {
+    pub args: Vec<Option<Type>>,
+    pub ret: Option<Type>,
+}
+
+impl Type {
+    // Types written with an identifier, 'fn' and 'nil' are keywords
+    pub fn from_name(name: &str) -> Option<Type> {
+        Some(match name {
+            "int" => Type::Int,
+            "number" => Type::Number,
+            "str" => Type::Str,
+            "bool" => Type::Bool,
+            "table" => Type::Table,
+            "any" => Type::Any,
+            _ => return None,
+        })
+    }
+
+    // Whether a value of type 'found' can be used where this type is expected.
+    // Ints are numbers too, the arithmetic of the vm mixes them
+    pub fn accepts(&self, found: &Type) -> bool {
+        match (self, found) {
+            (Type::Any, _) | (_, Type::Any) | (Type::Number, Type::Int) => true,
+            (Type::Tuple(expected), Type::Tuple(found)) => {
+                expected.len() == found.len()
+                    && expected.iter().zip(found).all(|(e, f)| e.accepts(f))
+            }
+            (expected, found) => expected == found,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Number => write!(f, "number"),
+            Type::Str => write!(f, "str"),
+            Type::Bool => write!(f, "bool"),
+            Type::Nil => write!(f, "nil"),
+            Type::Unit => write!(f, "()"),
+            Type::Table => write!(f, "table"),
+            Type::Function => write!(f, "fn"),
+            Type::Any => write!(f, "any"),
+            Type::Tuple(types) => {
+                write!(f, "(")?;
+                for (i, ty) in types.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", ty)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Python bindings with pyo3. Build the extension module with
+// `cargo rustc --release --lib --features python --crate-type cdylib` and copy
+// target/release/libflux_rs.so to flux_rs.so on the Python path (maturin does both).
+// Values are copied across: nil and unit become None, tables dicts, tuples tuples,
+// and Python lists become tables indexed from 0. Functions are not converted
+// The pyo3 macros convert the errors of the methods into PyErr, which they already are
+#![allow(clippy::useless_conversion)]
+
+use crate::compiler::{CompiledSource, Compiler};
+use crate::diagnostic::render_error;
+use crate::error::FluxResult;
+use crate::parser::Parser;
+use crate::sourcefile::{MetaData, SourceFile};
+use crate::vm::{ArgsLen, RuntimeError, Table, Value, Vm};
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyList, PyTuple};
+
+// Tables holding themselves would never end
+const MAX_DEPTH: usize = 64;
+
+#[pyclass(name = "Vm", unsendable)]
+pub struct PyVm {
+    vm: Vm,
+}
+
+#[pymethods]
+impl PyVm {
+    #[new]
+    fn new() -> Self {
+        PyVm { vm: Vm::new() }
+    }
+
+    // Runs the source, the globals it defines stay for the next ones
+    fn eval(&mut self, py: Python<'_>, source: &str) -> PyResult<PyObject> {
+        let value = run(&mut self.vm, source)?;
+        to_python(py, &value, 0)
+    }
+
+    fn get_global(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        match self.vm.global(name) {
+            Some(value) => to_python(py, value, 0),
+            None => Ok(py.None()),
+        }
+    }
+
+    fn set_global(&mut self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let value = from_python(value, 0)?;
+        self.vm.set_global(name, value);
+        Ok(())
+    }
+
+    // The callable gets the arguments converted, an exception it raises fails the
+    // script with RuntimeError::Native
+    fn register(&mut self, name: &str, callable: PyObject) {
+        self.vm
+            .register_native(name, ArgsLen::Variadic, move |_vm, args| {
+                Python::with_gil(|py| {
+                    let args = args
+                        .iter()
+                        .map(|arg| to_python(py, arg, 0))
+                        .collect::<PyResult<Vec<_>>>()?;
+                    let result = callable.call1(py, PyTuple::new_bound(py, args))?;
+                    from_python(result.bind(py), 0)
+                })
+                .map_err(|error| RuntimeError::Native(error.to_string()))
+            });
+    }
+}
+
+// Runs the source in a new vm
+#[pyfunction]
+fn eval(py: Python<'_>, source: &str) -> PyResult<PyObject> {
+    let value = run(&mut Vm::new(), source)?;
+    to_python(py, &value, 0)
+}
+
+#[pymodule]
+fn flux_rs(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyVm>()?;
+    module.add_function(wrap_pyfunction!(eval, module)?)?;
+    Ok(())
+}
+
+// Errors are raised as RuntimeError with the rendered message
+fn run(vm: &mut Vm, source: &str) -> PyResult<Value> {
+    let result = match compile(vm, source) {
+        Ok(compiled) => vm.run(&compiled).map_err(Into::into),
+        Err(error) => Err(error),
+    };
+    result.map_err(|error| PyRuntimeError::new_err(render_error(&error, source, None, false)))
+}
+
+fn compile(vm: &Vm, source: &str) -> FluxResult<CompiledSource> {
+    let ast = Parser::new(source)
+        .with_globals(vm.global_names())
+        .parse_all()?;
+    Ok(Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })?)
+}
+
+pub fn to_python(py: Python<'_>, value: &Value, depth: usize) -> PyResult<PyObject> {
+    if depth > MAX_DEPTH {
+        return Err(PyValueError::new_err("the table is nested too deep"));
+    }
+    Ok(match value {
+        Value::Nil | Value::Unit => py.None(),
+        Value::Bool(b) => b.into_py(py),
+        Value::Int(i) => i.into_py(py),
+        Value::Number(n) => n.into_py(py),
+        Value::Str(_) | Value::Embedded(_) => {
+            value.as_str().expect("Expected a string").into_py(py)
+        }
+        Value::Tuple(values) => {
+            let values = values
+                .iter()
+                .map(|value| to_python(py, value, depth + 1))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyTuple::new_bound(py, values).into_py(py)
+        }
+        Value::Table(table) => {
+            let dict = PyDict::new_bound(py);
+            for (key, value) in table.borrow().pairs() {
+                dict.set_item(
+                    to_python(py, key, depth + 1)?,
+                    to_python(py, value, depth + 1)?,
+                )?;
+            }
+            dict.into_py(py)
+        }
+        Value::Function(_) => {
+            return Err(PyTypeError::new_err("functions can't be converted"));
+        }
+    })
+}
+
+pub fn from_python(object: &Bound<'_, PyAny>, depth: usize) -> PyResult<Value> {
+    if depth > MAX_DEPTH {
+        return Err(PyValueError::new_err("the value is nested too deep"));
+    }
+    // bool before int, Python bools are ints too
+    if object.is_none() {
+        Ok(Value::Nil)
+    } else if let Ok(b) = object.downcast::<PyBool>() {
+        Ok(Value::Bool(b.is_true()))
+    } else if let Ok(i) = object.extract::<i64>() {
+        Ok(Value::Int(i))
+    } else if let Ok(n) = object.extract::<f64>() {
+        Ok(Value::Number(n))
+    } else if let Ok(s) = object.extract::<&str>() {
+        Ok(Value::new_str(s))
+    } else if let Ok(tuple) = object.downcast::<PyTuple>() {
+        let values = tuple
+            .iter()
+            .map(|item| from_python(&item, depth + 1))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(Value::Tuple(values.into()))
+    } else if let Ok(list) = object.downcast::<PyList>() {
+        let values = list
+            .iter()
+            .enumerate()
+            .map(|(i, item)| Ok((Value::Int(i as i64), from_python(&item, depth + 1)?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(Table::from_array(values).into())
+    } else if let Ok(dict) = object.downcast::<PyDict>() {
+        let mut table = Table::new();
+        for (key, value) in dict.iter() {
+            table.set(
+                from_python(&key, depth + 1)?,
+                from_python(&value, depth + 1)?,
+            );
+        }
+        Ok(table.into())
+    } else {
+        let type_name = object.get_type().name()?.to_string();
+        Err(PyTypeError::new_err(format!(
+            "can't convert {} to a flux value",
+            type_name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_in(py: Python<'_>, vm: &mut PyVm, source: &str) -> PyObject {
+        vm.eval(py, source).unwrap()
+    }
+
+    #[test]
+    fn values_are_converted() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut vm = PyVm::new();
+            let value = eval_in(py, &mut vm, "(true, 2.5, \"s\", nil)");
+            let value: (bool, f64, String, Option<i64>) = value.extract(py).unwrap();
+            assert_eq!(value, (true, 2.5, "s".to_owned(), None));
+
+            let list = PyList::new_bound(py, [1, 2, 3]);
+            vm.set_global("list", &list).unwrap();
+            let value = eval_in(py, &mut vm, "var t = { \"x\" = list[2] }; t");
+            let dict = value.bind(py).downcast::<PyDict>().unwrap();
+            let x: i64 = dict.get_item("x").unwrap().unwrap().extract().unwrap();
+            assert_eq!(x, 3);
+            let x: i64 = vm
+                .get_global(py, "t")
+                .unwrap()
+                .bind(py)
+                .get_item("x")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(x, 3);
+        });
+    }
+
+    #[test]
+    fn python_callables_are_natives() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut vm = PyVm::new();
+            let mul = py.eval_bound("lambda a, b: a * b", None, None).unwrap();
+            vm.register("mul", mul.unbind());
+            let value: i64 = eval_in(py, &mut vm, "mul(6, 7)").extract(py).unwrap();
+            assert_eq!(value, 42);
+            let error = vm.eval(py, "mul(1)").unwrap_err();
+            assert!(error.is_instance_of::<PyRuntimeError>(py));
+            assert!(eval(py, "1 / 0").is_err());
+        });
+    }
+}
@@ -1,19 +1,159 @@
 mod error;
 mod token;
+mod trivia;
 
+use alloc::{string::String, string::ToString, vec::Vec};
+use core::iter::Peekable;
+use core::str::CharIndices;
 pub use error::{LexError, LexErrorKind};
-use std::iter::Peekable;
-use std::str::CharIndices;
 use token::KEYWORDS;
-pub use token::{Token, TokenType};
+pub use token::{Span, Token, TokenType};
+pub use trivia::{LosslessToken, Trivia, TriviaKind};
+use unicode_ident::{is_xid_continue, is_xid_start};
+use unicode_normalization::UnicodeNormalization;
 
-type Result<T> = std::result::Result<T, LexError>;
+type Result<T> = core::result::Result<T, LexError>;
+
+// The char the escape sequence at the start of 'rest', which follows a backslash,
+// stands for and the length of the sequence. Besides the single char ones like \n
+// there are \x41 for ASCII chars and \u{1F600} for any codepoint. Errors come with
+// the length of the malformed sequence
+fn escape(rest: &str) -> core::result::Result<(char, usize), (LexErrorKind, usize)> {
+    let c = match rest.chars().next() {
+        Some(c) => c,
+        None => return Err((LexErrorKind::InvalidEscape('\\'), 0)),
+    };
+    match c {
+        'n' => Ok(('\n', 1)),
+        't' => Ok(('\t', 1)),
+        'r' => Ok(('\r', 1)),
+        '0' => Ok(('\0', 1)),
+        '\\' | '"' | '\'' => Ok((c, 1)),
+        'x' => {
+            let digits = rest[1..]
+                .char_indices()
+                .take_while(|&(i, c)| i < 2 && c.is_ascii_hexdigit())
+                .count();
+            match u8::from_str_radix(&rest[1..1 + digits], 16) {
+                Ok(byte) if digits == 2 && byte.is_ascii() => Ok((byte as char, 3)),
+                _ => Err((LexErrorKind::InvalidHexEscape, 1 + digits)),
+            }
+        }
+        'u' => {
+            let digits = rest[1..].strip_prefix('{').map(|body| {
+                let end = body.find(|c: char| !c.is_ascii_hexdigit());
+                &body[..end.unwrap_or(body.len())]
+            });
+            match digits {
+                Some(digits)
+                    if (1..=6).contains(&digits.len())
+                        && rest[2 + digits.len()..].starts_with('}') =>
+                {
+                    let len = digits.len() + 3;
+                    let codepoint = u32::from_str_radix(digits, 16).unwrap();
+                    match core::char::from_u32(codepoint) {
+                        Some(c) => Ok((c, len)),
+                        None => Err((LexErrorKind::InvalidCodepoint(codepoint), len)),
+                    }
+                }
+                Some(digits) => Err((LexErrorKind::InvalidUnicodeEscape, digits.len() + 2)),
+                None => Err((LexErrorKind::InvalidUnicodeEscape, 1)),
+            }
+        }
+        c => Err((LexErrorKind::InvalidEscape(c), c.len_utf8())),
+    }
+}
+
+// Replaces the escape sequences of a string body. Invalid ones were reported while
+// scanning, they keep the char after the backslash
+fn unescape(raw: &str) -> String {
+    let mut text = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(i) = rest.find('\\') {
+        text.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let len = match (escape(rest), rest.chars().next()) {
+            (Ok((c, len)), _) => {
+                text.push(c);
+                len
+            }
+            (Err(_), Some(c)) => {
+                text.push(c);
+                c.len_utf8()
+            }
+            (Err(_), None) => {
+                text.push('\\');
+                0
+            }
+        };
+        rest = &rest[len..];
+    }
+    text.push_str(rest);
+    text
+}
+
+// The lines of a multi-line string body. Like in Swift, the line break after the
+// opening quotes and the line holding only the closing quotes are not part of the
+// string, and the indentation common to the lines is removed so that the string can
+// be indented along with the code around it
+fn dedent(raw: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = raw.split('\n').collect();
+    let indent_of = |line: &str| line.len() - line.trim_start_matches([' ', '\t']).len();
+    // Text right after the opening quotes keeps its spaces and doesn't count
+    // towards the indentation
+    let first = if lines.len() > 1 && lines[0].trim().is_empty() {
+        lines.remove(0);
+        None
+    } else {
+        Some(lines.remove(0))
+    };
+    let mut indent = usize::MAX;
+    if let Some(last) = lines.last() {
+        if last.trim().is_empty() {
+            indent = last.len();
+            lines.pop();
+        }
+    }
+    indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indent_of(line))
+        .fold(indent, usize::min);
+    let stripped = lines
+        .into_iter()
+        .map(|line| &line[indent.min(indent_of(line))..]);
+    first.into_iter().chain(stripped).collect()
+}
+
+// Whether the name scans as this identifier, so that it can be written without quotes
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if is_xid_start(c) || c == '_')
+        && chars.all(is_xid_continue)
+        && (name.is_ascii() || name.nfkc().eq(name.chars()))
+}
+
+// Keywords scan as their own tokens, never as identifiers
+pub fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains_key(name)
+}
 
 pub struct Scanner<'a> {
     source: &'a str,
     chars: Peekable<CharIndices<'a>>,
     line: usize,
+    // Offset of the first char of the line
+    line_start: usize,
+    // Offset after the last char consumed
+    offset: usize,
     tokens: Vec<Token>,
+    errors: Vec<LexError>,
+    // Whitespace and comments are recorded instead of dropped, see 'with_trivia'
+    keep_trivia: bool,
+    // Trivia read since the last token
+    trivia: Vec<Trivia>,
+    // The trivia before each token
+    leading: Vec<Vec<Trivia>>,
 }
 
 impl<'a> Scanner<'a> {
@@ -22,7 +162,21 @@ impl<'a> Scanner<'a> {
             source,
             chars: source.char_indices().peekable(),
             line: 1,
+            line_start: 0,
+            offset: 0,
             tokens: Vec::new(),
+            errors: Vec::new(),
+            keep_trivia: false,
+            trivia: Vec::new(),
+            leading: Vec::new(),
+        }
+    }
+
+    // Scans without losing anything of the source, for tools that rewrite it
+    pub fn with_trivia(source: &'a str) -> Self {
+        Scanner {
+            keep_trivia: true,
+            ..Scanner::new(source)
         }
     }
 
@@ -30,31 +184,81 @@ impl<'a> Scanner<'a> {
         self.tokens
     }
 
-    pub fn scan(&mut self) -> Result<&Vec<Token>> {
+    // The tokens with their trivia when scanned 'with_trivia', ending with a single
+    // Eof token
+    pub fn extract_lossless(mut self) -> Vec<LosslessToken> {
+        self.tokens.truncate(self.leading.len());
+        self.leading
+            .into_iter()
+            .zip(self.tokens)
+            .map(|(leading, token)| LosslessToken { leading, token })
+            .collect()
+    }
+
+    // Errors found while scanning, the source they are about is skipped
+    pub fn errors(&self) -> &[LexError] {
+        &self.errors
+    }
+
+    // Scans the whole source. Errors don't stop it, they are recorded and scanning
+    // goes on after them so that all of them are reported at once
+    pub fn scan(&mut self) -> &Vec<Token> {
         loop {
             match self.scan_next() {
-                Ok(token) => self.tokens.push(token),
-                Err(e) => match e {
-                    LexError {
-                        kind: LexErrorKind::Eof,
-                        ..
-                    } => {
-                        self.tokens.push(self.new_token(TokenType::Eof, 0, 0));
-                        // We need this for match_token
-                        self.tokens.push(self.new_token(TokenType::Eof, 0, 0));
-                        return Ok(&self.tokens);
+                Ok(token) => self.push_token(token),
+                Err(LexError {
+                    kind: LexErrorKind::Eof,
+                    ..
+                }) => {
+                    let end = self.source.len();
+                    self.push_token(self.new_token(TokenType::Eof, end, end));
+                    // We need this for match_token
+                    self.tokens.push(self.new_token(TokenType::Eof, end, end));
+                    return &self.tokens;
+                }
+                Err(err) => {
+                    // What the error consumed has no token, it is kept as trivia
+                    if self.keep_trivia {
+                        let start = match (self.trivia.last(), self.tokens.last()) {
+                            (Some(trivia), _) => trivia.span.end,
+                            (None, Some(token)) => token.span.end,
+                            (None, None) => 0,
+                        };
+                        self.push_trivia(TriviaKind::Skipped, self.span(start, self.offset));
                     }
-                    _ => return Err(e),
-                },
+                    self.errors.push(err);
+                }
             }
         }
     }
 
+    fn push_token(&mut self, token: Token) {
+        if self.keep_trivia {
+            self.leading.push(core::mem::take(&mut self.trivia));
+        }
+        self.tokens.push(token);
+    }
+
+    // Runs of whitespace are kept as one trivia
+    fn push_trivia(&mut self, kind: TriviaKind, span: Span) {
+        if !self.keep_trivia || span.start == span.end {
+            return;
+        }
+        match self.trivia.last_mut() {
+            Some(last) if last.kind == kind && kind == TriviaKind::Whitespace => {
+                last.span.end = span.end
+            }
+            _ => self.trivia.push(Trivia { kind, span }),
+        }
+    }
+
     fn scan_next(&mut self) -> Result<Token> {
         loop {
             let (start, c) = self.advance()?;
             match c {
-                '.' => return Ok(self.new_token(TokenType::Dot, start, start + 1)),
+                '.' => {
+                    return self.double_char_token(TokenType::Dot, TokenType::DotDot, '.', start)
+                }
                 ',' => return Ok(self.new_token(TokenType::Comma, start, start + 1)),
                 ';' => return Ok(self.new_token(TokenType::Semicolon, start, start + 1)),
                 ':' => return Ok(self.new_token(TokenType::Colon, start, start + 1)),
@@ -68,22 +272,37 @@ impl<'a> Scanner<'a> {
                 ']' => return Ok(self.new_token(TokenType::RightBracket, start, start + 1)),
 
                 '+' => return Ok(self.new_token(TokenType::Plus, start, start + 1)),
-                '-' => return Ok(self.new_token(TokenType::Minus, start, start + 1)),
+                '-' => {
+                    return self.double_char_token(
+                        TokenType::Minus,
+                        TokenType::ThinArrow,
+                        '>',
+                        start,
+                    )
+                }
                 '*' => return Ok(self.new_token(TokenType::Star, start, start + 1)),
                 '/' => match self.peek() {
-                    '/' => self.single_line_comment()?,
+                    '/' => {
+                        self.single_line_comment()?;
+                        self.push_trivia(TriviaKind::Comment, self.span(start, self.offset));
+                    }
                     _ => return Ok(self.new_token(TokenType::Slash, start, start + 1)),
                 },
                 '%' => return Ok(self.new_token(TokenType::Rem, start, start + 1)),
+                // A '#!' line starting the source, so scripts can be run directly on unix
+                '#' if start == 0 && self.peek() == '!' => {
+                    while self.match_pred(|c| c != '\n').is_ok() {}
+                    self.push_trivia(TriviaKind::Comment, self.span(start, self.offset));
+                }
 
                 '=' => match self.peek() {
                     '=' => {
                         let (end, _) = self.advance().unwrap();
-                        return Ok(self.new_token(TokenType::EqualEqual, start, end));
+                        return Ok(self.new_token(TokenType::EqualEqual, start, end + 1));
                     }
                     '>' => {
                         let (end, _) = self.advance().unwrap();
-                        return Ok(self.new_token(TokenType::RightArrow, start, end));
+                        return Ok(self.new_token(TokenType::RightArrow, start, end + 1));
                     }
                     _ => return Ok(self.new_token(TokenType::Equal, start, start + 1)),
                 },
@@ -112,27 +331,38 @@ impl<'a> Scanner<'a> {
                     )
                 }
 
-                '\"' => return self.string(start + 1),
+                '"' | '\'' => return self.string(start + 1, c),
 
-                ' ' | '\t' | '\r' => {}
-                '\n' => self.line += 1,
+                ' ' | '\t' | '\r' => {
+                    self.push_trivia(TriviaKind::Whitespace, self.span(start, self.offset))
+                }
+                '\n' => {
+                    self.push_trivia(TriviaKind::Newline, self.span(start, self.offset));
+                    self.line += 1;
+                    self.line_start = start + 1;
+                }
+                'c' if self.peek() == '\'' => return self.char_literal(start),
                 c => {
-                    if c.is_alphabetic() || c == '_' {
+                    if is_xid_start(c) || c == '_' {
                         let token = self.identifier(start)?;
                         match KEYWORDS.get(token.text.as_str()) {
                             Some(&typ) => {
                                 return Ok(Token {
                                     typ,
                                     text: token.text,
-                                    line: token.line,
+                                    span: token.span,
                                 })
                             }
                             None => return Ok(token),
                         }
-                    } else if c.is_numeric() {
+                    } else if c.is_ascii_digit() {
                         return self.number(start);
                     } else {
-                        return Err(self.make_error(LexErrorKind::InvalidChar(c)));
+                        let span = self.span(start, start + c.len_utf8());
+                        return Err(LexError {
+                            kind: LexErrorKind::InvalidChar(c),
+                            span,
+                        });
                     }
                 }
             }
@@ -145,37 +375,163 @@ impl<'a> Scanner<'a> {
         Ok(())
     }
 
-    fn string(&mut self, start: usize) -> Result<Token> {
+    // Strings start after the opening quote and end at the same quote. Both kinds
+    // take the same escapes. Three quotes open a multi-line string that ends at three
+    // quotes again, see 'dedent' for how its lines are trimmed
+    fn string(&mut self, start: usize, quote: char) -> Result<Token> {
+        let mut span = self.span(start - 1, start);
+        let line_start = self.line_start;
+        let triple = self.source[start..]
+            .chars()
+            .take(2)
+            .eq([quote, quote].iter().copied());
+        if triple {
+            self.advance()?;
+            self.advance()?;
+        }
+        let body = self.offset;
         let end = loop {
-            match self.match_char('\"') {
-                Ok((i, _)) => break i,
-                Err(LexError {
-                    kind: LexErrorKind::UnexpectedChar(_),
-                    ..
-                }) => {
+            let (i, c) = match self.advance() {
+                Ok(next) => next,
+                // The rest of the line was likely meant to be in the string, scanning
+                // goes on from the next one
+                Err(_) => {
+                    let resume = self.source[start..]
+                        .find('\n')
+                        .map_or(self.source.len(), |i| start + i);
+                    self.line = span.line;
+                    self.line_start = line_start;
+                    span.end = resume;
+                    self.rewind(resume);
+                    return Err(LexError {
+                        kind: LexErrorKind::UnterminatedString,
+                        span,
+                    });
+                }
+            };
+            match c {
+                _ if c == quote && !triple => break i,
+                _ if c == quote
+                    && self.source[i + 1..]
+                        .chars()
+                        .take(2)
+                        .eq([quote, quote].iter().copied()) =>
+                {
                     self.advance()?;
+                    self.advance()?;
+                    break i;
+                }
+                '\n' => {
+                    self.line += 1;
+                    self.line_start = i + 1;
                 }
-                Err(err) => return Err(err),
+                // A backslash ending the source leaves the string unterminated
+                '\\' if self.chars.peek().is_some() => {
+                    let rest = &self.source[i + 1..];
+                    let len = match escape(rest) {
+                        Ok((_, len)) => len,
+                        // Only the char after the backslash is skipped, the rest of a
+                        // malformed sequence may hold the closing quote
+                        Err((kind, len)) => {
+                            self.errors.push(LexError {
+                                kind,
+                                span: self.span(i, i + 1 + len),
+                            });
+                            rest.chars().next().map_or(0, char::len_utf8)
+                        }
+                    };
+                    while self.offset < i + 1 + len {
+                        self.advance()?;
+                    }
+                    if rest.starts_with('\n') {
+                        self.line += 1;
+                        self.line_start = self.offset;
+                    }
+                }
+                _ => (),
             }
         };
-        Ok(self.new_token(TokenType::String, start, end))
+        let raw = &self.source[body..end];
+        let text = if triple {
+            dedent(raw)
+                .iter()
+                .map(|line| unescape(line))
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            unescape(raw)
+        };
+        span.end = self.offset;
+        Ok(Token {
+            typ: TokenType::String,
+            text,
+            span,
+        })
+    }
+
+    // A char literal, c'a', stands for the codepoint of its one char. It takes the
+    // escapes of strings
+    fn char_literal(&mut self, start: usize) -> Result<Token> {
+        self.advance()?;
+        let mut token = self.string(start + 2, '\'')?;
+        token.typ = TokenType::Char;
+        token.span.start = start;
+        token.span.column -= 1;
+        if token.text.chars().count() != 1 {
+            self.errors.push(LexError {
+                kind: LexErrorKind::InvalidCharLiteral,
+                span: token.span,
+            });
+        }
+        Ok(token)
     }
 
+    // Identifiers are NFKC normalized like in Python, names written with compatibility
+    // characters such as fullwidth letters or ligatures are the same as the plain ones.
+    // Letters of different scripts that only look alike stay different names
     fn identifier(&mut self, start: usize) -> Result<Token> {
-        let end = self.skip_while(start, |c| c.is_alphanumeric() || c == '_');
-        Ok(self.new_token(TokenType::Identifier, start, end + 1))
+        let end = self.skip_while(is_xid_continue);
+        let mut token = self.new_token(TokenType::Identifier, start, end);
+        if !token.text.is_ascii() {
+            token.text = token.text.nfkc().collect();
+        }
+        Ok(token)
     }
 
+    // Numbers are decimal, or hexadecimal after 0x. Underscores may separate their
+    // digits, like in 1_000_000 or 0xFF_FF
     fn number(&mut self, start: usize) -> Result<Token> {
-        let end = self.skip_while(start, char::is_numeric);
-        match self.peek() {
-            '.' => {
-                self.advance().unwrap();
-                let end = self.skip_while(end + 1, char::is_numeric);
-                Ok(self.new_token(TokenType::Number, start, end + 1))
-            }
-            _ => Ok(self.new_token(TokenType::Number, start, end + 1)),
+        if self.source[start..].starts_with("0x") || self.source[start..].starts_with("0X") {
+            let (digits, _) = self.advance()?;
+            let end = self.digits(digits + 1, |c| c.is_ascii_hexdigit());
+            return Ok(self.new_token(TokenType::Number, start, end));
+        }
+        let mut end = self.digits(start, |c| c.is_ascii_digit());
+        // The dot of '0..10' starts a range, not a fraction
+        if self.peek() == '.' && !self.source[end..].starts_with("..") {
+            let (fraction, _) = self.advance().unwrap();
+            end = match self.peek() {
+                c if c.is_ascii_digit() => self.digits(fraction + 1, |c| c.is_ascii_digit()),
+                _ => self.offset,
+            };
+        }
+        Ok(self.new_token(TokenType::Number, start, end))
+    }
+
+    // Consumes the digits and underscores of a number, 'start' is the offset of its
+    // first digit. Underscores only go between digits, others are reported
+    fn digits<F>(&mut self, start: usize, is_digit: F) -> usize
+    where
+        F: Fn(char) -> bool + Copy,
+    {
+        let end = self.skip_while(|c| is_digit(c) || c == '_');
+        if self.source[start..end].split('_').any(str::is_empty) {
+            self.errors.push(LexError {
+                kind: LexErrorKind::InvalidNumber,
+                span: self.span(start, end),
+            });
         }
+        end
     }
 
     #[inline]
@@ -187,7 +543,7 @@ impl<'a> Scanner<'a> {
         start: usize,
     ) -> Result<Token> {
         match self.match_char(second_char) {
-            Ok((end, _)) => Ok(self.new_token(double_type, start, end)),
+            Ok((end, _)) => Ok(self.new_token(double_type, start, end + 1)),
             Err(LexError {
                 kind: LexErrorKind::UnexpectedChar(_),
                 ..
@@ -198,11 +554,21 @@ impl<'a> Scanner<'a> {
 
     fn advance(&mut self) -> Result<(usize, char)> {
         match self.chars.next() {
-            Some(t) => Ok(t),
+            Some((i, c)) => {
+                self.offset = i + c.len_utf8();
+                Ok((i, c))
+            }
             None => Err(self.make_error(LexErrorKind::Eof)),
         }
     }
 
+    // Goes back to scan from 'offset' again
+    fn rewind(&mut self, offset: usize) {
+        self.chars = self.source.char_indices().peekable();
+        while self.chars.next_if(|&(i, _)| i < offset).is_some() {}
+        self.offset = offset;
+    }
+
     fn match_char(&mut self, pred: char) -> Result<(usize, char)> {
         match self.chars.peek() {
             Some(&(_, c)) => {
@@ -229,15 +595,13 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn skip_while<F>(&mut self, start: usize, pred: F) -> usize
+    // Consumes the chars matching 'pred', returns the offset after them
+    fn skip_while<F>(&mut self, pred: F) -> usize
     where
         F: Fn(char) -> bool + Copy,
     {
-        let mut result = start;
-        while self.match_pred(pred).is_ok() {
-            result += 1
-        }
-        result
+        while self.match_pred(pred).is_ok() {}
+        self.offset
     }
 
     fn peek(&mut self) -> char {
@@ -248,34 +612,65 @@ impl<'a> Scanner<'a> {
         Token {
             typ,
             text: self.source[start..end].to_string(),
+            span: self.span(start, end),
+        }
+    }
+
+    // The span of 'start..end' on the current line
+    fn span(&self, start: usize, end: usize) -> Span {
+        Span {
+            start,
+            end,
             line: self.line,
+            column: self.source[self.line_start..start].chars().count() + 1,
         }
     }
 
+    // Points at the next char, which is not consumed yet
     fn make_error(&self, kind: LexErrorKind) -> LexError {
+        let next = self.source[self.offset..].chars().next();
         LexError {
             kind,
-            line: self.line,
+            span: self.span(self.offset, self.offset + next.map_or(0, char::len_utf8)),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::token::{Token, TokenType};
-    use super::Scanner;
+    use super::token::{Span, Token, TokenType};
+    use super::{is_identifier, LexError, LexErrorKind, LosslessToken, Scanner, TriviaKind};
+
+    fn expected(typ: TokenType, text: &str, start: usize, line: usize, column: usize) -> Token {
+        Token {
+            typ,
+            text: text.to_string(),
+            span: Span {
+                start,
+                end: start + text.len(),
+                line,
+                column,
+            },
+        }
+    }
 
     #[test]
     fn string_works() {
         let source = "\"abcd\"";
         let mut scanner = Scanner::new(source);
         let token = scanner.scan_next().unwrap();
+        // The span covers the quotes
         assert_eq!(
             token,
             Token {
                 typ: TokenType::String,
                 text: "abcd".to_string(),
-                line: 1,
+                span: Span {
+                    start: 0,
+                    end: 6,
+                    line: 1,
+                    column: 1,
+                },
             }
         );
     }
@@ -285,13 +680,56 @@ mod tests {
         let source = " 43.23ab";
         let mut scanner = Scanner::new(source);
         let token = scanner.scan_next().unwrap();
+        assert_eq!(token, expected(TokenType::Number, "43.23", 1, 1, 2));
+    }
+
+    #[test]
+    fn ranges_are_not_fractions() {
+        let source = "0..10 1.5..x";
+        let mut scanner = Scanner::new(source);
+        let texts: Vec<_> = scanner
+            .scan()
+            .iter()
+            .map(|token| token.text.clone())
+            .collect();
+        assert_eq!(texts, vec!["0", "..", "10", "1.5", "..", "x", "", ""]);
+    }
+
+    #[test]
+    fn digit_separators_work() {
+        let source = "1_000_000 0xFF_ff 1_0.5_5 1. 1__0 0x 2_";
+        let mut scanner = Scanner::new(source);
+        let texts: Vec<_> = scanner
+            .scan()
+            .iter()
+            .map(|token| token.text.clone())
+            .collect();
         assert_eq!(
-            token,
-            Token {
-                typ: TokenType::Number,
-                text: "43.23".to_string(),
-                line: 1,
-            }
+            texts,
+            vec![
+                "1_000_000",
+                "0xFF_ff",
+                "1_0.5_5",
+                "1.",
+                "1__0",
+                "0x",
+                "2_",
+                "",
+                ""
+            ]
+        );
+        let errors: Vec<_> = scanner
+            .errors()
+            .iter()
+            .map(|error| (error.kind, &source[error.span.start..error.span.end]))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (LexErrorKind::InvalidNumber, "1__0"),
+                (LexErrorKind::InvalidNumber, ""),
+                (LexErrorKind::InvalidNumber, "2_"),
+            ]
         );
     }
 
@@ -300,14 +738,7 @@ mod tests {
         let source = " \nvariable";
         let mut scanner = Scanner::new(source);
         let token = scanner.scan_next().unwrap();
-        assert_eq!(
-            token,
-            Token {
-                typ: TokenType::Identifier,
-                text: "variable".to_string(),
-                line: 2,
-            }
-        )
+        assert_eq!(token, expected(TokenType::Identifier, "variable", 2, 2, 1))
     }
 
     #[test]
@@ -315,14 +746,7 @@ mod tests {
         let source = " while ";
         let mut scanner = Scanner::new(source);
         let token = scanner.scan_next().unwrap();
-        assert_eq!(
-            token,
-            Token {
-                typ: TokenType::While,
-                text: "while".to_string(),
-                line: 1,
-            }
-        );
+        assert_eq!(token, expected(TokenType::While, "while", 1, 1, 2));
     }
 
     #[test]
@@ -331,55 +755,286 @@ mod tests {
         if";
         let mut scanner = Scanner::new(source);
         let token = scanner.scan_next().unwrap();
-        assert_eq!(
-            token,
-            Token {
-                typ: TokenType::If,
-                text: "if".to_string(),
-                line: 2
-            }
-        )
+        assert_eq!(token, expected(TokenType::If, "if", 19, 2, 9))
     }
 
     #[test]
     fn underscore_works() {
         let source = "let __underscored_variable = 5";
         let mut scanner = Scanner::new(source);
-        let scanned = scanner.scan().unwrap();
+        let scanned = scanner.scan();
         assert_eq!(
             scanned,
             &[
-                Token {
-                    typ: TokenType::Let,
-                    text: "let".to_string(),
-                    line: 1,
-                },
-                Token {
-                    typ: TokenType::Identifier,
-                    text: "__underscored_variable".to_string(),
-                    line: 1,
-                },
-                Token {
-                    typ: TokenType::Equal,
-                    text: '='.to_string(),
-                    line: 1,
-                },
-                Token {
-                    typ: TokenType::Number,
-                    text: "5".to_string(),
-                    line: 1,
-                },
-                Token {
-                    typ: TokenType::Eof,
-                    text: "".to_string(),
-                    line: 1,
-                },
-                Token {
-                    typ: TokenType::Eof,
-                    text: "".to_string(),
-                    line: 1,
+                expected(TokenType::Let, "let", 0, 1, 1),
+                expected(TokenType::Identifier, "__underscored_variable", 4, 1, 5),
+                expected(TokenType::Equal, "=", 27, 1, 28),
+                expected(TokenType::Number, "5", 29, 1, 30),
+                expected(TokenType::Eof, "", 30, 1, 31),
+                expected(TokenType::Eof, "", 30, 1, 31),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_work() {
+        let source = "x == 1;\n  y => \"é\" != z";
+        let mut scanner = Scanner::new(source);
+        let spans: Vec<_> = scanner.scan().iter().map(Token::span).collect();
+        let positions: Vec<_> = spans
+            .iter()
+            .map(|span| (&source[span.start..span.end], span.line, span.column))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                ("x", 1, 1),
+                ("==", 1, 3),
+                ("1", 1, 6),
+                (";", 1, 7),
+                ("y", 2, 3),
+                ("=>", 2, 5),
+                ("\"é\"", 2, 8),
+                // Columns count chars, not bytes
+                ("!=", 2, 12),
+                ("z", 2, 15),
+                ("", 2, 16),
+                ("", 2, 16),
+            ]
+        );
+
+        let mut scanner = Scanner::new("let x = 1;\nlet y = #;");
+        scanner.scan();
+        assert_eq!(
+            scanner.errors(),
+            &[LexError {
+                kind: LexErrorKind::InvalidChar('#'),
+                span: Span {
+                    start: 19,
+                    end: 20,
+                    line: 2,
+                    column: 9,
                 },
+            }]
+        );
+    }
+
+    #[test]
+    fn recovers_from_errors() {
+        let source = "let a = 1 # 2;\nlet s = \"abc;\nlet b = @;";
+        let mut scanner = Scanner::new(source);
+        let types: Vec<_> = scanner.scan().iter().map(Token::get_type).collect();
+        use TokenType::*;
+        assert_eq!(
+            types,
+            vec![
+                Let, Identifier, Equal, Number, Number, Semicolon, Let, Identifier, Equal, Let,
+                Identifier, Equal, Semicolon, Eof, Eof
             ]
         );
+        let errors: Vec<_> = scanner
+            .errors()
+            .iter()
+            .map(|err| {
+                (
+                    err.kind,
+                    &source[err.span.start..err.span.end],
+                    err.span.line,
+                )
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (LexErrorKind::InvalidChar('#'), "#", 1),
+                (LexErrorKind::UnterminatedString, "\"abc;", 2),
+                (LexErrorKind::InvalidChar('@'), "@", 3),
+            ]
+        );
+        // The second error starts on a line after the last token
+        let mut scanner = Scanner::new("f(''', '\n');");
+        scanner.scan();
+        assert_eq!(scanner.errors().len(), 2);
+    }
+
+    #[test]
+    fn unicode_identifiers_work() {
+        let texts = |source| -> Vec<String> {
+            let mut scanner = Scanner::new(source);
+            scanner.scan();
+            assert_eq!(scanner.errors(), &[]);
+            scanner.tokens[..scanner.tokens.len() - 2]
+                .iter()
+                .map(|token| token.text.clone())
+                .collect()
+        };
+        assert_eq!(texts("größe_2 数"), vec!["größe_2", "数"]);
+        // Composed and decomposed accents, fullwidth letters and ligatures are normalized
+        assert_eq!(texts("caf\u{e9} cafe\u{301}"), vec!["café", "café"]);
+        assert_eq!(texts("ｘ ﬁle"), vec!["x", "file"]);
+        // The cyrillic 'а' only looks like 'a'
+        assert_ne!(texts("\u{430}"), vec!["a"]);
+
+        assert!(is_identifier("größe_2"));
+        // It would scan as 'x'
+        assert!(!is_identifier("ｘ"));
+        assert!(!is_identifier("2x"));
+
+        // Digits of other scripts are not numbers
+        let mut scanner = Scanner::new("let x = ٣;");
+        scanner.scan();
+        assert_eq!(scanner.errors()[0].kind, LexErrorKind::InvalidChar('٣'));
+    }
+
+    #[test]
+    fn quotes_and_escapes_work() {
+        let source = r#"'it said "hi"' "don't" "a\tb\\\"" 'it\'s\n' "\q""#;
+        let mut scanner = Scanner::new(source);
+        let texts: Vec<_> = scanner
+            .scan()
+            .iter()
+            .map(|token| token.text.clone())
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["it said \"hi\"", "don't", "a\tb\\\"", "it's\n", "q", "", ""]
+        );
+        // The unknown escape is reported and the string still scanned
+        let errors = scanner.errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, LexErrorKind::InvalidEscape('q'));
+        assert_eq!(&source[errors[0].span.start..errors[0].span.end], "\\q");
+    }
+
+    #[test]
+    fn unicode_escapes_work() {
+        let source = r#""\u{41}\x42\u{1F600}" "\u{D800}" "\x80" "\u41" "\u{1f" "\xG1""#;
+        let mut scanner = Scanner::new(source);
+        let texts: Vec<_> = scanner
+            .scan()
+            .iter()
+            .map(|token| token.text.clone())
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["AB😀", "u{D800}", "x80", "u41", "u{1f", "xG1", "", ""]
+        );
+        let errors: Vec<_> = scanner
+            .errors()
+            .iter()
+            .map(|error| (error.kind, &source[error.span.start..error.span.end]))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (LexErrorKind::InvalidCodepoint(0xD800), "\\u{D800}"),
+                (LexErrorKind::InvalidHexEscape, "\\x80"),
+                (LexErrorKind::InvalidUnicodeEscape, "\\u"),
+                (LexErrorKind::InvalidUnicodeEscape, "\\u{1f"),
+                (LexErrorKind::InvalidHexEscape, "\\x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_line_strings_work() {
+        let source = r#"let s = """
+    select *
+      from t\twhere "x"
+
+    """;
+'''a
+  b''' x"#;
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan().clone();
+        assert_eq!(tokens[3].text, "select *\n  from t\twhere \"x\"\n");
+        assert_eq!((tokens[3].span.start, tokens[3].span.end), (8, 57));
+        // Text right after the opening quotes is kept as it is
+        assert_eq!(tokens[5].text, "a\nb");
+        assert_eq!(tokens[6], expected(TokenType::Identifier, "x", 71, 7, 8));
+        assert!(scanner.errors().is_empty());
+    }
+
+    #[test]
+    fn skips_shebang() {
+        let mut scanner = Scanner::new("#!/usr/bin/env flux\nlet x = 1;");
+        assert_eq!(scanner.scan()[0], expected(TokenType::Let, "let", 20, 2, 1));
+        assert!(scanner.errors().is_empty());
+        // Only on the first line
+        let mut scanner = Scanner::new("let x = 1;\n#!/usr/bin/env flux");
+        scanner.scan();
+        assert_eq!(scanner.errors()[0].kind, LexErrorKind::InvalidChar('#'));
+    }
+
+    #[test]
+    fn char_literals_work() {
+        let source = r"c'a' c'\'' c c'' c'ab' c";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan().clone();
+        let chars: Vec<_> = tokens[..2]
+            .iter()
+            .map(|token| {
+                (
+                    token.typ,
+                    token.text.as_str(),
+                    token.span.start,
+                    token.span.end,
+                )
+            })
+            .collect();
+        assert_eq!(
+            chars,
+            vec![(TokenType::Char, "a", 0, 4), (TokenType::Char, "'", 5, 10)]
+        );
+        assert_eq!(tokens[1].span.column, 6);
+        assert_eq!(tokens[2].get_type(), TokenType::Identifier);
+        assert_eq!(tokens[5].get_type(), TokenType::Identifier);
+        let errors: Vec<_> = scanner
+            .errors()
+            .iter()
+            .map(|error| (error.kind, &source[error.span.start..error.span.end]))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (LexErrorKind::InvalidCharLiteral, "c''"),
+                (LexErrorKind::InvalidCharLiteral, "c'ab'")
+            ]
+        );
+    }
+
+    #[test]
+    fn trivia_keeps_the_source() {
+        let source = "// header\r\nlet ｘ = 'a\\n' # 1; // one\n\n  \"\"\"\n  b\n  \"\"\" \"abc";
+        let mut scanner = Scanner::with_trivia(source);
+        scanner.scan();
+        let tokens = scanner.extract_lossless();
+        let mut written = String::new();
+        for token in &tokens {
+            for trivia in &token.leading {
+                written.push_str(trivia.text(source));
+            }
+            written.push_str(token.text(source));
+        }
+        assert_eq!(written, source);
+
+        let kinds = |token: &LosslessToken| -> Vec<TriviaKind> {
+            token.leading.iter().map(|trivia| trivia.kind).collect()
+        };
+        use TriviaKind::*;
+        assert_eq!(kinds(&tokens[0]), vec![Comment, Newline]);
+        // The invalid char is skipped, the unterminated string too
+        assert_eq!(kinds(&tokens[4]), vec![Whitespace, Skipped, Whitespace]);
+        assert_eq!(tokens[4].token.text, "1");
+        let last = tokens.last().unwrap();
+        assert_eq!(last.token.typ, TokenType::Eof);
+        assert_eq!(kinds(last), vec![Whitespace, Skipped]);
+        assert_eq!(
+            tokens
+                .iter()
+                .filter(|t| t.token.typ == TokenType::Eof)
+                .count(),
+            1
+        );
     }
 }
@@ -1,9 +1,11 @@
-use std::fmt::{Display, Formatter};
+use super::Span;
+use core::fmt::{Display, Formatter};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct LexError {
     pub kind: LexErrorKind,
-    pub line: usize,
+    // The char the error is about, empty at the end of the source
+    pub span: Span,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -14,11 +16,29 @@ pub enum LexErrorKind {
     TooShort,
     // Invalid character
     InvalidChar(char),
+    // A string without its closing quote
+    UnterminatedString,
+    // An escape sequence in a string that stands for nothing, like \q
+    InvalidEscape(char),
+    // \x without two hex digits or above \x7F
+    InvalidHexEscape,
+    // \u without one to six hex digits in braces
+    InvalidUnicodeEscape,
+    // \u{..} of a surrogate or past 10FFFF
+    InvalidCodepoint(u32),
+    // A number without digits or with misplaced underscores, like 0x or 1__0
+    InvalidNumber,
+    // A char literal holding no or several chars, like c'' or c'ab'
+    InvalidCharLiteral,
     Eof,
 }
 
 impl Display for LexError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[line {}] Lex Error: {:?}", self.line, self.kind)
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] Lex Error: {:?}",
+            self.span.line, self.span.column, self.kind
+        )
     }
 }
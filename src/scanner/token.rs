@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use alloc::string::String;
+use hashbrown::HashMap;
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum TokenType {
     /* Primitives */
     String,
+    Char,
     Number,
     True,
     False,
@@ -41,6 +43,8 @@ pub enum TokenType {
     GreaterEqual,
     LessEqual,
     RightArrow, // '=>'
+    ThinArrow,  // '->'
+    DotDot,     // '..'
 
     /* Keywords */
     Let,
@@ -50,15 +54,21 @@ pub enum TokenType {
     For,
     Return,
     Break,
+    Continue,
+    Yield,
     Fn,
     Then,
     Do,
     End,
     Print,
     Import,
+    Enum,
     As,
     In,
     Var,
+    And,
+    Or,
+    Not,
 
     Eof,
     Invalid,
@@ -68,32 +78,51 @@ pub enum TokenType {
 pub struct Token {
     pub(super) typ: TokenType,
     pub(super) text: String,
-    pub(super) line: usize,
+    pub(super) span: Span,
+}
+
+// Where a token is in the source. Offsets are in bytes and the end is exclusive,
+// line and column start from 1 and the column counts chars
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
 }
 
 lazy_static! {
-    pub static ref KEYWORDS: HashMap<&'static str, TokenType> = hashmap! {
-        "nil" => TokenType::Nil,
-        "true" => TokenType::True,
-        "false" => TokenType::False,
-
-        "let" => TokenType::Let,
-        "if" => TokenType::If,
-        "else" => TokenType::Else,
-        "while" => TokenType::While,
-        "for" => TokenType::For,
-        "return" => TokenType::Return,
-        "break" => TokenType::Break,
-        "fn" => TokenType::Fn,
-        "then" => TokenType::Then,
-        "do" => TokenType::Do,
-        "end" => TokenType::End,
-        "import" => TokenType::Import,
-        "as" => TokenType::As,
-        "in" => TokenType::In,
-        "var" => TokenType::Var,
-        // "print" => TokenType::Print,
-    };
+    pub static ref KEYWORDS: HashMap<&'static str, TokenType> = [
+        ("nil", TokenType::Nil),
+        ("true", TokenType::True),
+        ("false", TokenType::False),
+
+        ("let", TokenType::Let),
+        ("if", TokenType::If),
+        ("else", TokenType::Else),
+        ("while", TokenType::While),
+        ("for", TokenType::For),
+        ("return", TokenType::Return),
+        ("break", TokenType::Break),
+        ("continue", TokenType::Continue),
+        ("yield", TokenType::Yield),
+        ("fn", TokenType::Fn),
+        ("then", TokenType::Then),
+        ("do", TokenType::Do),
+        ("end", TokenType::End),
+        ("import", TokenType::Import),
+        ("enum", TokenType::Enum),
+        ("as", TokenType::As),
+        ("in", TokenType::In),
+        ("var", TokenType::Var),
+        ("and", TokenType::And),
+        ("or", TokenType::Or),
+        ("not", TokenType::Not),
+        // ("print", TokenType::Print),
+    ]
+    .iter()
+    .copied()
+    .collect();
 }
 
 impl Token {
@@ -114,7 +143,12 @@ impl Token {
     }
 
     pub fn get_line(&self) -> usize {
-        self.line
+        self.span.line
+    }
+
+    // Covers the quotes of strings, which the text leaves out
+    pub fn span(&self) -> Span {
+        self.span
     }
 }
 
@@ -123,7 +157,7 @@ impl Default for Token {
         Token {
             typ: TokenType::Invalid,
             text: String::new(),
-            line: 0,
+            span: Span::default(),
         }
     }
 }
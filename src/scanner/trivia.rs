@@ -0,0 +1,42 @@
+use super::{Span, Token};
+use alloc::vec::Vec;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriviaKind {
+    // Spaces, tabs and carriage returns
+    Whitespace,
+    Newline,
+    // A '//' comment up to its line break
+    Comment,
+    // Source no token could be made of, like an invalid char
+    Skipped,
+}
+
+// Source between tokens that doesn't change the program
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub span: Span,
+}
+
+impl Trivia {
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.span.start..self.span.end]
+    }
+}
+
+// A token with the trivia before it. The last Eof token holds the trivia ending the
+// source, so writing out every token with its trivia gives back the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct LosslessToken {
+    pub leading: Vec<Trivia>,
+    pub token: Token,
+}
+
+impl LosslessToken {
+    // The text of the token as written, before escapes and normalization
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        let span = self.token.span();
+        &source[span.start..span.end]
+    }
+}
@@ -0,0 +1,252 @@
+use crate::parser::{pretty, Expr, Literal, Parser, Statement};
+use crate::vm::{lib, Function, Value, Vm, PREDEFINED_CONSTANTS};
+use alloc::rc::Rc;
+use alloc::{borrow::ToOwned, string::String, string::ToString, vec::Vec};
+use hashbrown::HashSet;
+
+// Saves the globals of a REPL session as a script that recreates them. Functions
+// can't be turned back into source, so the statements that defined them are kept
+// and saved after the other values, which are written as literals
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    // The function each global was set to and the statement that set it, in the
+    // order the globals were first defined
+    functions: Vec<(String, Value, String)>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called after the vm evaluated 'input', a REPL input or a loaded script. Only
+    // the top level statements setting a global to a function are kept, the rest of
+    // the input isn't run again by the saved script
+    pub fn record(&mut self, vm: &Vm, input: &str) {
+        let ast = match Parser::new(input).parse_syntax() {
+            Ok(ast) => ast,
+            _ => return,
+        };
+        for stmt in &ast.expr().stmts {
+            let (name, value) = match stmt {
+                Statement::Var { name, value, .. } => (name, value),
+                Statement::Set {
+                    variable: Expr::Identifier { name, .. },
+                    value,
+                } => (name, value),
+                _ => continue,
+            };
+            let function = match vm.global(name) {
+                Some(function @ Value::Function(Function::User(_))) => function.clone(),
+                _ => continue,
+            };
+            let source = pretty::print(&[Statement::Var {
+                name: name.clone(),
+                ty: None,
+                value: value.clone(),
+                line: 0,
+                column: 0,
+            }]);
+            match self
+                .functions
+                .iter_mut()
+                .find(|(recorded, _, _)| recorded == name)
+            {
+                Some(recorded) => *recorded = (name.clone(), function, source),
+                None => self.functions.push((name.clone(), function, source)),
+            }
+        }
+    }
+
+    pub fn save(&self, vm: &Vm) -> String {
+        let mut out = String::new();
+        let saved: HashSet<&str> = self
+            .functions
+            .iter()
+            .filter(|(name, function, _)| vm.global(name) == Some(function))
+            .map(|(name, _, _)| name.as_str())
+            .collect();
+        let mut globals: Vec<(&str, &Value)> = vm
+            .globals()
+            .filter_map(|(name, value)| match name {
+                Value::Str(name) if !saved.contains(name.as_str()) && !is_predefined(name) => {
+                    Some((name.as_str(), value))
+                }
+                _ => None,
+            })
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+        // The functions may refer to any of the other globals
+        for (name, value) in globals {
+            match literal(value, &mut Vec::new()) {
+                Some(value) => out.push_str(&pretty::print(&[Statement::Var {
+                    name: name.to_owned(),
+                    ty: None,
+                    value,
+                    line: 0,
+                    column: 0,
+                }])),
+                None => out.push_str(&format!("// {} could not be saved\n", name)),
+            }
+        }
+        for (name, _, source) in &self.functions {
+            if saved.contains(name.as_str()) {
+                out.push_str(source);
+            }
+        }
+        out
+    }
+}
+
+fn is_predefined(name: &str) -> bool {
+    PREDEFINED_CONSTANTS
+        .iter()
+        .any(|(predefined, _)| *predefined == name)
+        || lib::PREDEFINED_GLOBALS.contains(&name)
+}
+
+// The expression evaluating to a copy of the value. Functions and tables holding
+// themselves have none. 'tables' holds the tables being converted
+fn literal(value: &Value, tables: &mut Vec<*const ()>) -> Option<Expr> {
+    let expr = match value {
+        Value::Nil => Expr::Literal(Literal::Nil),
+        Value::Unit => Expr::Literal(Literal::Unit),
+        Value::Bool(b) => Expr::Literal(Literal::Bool(*b)),
+        Value::Int(i) => Expr::Literal(Literal::Int(*i)),
+        Value::Number(n) if n.is_finite() => Expr::Literal(Literal::Float(*n)),
+        Value::Str(s) => Expr::Literal(Literal::Str(s.to_string())),
+        Value::Embedded(s) => Expr::Literal(Literal::Str(s.to_string())),
+        Value::Tuple(values) => Expr::Tuple(
+            values
+                .iter()
+                .map(|value| literal(value, tables))
+                .collect::<Option<_>>()?,
+        ),
+        Value::Table(table) => {
+            let ptr = Rc::as_ptr(table) as *const ();
+            if tables.contains(&ptr) {
+                return None;
+            }
+            tables.push(ptr);
+            let table = table.borrow();
+            let mut keys = Vec::new();
+            let mut values = Vec::new();
+            // The hash part is sorted so saving gives the same script every time
+            let mut hash: Vec<_> = table.hash_part().collect();
+            hash.sort_by_key(|(key, _)| key.to_string());
+            let array = table.array_part().iter().map(|(key, value)| (key, value));
+            for (key, value) in array.chain(hash) {
+                keys.push(literal(key, tables)?);
+                values.push(literal(value, tables)?);
+            }
+            tables.pop();
+            // The array part alone can be written without keys
+            let keys = if table.hash_len() == 0 {
+                None
+            } else {
+                Some(keys)
+            };
+            Expr::TableInit { keys, values }
+        }
+        _ => return None,
+    };
+    Some(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+
+    fn eval(vm: &mut Vm, source: &str) {
+        let ast = Parser::new(source)
+            .with_globals(vm.global_names())
+            .parse_all()
+            .unwrap();
+        let compiled = Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap();
+        vm.run(&compiled).unwrap();
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let inputs = [
+            "var square = fn(x) x * x end;",
+            "var point = {\"x\" = 1, \"y\" = 2.5}; var list = {1, 2, 3};",
+            "var half = 1.0 / 2; var whole = number(\"4\"); var name = \"flux\";",
+            "var loop = {}; loop.self = loop;",
+        ];
+        let mut vm = Vm::new();
+        let mut session = Session::new();
+        for input in inputs.iter() {
+            eval(&mut vm, input);
+            session.record(&vm, input);
+        }
+        let saved = session.save(&vm);
+        assert!(
+            saved.ends_with("var square = fn(x)\n    x * x\nend;\n"),
+            "{}",
+            saved
+        );
+        assert!(saved.contains("// loop could not be saved"));
+
+        let mut restored = Vm::new();
+        eval(&mut restored, &saved);
+        for name in &["half", "name", "whole"] {
+            assert_eq!(restored.global(name), vm.global(name), "{}", name);
+        }
+        assert_eq!(restored.global("whole"), Some(&Value::Number(4.0)));
+        let field = |name: &str, key: Value| match restored.global(name) {
+            Some(Value::Table(table)) => table.borrow().get(&key).clone(),
+            _ => Value::Nil,
+        };
+        assert_eq!(field("point", Value::new_str("y")), Value::Number(2.5));
+        assert_eq!(field("list", Value::Int(2)), Value::Int(3));
+        // Restoring and saving again gives the same script
+        let mut session = Session::new();
+        session.record(&restored, &saved);
+        let resaved = session.save(&restored);
+        let mut again = Vm::new();
+        eval(&mut again, &resaved);
+        let mut session = Session::new();
+        session.record(&again, &resaved);
+        assert_eq!(session.save(&again), resaved);
+
+        let square = restored.global("square").unwrap().clone();
+        assert_eq!(
+            restored.call_function(square, &[Value::Int(3)]),
+            Ok(Value::Int(9))
+        );
+    }
+
+    #[test]
+    fn functions_are_saved_after_the_globals_they_use() {
+        let inputs = [
+            "var n = 3;",
+            "var count = 0; var f = fn() n end; count = count + 1;",
+            "f = fn() n * 2 end; count = count + 1;",
+        ];
+        let mut vm = Vm::new();
+        let mut session = Session::new();
+        for input in inputs.iter() {
+            eval(&mut vm, input);
+            session.record(&vm, input);
+        }
+        let saved = session.save(&vm);
+        assert_eq!(
+            saved,
+            "var count = 2;\nvar n = 3;\nvar f = fn()\n    n * 2\nend;\n"
+        );
+
+        let mut restored = Vm::new();
+        eval(&mut restored, &saved);
+        assert_eq!(restored.global("count"), Some(&Value::Int(2)));
+        let f = restored.global("f").unwrap().clone();
+        assert_eq!(restored.call_function(f, &[]), Ok(Value::Int(6)));
+    }
+}
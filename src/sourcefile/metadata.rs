@@ -1,20 +1,50 @@
+use crate::parser::DEFAULT_MAX_DEPTH;
+use alloc::string::String;
+#[cfg(feature = "std")]
 use std::path::PathBuf;
 
+// Without std there are no files, so no paths either
 #[derive(Clone, Debug, PartialEq)]
 pub struct MetaData {
+    #[cfg(feature = "std")]
     pub dir: PathBuf,
+    // Path of the source, recorded in the debug info of its functions
+    #[cfg(feature = "std")]
+    pub file: Option<PathBuf>,
+    // How deep the compiler lets the tree nest, the modules it imports get the same
+    pub max_depth: usize,
 }
 
 impl MetaData {
+    #[cfg(feature = "std")]
     pub fn current_dir(&self) -> &str {
         self.dir.to_str().unwrap()
     }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn file_name(&self) -> Option<String> {
+        #[cfg(feature = "std")]
+        return self
+            .file
+            .as_ref()
+            .map(|file| file.to_string_lossy().into_owned());
+        #[cfg(not(feature = "std"))]
+        return None;
+    }
 }
 
 impl Default for MetaData {
     fn default() -> Self {
         MetaData {
+            #[cfg(feature = "std")]
             dir: PathBuf::default(),
+            #[cfg(feature = "std")]
+            file: None,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 }
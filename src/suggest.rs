@@ -0,0 +1,60 @@
+// "Did you mean" suggestions for misspelled names
+use crate::scanner::is_keyword;
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+
+// The candidate closest to 'name' by edit distance, if close enough to be a typo:
+// a third of the name may differ, so names under three chars get no suggestion. Ties
+// go to the first name in alphabetical order so the suggestion doesn't depend on hash order.
+// Natives named by a keyword, like the one of for loops, can't be written and aren't suggested
+pub fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<String> {
+    let max_distance = name.chars().count() / 3;
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name && !is_keyword(candidate))
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate.to_owned())
+}
+
+// Levenshtein distance over chars
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + (ca != *cb) as usize;
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_close_names() {
+        let names = ["println", "print", "count", "counter"];
+        assert_eq!(
+            did_you_mean("pritnln", names.iter().copied()),
+            Some("println".to_owned())
+        );
+        assert_eq!(
+            did_you_mean("cont", names.iter().copied()),
+            Some("count".to_owned())
+        );
+        assert_eq!(did_you_mean("x", names.iter().copied()), None);
+        assert_eq!(
+            did_you_mean("countr", names.iter().copied()),
+            Some("count".to_owned())
+        );
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::{FluxError, FluxResult};
+use crate::util::load_file;
+use crate::vm::{FrameView, Vm};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// Test files end with this, 'flux_rs test' looks for them in directories
+pub const TEST_FILE_SUFFIX: &str = "_test.flux";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub failure: Option<Failure>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub error: FluxError,
+    // Frames of the failed call, the innermost first
+    pub traceback: Vec<FrameView>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Error: {}", self.error)?;
+        for frame in &self.traceback {
+            write!(f, "  at {}", frame.name)?;
+            match (&frame.file, frame.line) {
+                (Some(file), Some(line)) => writeln!(f, " ({}:{})", file, line)?,
+                (None, Some(line)) => writeln!(f, " (line {})", line)?,
+                _ => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// The test files under 'path' in a stable order, or 'path' itself if it is a file
+pub fn discover(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_owned()];
+    }
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.to_string_lossy().ends_with(TEST_FILE_SUFFIX) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+// Runs every test the script registers, each in a fresh vm that ran the script first.
+// Fails if the script itself does not compile or run
+pub fn run_test_file(path: &str) -> FluxResult<Vec<TestResult>> {
+    let compiled = load_file(path)?;
+    let setup = || -> FluxResult<Vm> {
+        let mut vm = Vm::new();
+        vm.set_args(&[path.to_owned()]);
+        vm.run(&compiled)?;
+        Ok(vm)
+    };
+    let names: Vec<String> = setup()?
+        .tests()
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+    let mut results = Vec::new();
+    for (i, name) in names.into_iter().enumerate() {
+        let mut vm = setup()?;
+        let function = vm.tests()[i].1.clone();
+        let failure = match vm.call_function(function, &[]) {
+            Ok(_) => None,
+            Err(error) => Some(Failure {
+                traceback: vm.call_stack(),
+                error: error.into(),
+            }),
+        };
+        results.push(TestResult { name, failure });
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RuntimeError;
+    use crate::vm::Value;
+
+    #[test]
+    fn runs_registered_tests() {
+        let dir = std::env::temp_dir().join("flux_test_runner");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("math_test.flux");
+        std::fs::write(
+            &path,
+            "var count = 0;
+test.register(\"passes\", fn()
+    count = count + 1;
+    assert(count == 1);
+end);
+test.register(\"fails\", fn()
+    count = count + 1;
+    assert(count == 2);
+end);
+",
+        )
+        .unwrap();
+        std::fs::write(dir.join("helper.flux"), "").unwrap();
+
+        assert_eq!(discover(&dir), vec![path.clone()]);
+        let results = run_test_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Each test starts from a fresh count
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "passes");
+        assert!(results[0].passed());
+        let failure = results[1].failure.as_ref().unwrap();
+        assert_eq!(
+            failure.error.clone().without_location(),
+            RuntimeError::AssertionFailed(Value::Bool(false)).into()
+        );
+        assert_eq!(failure.traceback.len(), 1);
+        assert_eq!(failure.traceback[0].line, Some(8));
+        assert!(failure.to_string().contains("math_test.flux:8"));
+    }
+}
@@ -0,0 +1,236 @@
+use crate::compiler::{Chunk, CompiledSource, Compiler, Instruction};
+use crate::error::FluxResult;
+use crate::parser::Parser;
+use crate::sourcefile::{MetaData, SourceFile};
+use crate::vm::Value;
+use core::fmt::Write;
+
+// Helpers for tests that lock in the compiler output, public with the testing feature
+
+// Compiles the source and renders its code for snapshot assertions. Unlike
+// disassemble, lines and source text are left out so only codegen changes show
+// up, and the constants instructions refer to are written next to them
+pub fn compile_snapshot(source: &str) -> FluxResult<String> {
+    let ast = Parser::new(source).parse_all()?;
+    let compiled = Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })?;
+    Ok(snapshot(&compiled))
+}
+
+pub fn snapshot(compiled: &CompiledSource) -> String {
+    let mut out = String::new();
+    for (i, proto) in compiled.constant_table.prototypes.iter().enumerate() {
+        writeln!(out, "fn #{} (args: {})", i, proto.args_len).unwrap();
+        snapshot_code(&mut out, &proto.instructions, compiled);
+    }
+    snapshot_chunk(&mut out, "main", &compiled.chunk, compiled);
+    out
+}
+
+fn snapshot_chunk(out: &mut String, name: &str, chunk: &Chunk, compiled: &CompiledSource) {
+    writeln!(out, "{}", name).unwrap();
+    snapshot_code(out, chunk.instructions(), compiled);
+    // Sorted, the imports are kept in a map
+    let mut imports: Vec<_> = chunk.imported().collect();
+    imports.sort_by_key(|(name, _)| *name);
+    for (name, import) in imports {
+        snapshot_chunk(out, name, import, compiled);
+    }
+}
+
+fn snapshot_code(out: &mut String, instructions: &[Instruction], compiled: &CompiledSource) {
+    for (i, instr) in instructions.iter().enumerate() {
+        let constant =
+            constant_index(instr).and_then(|index| compiled.constant_table.constants.get(index));
+        match constant {
+            Some(constant) => writeln!(out, "  {:04} {:?} ; {}", i, instr, render(constant)),
+            None => writeln!(out, "  {:04} {:?}", i, instr),
+        }
+        .unwrap();
+    }
+}
+
+fn constant_index(instr: &Instruction) -> Option<usize> {
+    let index = match *instr {
+        Instruction::Constant { index }
+        | Instruction::SetGlobal { index }
+        | Instruction::GetGlobal { index }
+        | Instruction::GetMethodImm { index, .. }
+        | Instruction::CallGlobal { index, .. }
+        | Instruction::CallFieldImm { index, .. }
+        | Instruction::GetFieldImm { index }
+        | Instruction::SetFieldImm { index }
+        | Instruction::TableTemplate { index }
+        | Instruction::Cast { index } => index,
+        Instruction::Import { name_index } => name_index,
+        _ => return None,
+    };
+    Some(index as usize)
+}
+
+// Strings are quoted to tell them from the other values
+fn render(value: &Value) -> String {
+    match value {
+        Value::Str(string) => format!("{:?}", string.as_str()),
+        Value::Embedded(string) => format!("{:?}", string),
+        // On one line, with the keys of the hash part sorted
+        Value::Table(table) => {
+            let table = table.borrow();
+            let mut hash: Vec<String> = table
+                .hash_part()
+                .map(|(key, value)| format!("{} = {}", render(key), render(value)))
+                .collect();
+            hash.sort();
+            let pairs: Vec<String> = table
+                .array_part()
+                .iter()
+                .map(|(_, value)| render(value))
+                .chain(hash)
+                .collect();
+            format!("{{{}}}", pairs.join(", "))
+        }
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expected snapshot starts on the line after the opening quote
+    fn assert_snapshot(source: &str, expected: &str) {
+        assert_eq!(compile_snapshot(source).unwrap(), &expected[1..]);
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_snapshot(
+            "let x = 1;\nif x < 2 then x = 2; else x = 3; end\nwhile x > 0 then x = x - 1; end\nreturn x;",
+            r#"
+fn #0 (args: 0)
+  0000 Integer(1)
+  0001 BinLocalInt { op: Lt, index: 0, frame: 1, value: 2 }
+  0002 Integer(2)
+  0003 LtInt
+  0004 JumpIf { when_true: false, offset: 7 }
+  0005 Integer(2)
+  0006 SetLocal { index: 0, frame: 1 }
+  0007 Unit
+  0008 ExitBlock { pop: 0, return_value: true }
+  0009 Pop
+  0010 Jump { offset: 6 }
+  0011 Integer(3)
+  0012 SetLocal { index: 0, frame: 1 }
+  0013 Unit
+  0014 ExitBlock { pop: 0, return_value: true }
+  0015 Pop
+  0016 BinLocalInt { op: Gt, index: 0, frame: 1, value: 0 }
+  0017 Integer(0)
+  0018 Bin(Gt)
+  0019 JumpIf { when_true: false, offset: 7 }
+  0020 BinLocalInt { op: Sub, index: 0, frame: 1, value: 1 }
+  0021 Integer(1)
+  0022 SubInt
+  0023 SetLocal { index: 0, frame: 1 }
+  0024 ExitBlock { pop: 0, return_value: false }
+  0025 Jump { offset: -9 }
+  0026 GetLocal { index: 0, frame: 1 }
+  0027 Return { return_value: true }
+  0028 Unit
+  0029 Return { return_value: true }
+  0030 Pop
+main
+  0000 FuncDef { proto_index: 0, has_env: false }
+  0001 Call { args_len: 0 }
+  0002 Return { return_value: true }
+"#,
+        );
+    }
+
+    #[test]
+    fn closures() {
+        assert_snapshot(
+            "let counter = fn() let n = 0; return fn() n = n + 1; return n; end; end; var c = counter(); c();",
+            r#"
+fn #0 (args: 0)
+  0000 GetLocal { index: 0, frame: 1 }
+  0001 GetFieldImm { index: 0 } ; "n"
+  0002 Integer(1)
+  0003 AddInt
+  0004 Constant { index: 0 } ; "n"
+  0005 GetLocal { index: 0, frame: 1 }
+  0006 SetField
+  0007 GetLocal { index: 0, frame: 1 }
+  0008 GetFieldImm { index: 0 } ; "n"
+  0009 Return { return_value: true }
+  0010 Unit
+  0011 Return { return_value: true }
+  0012 Pop
+fn #1 (args: 0)
+  0000 Integer(0)
+  0001 Constant { index: 0 } ; "n"
+  0002 GetLocal { index: 0, frame: 1 }
+  0003 InitTable { len: 1, has_keys: true }
+  0004 FuncDef { proto_index: 0, has_env: true }
+  0005 Return { return_value: true }
+  0006 Unit
+  0007 Return { return_value: true }
+  0008 Pop
+fn #2 (args: 0)
+  0000 FuncDef { proto_index: 1, has_env: false }
+  0001 GetLocal { index: 0, frame: 1 }
+  0002 Call { args_len: 0 }
+  0003 SetGlobal { index: 1 } ; "c"
+  0004 CallGlobal { index: 1, args_len: 0 } ; "c"
+  0005 Call { args_len: 0 }
+  0006 Pop
+  0007 Unit
+  0008 Return { return_value: true }
+  0009 Pop
+main
+  0000 FuncDef { proto_index: 2, has_env: false }
+  0001 Call { args_len: 0 }
+  0002 Return { return_value: true }
+"#,
+        );
+    }
+
+    #[test]
+    fn tables() {
+        assert_snapshot(
+            "let t = {1, 2, 3};\nlet u = {\"a\" = 1, \"b\" = t};\nt[1] = u.a;\nu.b = 2;\nu:size();",
+            r#"
+fn #0 (args: 0)
+  0000 TableTemplate { index: 0 } ; {1, 2, 3}
+  0001 Constant { index: 1 } ; "a"
+  0002 Integer(1)
+  0003 Constant { index: 2 } ; "b"
+  0004 GetLocal { index: 0, frame: 1 }
+  0005 InitTable { len: 2, has_keys: true }
+  0006 GetLocal { index: 1, frame: 1 }
+  0007 GetFieldImm { index: 1 } ; "a"
+  0008 Integer(1)
+  0009 GetLocal { index: 0, frame: 1 }
+  0010 SetField
+  0011 Integer(2)
+  0012 Constant { index: 2 } ; "b"
+  0013 GetLocal { index: 1, frame: 1 }
+  0014 SetField
+  0015 GetLocal { index: 1, frame: 1 }
+  0016 GetMethodImm { index: 3, table_stack_index: 0 } ; "size"
+  0017 Call { args_len: 1 }
+  0018 Pop
+  0019 Unit
+  0020 Return { return_value: true }
+  0021 Pop
+  0022 Pop
+main
+  0000 FuncDef { proto_index: 0, has_env: false }
+  0001 Call { args_len: 0 }
+  0002 Return { return_value: true }
+"#,
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use crate::parser::{BinaryOp, BlockExpr, Expr, Literal, Signature, Statement, Type, UnaryOp};
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::fmt::{self, Display, Formatter};
+use hashbrown::HashMap;
+
+// Checks the annotations of the syntax tree (see Parser::parse_syntax) before it is
+// compiled. Only literals and annotated names have a type, everything else is 'any'
+// and accepted anywhere, so code without annotations is never reported
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TypeError {
+    // None when the tree doesn't record the line of the node
+    pub line: Option<usize>,
+    pub kind: TypeErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeErrorKind {
+    // 'what' is the variable, argument or result the value was given to
+    Mismatch {
+        what: String,
+        expected: Type,
+        found: Type,
+    },
+    // A function with annotations called with another number of arguments
+    WrongArity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+pub fn check(block: &BlockExpr) -> Vec<TypeError> {
+    let mut checker = Checker {
+        scopes: vec![HashMap::new()],
+        results: Vec::new(),
+        line: None,
+        errors: Vec::new(),
+    };
+    checker.block(block);
+    checker.errors
+}
+
+#[derive(Clone)]
+struct Binding {
+    ty: Type,
+    // Functions declared with annotations, to check their calls
+    signature: Option<Signature>,
+}
+
+struct Checker {
+    // Names declared in each enclosing block and function
+    scopes: Vec<HashMap<String, Binding>>,
+    // Name and declared result of each enclosing function
+    results: Vec<(String, Option<Type>)>,
+    // Line of the last visited node that records one
+    line: Option<usize>,
+    errors: Vec<TypeError>,
+}
+
+impl Checker {
+    fn expect(&mut self, what: impl FnOnce() -> String, expected: &Type, found: Type) {
+        if !expected.accepts(&found) {
+            self.errors.push(TypeError {
+                line: self.line,
+                kind: TypeErrorKind::Mismatch {
+                    what: what(),
+                    expected: expected.clone(),
+                    found,
+                },
+            })
+        }
+    }
+
+    fn scoped<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.scopes.push(HashMap::new());
+        let result = f(self);
+        self.scopes.pop();
+        result
+    }
+
+    fn declare(&mut self, name: &str, ty: Option<&Type>, signature: Option<&Signature>) {
+        let binding = Binding {
+            ty: ty.cloned().unwrap_or(Type::Any),
+            signature: signature.cloned(),
+        };
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_owned(), binding);
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn block(&mut self, block: &BlockExpr) -> Type {
+        self.scoped(|checker| {
+            block.stmts.iter().for_each(|stmt| checker.statement(stmt));
+            checker.expr(&block.expr)
+        })
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        if let Some(line) = stmt.line() {
+            self.line = Some(line);
+        }
+        match stmt {
+            Statement::Expr(expr) | Statement::Print(expr) => {
+                self.expr(expr);
+            }
+            Statement::Return(expr) => {
+                let found = self.expr(expr);
+                if let Some((name, Some(expected))) = self.results.last().cloned() {
+                    self.expect(|| result_of(&name), &expected, found)
+                }
+            }
+            Statement::Let {
+                name, ty, value, ..
+            }
+            | Statement::Var {
+                name, ty, value, ..
+            } => self.declaration(name, ty.as_ref(), value),
+            Statement::Set { variable, value } => {
+                let found = self.expr(value);
+                match variable {
+                    Expr::Identifier { name, .. } => {
+                        if let Some(expected) = self.lookup(name).map(|b| b.ty.clone()) {
+                            self.expect(|| format!("'{}'", name), &expected, found)
+                        }
+                    }
+                    variable => {
+                        self.expr(variable);
+                    }
+                }
+            }
+            Statement::Block(stmts) => {
+                self.scoped(|checker| stmts.iter().for_each(|stmt| checker.statement(stmt)))
+            }
+            Statement::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition);
+                self.expr(then_block);
+                if let Some(else_block) = else_block {
+                    self.expr(else_block);
+                }
+            }
+            Statement::While {
+                condition,
+                then_block,
+                step,
+            } => {
+                self.expr(condition);
+                self.statement(then_block);
+                if let Some(step) = step {
+                    self.statement(step)
+                }
+            }
+            Statement::For {
+                variable,
+                start,
+                end,
+                body,
+                ..
+            } => {
+                let ty = self.expr(start);
+                self.expr(end);
+                self.scoped(|checker| {
+                    checker.declare(variable, Some(&ty), None);
+                    checker.statement(body)
+                })
+            }
+            Statement::Import { name, .. } | Statement::Enum { name, .. } => {
+                self.declare(name, Some(&Type::Table), None)
+            }
+            Statement::Break { .. } | Statement::Continue { .. } => (),
+        }
+    }
+
+    fn declaration(&mut self, name: &str, ty: Option<&Type>, value: &Expr) {
+        match value {
+            // Declared before the body so the function can call itself
+            Expr::Function {
+                args,
+                body,
+                signature,
+                ..
+            } => {
+                self.declare(name, ty, signature.as_deref());
+                let found = self.function(name, args, body, signature.as_deref());
+                if let Some(expected) = ty {
+                    self.expect(|| format!("'{}'", name), expected, found)
+                }
+            }
+            value => {
+                let found = self.expr(value);
+                if let Some(expected) = ty {
+                    self.expect(|| format!("'{}'", name), expected, found)
+                }
+                self.declare(name, ty, None)
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Type {
+        if let Some(line) = expr.line() {
+            self.line = Some(line);
+        }
+        match expr {
+            Expr::Literal(literal) => literal_type(literal),
+            Expr::Identifier { name, .. } => self
+                .lookup(name)
+                .map_or(Type::Any, |binding| binding.ty.clone()),
+            Expr::Unary { op, expr } => {
+                let ty = self.expr(expr);
+                match op {
+                    UnaryOp::Bang => Type::Bool,
+                    UnaryOp::Minus if matches!(ty, Type::Int | Type::Number) => ty,
+                    UnaryOp::Minus => Type::Any,
+                }
+            }
+            Expr::Binary { left, op, right } => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                binary_type(*op, left, right)
+            }
+            Expr::Grouping(expr) => self.expr(expr),
+            Expr::Tuple(exprs) => Type::Tuple(exprs.iter().map(|expr| self.expr(expr)).collect()),
+            Expr::Access { table, field } => {
+                self.expr(table);
+                self.expr(field);
+                Type::Any
+            }
+            Expr::SelfAccess { table, args, .. } => {
+                self.expr(table);
+                args.iter().for_each(|arg| drop(self.expr(arg)));
+                Type::Any
+            }
+            Expr::TableInit { keys, values } => {
+                keys.iter()
+                    .flatten()
+                    .chain(values)
+                    .for_each(|expr| drop(self.expr(expr)));
+                Type::Table
+            }
+            Expr::Function {
+                args,
+                body,
+                signature,
+                ..
+            } => self.function("fn", args, body, signature.as_deref()),
+            Expr::Call { func, args } => self.call(func, args),
+            Expr::Block(block) => self.block(block),
+            Expr::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition);
+                let then_type = self.expr(then_block);
+                let else_type = self.expr(else_block);
+                if then_type == else_type {
+                    then_type
+                } else {
+                    Type::Any
+                }
+            }
+            Expr::Rec => Type::Function,
+            // Numbers are converted, other values stop the script when the type differs
+            Expr::Cast { expr, ty } => {
+                self.expr(expr);
+                ty.clone()
+            }
+            Expr::Yield(expr) => {
+                self.expr(expr);
+                Type::Any
+            }
+        }
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        args: &[String],
+        body: &BlockExpr,
+        signature: Option<&Signature>,
+    ) -> Type {
+        let result = signature.and_then(|signature| signature.ret.clone());
+        self.results.push((name.to_owned(), result.clone()));
+        let found = self.scoped(|checker| {
+            for (i, arg) in args.iter().enumerate() {
+                let ty = signature.and_then(|signature| signature.args.get(i)?.as_ref());
+                checker.declare(arg, ty, None);
+            }
+            checker.block(body)
+        });
+        self.results.pop();
+        // Falling off the end after returning early elsewhere isn't tracked
+        let falls_through = !is_unit(&body.expr) || !block_returns(body);
+        if let (Some(expected), true) = (result, falls_through) {
+            self.expect(|| result_of(name), &expected, found)
+        }
+        Type::Function
+    }
+
+    fn call(&mut self, func: &Expr, args: &[Expr]) -> Type {
+        self.expr(func);
+        let found: Vec<Type> = args.iter().map(|arg| self.expr(arg)).collect();
+        let (name, signature) = match func {
+            Expr::Identifier { name, .. } => match self.lookup(name) {
+                Some(Binding {
+                    signature: Some(signature),
+                    ..
+                }) => (name.as_str(), signature.clone()),
+                _ => return Type::Any,
+            },
+            Expr::Function {
+                signature: Some(signature),
+                ..
+            } => ("fn", signature.as_ref().clone()),
+            _ => return Type::Any,
+        };
+        if signature.args.len() != found.len() {
+            self.errors.push(TypeError {
+                line: self.line,
+                kind: TypeErrorKind::WrongArity {
+                    name: name.to_owned(),
+                    expected: signature.args.len(),
+                    found: found.len(),
+                },
+            });
+        } else {
+            for (i, found) in found.into_iter().enumerate() {
+                if let Some(expected) = &signature.args[i] {
+                    self.expect(
+                        || format!("argument {} of '{}'", i + 1, name),
+                        expected,
+                        found,
+                    )
+                }
+            }
+        }
+        signature.ret.unwrap_or(Type::Any)
+    }
+}
+
+fn literal_type(literal: &Literal) -> Type {
+    match literal {
+        // Chars compile to their codepoint
+        Literal::Int(_) | Literal::Char(_) => Type::Int,
+        Literal::Float(_) => Type::Number,
+        Literal::Str(_) => Type::Str,
+        Literal::Bool(_) => Type::Bool,
+        Literal::Nil => Type::Nil,
+        Literal::Unit => Type::Unit,
+    }
+}
+
+// The arithmetic of Vm::binary_value, ints divided can give numbers
+fn binary_type(op: BinaryOp, left: Type, right: Type) -> Type {
+    let numeric = |ty: &Type| matches!(ty, Type::Int | Type::Number);
+    match op {
+        BinaryOp::EqualEqual
+        | BinaryOp::BangEqual
+        | BinaryOp::Greater
+        | BinaryOp::Less
+        | BinaryOp::GreaterEqual
+        | BinaryOp::LessEqual => Type::Bool,
+        BinaryOp::And | BinaryOp::Or if left == right => left,
+        BinaryOp::Plus if left == Type::Str && right == Type::Str => Type::Str,
+        BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Star | BinaryOp::Rem
+            if left == Type::Int && right == Type::Int =>
+        {
+            Type::Int
+        }
+        BinaryOp::Plus | BinaryOp::Minus | BinaryOp::Star | BinaryOp::Rem | BinaryOp::Slash
+            if numeric(&left) && numeric(&right) =>
+        {
+            Type::Number
+        }
+        _ => Type::Any,
+    }
+}
+
+fn result_of(name: &str) -> String {
+    format!("the result of '{}'", name)
+}
+
+fn is_unit(expr: &Expr) -> bool {
+    matches!(expr, Expr::Literal(Literal::Unit))
+}
+
+// Whether a return statement is reached in the block, leaving out the functions in it
+fn block_returns(block: &BlockExpr) -> bool {
+    block.stmts.iter().any(stmt_returns) || expr_returns(&block.expr)
+}
+
+fn stmt_returns(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Return(_) => true,
+        Statement::Expr(expr) => expr_returns(expr),
+        Statement::Block(stmts) => stmts.iter().any(stmt_returns),
+        Statement::If {
+            then_block,
+            else_block,
+            ..
+        } => expr_returns(then_block) || else_block.as_deref().is_some_and(expr_returns),
+        Statement::While { then_block, .. }
+        | Statement::For {
+            body: then_block, ..
+        } => stmt_returns(then_block),
+        _ => false,
+    }
+}
+
+fn expr_returns(expr: &Expr) -> bool {
+    match expr {
+        Expr::Block(block) => block_returns(block),
+        Expr::If {
+            then_block,
+            else_block,
+            ..
+        } => expr_returns(then_block) || expr_returns(else_block),
+        _ => false,
+    }
+}
+
+impl Display for TypeErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeErrorKind::Mismatch {
+                what,
+                expected,
+                found,
+            } => write!(f, "{} is declared {}, found {}", what, expected, found),
+            TypeErrorKind::WrongArity {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "'{}' takes {} arguments, called with {}",
+                name, expected, found
+            ),
+        }
+    }
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "{}: ", line)?;
+        }
+        write!(f, "type error: {}", self.kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn errors(source: &str) -> Vec<String> {
+        let ast = Parser::new(source).parse_syntax().unwrap();
+        check(ast.expr())
+            .into_iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reports_mismatched_annotations() {
+        assert_eq!(
+            errors("let x: int = \"a\";"),
+            vec!["1: type error: 'x' is declared int, found str"]
+        );
+        assert_eq!(
+            errors("let x: number = 1;\nx = (1, 2);"),
+            vec!["2: type error: 'x' is declared number, found (int, int)"]
+        );
+        assert_eq!(
+            errors("fn f(a: str) -> int\n a\nend"),
+            vec!["2: type error: the result of 'f' is declared int, found str"]
+        );
+        assert_eq!(
+            errors("fn f(a: int) -> int return \"a\"; end"),
+            vec!["1: type error: the result of 'f' is declared int, found str"]
+        );
+        assert_eq!(
+            errors("fn f(a: int, b) -> int a end\nlet s: str = f(\"a\", nil);\nf(1)"),
+            vec![
+                "2: type error: argument 1 of 'f' is declared int, found str",
+                "2: type error: 's' is declared str, found int",
+                "3: type error: 'f' takes 2 arguments, called with 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn unannotated_code_stays_dynamic() {
+        assert!(errors("let x = 1; x = \"a\"; fn f(a) a end f(1, 2)").is_empty());
+        assert!(errors("fn f(a: int) -> int if a > 0 then return a; end return 0; end").is_empty());
+        assert!(errors("let x: int = 1; do let x = \"a\"; x = nil; end").is_empty());
+        assert!(errors("fn f(t) -> str t.name end let n: int = 1 + 2 * 3;").is_empty());
+        assert!(errors("fn f(t) t end let n: int = f(1.5) as int;").is_empty());
+    }
+}
@@ -1,40 +1,241 @@
-use crate::compiler::{Chunk, Compiler};
+use crate::bench::{self, Bench, BenchConfig};
+use crate::bundle;
+use crate::compiler::{disassemble, is_bytecode, CompileError, CompiledSource, Compiler};
+use crate::diagnostic::render_error;
 use crate::error::FluxResult;
-use crate::parser::Parser;
+use crate::formatter::{self, FormatConfig};
+use crate::lint::{self, Lint, LintConfig};
+use crate::parser::{Ast, Parser};
 use crate::sourcefile::{MetaData, SourceFile};
-use crate::vm::{Value, Vm};
+use crate::typecheck::{self, TypeError};
+use crate::vm::{trace_writer, Coverage, Profile, Value, Vm};
+use std::cell::RefCell;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::rc::Rc;
 
-pub fn run_file(path: &str) -> FluxResult<Value> {
+// 'args' holds the script path followed by the arguments
+pub fn run_file(path: &str, args: &[String]) -> FluxResult<Value> {
+    let bytes = std::fs::read(path).unwrap();
+    let mut script_args = vec![path.to_owned()];
+    script_args.extend_from_slice(args);
+    if is_bytecode(&bytes) {
+        let compiled = CompiledSource::from_bytes(&bytes)?;
+        return run(compiled, &script_args);
+    }
+    let buffer = String::from_utf8(bytes).unwrap();
+    eval_with_args(buffer.as_str(), path, &script_args)
+}
+
+// Runs the script like run_file and reports where the time went
+pub fn profile_file(path: &str, args: &[String]) -> FluxResult<(Value, Profile)> {
+    let compiled = load_file(path)?;
+    let mut vm = Vm::new();
+    vm.set_args(&script_args(path, args));
+    vm.run_profiled(&compiled).map_err(|e| e.into())
+}
+
+// Runs the script like run_file and records the lines that executed
+pub fn coverage_file(path: &str, args: &[String]) -> FluxResult<(Value, Coverage)> {
+    let compiled = load_file(path)?;
+    let mut vm = Vm::new();
+    vm.set_args(&script_args(path, args));
+    vm.run_with_coverage(&compiled).map_err(|e| e.into())
+}
+
+// Runs the script like run_file writing every executed instruction to 'out'
+pub fn trace_file<W: Write + 'static>(path: &str, args: &[String], out: W) -> FluxResult<Value> {
+    let compiled = load_file(path)?;
+    let mut vm = Vm::new();
+    vm.set_args(&script_args(path, args));
+    vm.set_trace(trace_writer(out));
+    vm.run(&compiled).map_err(|e| e.into())
+}
+
+// Runs the script, or one of its functions, as configured and measures each run
+pub fn bench_file(path: &str, args: &[String], config: &BenchConfig) -> FluxResult<Bench> {
+    let compiled = load_file(path)?;
+    bench::bench(&compiled, &script_args(path, args), config)
+}
+
+// Compiles the file unless it is bytecode already
+pub fn load_file(path: &str) -> FluxResult<CompiledSource> {
+    let bytes = std::fs::read(path).map_err(|e| CompileError::IoError(e.kind()))?;
+    if is_bytecode(&bytes) {
+        return Ok(CompiledSource::from_bytes(&bytes)?);
+    }
+    compile(String::from_utf8(bytes).unwrap().as_str(), path)
+}
+
+fn script_args(path: &str, args: &[String]) -> Vec<String> {
+    let mut script_args = vec![path.to_owned()];
+    script_args.extend_from_slice(args);
+    script_args
+}
+
+// Precompiles the script so it can be run without parsing
+pub fn compile_file(path: &str, output: &str) -> FluxResult<()> {
+    let buffer = read_source(path);
+    let compiled = compile(buffer.as_str(), path)?;
+    std::fs::write(output, compiled.to_bytes()).map_err(|e| CompileError::IoError(e.kind()).into())
+}
+
+// Writes a copy of the running executable that runs the script, see bundle
+pub fn build_file(path: &str, output: &str) -> FluxResult<()> {
+    let io_error = |e: std::io::Error| CompileError::IoError(e.kind());
+    let compiled = load_file(path)?;
+    let exe = std::env::current_exe()
+        .and_then(std::fs::read)
+        .map_err(io_error)?;
+    std::fs::write(output, bundle::bundle(&exe, &compiled.to_bytes())).map_err(io_error)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(0o755);
+        std::fs::set_permissions(output, permissions).map_err(io_error)?;
+    }
+    Ok(())
+}
+
+// Runs the script 'flux_rs build' embedded in the running executable, if any.
+// 'args' are the arguments of the executable, its path first
+pub fn run_embedded(args: &[String]) -> Option<FluxResult<Value>> {
+    let payload = std::env::current_exe()
+        .and_then(|exe| bundle::read_payload(&exe))
+        .ok()??;
+    Some(match CompiledSource::from_bytes(&payload) {
+        Ok(compiled) => run(compiled, args),
+        Err(err) => Err(err.into()),
+    })
+}
+
+// Rewrites the file in the canonical layout, returns whether it changed
+pub fn format_file(path: &str, config: &FormatConfig) -> FluxResult<bool> {
+    let buffer = read_source(path);
+    let formatted = formatter::format(buffer.as_str(), config)?;
+    if formatted == buffer {
+        return Ok(false);
+    }
+    std::fs::write(path, formatted).map_err(|e| CompileError::IoError(e.kind()))?;
+    Ok(true)
+}
+
+pub fn lint_file(path: &str, config: &LintConfig) -> FluxResult<Vec<Lint>> {
+    let ast = parse_syntax_file(path)?;
+    Ok(lint::lint(ast.expr(), config))
+}
+
+// Type errors of the annotations, see typecheck::check
+pub fn check_file(path: &str) -> FluxResult<Vec<TypeError>> {
+    let ast = parse_syntax_file(path)?;
+    Ok(typecheck::check(ast.expr()))
+}
+
+pub fn parse_file(path: &str) -> FluxResult<Ast> {
+    let buffer = read_source(path);
+    let mut parser = Parser::new(buffer.as_str());
+    Ok(parser.parse_all()?)
+}
+
+// Parses without the analyzer, for tools that need the tree as written
+pub fn parse_syntax_file(path: &str) -> FluxResult<Ast> {
+    let buffer = read_source(path);
+    let mut parser = Parser::new(buffer.as_str());
+    Ok(parser.parse_syntax()?)
+}
+
+fn read_source(path: &str) -> String {
     let mut file = File::open(path).unwrap();
     let mut buffer = String::new();
     file.read_to_string(&mut buffer).unwrap();
+    buffer
+}
 
-    eval(buffer.as_str(), path)
+pub fn disassemble_file(path: &str) -> FluxResult<String> {
+    let buffer = read_source(path);
+    let compiled = compile(buffer.as_str(), path)?;
+    Ok(disassemble(&compiled, Some(buffer.as_str())))
 }
 
+// Used by the tests of natives that need std, the others use macros::eval
+#[allow(dead_code)]
 pub fn eval(source: &str, path: &str) -> FluxResult<Value> {
-    let mut parser = Parser::new(source)?;
-    let ast = parser.parse()?;
+    eval_with_args(source, path, &[])
+}
+
+// Runs the source and returns what it printed followed by the error, if any. The
+// whole round trip stays in memory so hosts without a terminal, like a browser
+// page calling into the wasm32 build, can show the result
+pub fn eval_to_string(source: &str) -> String {
+    let output = Captured::default();
+    let mut vm = Vm::new();
+    vm.set_output(output.clone());
+    let result = match compile(source, "main.flux") {
+        Ok(compiled) => vm.run(&compiled).map_err(|e| e.into()),
+        Err(error) => Err(error),
+    };
+    let mut text = output.0.borrow().clone();
+    if let Err(error) = result {
+        text.push_str(&render_error(&error, source, Some("main.flux"), false));
+    }
+    text
+}
+
+// Runs an input of the REPL on its vm, the input can use the globals that the
+// earlier inputs defined
+pub fn eval_repl(vm: &mut Vm, source: &str) -> FluxResult<Value> {
+    let ast = Parser::new(source)
+        .with_globals(vm.global_names())
+        .parse_all()?;
+    dbg!(&ast);
+    let compiled = Compiler::compile_repl(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })?;
+    dbg!(&compiled.chunk);
+    vm.run(&compiled).map_err(|e| e.into())
+}
+
+// Collects what the script prints
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<String>>);
+
+impl fmt::Write for Captured {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.borrow_mut().push_str(s);
+        Ok(())
+    }
+}
+
+fn eval_with_args(source: &str, path: &str, args: &[String]) -> FluxResult<Value> {
+    let compiled = compile(source, path)?;
+    dbg!(&compiled.chunk);
+    debug!("{}", disassemble(&compiled, Some(source)));
+    run(compiled, args)
+}
+
+fn run(compiled: CompiledSource, args: &[String]) -> FluxResult<Value> {
+    let mut vm = Vm::new();
+    vm.set_args(args);
+    vm.run(&compiled).map_err(|e| e.into())
+}
+
+fn compile(source: &str, path: &str) -> FluxResult<CompiledSource> {
+    let mut parser = Parser::new(source);
+    let ast = parser.parse_all()?;
     dbg!(&ast);
     let dir = {
         let mut dir = PathBuf::from(path);
         dir.pop();
         dir
     };
-    let metadata = MetaData { dir };
+    let metadata = MetaData {
+        dir,
+        file: Some(PathBuf::from(path)),
+        ..MetaData::default()
+    };
     dbg!(&metadata);
-    let compiled = Compiler::compile(SourceFile { ast, metadata })?;
-    dbg!(&compiled.chunk);
-    print_instructions(&compiled.chunk);
-    let mut vm = Vm::new();
-    vm.run(compiled).map_err(|e| e.into())
-}
-
-fn print_instructions(chunk: &Chunk) {
-    for (i, instr) in chunk.instructions().iter().enumerate() {
-        debug!("{}: {:?}", i, instr);
-    }
+    Ok(Compiler::compile(SourceFile { ast, metadata })?)
 }
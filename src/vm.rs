@@ -1,29 +1,120 @@
+#[cfg(feature = "std")]
+mod awaiting;
+mod coverage;
+mod debugger;
+mod dispatch;
 mod error;
 mod frame;
+mod generator;
+#[cfg(feature = "jit")]
+mod jit;
 pub mod lib;
+mod memory;
+#[cfg(feature = "std")]
+mod net;
+mod output;
+#[cfg(feature = "std")]
+mod process;
+mod profile;
+mod quicken;
+mod scheduler;
+#[cfg(feature = "signals")]
+mod signal;
+mod stats;
 #[cfg(test)]
 mod tests;
+mod trace;
 mod value;
+#[cfg(feature = "std")]
+mod worker;
 
 use crate::compiler::{BinaryInstr, Chunk, CompiledSource, Instruction, UnaryInstr};
+use crate::suggest::did_you_mean;
+use alloc::rc::Rc;
+use alloc::{borrow::ToOwned, boxed::Box, string::String, string::ToString, vec::Vec};
+use core::cell::RefCell;
+use core::convert::TryFrom;
+use core::fmt;
+pub use coverage::Coverage;
+use debugger::Debugger;
+pub use debugger::{FrameView, Resume, RunState};
+use dispatch::Step;
 pub use error::RuntimeError;
-use frame::Frame;
-pub use lib::PREDEFINED_CONSTANTS;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+pub use frame::Frame;
+use hashbrown::hash_map;
+#[cfg(feature = "jit")]
+pub(crate) use jit::Jit;
+pub use lib::{PREDEFINED_CONSTANTS, PREDEFINED_GLOBALS};
+pub use memory::{allocated, is_tracking, TrackingAllocator};
+use output::Output;
+use profile::Profiler;
+pub use profile::{FunctionProfile, Profile};
+pub(crate) use quicken::Quickened;
+use scheduler::Scheduler;
+use stats::Census;
+pub use stats::Stats;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "std")]
+pub use trace::trace_writer;
+use trace::Trace;
+pub use trace::TraceFn;
 pub use value::{
-    ArgsLen, Float, FuncProtoRef, Function, Integer, NativeFunction, Table, UserFunction, Value,
+    ArgsLen, Float, FuncProtoRef, Function, FxHashMap, HostFn, Inspect, Integer, NativeFn,
+    NativeFunction, Table, TableRef, UserFunction, Value, DISPLAY_DEPTH,
 };
 
+#[cfg(feature = "std")]
+pub use awaiting::NativeFuture;
+#[cfg(feature = "signals")]
+pub use signal::Signal;
+#[cfg(feature = "std")]
+pub use worker::Message;
+
 pub type RuntimeResult<T> = Result<T, RuntimeError>;
 
+// Reserved by a new vm, enough for most scripts to never reallocate
+const INITIAL_STACK: usize = 256;
+const INITIAL_FRAMES: usize = 64;
+// Instructions between two looks at the memory used, the signals received and the
+// clock, see set_memory_limit, on_signal and run_with_timeout
+const SAMPLE_INTERVAL: u64 = 256;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Vm {
     frames: Vec<Frame>,
     stack: Vec<Value>,
-    globals: HashMap<Value, Value>,
+    globals: FxHashMap<Value, Value>,
+    // The _G table of the scripts, a copy of the globals kept in sync with them
+    globals_table: TableRef,
     compiled: Option<CompiledSource>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    trace: Option<Trace>,
+    output: Option<Output>,
+    debugger: Option<Debugger>,
+    // Depth of natives running user functions, the debugger can't pause inside them
+    blocking: usize,
+    // Set by the yield of the generator being resumed
+    yielded: Option<Value>,
+    paused: bool,
+    stats: Stats,
+    // Registered with test.register, in order
+    tests: Vec<(String, Value)>,
+    memory_limit: Option<usize>,
+    // When run_with_timeout gives up, and the timeout it was given
+    #[cfg(feature = "std")]
+    deadline: Option<(Instant, Duration)>,
+    // Set by set_sandboxed
+    sandboxed: bool,
+    #[cfg(feature = "signals")]
+    signal_handlers: Vec<(Signal, UserFunction)>,
+    // Bytes the thread had allocated when the source started running
+    memory_base: isize,
+    scheduler: Scheduler,
+    // Calls of a prototype before it's compiled, see set_jit_threshold
+    #[cfg(feature = "jit")]
+    jit: Option<u32>,
 }
 
 impl Vm {
@@ -31,186 +122,461 @@ impl Vm {
         Self::default()
     }
 
-    pub fn run(&mut self, source: CompiledSource) -> RuntimeResult<Value> {
-        /* self.set_chunk(chunk);
-        self.set_constants(constants); */
+    // Exposes the script path and its arguments as the 'args' table
+    pub fn set_args(&mut self, args: &[String]) {
+        let array = args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| (Value::Int(i as Integer), Value::new_str(arg.as_str())))
+            .collect();
+        self.insert_global(Value::Embedded(lib::ARGS), Table::from_array(array).into());
+    }
+
+    // Calls 'trace' before every instruction, including the ones of imported modules
+    pub fn set_trace<F>(&mut self, trace: F)
+    where
+        F: FnMut(&Frame, &Instruction, &[Value]) + 'static,
+    {
+        self.trace = Some(Trace::new(Box::new(trace)));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    // Redirects print, println and the REPL echo
+    pub fn set_output<W: fmt::Write + 'static>(&mut self, out: W) {
+        self.output = Some(Output::new(Box::new(out)));
+    }
+
+    pub(crate) fn write_output(&self, args: fmt::Arguments) -> RuntimeResult<()> {
+        match &self.output {
+            Some(output) => output.write(args).map_err(|_| RuntimeError::IOError),
+            #[cfg(feature = "std")]
+            None => {
+                use std::io::Write;
+                std::io::stdout()
+                    .write_fmt(args)
+                    .map_err(|_| RuntimeError::IOError)
+            }
+            // Without a writer set the output is dropped
+            #[cfg(not(feature = "std"))]
+            None => Ok(()),
+        }
+    }
+
+    // Defines the global 'name' as a native calling 'function', scripts run
+    // afterwards can call it like the builtin natives
+    pub fn register_native<F>(&mut self, name: &str, args_len: ArgsLen, function: F)
+    where
+        F: Fn(&mut Vm, Vec<Value>) -> RuntimeResult<Value> + 'static,
+    {
+        let native = NativeFunction::host(args_len, function);
+        self.set_global(name, Value::Function(Function::Native(native)));
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.insert_global(Value::new_str(name), value);
+    }
+
+    // Every global set goes through here to reach _G too
+    fn insert_global(&mut self, name: Value, value: Value) {
+        self.globals_table
+            .borrow_mut()
+            .set(name.clone(), value.clone());
+        self.globals.insert(name, value);
+    }
+
+    // Fails the script with OutOfMemory once it allocated more than 'limit' bytes
+    // over what was live when it started, the memory is looked at every few hundred
+    // instructions so the script can go a bit over. Requires the TrackingAllocator
+    // as the #[global_allocator], without it no limit is set and this fails with
+    // MemoryUntracked
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) -> RuntimeResult<()> {
+        if limit.is_some() && !memory::is_tracking() {
+            return Err(RuntimeError::MemoryUntracked);
+        }
+        self.memory_limit = limit;
+        Ok(())
+    }
+
+    // Makes the natives reaching out of the process fail with Sandboxed: running
+    // programs, sockets and native modules. Workers inherit it
+    pub fn set_sandboxed(&mut self, sandboxed: bool) {
+        self.sandboxed = sandboxed;
+    }
+
+    pub(crate) fn check_sandbox(&self, native: &str) -> RuntimeResult<()> {
+        match self.sandboxed {
+            true => Err(RuntimeError::Sandboxed(native.to_owned())),
+            false => Ok(()),
+        }
+    }
+
+    pub fn globals(&self) -> hash_map::Iter<'_, Value, Value> {
+        self.globals.iter()
+    }
+
+    // The tests the script registered, by name
+    pub fn tests(&self) -> &[(String, Value)] {
+        &self.tests
+    }
+
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(&Value::new_str(name))
+    }
+
+    // The defined global, natives included, closest to an undefined name
+    pub fn suggest_global(&self, name: &str) -> Option<String> {
+        did_you_mean(name, self.global_names())
+    }
+
+    // To parse scripts with Parser::with_globals
+    pub fn global_names(&self) -> impl Iterator<Item = &str> {
+        self.globals.keys().filter_map(|key| match key {
+            Value::Str(name) => Some(name.as_str()),
+            Value::Embedded(name) => Some(*name),
+            _ => None,
+        })
+    }
+
+    // Calls a function of a script that already ran and returns its result,
+    // breakpoints don't pause it
+    pub fn call_function(&mut self, function: Value, args: &[Value]) -> RuntimeResult<Value> {
+        let function = match function {
+            Value::Function(function) => function,
+            _ => return Err(RuntimeError::TypeError),
+        };
+        let depth = self.frames.len();
+        self.stack.extend_from_slice(args);
+        self.call(function, args.len() as u8)?;
+        self.blocking += 1;
+        let result = self.execute_until(depth);
+        self.blocking -= 1;
+        result?;
+        self.pop_stack()
+    }
+
+    // Counts the live values on demand, the other counters are kept while running
+    pub fn stats(&self) -> Stats {
+        let mut census = Census::default();
+        for (key, value) in &self.globals {
+            census.visit(key);
+            census.visit(value);
+        }
+        for value in &self.stack {
+            census.visit(value);
+        }
+        for frame in &self.frames {
+            if let Some(function) = frame.function() {
+                census.visit(&function.clone().into());
+            }
+        }
+        let mut stats = self.stats.clone();
+        census.finish(&mut stats);
+        stats
+    }
+
+    // The source is shared with the vm, running it again doesn't copy it
+    pub fn run(&mut self, source: &CompiledSource) -> RuntimeResult<Value> {
         self.set_compiled_source(source);
         self.init_call();
-        self.main_loop()
+        let result = self.main_loop();
+        #[cfg(feature = "signals")]
+        let result = self.handle_signal(result);
+        if result.is_err() {
+            self.abort();
+        }
+        result
+    }
+
+    // Runs the source like 'run', failing with Timeout once it ran longer than
+    // 'timeout'. The clock is looked at every few hundred instructions and while
+    // tasks sleep, a native blocking the thread isn't interrupted
+    #[cfg(feature = "std")]
+    pub fn run_with_timeout(
+        &mut self,
+        source: &CompiledSource,
+        timeout: Duration,
+    ) -> RuntimeResult<Value> {
+        self.deadline = Some((Instant::now() + timeout, timeout));
+        let result = self.run(source);
+        self.deadline = None;
+        result
+    }
+
+    // Runs the source like 'run' while counting the instructions executed and
+    // the time spent in each function
+    pub fn run_profiled(&mut self, source: &CompiledSource) -> RuntimeResult<(Value, Profile)> {
+        self.profiler = Some(Profiler::default());
+        let result = self.run(source);
+        let profiler = self.profiler.take().unwrap_or_default();
+        let value = result?;
+        Ok((value, profiler.finish(self.prototypes())))
+    }
+
+    // Runs the source like 'run' while recording the lines of the root module
+    // that executed
+    pub fn run_with_coverage(
+        &mut self,
+        source: &CompiledSource,
+    ) -> RuntimeResult<(Value, Coverage)> {
+        self.coverage = Some(Coverage::new(source));
+        let result = self.run(source);
+        let coverage = self.coverage.take().expect("Expected the coverage");
+        Ok((result?, coverage))
+    }
+
+    // Prepares the source to run with Vm::resume
+    pub fn start(&mut self, source: &CompiledSource) {
+        self.set_compiled_source(source);
+        self.init_call();
+    }
+
+    // Statements starting on the line pause Vm::resume. 'file' is the path the
+    // source was compiled from, or its end like 'main.flux'; sources compiled
+    // without a path use the empty name
+    pub fn set_breakpoint(&mut self, file: &str, line: usize) {
+        let debugger = self.debugger.get_or_insert_with(Debugger::default);
+        debugger
+            .breakpoints
+            .entry(file.to_owned())
+            .or_default()
+            .insert(line);
+    }
+
+    pub fn clear_breakpoints(&mut self, file: &str) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.breakpoints.remove(file);
+        }
+    }
+
+    // Runs until the next statement, in any function
+    pub fn step(&mut self) -> RuntimeResult<RunState> {
+        self.resume(Resume::StepIn)
+    }
+
+    // Runs until a breakpoint or the end of the source
+    pub fn continue_run(&mut self) -> RuntimeResult<RunState> {
+        self.resume(Resume::Continue)
+    }
+
+    // Runs the started source until a breakpoint, the end of the step or the
+    // end of the source
+    pub fn resume(&mut self, resume: Resume) -> RuntimeResult<RunState> {
+        let depth = self.frames.len();
+        self.debugger.get_or_insert_with(Debugger::default).resume = Some((resume, depth));
+        let result = self.resume_loop();
+        if let Some(debugger) = &mut self.debugger {
+            debugger.resume = None;
+        }
+        result
+    }
+
+    fn resume_loop(&mut self) -> RuntimeResult<RunState> {
+        loop {
+            self.execute()?;
+            if self.paused {
+                self.paused = false;
+                let line = self.call_stack().first().and_then(|frame| frame.line);
+                return Ok(RunState::Paused {
+                    line: line.unwrap_or(0),
+                });
+            }
+            if let Some(value) = self.after_execute()? {
+                return Ok(RunState::Finished(value));
+            }
+        }
+    }
+
+    // Frames of the functions being run, innermost first
+    pub fn call_stack(&self) -> Vec<FrameView> {
+        (0..self.frames.len())
+            .rev()
+            .filter_map(|i| self.frame_view(i))
+            .collect()
+    }
+
+    // The i-th frame of the call stack, 0 is the innermost one
+    pub fn inspect_frame(&self, i: usize) -> Option<FrameView> {
+        (0..self.frames.len())
+            .rev()
+            .filter(|index| self.frames[*index].proto().is_some())
+            .nth(i)
+            .and_then(|index| self.frame_view(index))
+    }
+
+    fn frame_view(&self, i: usize) -> Option<FrameView> {
+        let frame = &self.frames[i];
+        // The main chunk only calls the module body
+        let proto = frame.proto()?;
+        let name = if i == 1 && self.frames[0].proto().is_none() {
+            "main".to_owned()
+        } else if let Some(name) = proto.debug_info.name() {
+            name.to_owned()
+        } else {
+            let index = self.prototypes().iter().position(|p| Rc::ptr_eq(p, proto));
+            format!("fn #{}", index.unwrap_or_default())
+        };
+        let end = self
+            .frames
+            .get(i + 1)
+            .map_or(self.stack.len(), |next| next.stack_top());
+        let mut locals: Vec<_> = proto
+            .debug_info
+            .locals_at(frame.pc)
+            .filter(|local| !local.name.is_empty() && frame.stack_top() + local.slot < end)
+            .collect();
+        locals.sort_by_key(|local| local.slot);
+        Some(FrameView {
+            name,
+            file: proto.debug_info.file().map(str::to_owned),
+            line: proto.debug_info.line(frame.pc),
+            locals: locals
+                .into_iter()
+                .map(|local| {
+                    let value = self.stack[frame.stack_top() + local.slot].clone();
+                    (local.name.clone(), value)
+                })
+                .collect(),
+        })
     }
 
     fn main_loop(&mut self) -> RuntimeResult<Value> {
         loop {
             self.execute()?;
-            if self.frames.is_empty() {
-                return Ok(self.pop_stack()?);
+            if let Some(value) = self.after_execute()? {
+                return Ok(value);
+            }
+        }
+    }
+
+    // Runs until the calls above 'depth' return
+    fn execute_until(&mut self, depth: usize) -> RuntimeResult<()> {
+        while self.frames.len() > depth {
+            self.execute()?;
+            if self.frames.len() > depth {
+                self.current_frame_mut()?.pc += 1;
             }
-            let f = self.current_frame_mut()?;
-            f.pc += 1;
         }
+        Ok(())
+    }
+
+    // Drops the frames, the stack and the tasks a failed run left behind, so the
+    // next source starts from scratch
+    fn abort(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.reset_tasks();
     }
 
     #[inline]
     fn init_call(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(None);
+        }
         let frame = Frame::default();
         self.frames.push(frame)
     }
 
+    // Errors are wrapped with the instruction that failed. The ones coming from a
+    // function a native called already are, and keep the innermost location
     fn execute(&mut self) -> RuntimeResult<()> {
+        self.execute_instructions().map_err(|error| match error {
+            RuntimeError::At { .. } => error,
+            error => self.locate(error),
+        })
+    }
+
+    fn locate(&self, error: RuntimeError) -> RuntimeError {
+        let frame = match self.frames.last() {
+            Some(frame) => frame,
+            None => return error,
+        };
+        match self.instruction_at(frame.pc) {
+            Some(instr) => RuntimeError::At {
+                pc: frame.pc,
+                instr,
+                frame: self.frames.len() - 1,
+                source: Box::new(error),
+            },
+            None => error,
+        }
+    }
+
+    fn execute_instructions(&mut self) -> RuntimeResult<()> {
         loop {
             let instr = self.next_instr()?;
-            match instr {
-                Instruction::Nil => self.stack.push(Value::Nil),
-                Instruction::Unit => self.stack.push(Value::Unit),
-                Instruction::True => self.stack.push(Value::Bool(true)),
-                Instruction::False => self.stack.push(Value::Bool(false)),
-                Instruction::Constant { index } => {
-                    let value = self.constant_table()[index as usize].clone();
-                    self.stack.push(value)
-                }
-                Instruction::Pop => {
-                    self.pop_stack()?;
-                }
-                Instruction::Return { return_value } => {
-                    let value = if return_value {
-                        self.pop_stack()?
-                    } else {
-                        Value::Unit
-                    };
-                    while self.stack.len() > self.current_frame()?.stack_top() {
-                        self.pop_stack()?;
-                    }
-                    self.stack.push(value);
-                    self.frames.pop().expect("Stack frame is empty");
-                    // self.print_stack();
-                    return Ok(());
-                }
-                Instruction::Bin(bin) => self.binary(bin)?,
-                Instruction::Unary(unary) => self.unary(unary)?,
-                Instruction::GetGlobal { index } => {
-                    let name = &self.constant_table()[index as usize];
-                    match self.globals.get(name) {
-                        Some(value) => self.stack.push(value.clone()),
-                        None => {
-                            return Err(RuntimeError::UndefinedVariable {
-                                name: name.to_string(),
-                            })
-                        }
-                    }
-                }
-                Instruction::SetGlobal { index } => {
-                    let name = self.constant_table()[index as usize].clone();
-                    let value = self.stack.pop().unwrap();
-                    self.globals.insert(name, value);
-                }
-                Instruction::GetLocal { index, frame } => {
-                    let frame_index = self.frame_from_offset(frame);
-                    let index = self.frames[frame_index].stack_top() + index as usize;
-                    self.stack.push(self.stack[index as usize].clone());
-                }
-                Instruction::SetLocal { index, frame } => {
-                    let frame_index = self.frame_from_offset(frame);
-                    let index = self.frames[frame_index].stack_top() + index as usize;
-                    if self.stack.len() != index as usize {
-                        self.stack[index as usize] = self.pop_stack()?;
-                    }
-                }
-                Instruction::Jump { offset } => self.jump(offset)?,
-                Instruction::JumpIf { offset, when_true } => {
-                    let value = self.pop_stack()?;
-                    if value.as_bool() == when_true {
-                        self.jump(offset)?;
-                    }
-                }
-                Instruction::InitTable { len, has_keys } => self.init_table(len, has_keys)?,
-                Instruction::GetField => self.get_field()?,
-                Instruction::GetFieldImm { index } => self.get_field_imm(index)?,
-                Instruction::GetMethodImm {
-                    index,
-                    table_stack_index,
-                } => self.get_method_imm(index, table_stack_index)?,
-                Instruction::SetField => self.set_field()?,
-                Instruction::SetFieldImm { index } => self.set_field_imm(index)?,
-                Instruction::Print => {
-                    let value = self.pop_stack()?;
-                    println!("{}", value)
-                }
-                Instruction::Tuple { len } => {
-                    let mut values = Vec::with_capacity(len as usize);
-                    for _ in 0..len {
-                        values.push(self.pop_stack()?)
-                    }
-                    let tuple = Value::Tuple(values.into_iter().rev().collect());
-                    self.stack.push(tuple)
-                }
-                Instruction::FuncDef {
-                    proto_index,
-                    has_env,
-                } => {
-                    let proto = self.prototypes()[proto_index as usize].clone();
-                    let function = Value::Function(if has_env {
-                        let env = self
-                            .pop_stack()?
-                            .into_table()
-                            .expect("Expected a table as env");
-                        Function::new_user_with_env(proto, env)
-                    } else {
-                        Function::new_user(proto)
-                    });
-                    self.stack.push(function)
-                }
-                Instruction::Call { args_len } => {
-                    let function = self.pop_stack()?;
-                    match function {
-                        Value::Function(function) => {
-                            self.call(function, args_len)?;
-                            continue; // Don't increment pc
-                        }
-                        _ => return Err(RuntimeError::TypeError),
-                    }
-                }
-                Instruction::Integer(value) => self.stack.push(value.into()),
-                Instruction::Import { name_index } => self.import(name_index as usize)?,
-                Instruction::ExitBlock { pop, return_value } => {
-                    let return_value = if return_value {
-                        let value = self.pop_stack()?;
-                        Some(value)
-                    } else {
-                        None
-                    };
-                    for _ in 0..pop {
-                        self.pop_stack()?;
-                    }
-                    if let Some(value) = return_value {
-                        self.stack.push(value);
-                    }
-                }
-                Instruction::Rec => {
-                    let frame = self.frames.last().expect("Expected a call frame");
-                    let func = frame
-                        .function()
-                        .expect("Expected call has a function")
-                        .clone();
-                    self.stack.push(func.into());
-                }
-                _ => return Err(RuntimeError::UnsupportedInstruction(instr)),
+            if let (Some(trace), Some(frame)) = (&self.trace, self.frames.last()) {
+                trace.call(frame, &instr, &self.stack);
+            }
+            if self.debugger.is_some() && self.blocking == 0 && self.should_pause() {
+                self.paused = true;
+                return Ok(());
+            }
+            self.stats.record(self.stack.len(), self.frames.len());
+            if self.stats.instructions.is_multiple_of(SAMPLE_INTERVAL) {
+                self.sample_memory()?;
+                #[cfg(feature = "std")]
+                self.check_deadline()?;
+                #[cfg(feature = "signals")]
+                self.check_signals()?;
+            }
+            if let Some(profiler) = &mut self.profiler {
+                profiler.instruction(instr);
+            }
+            if self.coverage.is_some() {
+                self.record_line();
+            }
+            match self.dispatch(instr)? {
+                Step::Next => self.current_frame_mut()?.pc += 1,
+                // Natives switch tasks once they returned
+                Step::Stay if self.scheduler.switch.is_some() => return Ok(()),
+                Step::Stay => (),
+                Step::Return => return Ok(()),
             }
-            let f = self.current_frame_mut()?;
-            f.pc += 1;
-            self.print_call_stack();
-            self.print_stack();
-            // self.print_globals();
         }
     }
 
+    #[cfg(not(feature = "threaded"))]
+    #[inline(always)]
+    fn dispatch(&mut self, instr: Instruction) -> RuntimeResult<Step> {
+        self.run_instruction(instr)
+    }
+
+    // The main chunk has no prototype, its few instructions are decoded as they run
+    #[cfg(feature = "threaded")]
+    #[inline(always)]
+    fn dispatch(&mut self, instr: Instruction) -> RuntimeResult<Step> {
+        let frame = self.current_frame()?;
+        let handler = match frame.proto() {
+            Some(proto) => proto.quickened.handler(frame.pc),
+            None => dispatch::handler(&instr),
+        };
+        handler(self, instr)
+    }
+
     fn import(&mut self, name_index: usize) -> RuntimeResult<()> {
         let mod_name = self.constant_table()[name_index].as_str()?.to_string();
-        let chunk = self
-            .current_chunk_mut()
-            .imports()
-            .remove(&mod_name)
-            .expect("Expected module");
+        let chunk = Rc::clone(
+            self.current_chunk()
+                .imports()
+                .get(&mod_name)
+                .expect("Expected module"),
+        );
         let mut vm = Vm::new();
+        vm.trace = self.trace.clone();
+        vm.output = self.output.clone();
+        vm.memory_limit = self.memory_limit;
+        #[cfg(feature = "std")]
+        {
+            vm.deadline = self.deadline;
+        }
+        vm.sandboxed = self.sandboxed;
+        #[cfg(feature = "jit")]
+        {
+            vm.jit = self.jit;
+        }
         let source = CompiledSource {
             chunk,
             constant_table: Rc::clone(
@@ -222,16 +588,27 @@ impl Vm {
             ),
         };
         // TODO: wrap error
-        // vm.run(chunk, Rc::clone(self.constant_table.as_ref().expect("Expected a constant table")))?;
-        vm.run(source)?;
-        self.globals
-            .insert(mod_name.into(), Table::from_map(vm.globals).into());
+        let result = vm.run(&source);
+        self.stats.merge(&vm.stats);
+        result?;
+        self.insert_global(mod_name.into(), Table::from_map(vm.globals).into());
         Ok(())
     }
 
     // TODO: look recursively for '__class__' attribute when something is returns nil
     fn get_table(key: &Value, table: &Value) -> RuntimeResult<Value> {
         match table {
+            Value::Tuple(values) => match key {
+                Value::Int(index) => usize::try_from(*index)
+                    .ok()
+                    .and_then(|i| values.get(i))
+                    .cloned()
+                    .ok_or(RuntimeError::IndexOutOfRange {
+                        index: *index,
+                        len: values.len(),
+                    }),
+                _ => Err(RuntimeError::TypeError),
+            },
             Value::Table(rc) => {
                 let table = rc.borrow_mut();
                 let value = match table.get(&key) {
@@ -279,7 +656,11 @@ impl Vm {
         let value = self.pop_stack()?;
         match table {
             Value::Table(rc) => {
+                self.set_through_globals_table(&rc, &key, &value)?;
                 let mut table = rc.borrow_mut();
+                if table.is_frozen() {
+                    return Err(RuntimeError::FrozenTable { key });
+                }
                 table.set(key, value);
                 Ok(())
             }
@@ -290,17 +671,35 @@ impl Vm {
     fn set_field_imm(&mut self, index: u8) -> RuntimeResult<()> {
         let value = self.pop_stack()?;
         let table = self.pop_stack()?;
-        let key = &self.constant_table()[index as usize];
+        let key = self.constant_table()[index as usize].clone();
         match table {
             Value::Table(rc) => {
+                self.set_through_globals_table(&rc, &key, &value)?;
                 let mut table = rc.borrow_mut();
-                table.set(key.clone(), value);
+                if table.is_frozen() {
+                    return Err(RuntimeError::FrozenTable { key });
+                }
+                table.set(key, value);
                 Ok(())
             }
             _ => Err(RuntimeError::TypeError),
         }
     }
 
+    // Setting a field of _G sets the global, the caller sets the field itself
+    fn set_through_globals_table(
+        &mut self,
+        table: &TableRef,
+        key: &Value,
+        value: &Value,
+    ) -> RuntimeResult<()> {
+        if Rc::ptr_eq(table, &self.globals_table) && !table.borrow().is_frozen() {
+            self.check_sandbox(lib::GLOBALS)?;
+            self.globals.insert(key.clone(), value.clone());
+        }
+        Ok(())
+    }
+
     fn init_table(&mut self, len: u16, has_keys: bool) -> RuntimeResult<()> {
         let table = if has_keys {
             let mut table = Table::new();
@@ -321,6 +720,18 @@ impl Vm {
         Ok(())
     }
 
+    fn table_template(&mut self, index: u8) -> RuntimeResult<()> {
+        let template = self.constant_table()[index as usize].clone().into_table()?;
+        // Nothing can change a frozen table, every evaluation shares it
+        if template.borrow().is_frozen() {
+            self.stack.push(template.into());
+            return Ok(());
+        }
+        let table = template.borrow().clone();
+        self.stack.push(table.into());
+        Ok(())
+    }
+
     fn jump(&mut self, offset: i8) -> RuntimeResult<()> {
         let f = self.current_frame_mut()?;
         if offset > 0 {
@@ -331,26 +742,73 @@ impl Vm {
         Ok(())
     }
 
+    fn get_global(&self, index: u8) -> RuntimeResult<Value> {
+        let name = &self.constant_table()[index as usize];
+        match self.globals.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(RuntimeError::UndefinedVariable {
+                name: name.to_string(),
+                suggestion: self.suggest_global(&name.to_string()),
+            }),
+        }
+    }
+
+    fn call_value(&mut self, function: Value, pushed_args: u8) -> RuntimeResult<()> {
+        match function {
+            Value::Function(function) => self.call(function, pushed_args),
+            _ => Err(RuntimeError::TypeError),
+        }
+    }
+
     fn call(&mut self, function: Function, pushed_args: u8) -> RuntimeResult<()> {
         match function {
-            Function::User(function) => self.call_user(function, pushed_args),
+            Function::User(function) => {
+                #[cfg(feature = "jit")]
+                {
+                    if self.call_compiled(&function, pushed_args) {
+                        // A host calling through call_function has no frame to move
+                        if let Some(frame) = self.frames.last_mut() {
+                            frame.pc += 1;
+                        }
+                        return Ok(());
+                    }
+                }
+                self.call_user(function, pushed_args)
+            }
             Function::Native(native_fn) => self.call_native(native_fn, pushed_args),
         }
     }
 
     fn call_user(&mut self, function: UserFunction, pushed_args: u8) -> RuntimeResult<()> {
+        // Returns right away like natives do
+        if function.proto_ref().generator {
+            self.call_generator(function, pushed_args)?;
+            self.current_frame_mut()?.pc += 1;
+            return Ok(());
+        }
         if pushed_args == function.args_len() {
             let stack_top = self.stack.len() - function.args_len() as usize;
+            // The call can't outgrow this, only deeper calls reallocate
+            let env = function.env().is_some() as usize;
+            self.stack.reserve(function.proto_ref().max_stack + env);
 
             // Push env if exists
             if let Some(env) = function.env() {
                 self.stack.push(Rc::clone(env).into())
             }
 
+            if self.profiler.is_some() {
+                let index = self
+                    .prototypes()
+                    .iter()
+                    .position(|proto| Rc::ptr_eq(proto, function.proto_ref()));
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.enter(index);
+                }
+            }
+
             // let upvalues = function.extract_upvalues();
             self.frames.push(Frame::new(0, function, stack_top));
-            self.print_call_stack();
-            self.print_stack();
             Ok(())
         } else {
             Err(RuntimeError::WrongNumberOfArgs {
@@ -361,12 +819,24 @@ impl Vm {
     }
 
     fn call_user_blocking(&mut self, function: UserFunction, pushed_args: u8) -> RuntimeResult<()> {
+        if function.proto_ref().generator {
+            return self.call_generator(function, pushed_args);
+        }
+        #[cfg(feature = "jit")]
+        {
+            if self.call_compiled(&function, pushed_args) {
+                return Ok(());
+            }
+        }
         self.call_user(function, pushed_args)?;
-        self.execute()
+        self.blocking += 1;
+        let result = self.execute();
+        self.blocking -= 1;
+        result
     }
 
     fn call_native(&mut self, native_fn: NativeFunction, pushed_args: u8) -> RuntimeResult<()> {
-        let mut args = Vec::new();
+        let mut args = Vec::with_capacity(pushed_args as usize);
         match native_fn.args_len() {
             ArgsLen::Variadic => {
                 for _ in 0..pushed_args {
@@ -386,7 +856,13 @@ impl Vm {
                 }
             }
         }
-        let value = (native_fn.function)(self, args)?;
+        let value = match &native_fn.function {
+            NativeFn::Builtin(function) => function(self, args)?,
+            NativeFn::Host(function) => {
+                args.reverse();
+                function(self, args)?
+            }
+        };
         self.stack.push(value);
         let f = self.current_frame_mut()?;
         f.pc += 1;
@@ -396,108 +872,174 @@ impl Vm {
     fn binary(&mut self, op: BinaryInstr) -> RuntimeResult<()> {
         let right = self.pop_stack()?;
         let left = self.pop_stack()?;
+        self.release_assigned_local(op, &left, &right);
+        let value = Self::binary_value(op, left, right)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    // Specialized instructions replace the top two values in place when both have
+    // the type they were compiled for, anything else goes the generic way
+    fn binary_int(
+        &mut self,
+        op: BinaryInstr,
+        apply: fn(Integer, Integer) -> Value,
+    ) -> RuntimeResult<()> {
+        if let &[.., Value::Int(a), Value::Int(b)] = &self.stack[..] {
+            self.stack.pop();
+            let top = self.stack.len() - 1;
+            self.stack[top] = apply(a, b);
+            return Ok(());
+        }
+        self.deoptimize(op);
+        self.binary(op)
+    }
+
+    fn binary_float(
+        &mut self,
+        op: BinaryInstr,
+        apply: fn(Float, Float) -> Value,
+    ) -> RuntimeResult<()> {
+        if let &[.., Value::Number(a), Value::Number(b)] = &self.stack[..] {
+            self.stack.pop();
+            let top = self.stack.len() - 1;
+            self.stack[top] = apply(a, b);
+            return Ok(());
+        }
+        self.deoptimize(op);
+        self.binary(op)
+    }
+
+    // 's = s + x' appends to s in place when nothing else holds it. The local's
+    // own reference is dropped first, the next instruction overwrites it anyway
+    fn release_assigned_local(&mut self, op: BinaryInstr, left: &Value, right: &Value) {
+        let string = match (op, left, right) {
+            (BinaryInstr::Add, Value::Str(string), Value::Str(_)) => string,
+            _ => return,
+        };
+        let pc = match self.frames.last() {
+            Some(frame) => frame.pc,
+            None => return,
+        };
+        let (index, frame) = match self.instruction_at(pc + 1) {
+            Some(Instruction::SetLocal { index, frame }) => (index, frame),
+            _ => return,
+        };
+        let slot = self.frames[self.frame_from_offset(frame)].stack_top() + index as usize;
+        if let Some(Value::Str(local)) = self.stack.get(slot) {
+            if Rc::ptr_eq(local, string) {
+                self.stack[slot] = Value::Nil;
+            }
+        }
+    }
+
+    // Superinstructions apply the operator to their operands without the stack
+    fn binary_value(op: BinaryInstr, left: Value, right: Value) -> RuntimeResult<Value> {
         if op == BinaryInstr::Eq {
-            self.stack.push(Value::Bool(left == right));
+            return Ok(Value::Bool(left == right));
         } else if op == BinaryInstr::Ne {
-            self.stack.push(Value::Bool(left != right));
-        } else {
-            let new_value = match (left, right) {
-                (Value::Number(a), Value::Number(b)) => Ok(match op {
-                    BinaryInstr::Add => Value::Number(a + b),
-                    BinaryInstr::Sub => Value::Number(a - b),
-                    BinaryInstr::Mul => Value::Number(a * b),
-                    BinaryInstr::Div => {
-                        if b == 0.0 {
-                            return Err(RuntimeError::DivideByZero);
-                        } else {
-                            Value::Number(a / b)
-                        }
+            return Ok(Value::Bool(left != right));
+        }
+        match (left, right) {
+            (Value::Number(a), Value::Number(b)) => Ok(match op {
+                BinaryInstr::Add => Value::Number(a + b),
+                BinaryInstr::Sub => Value::Number(a - b),
+                BinaryInstr::Mul => Value::Number(a * b),
+                BinaryInstr::Div => {
+                    if b == 0.0 {
+                        return Err(RuntimeError::DivideByZero);
+                    } else {
+                        Value::Number(a / b)
                     }
-                    BinaryInstr::Rem => Value::Number(a % b),
+                }
+                BinaryInstr::Rem => Value::Number(a % b),
 
-                    BinaryInstr::Gt => Value::Bool(a > b),
-                    BinaryInstr::Lt => Value::Bool(a < b),
-                    BinaryInstr::Ge => Value::Bool(a >= b),
-                    BinaryInstr::Le => Value::Bool(a <= b),
-                    _ => unreachable!(),
-                }),
-                (Value::Number(a), Value::Int(b)) => Ok(match op {
-                    BinaryInstr::Add => Value::Number(a + (b as f64)),
-                    BinaryInstr::Sub => Value::Number(a - (b as f64)),
-                    BinaryInstr::Mul => Value::Number(a * (b as f64)),
+                BinaryInstr::Gt => Value::Bool(a > b),
+                BinaryInstr::Lt => Value::Bool(a < b),
+                BinaryInstr::Ge => Value::Bool(a >= b),
+                BinaryInstr::Le => Value::Bool(a <= b),
+                _ => unreachable!(),
+            }),
+            (Value::Number(a), Value::Int(b)) => Ok(match op {
+                BinaryInstr::Add => Value::Number(a + (b as f64)),
+                BinaryInstr::Sub => Value::Number(a - (b as f64)),
+                BinaryInstr::Mul => Value::Number(a * (b as f64)),
+                BinaryInstr::Div => {
+                    if b == 0 {
+                        return Err(RuntimeError::DivideByZero);
+                    } else {
+                        Value::Number(a / (b as f64))
+                    }
+                }
+                BinaryInstr::Rem => Value::Number(a % (b as f64)),
+
+                BinaryInstr::Gt => Value::Bool(a > (b as f64)),
+                BinaryInstr::Lt => Value::Bool(a < (b as f64)),
+                BinaryInstr::Ge => Value::Bool(a >= (b as f64)),
+                BinaryInstr::Le => Value::Bool(a <= (b as f64)),
+                _ => unreachable!(),
+            }),
+            (Value::Int(a), Value::Int(b)) => Ok({
+                if op.is_arithmetic() {
+                    match op {
+                        BinaryInstr::Add => Value::Int(a + b),
+                        BinaryInstr::Sub => Value::Int(a - b),
+                        BinaryInstr::Mul => Value::Int(a * b),
+                        BinaryInstr::Div => match b {
+                            0 => return Err(RuntimeError::DivideByZero),
+                            n if a % n == 0 => Value::Int(a / b),
+                            _ => Value::Number(a as f64 / b as f64),
+                        },
+                        BinaryInstr::Rem => Value::Int(a % b),
+                        _ => unreachable!(),
+                    }
+                } else {
+                    match op {
+                        BinaryInstr::Gt => Value::Bool(a > b),
+                        BinaryInstr::Lt => Value::Bool(a < b),
+                        BinaryInstr::Ge => Value::Bool(a >= b),
+                        BinaryInstr::Le => Value::Bool(a <= b),
+                        _ => unreachable!(),
+                    }
+                }
+            }),
+            (Value::Int(a), Value::Number(b)) => Ok({
+                match op {
+                    BinaryInstr::Add => Value::Number((a as f64) + b),
+                    BinaryInstr::Sub => Value::Number((a as f64) - b),
+                    BinaryInstr::Mul => Value::Number((a as f64) * b),
                     BinaryInstr::Div => {
-                        if b == 0 {
+                        if b == 0.0 {
                             return Err(RuntimeError::DivideByZero);
                         } else {
-                            Value::Number(a / (b as f64))
+                            Value::Number((a as f64) / b)
                         }
                     }
-                    BinaryInstr::Rem => Value::Number(a % (b as f64)),
-
-                    BinaryInstr::Gt => Value::Bool(a > (b as f64)),
-                    BinaryInstr::Lt => Value::Bool(a < (b as f64)),
-                    BinaryInstr::Ge => Value::Bool(a >= (b as f64)),
-                    BinaryInstr::Le => Value::Bool(a <= (b as f64)),
+                    BinaryInstr::Rem => Value::Number((a as f64) % b),
+                    BinaryInstr::Gt => Value::Bool((a as f64) > b),
+                    BinaryInstr::Lt => Value::Bool((a as f64) < b),
+                    BinaryInstr::Ge => Value::Bool((a as f64) >= b),
+                    BinaryInstr::Le => Value::Bool((a as f64) <= b),
                     _ => unreachable!(),
-                }),
-                (Value::Int(a), Value::Int(b)) => Ok({
-                    if op.is_arithmetic() {
-                        match op {
-                            BinaryInstr::Add => Value::Int(a + b),
-                            BinaryInstr::Sub => Value::Int(a - b),
-                            BinaryInstr::Mul => Value::Int(a * b),
-                            BinaryInstr::Div => match b {
-                                0 => return Err(RuntimeError::DivideByZero),
-                                n if a % n == 0 => Value::Int(a / b),
-                                _ => Value::Number(a as f64 / b as f64),
-                            },
-                            BinaryInstr::Rem => Value::Int(a % b),
-                            _ => unreachable!(),
-                        }
-                    } else {
-                        match op {
-                            BinaryInstr::Gt => Value::Bool(a > b),
-                            BinaryInstr::Lt => Value::Bool(a < b),
-                            BinaryInstr::Ge => Value::Bool(a >= b),
-                            BinaryInstr::Le => Value::Bool(a <= b),
-                            _ => unreachable!(),
-                        }
-                    }
-                }),
-                (Value::Int(a), Value::Number(b)) => Ok({
-                    match op {
-                        BinaryInstr::Add => Value::Number((a as f64) + b),
-                        BinaryInstr::Sub => Value::Number((a as f64) - b),
-                        BinaryInstr::Mul => Value::Number((a as f64) * b),
-                        BinaryInstr::Div => {
-                            if b == 0.0 {
-                                return Err(RuntimeError::DivideByZero);
-                            } else {
-                                Value::Number((a as f64) / b)
-                            }
-                        }
-                        BinaryInstr::Rem => Value::Number((a as f64) % b),
-                        BinaryInstr::Gt => Value::Bool((a as f64) > b),
-                        BinaryInstr::Lt => Value::Bool((a as f64) < b),
-                        BinaryInstr::Ge => Value::Bool((a as f64) >= b),
-                        BinaryInstr::Le => Value::Bool((a as f64) <= b),
-                        _ => unreachable!(),
+                }
+            }),
+            (Value::Str(mut a), Value::Str(b)) => match op {
+                BinaryInstr::Add => match Rc::get_mut(&mut a) {
+                    Some(string) => {
+                        string.push_str(&b);
+                        Ok(Value::Str(a))
                     }
-                }),
-                (Value::Str(a), Value::Str(b)) => match op {
-                    BinaryInstr::Add => {
+                    None => {
                         let mut new_string = String::with_capacity(a.len() + b.len());
-                        new_string.extend(a.chars());
-                        new_string.extend(b.chars());
-                        Ok(Value::Str(Rc::new(new_string)))
+                        new_string.push_str(&a);
+                        new_string.push_str(&b);
+                        Ok(new_string.into())
                     }
-                    _ => Err(RuntimeError::TypeError),
                 },
-                (value, _) => Err(RuntimeError::UnsupportedBinary { value, op }),
-            }?;
-            self.stack.push(new_value);
+                _ => Err(RuntimeError::TypeError),
+            },
+            (value, _) => Err(RuntimeError::UnsupportedBinary { value, op }),
         }
-        Ok(())
     }
 
     fn unary(&mut self, op: UnaryInstr) -> RuntimeResult<()> {
@@ -517,10 +1059,47 @@ impl Vm {
     }
 
     fn next_instr(&mut self) -> RuntimeResult<Instruction> {
-        let f = self.current_frame()?;
-        let instr = self.instructions()?[f.pc];
-        debug!("pc: {}, instr: {:?}", f.pc, instr);
-        Ok(instr)
+        let pc = self.current_frame()?.pc;
+        Ok(self.instruction_at(pc).expect("Expected an instruction"))
+    }
+
+    // Statements pause at their first instruction
+    fn should_pause(&mut self) -> bool {
+        let depth = self.frames.len();
+        let (proto, pc) = match self.frames.last() {
+            Some(frame) => match frame.proto() {
+                Some(proto) => (proto, frame.pc),
+                None => return false,
+            },
+            None => return false,
+        };
+        let line = match proto
+            .debug_info
+            .nodes()
+            .iter()
+            .find(|node| node.start == pc)
+        {
+            Some(node) => node.line,
+            None => return false,
+        };
+        let file = proto.debug_info.file();
+        match &mut self.debugger {
+            Some(debugger) => debugger.should_pause(depth, pc, file, line),
+            None => false,
+        }
+    }
+
+    fn record_line(&mut self) {
+        let (function, line) = match self.frames.last() {
+            Some(frame) => match frame.proto() {
+                Some(proto) => (Rc::as_ptr(proto), proto.debug_info.line(frame.pc)),
+                None => return,
+            },
+            None => return,
+        };
+        if let (Some(coverage), Some(line)) = (&mut self.coverage, line) {
+            coverage.hit(function, self.frames.len(), line);
+        }
     }
 
     fn current_frame(&self) -> RuntimeResult<&Frame> {
@@ -546,11 +1125,6 @@ impl Vm {
             .chunk
     }
 
-    #[inline]
-    fn current_chunk_mut(&mut self) -> &mut Chunk {
-        &mut self.compiled.as_mut().expect("Expected a chunk").chunk
-    }
-
     fn constant_table(&self) -> &[Value] {
         &self
             .compiled
@@ -569,11 +1143,13 @@ impl Vm {
             .prototypes
     }
 
-    fn instructions(&self) -> RuntimeResult<&[Instruction]> {
-        Ok(match self.current_frame()?.proto() {
-            Some(proto) => proto.instructions.as_ref(),
-            None => self.current_chunk().instructions(),
-        })
+    // The instruction of the current frame at 'pc', as quickened for functions
+    #[inline]
+    fn instruction_at(&self, pc: usize) -> Option<Instruction> {
+        match self.frames.last()?.proto() {
+            Some(proto) => proto.quickened.get(pc),
+            None => self.current_chunk().instructions().get(pc).copied(),
+        }
     }
 
     fn pop_stack(&mut self) -> RuntimeResult<Value> {
@@ -591,30 +1167,27 @@ impl Vm {
         }
     }
 
-    #[allow(dead_code)]
-    fn print_call_stack(&self) {
-        debug!("**********Call stack**********");
-        for frame in &self.frames {
-            debug!("{:#?}", frame);
-        }
+    fn set_compiled_source(&mut self, source: &CompiledSource) {
+        self.compiled = Some(source.clone());
+        self.memory_base = memory::allocated();
+        self.reset_tasks();
     }
 
-    #[allow(dead_code)]
-    fn print_stack(&self) {
-        debug!("**********STACK LEN: {}**********", self.stack.len());
-        for value in self.stack.iter() {
-            debug!("{}", &value);
+    fn sample_memory(&mut self) -> RuntimeResult<()> {
+        let used = (memory::allocated() - self.memory_base).max(0) as usize;
+        self.stats.peak_memory = self.stats.peak_memory.max(used);
+        match self.memory_limit {
+            Some(limit) if used > limit => Err(RuntimeError::OutOfMemory { limit }),
+            _ => Ok(()),
         }
-        debug!("**********STACK END**********");
     }
 
-    #[allow(dead_code)]
-    fn print_globals(&self) {
-        debug!("{:#?}", self.globals);
-    }
-
-    fn set_compiled_source(&mut self, source: CompiledSource) {
-        self.compiled = Some(source);
+    #[cfg(feature = "std")]
+    fn check_deadline(&self) -> RuntimeResult<()> {
+        match self.deadline {
+            Some((at, limit)) if Instant::now() >= at => Err(RuntimeError::Timeout { limit }),
+            _ => Ok(()),
+        }
     }
 
     #[inline]
@@ -629,16 +1202,71 @@ impl Vm {
 
 impl Default for Vm {
     fn default() -> Self {
-        Vm {
-            frames: Vec::new(),
-            stack: Vec::new(),
+        let mut vm = Vm {
+            // Frames only hold a function and two offsets, popped ones leave their
+            // storage to the next call
+            frames: Vec::with_capacity(INITIAL_FRAMES),
+            stack: Vec::with_capacity(INITIAL_STACK),
             compiled: None,
+            profiler: None,
+            coverage: None,
+            trace: None,
+            output: None,
+            debugger: None,
+            blocking: 0,
+            yielded: None,
+            paused: false,
+            stats: Stats::default(),
+            tests: Vec::new(),
+            memory_limit: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            sandboxed: false,
+            #[cfg(feature = "signals")]
+            signal_handlers: Vec::new(),
+            memory_base: 0,
+            scheduler: Scheduler::default(),
+            #[cfg(feature = "jit")]
+            jit: Some(jit::THRESHOLD),
             // current_chunk: None,
             // constant_table: None,
             globals: PREDEFINED_CONSTANTS
                 .iter()
                 .map(|(s, f)| (Value::Embedded(s), f.clone()))
+                .chain(core::iter::once((
+                    Value::Embedded(lib::ARGS),
+                    Table::new().into(),
+                )))
+                .chain(core::iter::once((
+                    Value::Embedded(lib::TEST),
+                    lib::test_table().into(),
+                )))
+                .chain(std_globals())
                 .collect(),
-        }
+            globals_table: Table::new().shared(),
+        };
+        // _G holds the other globals but not itself
+        vm.globals_table = Table::from_map(vm.globals.clone()).shared();
+        vm.globals.insert(
+            Value::Embedded(lib::GLOBALS),
+            Value::Table(Rc::clone(&vm.globals_table)),
+        );
+        vm
     }
 }
+
+// The globals talking to the operating system: workers, sockets, programs and
+// signals
+fn std_globals() -> Vec<(Value, Value)> {
+    #[cfg_attr(not(feature = "std"), allow(unused_mut))]
+    let mut globals = Vec::new();
+    #[cfg(feature = "std")]
+    globals.extend([
+        (Value::Embedded(lib::WORKER), lib::worker_table(None).into()),
+        (Value::Embedded(lib::NET), net::net_table().into()),
+        (Value::Embedded(lib::PROC), process::proc_table().into()),
+    ]);
+    #[cfg(feature = "signals")]
+    globals.push((Value::Embedded(lib::OS), lib::os_table().into()));
+    globals
+}
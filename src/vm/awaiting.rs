@@ -0,0 +1,240 @@
+// Running the vm inside an async runtime. Async natives return a future, the task
+// calling one waits for it like for a channel, and the vm is polled with
+// Vm::run_until_yield so waiting tasks don't block the executor's thread
+use super::scheduler::Channel;
+use super::{ArgsLen, CompiledSource, Function, NativeFunction, RuntimeError, RuntimeResult};
+use super::{Value, Vm};
+use std::cell::RefCell;
+use std::fmt;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+pub type NativeFuture = Pin<Box<dyn Future<Output = RuntimeResult<Value>>>>;
+
+// A future a task waits for, its value goes to the channel the task receives from
+#[derive(Clone)]
+pub(super) struct Awaiting {
+    channel: Channel,
+    future: Rc<RefCell<NativeFuture>>,
+}
+
+impl fmt::Debug for Awaiting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Awaiting")
+    }
+}
+
+impl PartialEq for Awaiting {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.future, &other.future)
+    }
+}
+
+// The waker of the task running Vm::run_until_yield
+#[derive(Clone, Debug)]
+pub(super) struct Executor(Waker);
+
+impl Executor {
+    pub fn wake_by_ref(&self) {
+        self.0.wake_by_ref()
+    }
+}
+
+impl PartialEq for Executor {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.will_wake(&other.0)
+    }
+}
+
+// Unparks the thread waiting in Vm::run
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark()
+    }
+}
+
+impl Vm {
+    // Defines the global 'name' as a native returning the future of 'function',
+    // called with the arguments in order. The task calling it waits for the future
+    // while the other tasks run. Functions called by natives can't wait, calling
+    // it there fails
+    pub fn register_async_native<F>(&mut self, name: &str, args_len: ArgsLen, function: F)
+    where
+        F: Fn(Vec<Value>) -> NativeFuture + 'static,
+    {
+        let native = NativeFunction::host(args_len, move |vm, args| vm.wait_for(function(args)));
+        self.set_global(name, Value::Function(Function::Native(native)));
+    }
+
+    fn wait_for(&mut self, future: NativeFuture) -> RuntimeResult<Value> {
+        if self.blocking > 0 {
+            return Err(RuntimeError::Native(
+                "async natives can't be called by functions natives run".to_owned(),
+            ));
+        }
+        let channel: Channel = Rc::default();
+        self.scheduler.awaiting.push(Awaiting {
+            channel: Rc::clone(&channel),
+            future: Rc::new(RefCell::new(future)),
+        });
+        self.receive(channel)
+    }
+
+    // Polls the futures tasks wait for, the ones that finished wake their task
+    pub(super) fn poll_awaiting(&mut self) -> RuntimeResult<()> {
+        if self.scheduler.awaiting.is_empty() {
+            return Ok(());
+        }
+        let waker = match &self.scheduler.executor {
+            Some(executor) => executor.0.clone(),
+            None => Waker::from(Arc::new(ThreadWaker(thread::current()))),
+        };
+        let mut cx = Context::from_waker(&waker);
+        let mut i = 0;
+        while i < self.scheduler.awaiting.len() {
+            let poll = self.scheduler.awaiting[i]
+                .future
+                .borrow_mut()
+                .as_mut()
+                .poll(&mut cx);
+            match poll {
+                Poll::Ready(result) => {
+                    let awaiting = self.scheduler.awaiting.remove(i);
+                    awaiting.channel.borrow_mut().push_back(result?);
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the source given to Vm::start until the running task yields, sleeps or
+    // waits. Pending means the script isn't finished, 'cx' is woken once it can go
+    // on: right away after a yield, by the futures of async natives otherwise.
+    // Sleeping tasks have no timer to wake them, the vm is polled again right away
+    pub fn run_until_yield(&mut self, cx: &mut Context<'_>) -> Poll<RuntimeResult<Value>> {
+        self.scheduler.executor = Some(Executor(cx.waker().clone()));
+        let result = self.poll_tasks();
+        self.scheduler.executor = None;
+        match result {
+            Ok(Some(value)) => Poll::Ready(Ok(value)),
+            Ok(None) => Poll::Pending,
+            Err(error) => Poll::Ready(Err(error)),
+        }
+    }
+
+    fn poll_tasks(&mut self) -> RuntimeResult<Option<Value>> {
+        // Every task waited the last time
+        if self.frames.is_empty() {
+            self.run_next_task()?;
+            if self.frames.is_empty() {
+                return Ok(None);
+            }
+        }
+        loop {
+            self.execute()?;
+            let switched = self.scheduler.switch.is_some();
+            if let Some(value) = self.after_execute()? {
+                return Ok(Some(value));
+            }
+            if self.frames.is_empty() {
+                return Ok(None);
+            }
+            if switched {
+                if let Some(executor) = &self.scheduler.executor {
+                    executor.wake_by_ref();
+                }
+                return Ok(None);
+            }
+        }
+    }
+
+    // The source run as a future, see Vm::run_until_yield. The vm isn't Send, with
+    // tokio it runs in a LocalSet
+    pub fn run_async<'a>(
+        &'a mut self,
+        source: &CompiledSource,
+    ) -> impl Future<Output = RuntimeResult<Value>> + 'a {
+        self.start(source);
+        future::poll_fn(move |cx| self.run_until_yield(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+
+    // Pending the first time it's polled, woken from another thread
+    struct Later {
+        value: Value,
+        polled: bool,
+    }
+
+    impl Future for Later {
+        type Output = RuntimeResult<Value>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.polled {
+                return Poll::Ready(Ok(self.value.clone()));
+            }
+            self.polled = true;
+            let waker = cx.waker().clone();
+            thread::spawn(move || waker.wake());
+            Poll::Pending
+        }
+    }
+
+    fn vm_and_source(source: &str) -> (Vm, CompiledSource) {
+        let mut vm = Vm::new();
+        vm.register_async_native("fetch", ArgsLen::Exact(1), |args| {
+            Box::pin(Later {
+                value: args[0].clone(),
+                polled: false,
+            })
+        });
+        let ast = Parser::new(source)
+            .with_globals(vm.global_names())
+            .parse_all()
+            .unwrap();
+        let compiled = Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap();
+        (vm, compiled)
+    }
+
+    #[test]
+    fn tasks_wait_for_async_natives() {
+        let source = "
+        var done = channel();
+        spawn(fn() done.send(fetch(1)); end);
+        fetch(2) * 10 + done.receive()
+        ";
+        let (mut vm, compiled) = vm_and_source(source);
+        vm.start(&compiled);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pending = 0;
+        let value = loop {
+            match vm.run_until_yield(&mut cx) {
+                Poll::Ready(value) => break value,
+                Poll::Pending => pending += 1,
+            }
+        };
+        assert_eq!(value, Ok(Value::Int(21)));
+        assert!(pending > 0);
+
+        // Vm::run blocks the thread until the futures are done
+        let (mut vm, compiled) = vm_and_source(source);
+        assert_eq!(vm.run(&compiled), Ok(Value::Int(21)));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::compiler::{CompiledSource, FuncProto, Instruction};
+use alloc::collections::BTreeMap;
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use hashbrown::HashSet;
+
+// Execution counts of the source lines of the root module, see Vm::run_with_coverage
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    // Prototypes compiled from the root module, imported modules share the
+    // constant table but are not covered
+    functions: HashSet<*const FuncProto>,
+    hits: BTreeMap<usize, u64>,
+    // Line of the last instruction in each active call, so a line is hit once
+    // per run of its instructions even if it calls other functions
+    last: Vec<usize>,
+}
+
+impl Coverage {
+    // Every line that produced an instruction starts with no hits
+    pub fn new(source: &CompiledSource) -> Self {
+        let prototypes = &source.constant_table.prototypes;
+        let mut coverage = Coverage {
+            functions: HashSet::new(),
+            hits: BTreeMap::new(),
+            last: Vec::new(),
+        };
+        // The main chunk only calls the module body, its lines are not counted
+        let mut code = vec![source.chunk.instructions()];
+        while let Some(instructions) = code.pop() {
+            for instr in instructions.iter() {
+                if let Instruction::FuncDef { proto_index, .. } = instr {
+                    let proto = &prototypes[*proto_index as usize];
+                    if coverage.functions.insert(proto.as_ref()) {
+                        let lines = proto.debug_info.lines().iter();
+                        coverage.hits.extend(lines.map(|line| (*line, 0)));
+                        code.push(&proto.instructions);
+                    }
+                }
+            }
+        }
+        coverage
+    }
+
+    // 'depth' is the number of call frames, starting from 1
+    #[inline]
+    pub(super) fn hit(&mut self, function: *const FuncProto, depth: usize, line: usize) {
+        self.last.truncate(depth);
+        if self.last.len() < depth {
+            self.last.resize(depth, 0);
+        } else if self.last[depth - 1] == line {
+            return;
+        }
+        self.last[depth - 1] = line;
+        if self.functions.contains(&function) {
+            if let Some(hits) = self.hits.get_mut(&line) {
+                *hits += 1;
+            }
+        }
+    }
+
+    // Executable lines and how many times they ran
+    pub fn lines(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.hits.iter().map(|(line, hits)| (*line, *hits))
+    }
+
+    pub fn hits(&self, line: usize) -> Option<u64> {
+        self.hits.get(&line).copied()
+    }
+
+    pub fn lines_found(&self) -> usize {
+        self.hits.len()
+    }
+
+    pub fn lines_hit(&self) -> usize {
+        self.hits.values().filter(|hits| **hits > 0).count()
+    }
+
+    // Report in the lcov tracefile format, 'path' is the covered source file
+    pub fn to_lcov(&self, path: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", path).unwrap();
+        for (line, hits) in self.lines() {
+            writeln!(out, "DA:{},{}", line, hits).unwrap();
+        }
+        writeln!(out, "LF:{}", self.lines_found()).unwrap();
+        writeln!(out, "LH:{}", self.lines_hit()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
@@ -0,0 +1,92 @@
+use super::Value;
+use alloc::{string::String, vec::Vec};
+use hashbrown::{HashMap, HashSet};
+
+// How far Vm::resume runs before pausing again, breakpoints pause in every mode
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Resume {
+    Continue,
+    // Next statement, in any function
+    StepIn,
+    // Next statement of the current function or its callers
+    StepOver,
+    // Next statement of a caller
+    StepOut,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunState {
+    Paused { line: usize },
+    Finished(Value),
+}
+
+// A call frame of the paused vm, locals are in slot order
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameView {
+    pub name: String,
+    // Source the function was compiled from, if it was compiled from a file
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub locals: Vec<(String, Value)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) struct Debugger {
+    // Lines by file, sources compiled without a path use the empty name
+    pub breakpoints: HashMap<String, HashSet<usize>>,
+    // Set while Vm::resume runs, with the call depth it started from
+    pub resume: Option<(Resume, usize)>,
+    // Call depth and pc of the statement the vm paused at, resuming runs it
+    // without pausing again
+    pub paused_at: Option<(usize, usize)>,
+}
+
+impl Debugger {
+    // Called at the first instruction of every statement
+    pub fn should_pause(
+        &mut self,
+        depth: usize,
+        pc: usize,
+        file: Option<&str>,
+        line: usize,
+    ) -> bool {
+        let (resume, start_depth) = match self.resume {
+            Some(resume) => resume,
+            None => return false,
+        };
+        if self.paused_at.take() == Some((depth, pc)) {
+            return false;
+        }
+        let stepping = match resume {
+            Resume::Continue => false,
+            Resume::StepIn => true,
+            Resume::StepOver => depth <= start_depth,
+            Resume::StepOut => depth < start_depth,
+        };
+        if stepping || self.has_breakpoint(file, line) {
+            self.paused_at = Some((depth, pc));
+            true
+        } else {
+            false
+        }
+    }
+
+    // A breakpoint set on a relative path matches the files ending with it
+    fn has_breakpoint(&self, file: Option<&str>, line: usize) -> bool {
+        self.breakpoints.iter().any(|(name, lines)| {
+            let matches = match file {
+                Some(file) => !name.is_empty() && ends_with_path(file, name),
+                None => name.is_empty(),
+            };
+            matches && lines.contains(&line)
+        })
+    }
+}
+
+// Whether the last components of the path are the ones of 'name', like Path::ends_with
+fn ends_with_path(path: &str, name: &str) -> bool {
+    match path.strip_suffix(name) {
+        Some(rest) => rest.is_empty() || rest.ends_with(['/', '\\']),
+        None => false,
+    }
+}
@@ -0,0 +1,248 @@
+use super::{BinaryInstr, Function, Instruction, Integer, RuntimeError, RuntimeResult, Value, Vm};
+
+// What the loop does with the pc after an instruction ran
+pub(crate) enum Step {
+    Next,
+    // Calls already moved it
+    Stay,
+    // The frame returned
+    Return,
+}
+
+impl Vm {
+    // The body of every instruction, the dispatch strategies only differ in how they get here
+    #[inline(always)]
+    pub(super) fn run_instruction(&mut self, instr: Instruction) -> RuntimeResult<Step> {
+        match instr {
+            Instruction::Nil => self.stack.push(Value::Nil),
+            Instruction::Unit => self.stack.push(Value::Unit),
+            Instruction::True => self.stack.push(Value::Bool(true)),
+            Instruction::False => self.stack.push(Value::Bool(false)),
+            Instruction::Constant { index } => {
+                let value = self.constant_table()[index as usize].clone();
+                self.stack.push(value)
+            }
+            Instruction::Pop => {
+                self.pop_stack()?;
+            }
+            Instruction::Return { return_value } => {
+                let value = if return_value {
+                    self.pop_stack()?
+                } else {
+                    Value::Unit
+                };
+                while self.stack.len() > self.current_frame()?.stack_top() {
+                    self.pop_stack()?;
+                }
+                self.stack.push(value);
+                self.frames.pop().expect("Stack frame is empty");
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.exit();
+                }
+                return Ok(Step::Return);
+            }
+            Instruction::Bin(bin) => self.binary_quickening(bin)?,
+            Instruction::Unary(unary) => self.unary(unary)?,
+            Instruction::GetGlobal { index } => {
+                let value = self.get_global(index)?;
+                self.stack.push(value)
+            }
+            Instruction::SetGlobal { index } => {
+                let name = self.constant_table()[index as usize].clone();
+                let value = self.stack.pop().unwrap();
+                self.insert_global(name, value);
+            }
+            Instruction::GetLocal { index, frame } => {
+                let frame_index = self.frame_from_offset(frame);
+                let index = self.frames[frame_index].stack_top() + index as usize;
+                self.stack.push(self.stack[index].clone());
+            }
+            Instruction::SetLocal { index, frame } => {
+                let frame_index = self.frame_from_offset(frame);
+                let index = self.frames[frame_index].stack_top() + index as usize;
+                if self.stack.len() != index {
+                    self.stack[index] = self.pop_stack()?;
+                }
+            }
+            Instruction::Jump { offset } => self.jump(offset)?,
+            Instruction::JumpIf { offset, when_true } => {
+                let value = self.pop_stack()?;
+                if value.as_bool() == when_true {
+                    self.jump(offset)?;
+                }
+            }
+            Instruction::InitTable { len, has_keys } => self.init_table(len, has_keys)?,
+            Instruction::TableTemplate { index } => self.table_template(index)?,
+            Instruction::GetField => self.get_field()?,
+            Instruction::GetFieldImm { index } => self.get_field_imm(index)?,
+            Instruction::GetMethodImm {
+                index,
+                table_stack_index,
+            } => self.get_method_imm(index, table_stack_index)?,
+            Instruction::SetField => self.set_field()?,
+            Instruction::SetFieldImm { index } => self.set_field_imm(index)?,
+            Instruction::Print => {
+                let value = self.pop_stack()?;
+                self.write_output(format_args!("{}\n", value))?
+            }
+            Instruction::Echo => match self.pop_stack()? {
+                Value::Unit => (),
+                value => self.write_output(format_args!("{}\n", value))?,
+            },
+            Instruction::Tuple { len } => {
+                let start = self
+                    .stack
+                    .len()
+                    .checked_sub(len as usize)
+                    .ok_or(RuntimeError::EmptyStack)?;
+                let tuple = Value::Tuple(self.stack.drain(start..).collect());
+                self.stack.push(tuple)
+            }
+            Instruction::FuncDef {
+                proto_index,
+                has_env,
+            } => {
+                let proto = self.prototypes()[proto_index as usize].clone();
+                let function = Value::Function(if has_env {
+                    let env = self
+                        .pop_stack()?
+                        .into_table()
+                        .expect("Expected a table as env");
+                    Function::new_user_with_env(proto, env)
+                } else {
+                    Function::new_user(proto)
+                });
+                self.stack.push(function)
+            }
+            Instruction::Call { args_len } => {
+                let function = self.pop_stack()?;
+                self.call_value(function, args_len)?;
+                return Ok(Step::Stay); // Don't increment pc
+            }
+            Instruction::Integer(value) => self.stack.push(value.into()),
+            Instruction::Import { name_index } => self.import(name_index as usize)?,
+            Instruction::ExitBlock { pop, return_value } => {
+                let return_value = if return_value {
+                    let value = self.pop_stack()?;
+                    Some(value)
+                } else {
+                    None
+                };
+                for _ in 0..pop {
+                    self.pop_stack()?;
+                }
+                if let Some(value) = return_value {
+                    self.stack.push(value);
+                }
+            }
+            Instruction::Rec => {
+                let frame = self.frames.last().expect("Expected a call frame");
+                let func = frame
+                    .function()
+                    .expect("Expected call has a function")
+                    .clone();
+                self.stack.push(func.into());
+            }
+            // The value is taken by Vm::resume_generator, the frame stays to resume it
+            Instruction::Yield => {
+                self.yielded = Some(self.pop_stack()?);
+                self.stack.push(Value::Unit);
+                return Ok(Step::Return);
+            }
+            Instruction::Cast { index } => {
+                let value = self.pop_stack()?;
+                let value = value.cast(&self.constant_table()[index as usize])?;
+                self.stack.push(value);
+            }
+            // The pc is moved to the last instruction of the sequence before it runs,
+            // so errors and calls see the same pc as without the superinstruction
+            Instruction::BinLocals { op, a, b, frame } => {
+                let top = self.frames[self.frame_from_offset(frame)].stack_top();
+                let left = self.stack[top + a as usize].clone();
+                let right = self.stack[top + b as usize].clone();
+                self.current_frame_mut()?.pc += 2;
+                self.release_assigned_local(op, &left, &right);
+                let value = Self::binary_value(op, left, right)?;
+                self.stack.push(value)
+            }
+            Instruction::BinLocalInt {
+                op,
+                index,
+                frame,
+                value,
+            } => {
+                let top = self.frames[self.frame_from_offset(frame)].stack_top();
+                let left = self.stack[top + index as usize].clone();
+                self.current_frame_mut()?.pc += 2;
+                let right = value as Integer;
+                // The operators the compiler specializes for integers
+                let value = match (op, left) {
+                    (BinaryInstr::Add, Value::Int(a)) => Value::Int(a + right),
+                    (BinaryInstr::Sub, Value::Int(a)) => Value::Int(a - right),
+                    (BinaryInstr::Lt, Value::Int(a)) => Value::Bool(a < right),
+                    (op, left) => Self::binary_value(op, left, Value::Int(right))?,
+                };
+                self.stack.push(value)
+            }
+            Instruction::CallGlobal { index, args_len } => {
+                let function = self.get_global(index)?;
+                self.current_frame_mut()?.pc += 1;
+                self.call_value(function, args_len)?;
+                return Ok(Step::Stay);
+            }
+            Instruction::CallFieldImm { index, args_len } => {
+                self.get_field_imm(index)?;
+                self.current_frame_mut()?.pc += 1;
+                let function = self.pop_stack()?;
+                self.call_value(function, args_len)?;
+                return Ok(Step::Stay);
+            }
+            Instruction::AddInt => self.binary_int(BinaryInstr::Add, |a, b| Value::Int(a + b))?,
+            Instruction::SubInt => self.binary_int(BinaryInstr::Sub, |a, b| Value::Int(a - b))?,
+            Instruction::LtInt => self.binary_int(BinaryInstr::Lt, |a, b| Value::Bool(a < b))?,
+            Instruction::AddFloat => {
+                self.binary_float(BinaryInstr::Add, |a, b| Value::Number(a + b))?
+            }
+            Instruction::SubFloat => {
+                self.binary_float(BinaryInstr::Sub, |a, b| Value::Number(a - b))?
+            }
+            Instruction::LtFloat => {
+                self.binary_float(BinaryInstr::Lt, |a, b| Value::Bool(a < b))?
+            }
+            _ => return Err(RuntimeError::UnsupportedInstruction(instr)),
+        }
+        Ok(Step::Next)
+    }
+}
+
+#[cfg(feature = "threaded")]
+pub(crate) type Handler = fn(&mut Vm, Instruction) -> RuntimeResult<Step>;
+
+// A handler for each opcode. It runs run_instruction on an instruction of a known
+// variant, so the other arms of its match are left out
+macro_rules! handlers {
+    ($($variant:ident),* $(,)?) => {
+        #[cfg(feature = "threaded")]
+        pub(crate) fn handler(instr: &Instruction) -> Handler {
+            match instr {
+                $(Instruction::$variant { .. } => {
+                    fn handle(vm: &mut Vm, instr: Instruction) -> RuntimeResult<Step> {
+                        match instr {
+                            Instruction::$variant { .. } => vm.run_instruction(instr),
+                            _ => unreachable!(),
+                        }
+                    }
+                    handle
+                })*
+            }
+        }
+    };
+}
+
+handlers! {
+    Bin, Unary, Nil, Unit, True, False, Constant, SetGlobal, GetGlobal, SetLocal, GetLocal,
+    GetMethodImm, GetFieldImm, GetField, SetFieldImm, SetField, Pop, Return, Tuple, InitTable,
+    TableTemplate, JumpIf, Jump, Placeholder, Print, Echo, FuncDef, Call, GetUpval, SetUpval,
+    CloseUpval, Integer, Import, ExitBlock, Rec, BinLocals, BinLocalInt, CallGlobal, CallFieldImm,
+    AddInt, SubInt, LtInt, AddFloat, SubFloat, LtFloat, Yield, Cast,
+}
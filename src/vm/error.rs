@@ -1,6 +1,8 @@
 use crate::compiler::{BinaryInstr, Instruction};
 use crate::error::FluxError;
-use crate::vm::Value;
+use crate::vm::{Integer, Value};
+use alloc::{boxed::Box, string::String};
+use core::time::Duration;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum RuntimeError {
@@ -8,13 +10,87 @@ pub enum RuntimeError {
     EmptyFrame,
     UnsupportedInstruction(Instruction),
     EmptyStack,
-    UndefinedVariable { name: String },
-    UnsupportedBinary { value: Value, op: BinaryInstr },
+    UndefinedVariable {
+        name: String,
+        // A defined global it may be a typo of
+        suggestion: Option<String>,
+    },
+    UnsupportedBinary {
+        value: Value,
+        op: BinaryInstr,
+    },
     IOError,
     InvalidFormat,
-    WrongNumberOfArgs { expected: u8, found: u8 },
+    WrongNumberOfArgs {
+        expected: u8,
+        found: u8,
+    },
     ExpectedArgsAtLeast(u8),
     DivideByZero,
+    // A tuple has no element at the index
+    IndexOutOfRange {
+        index: Integer,
+        len: usize,
+    },
     AssertionFailed(Value),
-    ImportError { error: FluxError, module: String },
+    // 'as' or assert_type found a value of another type, see Value::cast
+    WrongType {
+        expected: String,
+        found: String,
+    },
+    // The name given to assert_type isn't a type
+    UnknownType(String),
+    // Set a field of an enum table
+    FrozenTable {
+        key: Value,
+    },
+    // A native registered by the host failed, with its message
+    Native(String),
+    // Every task waits on a channel nothing can send to anymore
+    Deadlock,
+    // The generator was resumed from its own body
+    GeneratorRunning,
+    // The native isn't allowed by Vm::set_sandboxed
+    Sandboxed(String),
+    // Stopped by the signal to run its handler, see Vm::on_signal
+    Interrupted(&'static str),
+    // Over the limit of Vm::set_memory_limit
+    OutOfMemory {
+        limit: usize,
+    },
+    // Vm::set_memory_limit without TrackingAllocator as the global allocator
+    MemoryUntracked,
+    // Over the timeout of Vm::run_with_timeout
+    Timeout {
+        limit: Duration,
+    },
+    ImportError {
+        error: Box<FluxError>,
+        module: String,
+    },
+    // The instruction the error happened at, 'frame' is the index of its call frame
+    // from the outermost one
+    At {
+        pc: usize,
+        instr: Instruction,
+        frame: usize,
+        source: Box<RuntimeError>,
+    },
+}
+
+impl RuntimeError {
+    // The error without where it happened, to match on its kind
+    pub fn cause(&self) -> &RuntimeError {
+        match self {
+            RuntimeError::At { source, .. } => source.cause(),
+            error => error,
+        }
+    }
+
+    pub fn into_cause(self) -> RuntimeError {
+        match self {
+            RuntimeError::At { source, .. } => source.into_cause(),
+            error => error,
+        }
+    }
 }
@@ -16,6 +16,10 @@ impl Frame {
         }
     }
 
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
     pub fn stack_top(&self) -> usize {
         self.stack_top
     }
@@ -0,0 +1,106 @@
+use super::{
+    ArgsLen, Frame, Function, NativeFunction, RuntimeError, RuntimeResult, UserFunction, Value, Vm,
+};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::mem;
+
+// The call of a function with a yield, suspended between two values. It runs on
+// its own stack and frames like the tasks of the scheduler, swapped in while the
+// iterator resumes it
+#[derive(Default)]
+struct Generator {
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    running: bool,
+    done: bool,
+}
+
+impl Vm {
+    // Calling a generator only takes its arguments, the iterator pushed in place of
+    // the result runs the body up to each yield
+    pub(super) fn call_generator(
+        &mut self,
+        function: UserFunction,
+        pushed_args: u8,
+    ) -> RuntimeResult<()> {
+        if pushed_args != function.args_len() {
+            return Err(RuntimeError::WrongNumberOfArgs {
+                expected: function.args_len(),
+                found: pushed_args,
+            });
+        }
+        let start = self.stack.len() - pushed_args as usize;
+        let mut stack: Vec<Value> = self.stack.drain(start..).collect();
+        if let Some(env) = function.env() {
+            stack.push(Rc::clone(env).into())
+        }
+        let generator = Rc::new(RefCell::new(Generator {
+            stack,
+            frames: vec![Frame::new(0, function, 0)],
+            ..Generator::default()
+        }));
+        let next = NativeFunction::host(ArgsLen::Exact(0), move |vm, _| {
+            vm.resume_generator(&generator)
+        });
+        self.stack.push(Value::Function(Function::Native(next)));
+        Ok(())
+    }
+
+    // The next value the generator yields, nil once its function returned. It runs
+    // like the functions natives call, the other tasks wait until it yields
+    fn resume_generator(&mut self, generator: &Rc<RefCell<Generator>>) -> RuntimeResult<Value> {
+        let (stack, frames) = {
+            let mut generator = generator.borrow_mut();
+            if generator.done {
+                return Ok(Value::Nil);
+            }
+            if generator.running {
+                return Err(RuntimeError::GeneratorRunning);
+            }
+            generator.running = true;
+            (
+                mem::take(&mut generator.stack),
+                mem::take(&mut generator.frames),
+            )
+        };
+        let stack = mem::replace(&mut self.stack, stack);
+        let frames = mem::replace(&mut self.frames, frames);
+        self.blocking += 1;
+        let result = self.run_generator();
+        self.blocking -= 1;
+        let mut generator = generator.borrow_mut();
+        generator.stack = mem::replace(&mut self.stack, stack);
+        generator.frames = mem::replace(&mut self.frames, frames);
+        generator.running = false;
+        generator.done = !matches!(result, Ok(Some(_)));
+        result.map(|value| value.unwrap_or(Value::Nil))
+    }
+
+    // Runs the swapped in frames until a yield, None once they all returned. The
+    // profiler counts each resume as a call
+    fn run_generator(&mut self) -> RuntimeResult<Option<Value>> {
+        if self.profiler.is_some() {
+            let proto = self.frames[0].proto().expect("Expected a generator");
+            let index = self.prototypes().iter().position(|p| Rc::ptr_eq(p, proto));
+            if let Some(profiler) = &mut self.profiler {
+                profiler.enter(index);
+            }
+        }
+        loop {
+            self.execute()?;
+            if let Some(value) = self.yielded.take() {
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.exit();
+                }
+                self.current_frame_mut()?.pc += 1;
+                return Ok(Some(value));
+            }
+            if self.frames.is_empty() {
+                return Ok(None);
+            }
+            self.current_frame_mut()?.pc += 1;
+        }
+    }
+}
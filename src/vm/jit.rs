@@ -0,0 +1,1001 @@
+use super::{BinaryInstr, Float, Instruction, Integer, UnaryInstr, UserFunction, Value, Vm};
+use crate::compiler::FuncProto;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{types, AbiParam, Block, FuncRef, InstBuilder, MemFlags, Type};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+// Calls of a prototype before it's compiled, see Vm::set_jit_threshold
+pub const THRESHOLD: u32 = 1000;
+// Native code giving up this many times is dropped, the prototype stays interpreted
+const MAX_BAILS: u32 = 16;
+// Recursive calls the native code makes before it gives up, the interpreter's
+// frames are on the heap but these are on the thread's stack
+const MAX_DEPTH: i64 = 1000;
+
+// Compiles the prototypes that keep getting called to native code with Cranelift.
+// Only prototypes working on integers, floats and booleans in their own locals,
+// and calling nothing but themselves, are compiled, for the types of the
+// arguments of the call that made them hot. Such code has no side effects, so
+// wherever it can't do what the interpreter does (an overflow, an integer
+// division giving a float, a division by zero, a deep recursion) it gives up
+// and the call runs again in the interpreter
+#[derive(Default)]
+pub(crate) struct Jit {
+    calls: Cell<u32>,
+    bails: Cell<u32>,
+    code: RefCell<Code>,
+}
+
+#[derive(Default)]
+enum Code {
+    #[default]
+    Interpreted,
+    Native(Rc<Native>),
+    // Has an instruction the jit doesn't compile, or gave up too often
+    Unsupported,
+}
+
+// A copy counts the calls again
+impl Clone for Jit {
+    fn clone(&self) -> Self {
+        Jit::default()
+    }
+}
+
+// Derived from the instructions, which are compared instead
+impl PartialEq for Jit {
+    fn eq(&self, _: &Jit) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Jit({})", self.calls.get())
+    }
+}
+
+impl Jit {
+    #[cfg(test)]
+    pub fn is_compiled(&self) -> bool {
+        matches!(*self.code.borrow(), Code::Native(_))
+    }
+}
+
+// What a stack slot holds, for the code compiled for it
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Ty {
+    Int,
+    Float,
+    Bool,
+    Unit,
+    // The function itself, pushed by Rec
+    Rec,
+}
+
+const TYS: usize = 5;
+
+impl Ty {
+    fn of(value: &Value) -> Option<Ty> {
+        match value {
+            Value::Int(_) => Some(Ty::Int),
+            Value::Number(_) => Some(Ty::Float),
+            Value::Bool(_) => Some(Ty::Bool),
+            Value::Unit => Some(Ty::Unit),
+            _ => None,
+        }
+    }
+
+    fn is_number(self) -> bool {
+        self == Ty::Int || self == Ty::Float
+    }
+
+    fn clif(self) -> Type {
+        match self {
+            Ty::Int => types::I64,
+            Ty::Float => types::F64,
+            Ty::Bool | Ty::Unit | Ty::Rec => types::I8,
+        }
+    }
+
+    // Arguments and results cross into the native code as 64 bits
+    fn to_bits(value: &Value) -> u64 {
+        match value {
+            Value::Int(i) => *i as u64,
+            Value::Number(n) => n.to_bits(),
+            Value::Bool(b) => *b as u64,
+            _ => 0,
+        }
+    }
+
+    fn value(self, bits: u64) -> Value {
+        match self {
+            Ty::Int => Value::Int(bits as Integer),
+            Ty::Float => Value::Number(Float::from_bits(bits)),
+            Ty::Bool => Value::Bool(bits != 0),
+            Ty::Unit | Ty::Rec => Value::Unit,
+        }
+    }
+}
+
+type Entry = unsafe extern "C" fn(*const u64, *mut u64) -> u8;
+
+struct Native {
+    // Owns the memory of the code, freed with it
+    module: Option<JITModule>,
+    entry: Entry,
+    params: Box<[Ty]>,
+    ret: Ty,
+}
+
+impl Native {
+    // None when the arguments have other types than the ones compiled for or
+    // the code gave up
+    fn call(&self, args: &[Value]) -> Option<Value> {
+        if !args
+            .iter()
+            .map(Ty::of)
+            .eq(self.params.iter().map(|ty| Some(*ty)))
+        {
+            return None;
+        }
+        let args: Vec<u64> = args.iter().map(Ty::to_bits).collect();
+        let mut result = 0;
+        // The entry was compiled for these many arguments of these types
+        let ok = unsafe { (self.entry)(args.as_ptr(), &mut result) };
+        if ok != 0 {
+            Some(self.ret.value(result))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Native {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // Nothing points into the code anymore, the entry goes with it
+            unsafe { module.free_memory() }
+        }
+    }
+}
+
+impl Vm {
+    // Compiles a prototype once it was called more than 'threshold' times,
+    // None interprets everything. The jit is also off while the script is
+    // traced, profiled, covered, debugged, limited in memory or time or
+    // handles signals, the native code doesn't stop between instructions
+    pub fn set_jit_threshold(&mut self, threshold: Option<u32>) {
+        self.jit = threshold;
+    }
+
+    fn jit_threshold(&self) -> Option<u32> {
+        let observed = self.trace.is_some()
+            || self.profiler.is_some()
+            || self.coverage.is_some()
+            || self.debugger.is_some()
+            || self.memory_limit.is_some()
+            || self.deadline.is_some();
+        #[cfg(feature = "signals")]
+        let observed = observed || !self.signal_handlers.is_empty();
+        if observed {
+            None
+        } else {
+            self.jit
+        }
+    }
+
+    // Runs the call in native code, replacing the arguments with the result like
+    // a native does. False leaves the call to the interpreter
+    pub(super) fn call_compiled(&mut self, function: &UserFunction, pushed_args: u8) -> bool {
+        let threshold = match self.jit_threshold() {
+            Some(threshold) => threshold,
+            None => return false,
+        };
+        let proto = function.proto_ref();
+        if pushed_args != function.args_len() || function.env().is_some() || proto.generator {
+            return false;
+        }
+        let constants = match &self.compiled {
+            Some(compiled) => &compiled.constant_table.constants,
+            None => return false,
+        };
+        let start = self.stack.len() - pushed_args as usize;
+        let jit = &proto.jit;
+        let calls = jit.calls.get().saturating_add(1);
+        jit.calls.set(calls);
+        if calls > threshold && matches!(*jit.code.borrow(), Code::Interpreted) {
+            let code = match compile(proto, &self.stack[start..], constants) {
+                Some(native) => Code::Native(Rc::new(native)),
+                None => Code::Unsupported,
+            };
+            *jit.code.borrow_mut() = code;
+        }
+        let native = match &*jit.code.borrow() {
+            Code::Native(native) => Rc::clone(native),
+            _ => return false,
+        };
+        match native.call(&self.stack[start..]) {
+            Some(value) => {
+                self.stack.truncate(start);
+                self.stack.push(value);
+                true
+            }
+            None => {
+                let bails = jit.bails.get() + 1;
+                jit.bails.set(bails);
+                if bails >= MAX_BAILS {
+                    *jit.code.borrow_mut() = Code::Unsupported;
+                }
+                false
+            }
+        }
+    }
+}
+
+// The types on the stack before each instruction, None for the unreachable ones
+struct Flow {
+    states: Vec<Option<Vec<Ty>>>,
+    successors: Vec<Vec<usize>>,
+    ret: Ty,
+}
+
+fn compile(proto: &FuncProto, args: &[Value], constants: &[Value]) -> Option<Native> {
+    let params: Vec<Ty> = args.iter().map(Ty::of).collect::<Option<_>>()?;
+    let flow = infer(&proto.instructions, &params, constants)?;
+    let mut module = jit_module()?;
+    let entry = define(&mut module, &proto.instructions, &params, constants, &flow)?;
+    module.finalize_definitions().ok()?;
+    let entry = module.get_finalized_function(entry);
+    Some(Native {
+        entry: unsafe { std::mem::transmute::<*const u8, Entry>(entry) },
+        module: Some(module),
+        params: params.into(),
+        ret: flow.ret,
+    })
+}
+
+// Types every reachable instruction. The calls of the prototype to itself return
+// what its other returns do, so those are looked for first
+fn infer(instructions: &[Instruction], params: &[Ty], constants: &[Value]) -> Option<Flow> {
+    let (_, _, returns) = analyze(instructions, params, constants, None)?;
+    let ret = *returns.first()?;
+    let (states, successors, returns) = analyze(instructions, params, constants, Some(ret))?;
+    if ret == Ty::Rec || returns.iter().any(|ty| *ty != ret) {
+        return None;
+    }
+    Some(Flow {
+        states,
+        successors,
+        ret,
+    })
+}
+
+type Analysis = (Vec<Option<Vec<Ty>>>, Vec<Vec<usize>>, Vec<Ty>);
+
+// Paths reaching an instruction have to agree on the types of the stack
+fn analyze(
+    instructions: &[Instruction],
+    params: &[Ty],
+    constants: &[Value],
+    ret: Option<Ty>,
+) -> Option<Analysis> {
+    let mut states = vec![None; instructions.len()];
+    let mut successors = vec![Vec::new(); instructions.len()];
+    let mut returns = Vec::new();
+    states[0] = Some(params.to_vec());
+    let mut work = vec![0];
+    while let Some(pc) = work.pop() {
+        let mut stack = states[pc].clone()?;
+        let next = step(
+            instructions,
+            pc,
+            &mut stack,
+            params,
+            constants,
+            ret,
+            &mut returns,
+        )?;
+        for &next in &next {
+            match states.get(next)? {
+                Some(state) if *state != stack => return None,
+                Some(_) => (),
+                None => {
+                    states[next] = Some(stack.clone());
+                    work.push(next);
+                }
+            }
+        }
+        successors[pc] = next;
+    }
+    Some((states, successors, returns))
+}
+
+// The instructions after 'pc', None for the ones the jit doesn't compile
+fn step(
+    instructions: &[Instruction],
+    pc: usize,
+    stack: &mut Vec<Ty>,
+    params: &[Ty],
+    constants: &[Value],
+    ret: Option<Ty>,
+    returns: &mut Vec<Ty>,
+) -> Option<Vec<usize>> {
+    use Instruction::*;
+    match instructions[pc] {
+        Unit => stack.push(Ty::Unit),
+        True | False => stack.push(Ty::Bool),
+        Integer(_) => stack.push(Ty::Int),
+        Constant { index } => stack.push(Ty::of(constants.get(index as usize)?)?),
+        Pop => {
+            stack.pop()?;
+        }
+        GetLocal { index, frame: 1 } => stack.push(*stack.get(index as usize)?),
+        SetLocal { index, frame: 1 } => {
+            if stack.len() != index as usize {
+                let ty = stack.pop()?;
+                *stack.get_mut(index as usize)? = ty;
+            }
+        }
+        Unary(op) => {
+            let ty = stack.pop()?;
+            stack.push(unary_ty(op, ty)?);
+        }
+        JumpIf { offset, .. } => {
+            stack.pop()?;
+            return Some(vec![pc + 1, target(pc, offset)?]);
+        }
+        Jump { offset } => return Some(vec![target(pc, offset)?]),
+        Return { return_value } => {
+            returns.push(if return_value { stack.pop()? } else { Ty::Unit });
+            return Some(Vec::new());
+        }
+        ExitBlock { pop, return_value } => {
+            let value = if return_value {
+                Some(stack.pop()?)
+            } else {
+                None
+            };
+            let len = stack.len().checked_sub(pop as usize)?;
+            stack.truncate(len);
+            stack.extend(value);
+        }
+        Rec => stack.push(Ty::Rec),
+        Call { args_len } => {
+            if stack.pop()? != Ty::Rec || args_len as usize != params.len() {
+                return None;
+            }
+            let start = stack.len().checked_sub(params.len())?;
+            if stack[start..] != *params {
+                return None;
+            }
+            stack.truncate(start);
+            match ret {
+                Some(ty) => stack.push(ty),
+                None => return Some(Vec::new()),
+            }
+        }
+        // The superinstructions run the two instructions after them too
+        BinLocals { op, a, b, frame: 1 } => {
+            let ty = binary_ty(op, *stack.get(a as usize)?, *stack.get(b as usize)?)?;
+            stack.push(ty);
+            return Some(vec![pc + 3]);
+        }
+        BinLocalInt {
+            op,
+            index,
+            frame: 1,
+            ..
+        } => {
+            let ty = binary_ty(op, *stack.get(index as usize)?, Ty::Int)?;
+            stack.push(ty);
+            return Some(vec![pc + 3]);
+        }
+        instr => {
+            let op = instr.bin_op()?;
+            let right = stack.pop()?;
+            let left = stack.pop()?;
+            stack.push(binary_ty(op, left, right)?);
+        }
+    }
+    Some(vec![pc + 1])
+}
+
+fn target(pc: usize, offset: i8) -> Option<usize> {
+    use std::convert::TryFrom;
+    usize::try_from(pc as isize + offset as isize).ok()
+}
+
+fn binary_ty(op: BinaryInstr, left: Ty, right: Ty) -> Option<Ty> {
+    use BinaryInstr::*;
+    match (op, left, right) {
+        (_, Ty::Rec, _) | (_, _, Ty::Rec) => None,
+        (Eq, ..) | (Ne, ..) => Some(Ty::Bool),
+        (Rem, Ty::Int, Ty::Int) => Some(Ty::Int),
+        // Cranelift has no float remainder
+        (Rem, ..) => None,
+        (op, Ty::Int, Ty::Int) if op.is_arithmetic() => Some(Ty::Int),
+        (op, left, right) if left.is_number() && right.is_number() => Some(if op.is_arithmetic() {
+            Ty::Float
+        } else {
+            Ty::Bool
+        }),
+        _ => None,
+    }
+}
+
+fn unary_ty(op: UnaryInstr, ty: Ty) -> Option<Ty> {
+    match (op, ty) {
+        (UnaryInstr::Negate, Ty::Int) | (UnaryInstr::Negate, Ty::Float) => Some(ty),
+        (UnaryInstr::Not, Ty::Bool) => Some(Ty::Bool),
+        _ => None,
+    }
+}
+
+fn jit_module() -> Option<JITModule> {
+    let mut flags = settings::builder();
+    flags.set("use_colocated_libcalls", "false").ok()?;
+    flags.set("is_pic", "false").ok()?;
+    let isa = cranelift_native::builder()
+        .ok()?
+        .finish(settings::Flags::new(flags))
+        .ok()?;
+    Some(JITModule::new(JITBuilder::with_isa(
+        isa,
+        default_libcall_names(),
+    )))
+}
+
+// Defines the body, taking the typed arguments and the recursion depth and
+// returning the result and whether it didn't give up, and the entry the vm
+// calls with the arguments and the result as 64 bits
+fn define(
+    module: &mut JITModule,
+    instructions: &[Instruction],
+    params: &[Ty],
+    constants: &[Value],
+    flow: &Flow,
+) -> Option<cranelift_module::FuncId> {
+    let mut signature = module.make_signature();
+    for ty in params {
+        signature.params.push(AbiParam::new(ty.clif()));
+    }
+    signature.params.push(AbiParam::new(types::I64));
+    signature.returns.push(AbiParam::new(flow.ret.clif()));
+    signature.returns.push(AbiParam::new(types::I8));
+    let body = module
+        .declare_function("body", Linkage::Local, &signature)
+        .ok()?;
+
+    let mut ctx = module.make_context();
+    let mut builder_ctx = FunctionBuilderContext::new();
+    ctx.func.signature = signature;
+    let this = module.declare_func_in_func(body, &mut ctx.func);
+    let builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    Codegen::new(builder, this, params, constants, flow).body(instructions);
+    module.define_function(body, &mut ctx).ok()?;
+    module.clear_context(&mut ctx);
+
+    let pointer = module.target_config().pointer_type();
+    let mut signature = module.make_signature();
+    signature.params.push(AbiParam::new(pointer));
+    signature.params.push(AbiParam::new(pointer));
+    signature.returns.push(AbiParam::new(types::I8));
+    let entry = module
+        .declare_function("entry", Linkage::Local, &signature)
+        .ok()?;
+    ctx.func.signature = signature;
+    let body = module.declare_func_in_func(body, &mut ctx.func);
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    let (args, result) = (
+        builder.block_params(block)[0],
+        builder.block_params(block)[1],
+    );
+    let mut values = Vec::new();
+    for (i, ty) in params.iter().enumerate() {
+        let bits = builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), args, (i * 8) as i32);
+        values.push(match ty {
+            Ty::Int => bits,
+            Ty::Float => builder.ins().bitcast(types::F64, MemFlags::new(), bits),
+            _ => builder.ins().ireduce(types::I8, bits),
+        });
+    }
+    values.push(builder.ins().iconst(types::I64, 0));
+    let call = builder.ins().call(body, &values);
+    let (value, ok) = (builder.inst_results(call)[0], builder.inst_results(call)[1]);
+    let bits = match flow.ret {
+        Ty::Int => value,
+        Ty::Float => builder.ins().bitcast(types::I64, MemFlags::new(), value),
+        _ => builder.ins().uextend(types::I64, value),
+    };
+    builder.ins().store(MemFlags::trusted(), bits, result, 0);
+    builder.ins().return_(&[ok]);
+    builder.seal_all_blocks();
+    builder.finalize();
+    module.define_function(entry, &mut ctx).ok()?;
+    module.clear_context(&mut ctx);
+    Some(entry)
+}
+
+// Emits the instructions in order. A slot of the stack is a variable per type it
+// can have, the types before each instruction come from the analysis
+struct Codegen<'a> {
+    builder: FunctionBuilder<'a>,
+    this: FuncRef,
+    params: &'a [Ty],
+    constants: &'a [Value],
+    flow: &'a Flow,
+    // The types on the stack now
+    types: Vec<Ty>,
+    // Starts of the instructions jumped to
+    blocks: Vec<Option<Block>>,
+    // Returns that the code gave up
+    bail: Block,
+    depth: cranelift_codegen::ir::Value,
+}
+
+type Typed = (Ty, cranelift_codegen::ir::Value);
+
+impl<'a> Codegen<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        this: FuncRef,
+        params: &'a [Ty],
+        constants: &'a [Value],
+        flow: &'a Flow,
+    ) -> Self {
+        let mut blocks = vec![None; flow.states.len()];
+        blocks[0] = Some(builder.create_block());
+        for (pc, successors) in flow.successors.iter().enumerate() {
+            if *successors != [pc + 1] {
+                for &next in successors {
+                    if blocks[next].is_none() {
+                        blocks[next] = Some(builder.create_block());
+                    }
+                }
+            }
+        }
+        let slots = flow
+            .states
+            .iter()
+            .flatten()
+            .map(|state| state.len() + 1)
+            .max()
+            .unwrap_or(0);
+        for slot in 0..slots {
+            for ty in &[Ty::Int, Ty::Float, Ty::Bool, Ty::Unit, Ty::Rec] {
+                builder.declare_var(Self::var(slot, *ty), ty.clif());
+            }
+        }
+        let bail = builder.create_block();
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        let depth = builder.block_params(entry)[params.len()];
+        Codegen {
+            builder,
+            this,
+            params,
+            constants,
+            flow,
+            types: Vec::new(),
+            blocks,
+            bail,
+            depth,
+        }
+    }
+
+    fn var(slot: usize, ty: Ty) -> Variable {
+        Variable::from_u32((slot * TYS + ty as usize) as u32)
+    }
+
+    fn body(mut self, instructions: &[Instruction]) {
+        let entry = self
+            .builder
+            .current_block()
+            .expect("Expected the entry block");
+        for (i, ty) in self.params.iter().enumerate() {
+            let value = self.builder.block_params(entry)[i];
+            self.builder.def_var(Self::var(i, *ty), value);
+        }
+        let too_deep =
+            self.builder
+                .ins()
+                .icmp_imm(IntCC::SignedGreaterThanOrEqual, self.depth, MAX_DEPTH);
+        let start = self.block(0);
+        self.builder
+            .ins()
+            .brif(too_deep, self.bail, &[], start, &[]);
+        let mut terminated = true;
+        for (pc, instr) in instructions.iter().enumerate() {
+            let state = match &self.flow.states[pc] {
+                Some(state) => state.clone(),
+                None => continue,
+            };
+            if let Some(block) = self.blocks[pc] {
+                if !terminated {
+                    self.builder.ins().jump(block, &[]);
+                }
+                self.builder.switch_to_block(block);
+            }
+            self.types = state;
+            terminated = self.instruction(pc, *instr);
+        }
+
+        self.builder.switch_to_block(self.bail);
+        let zero = self.zero(self.flow.ret);
+        let failed = self.builder.ins().iconst(types::I8, 0);
+        self.builder.ins().return_(&[zero, failed]);
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+
+    fn block(&self, pc: usize) -> Block {
+        self.blocks[pc].expect("Expected a block for a jump target")
+    }
+
+    fn zero(&mut self, ty: Ty) -> cranelift_codegen::ir::Value {
+        match ty {
+            Ty::Int => self.builder.ins().iconst(types::I64, 0),
+            Ty::Float => self.builder.ins().f64const(0.0),
+            _ => self.builder.ins().iconst(types::I8, 0),
+        }
+    }
+
+    fn push(&mut self, (ty, value): Typed) {
+        self.builder.def_var(Self::var(self.types.len(), ty), value);
+        self.types.push(ty);
+    }
+
+    fn pop(&mut self) -> Typed {
+        let ty = self.types.pop().expect("Expected a value on the stack");
+        (ty, self.builder.use_var(Self::var(self.types.len(), ty)))
+    }
+
+    fn local(&mut self, index: u16) -> Typed {
+        let ty = self.types[index as usize];
+        (ty, self.builder.use_var(Self::var(index as usize, ty)))
+    }
+
+    // Continues in a new block when 'condition' is false
+    fn bail_if(&mut self, condition: cranelift_codegen::ir::Value) {
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, self.bail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    // Whether the block ended
+    fn instruction(&mut self, pc: usize, instr: Instruction) -> bool {
+        use Instruction::*;
+        match instr {
+            Unit => {
+                let unit = self.zero(Ty::Unit);
+                self.push((Ty::Unit, unit))
+            }
+            True | False => {
+                let value = self.builder.ins().iconst(types::I8, (instr == True) as i64);
+                self.push((Ty::Bool, value))
+            }
+            Integer(value) => {
+                let value = self.builder.ins().iconst(types::I64, value as i64);
+                self.push((Ty::Int, value))
+            }
+            Constant { index } => {
+                let value = match self.constants[index as usize] {
+                    Value::Int(i) => (Ty::Int, self.builder.ins().iconst(types::I64, i)),
+                    Value::Number(n) => (Ty::Float, self.builder.ins().f64const(n)),
+                    Value::Bool(b) => (Ty::Bool, self.builder.ins().iconst(types::I8, b as i64)),
+                    _ => (Ty::Unit, self.zero(Ty::Unit)),
+                };
+                self.push(value)
+            }
+            Pop => {
+                self.pop();
+            }
+            GetLocal { index, .. } => {
+                let local = self.local(index);
+                self.push(local)
+            }
+            SetLocal { index, .. } => {
+                if self.types.len() != index as usize {
+                    let (ty, value) = self.pop();
+                    self.types[index as usize] = ty;
+                    self.builder.def_var(Self::var(index as usize, ty), value);
+                }
+            }
+            Unary(op) => {
+                let operand = self.pop();
+                let value = self.unary(op, operand);
+                self.push(value)
+            }
+            JumpIf { when_true, offset } => {
+                let (ty, value) = self.pop();
+                // Only false and nil are falsy
+                let condition = match ty {
+                    Ty::Bool => value,
+                    _ => self.builder.ins().iconst(types::I8, 1),
+                };
+                let jump = self.block(target(pc, offset).expect("Expected a jump target"));
+                let next = self.block(pc + 1);
+                let (then, otherwise) = if when_true {
+                    (jump, next)
+                } else {
+                    (next, jump)
+                };
+                self.builder
+                    .ins()
+                    .brif(condition, then, &[], otherwise, &[]);
+                return true;
+            }
+            Jump { offset } => {
+                let jump = self.block(target(pc, offset).expect("Expected a jump target"));
+                self.builder.ins().jump(jump, &[]);
+                return true;
+            }
+            Return { return_value } => {
+                let value = if return_value {
+                    self.pop().1
+                } else {
+                    self.zero(Ty::Unit)
+                };
+                let ok = self.builder.ins().iconst(types::I8, 1);
+                self.builder.ins().return_(&[value, ok]);
+                return true;
+            }
+            ExitBlock { pop, return_value } => {
+                let value = if return_value { Some(self.pop()) } else { None };
+                let len = self.types.len() - pop as usize;
+                self.types.truncate(len);
+                if let Some(value) = value {
+                    self.push(value)
+                }
+            }
+            Rec => {
+                let function = self.zero(Ty::Rec);
+                self.push((Ty::Rec, function))
+            }
+            Call { .. } => {
+                self.pop();
+                let start = self.types.len() - self.params.len();
+                let mut args: Vec<_> = (start..self.types.len())
+                    .map(|slot| {
+                        let ty = self.types[slot];
+                        self.builder.use_var(Self::var(slot, ty))
+                    })
+                    .collect();
+                self.types.truncate(start);
+                args.push(self.builder.ins().iadd_imm(self.depth, 1));
+                let call = self.builder.ins().call(self.this, &args);
+                let results = self.builder.inst_results(call).to_vec();
+                let failed = self.builder.ins().icmp_imm(IntCC::Equal, results[1], 0);
+                self.bail_if(failed);
+                self.push((self.flow.ret, results[0]))
+            }
+            BinLocals { op, a, b, .. } => {
+                let left = self.local(a);
+                let right = self.local(b);
+                let value = self.binary(op, left, right);
+                self.push(value)
+            }
+            BinLocalInt {
+                op, index, value, ..
+            } => {
+                let left = self.local(index);
+                let right = self.builder.ins().iconst(types::I64, value as i64);
+                let value = self.binary(op, left, (Ty::Int, right));
+                self.push(value)
+            }
+            instr => {
+                let op = instr
+                    .bin_op()
+                    .expect("Expected an instruction the jit compiles");
+                let right = self.pop();
+                let left = self.pop();
+                let value = self.binary(op, left, right);
+                self.push(value)
+            }
+        }
+        false
+    }
+
+    fn unary(&mut self, op: UnaryInstr, (ty, value): Typed) -> Typed {
+        match (op, ty) {
+            (UnaryInstr::Negate, Ty::Int) => {
+                let zero = self.zero(Ty::Int);
+                let (value, overflow) = self.builder.ins().ssub_overflow(zero, value);
+                self.bail_if(overflow);
+                (Ty::Int, value)
+            }
+            (UnaryInstr::Negate, _) => (Ty::Float, self.builder.ins().fneg(value)),
+            (UnaryInstr::Not, _) => (Ty::Bool, self.builder.ins().bxor_imm(value, 1)),
+        }
+    }
+
+    fn binary(&mut self, op: BinaryInstr, left: Typed, right: Typed) -> Typed {
+        use BinaryInstr::*;
+        match (op, left.0, right.0) {
+            (Eq, ..) | (Ne, ..) => {
+                let equal = match (left.0, right.0) {
+                    (Ty::Float, Ty::Float) => {
+                        self.builder.ins().fcmp(FloatCC::Equal, left.1, right.1)
+                    }
+                    (a, b) if a == b => self.builder.ins().icmp(IntCC::Equal, left.1, right.1),
+                    // Values of different types are never equal, not even 1 and 1.0
+                    _ => self.builder.ins().iconst(types::I8, 0),
+                };
+                match op {
+                    Eq => (Ty::Bool, equal),
+                    _ => (Ty::Bool, self.builder.ins().bxor_imm(equal, 1)),
+                }
+            }
+            (_, Ty::Int, Ty::Int) => self.int_binary(op, left.1, right.1),
+            _ => {
+                let left = self.float(left);
+                let right = self.float(right);
+                self.float_binary(op, left, right)
+            }
+        }
+    }
+
+    fn float(&mut self, (ty, value): Typed) -> cranelift_codegen::ir::Value {
+        match ty {
+            Ty::Int => self.builder.ins().fcvt_from_sint(types::F64, value),
+            _ => value,
+        }
+    }
+
+    fn int_binary(
+        &mut self,
+        op: BinaryInstr,
+        left: cranelift_codegen::ir::Value,
+        right: cranelift_codegen::ir::Value,
+    ) -> Typed {
+        use BinaryInstr::*;
+        let ins = self.builder.ins();
+        let (value, overflow) = match op {
+            Add => ins.sadd_overflow(left, right),
+            Sub => ins.ssub_overflow(left, right),
+            Mul => ins.smul_overflow(left, right),
+            Div | Rem => {
+                // The interpreter fails on 0, and on -1 for the smallest integer
+                let zero = ins.icmp_imm(IntCC::Equal, right, 0);
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, right, -1);
+                let invalid = self.builder.ins().bor(zero, minus_one);
+                self.bail_if(invalid);
+                let remainder = self.builder.ins().srem(left, right);
+                if op == Rem {
+                    return (Ty::Int, remainder);
+                }
+                // A division with a remainder gives a float
+                let inexact = self.builder.ins().icmp_imm(IntCC::NotEqual, remainder, 0);
+                self.bail_if(inexact);
+                return (Ty::Int, self.builder.ins().sdiv(left, right));
+            }
+            _ => {
+                let condition = match op {
+                    Gt => IntCC::SignedGreaterThan,
+                    Lt => IntCC::SignedLessThan,
+                    Ge => IntCC::SignedGreaterThanOrEqual,
+                    _ => IntCC::SignedLessThanOrEqual,
+                };
+                return (Ty::Bool, ins.icmp(condition, left, right));
+            }
+        };
+        self.bail_if(overflow);
+        (Ty::Int, value)
+    }
+
+    fn float_binary(
+        &mut self,
+        op: BinaryInstr,
+        left: cranelift_codegen::ir::Value,
+        right: cranelift_codegen::ir::Value,
+    ) -> Typed {
+        use BinaryInstr::*;
+        let condition = match op {
+            Add => return (Ty::Float, self.builder.ins().fadd(left, right)),
+            Sub => return (Ty::Float, self.builder.ins().fsub(left, right)),
+            Mul => return (Ty::Float, self.builder.ins().fmul(left, right)),
+            Div => {
+                let zero = self.builder.ins().f64const(0.0);
+                let by_zero = self.builder.ins().fcmp(FloatCC::Equal, right, zero);
+                self.bail_if(by_zero);
+                return (Ty::Float, self.builder.ins().fdiv(left, right));
+            }
+            Gt => FloatCC::GreaterThan,
+            Lt => FloatCC::LessThan,
+            Ge => FloatCC::GreaterThanOrEqual,
+            _ => FloatCC::LessThanOrEqual,
+        };
+        (Ty::Bool, self.builder.ins().fcmp(condition, left, right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::macros::compile;
+    use crate::vm::{Value, Vm};
+
+    #[test]
+    fn hot_prototypes_are_compiled() {
+        let compiled = compile(
+            "
+            let fib = fn(n) if n < 2 then return n; end fib(n - 1) + fib(n - 2) end;
+            let name = fn(s) s end;
+            (fib(20), name(\"a\"), name(\"b\"), name(\"c\"))
+            ",
+        );
+        let mut vm = Vm::new();
+        vm.set_jit_threshold(Some(1));
+        assert_eq!(
+            vm.run(&compiled),
+            Ok(Value::Tuple(
+                vec![
+                    Value::Int(6765),
+                    Value::new_str("a"),
+                    Value::new_str("b"),
+                    Value::new_str("c")
+                ]
+                .into()
+            ))
+        );
+        let protos = &compiled.constant_table.prototypes;
+        assert!(protos[0].jit.is_compiled());
+        assert!(!protos[1].jit.is_compiled());
+    }
+
+    #[test]
+    fn hosts_call_compiled_functions() {
+        let compiled = compile("var square = fn(x) x * x end; square(2)");
+        let mut vm = Vm::new();
+        vm.set_jit_threshold(Some(0));
+        assert_eq!(vm.run(&compiled), Ok(Value::Int(4)));
+        let square = vm.global("square").cloned().unwrap();
+        assert_eq!(
+            vm.call_function(square, &[Value::Number(1.5)]),
+            Ok(Value::Number(2.25))
+        );
+        assert!(compiled.constant_table.prototypes[0].jit.is_compiled());
+    }
+
+    #[test]
+    fn code_gives_up_to_the_interpreter() {
+        // The argument types change and the division stops being exact after the
+        // code was compiled
+        let compiled = compile(
+            "
+            let half = fn(n) n / 2 end;
+            let add = fn(a, b) a + b end;
+            (half(4), half(4.0), half(3), add(1, 2), add(1, 1.5))
+            ",
+        );
+        let mut vm = Vm::new();
+        vm.set_jit_threshold(Some(0));
+        assert_eq!(
+            vm.run(&compiled),
+            Ok(Value::Tuple(
+                vec![
+                    Value::Int(2),
+                    Value::Number(2.0),
+                    Value::Number(1.5),
+                    Value::Int(3),
+                    Value::Number(2.5)
+                ]
+                .into()
+            ))
+        );
+    }
+}
@@ -2,28 +2,107 @@ mod array;
 #[cfg(test)]
 mod tests;
 
-use super::value::{ArgsLen, Function, NativeFunction, Table};
-use super::{Integer, Value};
-use crate::vm::{RuntimeError, Vm};
+use super::scheduler::{Channel, Switch};
+use super::value::{ArgsLen, Function, NativeFn, NativeFunction, Table};
+use super::{Integer, UserFunction, Value};
+use crate::vm::{RuntimeError, RuntimeResult, Vm};
+use alloc::borrow::ToOwned;
+use alloc::rc::Rc;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::time::Duration;
+#[cfg(feature = "std")]
 use std::io::{self, Write};
-use std::rc::Rc;
 
-pub const PREDEFINED_CONSTANTS: [(&str, Value); 9] = [
+pub const PREDEFINED_CONSTANTS: &[(&str, Value)] = &[
     ("print", PRINT),
     ("println", PRINTLN),
     ("readline", READLINE),
+    ("prompt", PROMPT),
     ("int", INT),
     ("number", NUMBER),
     ("assert", ASSERT),
+    ("assert_type", ASSERT_TYPE),
     ("new", NEW),
     ("for_each", FOR_EACH),
+    (ITERATE, FOR_ITERATOR),
+    ("pairs", PAIRS),
+    ("ipairs", IPAIRS),
     ("arity", ARITY),
+    ("fn_info", FN_INFO),
+    ("clone_table", CLONE_TABLE),
+    ("rawget", RAWGET),
+    ("rawset", RAWSET),
+    ("rawequal", RAWEQUAL),
+    ("inspect", INSPECT),
+    ("spawn", SPAWN),
+    ("yield", YIELD),
+    ("sleep", SLEEP),
+    ("channel", CHANNEL),
+    ("set_timeout", SET_TIMEOUT),
+    ("set_interval", SET_INTERVAL),
+    ("clear_timer", CLEAR_TIMER),
+    #[cfg(feature = "plugins")]
+    ("require_native", REQUIRE_NATIVE),
 ];
 
+// Turns the iterable of a for loop into the function the loop calls, see
+// FOR_ITERATOR. Named by the keyword so scripts can't shadow it
+pub const ITERATE: &str = "for";
+
+// Globals the host sets before running, such as the command line arguments
+pub const PREDEFINED_GLOBALS: &[&str] = &[
+    ARGS,
+    TEST,
+    GLOBALS,
+    #[cfg(feature = "std")]
+    WORKER,
+    #[cfg(feature = "std")]
+    NET,
+    #[cfg(feature = "std")]
+    PROC,
+    #[cfg(feature = "signals")]
+    OS,
+];
+pub const ARGS: &str = "args";
+// Holds 'register', which 'flux_rs test' uses to find the tests of a script
+pub const TEST: &str = "test";
+// The table of the globals, setting its fields sets them, see Vm::insert_global
+pub const GLOBALS: &str = "_G";
+// Holds 'spawn', and 'parent' in the scripts run by workers
+#[cfg(feature = "std")]
+pub const WORKER: &str = "worker";
+// TCP and UDP sockets, see vm::net
+#[cfg(feature = "std")]
+pub const NET: &str = "net";
+// Running programs, see vm::process
+#[cfg(feature = "std")]
+pub const PROC: &str = "proc";
+// Holds 'on_signal'
+#[cfg(feature = "signals")]
+pub const OS: &str = "os";
+
+pub fn test_table() -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("register"), REGISTER_TEST);
+    table
+}
+
+// 'parent' talks to the vm that spawned the worker, see vm::worker
+#[cfg(feature = "std")]
+pub fn worker_table(parent: Option<Table>) -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("spawn"), SPAWN_WORKER);
+    if let Some(parent) = parent {
+        table.set(Value::Embedded("parent"), parent.into());
+    }
+    table
+}
+
 macro_rules! define_native {
     ($name:ident, $function:expr, $len:expr) => {
         pub const $name: Value = Value::Function(Function::Native(NativeFunction {
-            function: $function,
+            function: NativeFn::Builtin($function),
             args_len: $len,
         }));
     };
@@ -31,29 +110,26 @@ macro_rules! define_native {
 
 define_native! {
     PRINT,
-    |_vm, args| {
+    |vm, args| {
         let mut args_iter = args.into_iter().rev();
         if let Some(arg) = args_iter.next() {
-            print!("{}", arg);
+            vm.write_output(format_args!("{}", arg))?;
             for arg in args_iter {
-                print!(" {}", arg);
+                vm.write_output(format_args!(" {}", arg))?;
             }
         }
-        match io::stdout().flush() {
-            Ok(_) => Ok(Value::Unit),
-            _ => Err(RuntimeError::IOError),
-        }
+        flush_stdout()
     },
     ArgsLen::Variadic
 }
 
 define_native! {
     PRINTLN,
-    |_vm, args| {
+    |vm, args| {
         for arg in args.into_iter().rev() {
-            print!("{} ", arg);
+            vm.write_output(format_args!("{} ", arg))?;
         }
-        println!("");
+        vm.write_output(format_args!("\n"))?;
         Ok(Value::Unit)
     },
     ArgsLen::Variadic
@@ -61,17 +137,92 @@ define_native! {
 
 define_native! {
     READLINE,
-    |_, _| {
-        let mut string = String::new();
-        match io::stdin().read_line(&mut string) {
-            Ok(_) => {
-                string.pop().unwrap();  // Remove newline
-                Ok(Value::Str(Rc::new(string)))
-            },
+    |_, _| read_line(),
+    ArgsLen::Exact(0)
+}
+
+// Reads a line after showing 'message', with the line editing and the history of
+// the REPL. Returns nil at the end of the input
+define_native! {
+    PROMPT,
+    |_, args| prompt_line(args[0].as_str()?),
+    ArgsLen::Exact(1)
+}
+
+#[cfg(feature = "std")]
+fn flush_stdout() -> RuntimeResult<Value> {
+    match io::stdout().flush() {
+        Ok(_) => Ok(Value::Unit),
+        _ => Err(RuntimeError::IOError),
+    }
+}
+
+#[cfg(not(feature = "std"))]
+fn flush_stdout() -> RuntimeResult<Value> {
+    Ok(Value::Unit)
+}
+
+#[cfg(feature = "std")]
+fn read_line() -> RuntimeResult<Value> {
+    let mut string = String::new();
+    match io::stdin().read_line(&mut string) {
+        Ok(_) => {
+            string.pop().unwrap(); // Remove newline
+            Ok(Value::new_str(string))
+        }
+        Err(_) => Err(RuntimeError::IOError),
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+fn prompt_line(message: &str) -> RuntimeResult<Value> {
+    use core::cell::RefCell;
+    use rustyline::error::ReadlineError;
+    use rustyline::DefaultEditor;
+
+    thread_local! {
+        // Keeps the history of the prompts of the script
+        static EDITOR: RefCell<Option<DefaultEditor>> = const { RefCell::new(None) };
+    }
+    EDITOR.with(|editor| {
+        let mut editor = editor.borrow_mut();
+        let editor = match editor.as_mut() {
+            Some(editor) => editor,
+            None => editor.insert(DefaultEditor::new().map_err(|_| RuntimeError::IOError)?),
+        };
+        match editor.readline(message) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                Ok(Value::new_str(line))
+            }
+            Err(ReadlineError::Eof) => Ok(Value::Nil),
+            Err(ReadlineError::Interrupted) => Err(RuntimeError::Native(
+                "the prompt was interrupted".to_owned(),
+            )),
             Err(_) => Err(RuntimeError::IOError),
         }
-    },
-    ArgsLen::Exact(0)
+    })
+}
+
+// Without a terminal library the line is read as it is typed
+#[cfg(all(feature = "std", target_arch = "wasm32"))]
+fn prompt_line(message: &str) -> RuntimeResult<Value> {
+    print!("{}", message);
+    flush_stdout()?;
+    read_line()
+}
+
+#[cfg(not(feature = "std"))]
+fn prompt_line(_message: &str) -> RuntimeResult<Value> {
+    Err(RuntimeError::IOError)
+}
+
+// There is no stdin to read from
+#[cfg(not(feature = "std"))]
+fn read_line() -> RuntimeResult<Value> {
+    Err(RuntimeError::IOError)
 }
 
 define_native! {
@@ -137,6 +288,32 @@ define_native! {
     ArgsLen::Exact(1)
 }
 
+// Returns the value if it has the type, like 'value as ty' without converting numbers
+define_native! {
+    ASSERT_TYPE,
+    |_vm, mut args| {
+        let value = args.pop().expect("Expected a value");
+        let ty = args.pop().expect("Expected a type");
+        value.assert_type(&ty)
+    },
+    ArgsLen::Exact(2)
+}
+
+define_native! {
+    REGISTER_TEST,
+    |vm, args| {
+        let name = args[1].as_str()?.to_owned();
+        match &args[0] {
+            Value::Function(_) => {
+                vm.tests.push((name, args[0].clone()));
+                Ok(Value::Unit)
+            }
+            _ => Err(RuntimeError::TypeError),
+        }
+    },
+    ArgsLen::Exact(2)
+}
+
 define_native! {
     NEW,
     |vm, args| {
@@ -205,3 +382,359 @@ define_native! {
     },
     ArgsLen::Exact(1)
 }
+
+// For loops call the function for each value until it returns nil. A table with
+// a 'next' method is iterated by calling the method the same way
+define_native! {
+    FOR_ITERATOR,
+    |_vm, mut args| {
+        let iterable = args.pop().expect("Expected an iterable");
+        let table = match iterable {
+            Value::Function(_) => return Ok(iterable),
+            Value::Table(table) => table,
+            _ => return Err(RuntimeError::TypeError),
+        };
+        let next = table.borrow().get(&Value::Embedded("next")).clone();
+        if !matches!(next, Value::Function(_)) {
+            return Err(RuntimeError::Native(
+                "for loops need a function or a table with a 'next' method".to_owned(),
+            ));
+        }
+        let native = NativeFunction::host(ArgsLen::Exact(0), move |vm, _| {
+            vm.call_function(next.clone(), &[Rc::clone(&table).into()])
+        });
+        Ok(Value::Function(Function::Native(native)))
+    },
+    ArgsLen::Exact(1)
+}
+
+// Iterates the (key, value) pairs of the table as they were when it was called
+define_native! {
+    PAIRS,
+    |_vm, mut args| {
+        let table = args.pop().expect("Expected a table").into_table()?;
+        let pairs: Vec<Value> = table
+            .borrow()
+            .pairs()
+            .map(|(key, value)| Value::Tuple(vec![key.clone(), value.clone()].into()))
+            .collect();
+        let next = core::cell::Cell::new(0);
+        let native = NativeFunction::host(ArgsLen::Exact(0), move |_, _| {
+            let i = next.get();
+            next.set(i + 1);
+            Ok(pairs.get(i).cloned().unwrap_or(Value::Nil))
+        });
+        Ok(Value::Function(Function::Native(native)))
+    },
+    ArgsLen::Exact(1)
+}
+
+// Iterates the (index, value) pairs from index 0 up to the first nil
+define_native! {
+    IPAIRS,
+    |_vm, mut args| {
+        let table = args.pop().expect("Expected a table").into_table()?;
+        let next = core::cell::Cell::new(0);
+        let native = NativeFunction::host(ArgsLen::Exact(0), move |_, _| {
+            let i = next.get();
+            let value = table.borrow().get(&Value::Int(i)).clone();
+            if value == Value::Nil {
+                return Ok(Value::Nil);
+            }
+            next.set(i + 1);
+            Ok(Value::Tuple(vec![Value::Int(i), value].into()))
+        });
+        Ok(Value::Function(Function::Native(native)))
+    },
+    ArgsLen::Exact(1)
+}
+
+// A table with the 'arity' (nil for variadic natives), 'native', 'name' and
+// 'upvalues' of the function. Functions written in flux also have the 'file' and
+// 'line' they start on, when they were compiled from a file
+define_native! {
+    FN_INFO,
+    |vm, mut args| {
+        let function = match args.pop().expect("Expected a function") {
+            Value::Function(function) => function,
+            _ => return Err(RuntimeError::TypeError),
+        };
+        let mut info = Table::new();
+        let arity = match function.args_len() {
+            ArgsLen::Exact(len) => Value::Int(len.into()),
+            ArgsLen::Variadic => Value::Nil,
+        };
+        info.set(Value::Embedded("arity"), arity);
+        info.set(Value::Embedded("native"), Value::Bool(function.is_native()));
+        match &function {
+            Function::User(user) => {
+                let proto = user.proto_ref();
+                let index = vm.prototypes().iter().position(|p| Rc::ptr_eq(p, proto));
+                let name = match (proto.debug_info.name(), index) {
+                    (Some(name), _) => Some(name.to_owned()),
+                    (None, Some(index)) => Some(format!("fn #{}", index)),
+                    (None, None) => None,
+                };
+                if let Some(name) = name {
+                    info.set(Value::Embedded("name"), Value::new_str(name));
+                }
+                let upvalues = user.env().map_or(0, |env| env.borrow().pairs().count());
+                info.set(Value::Embedded("upvalues"), Value::Int(upvalues as Integer));
+                if let Some(file) = proto.debug_info.file() {
+                    info.set(Value::Embedded("file"), Value::new_str(file));
+                }
+                if let Some(line) = proto.debug_info.start_line() {
+                    info.set(Value::Embedded("line"), Value::Int(line as Integer));
+                }
+            }
+            Function::Native(_) => {
+                // Natives are named by the global holding them
+                let value = Value::Function(function.clone());
+                let name = PREDEFINED_CONSTANTS
+                    .iter()
+                    .find(|(_, native)| *native == value)
+                    .map(|(name, _)| *name)
+                    .or_else(|| {
+                        vm.globals()
+                            .filter(|(_, global)| **global == value)
+                            .filter_map(|(name, _)| match name {
+                                Value::Str(name) => Some(name.as_str()),
+                                Value::Embedded(name) => Some(*name),
+                                _ => None,
+                            })
+                            .min()
+                    });
+                if let Some(name) = name {
+                    info.set(Value::Embedded("name"), Value::new_str(name));
+                }
+                info.set(Value::Embedded("upvalues"), Value::Int(0));
+            }
+        }
+        Ok(info.into())
+    },
+    ArgsLen::Exact(1)
+}
+
+// Table access and equality that will keep skipping the hooks of metatables once
+// tables have them, tables are equal when they are the same table
+define_native! {
+    RAWGET,
+    |_vm, mut args| {
+        let table = args.pop().expect("Expected a table").into_table()?;
+        let key = args.pop().expect("Expected a key");
+        let value = table.borrow().get(&key).clone();
+        Ok(value)
+    },
+    ArgsLen::Exact(2)
+}
+
+define_native! {
+    RAWSET,
+    |vm, mut args| {
+        let table = args.pop().expect("Expected a table").into_table()?;
+        let key = args.pop().expect("Expected a key");
+        let value = args.pop().expect("Expected a value");
+        vm.set_through_globals_table(&table, &key, &value)?;
+        let mut table = table.borrow_mut();
+        if table.is_frozen() {
+            return Err(RuntimeError::FrozenTable { key });
+        }
+        table.set(key, value);
+        Ok(Value::Unit)
+    },
+    ArgsLen::Exact(3)
+}
+
+define_native! {
+    RAWEQUAL,
+    |_vm, args| Ok(Value::Bool(args[0] == args[1])),
+    ArgsLen::Exact(2)
+}
+
+// The text print shows for the value, with 'depth' levels of nested tables
+// instead of DISPLAY_DEPTH
+define_native! {
+    INSPECT,
+    |_vm, mut args| {
+        let value = args.pop().expect("Expected a value");
+        let depth = match args.pop() {
+            Some(Value::Int(depth)) if depth >= 0 => depth as usize,
+            _ => return Err(RuntimeError::TypeError),
+        };
+        Ok(Value::new_str(value.inspect(depth).to_string()))
+    },
+    ArgsLen::Exact(2)
+}
+
+// A snapshot, the copy shares the entries it doesn't change with the original
+define_native! {
+    CLONE_TABLE,
+    |_vm, mut args| {
+        let table = args.pop().expect("Expected a table").into_table()?;
+        let copy = table.borrow_mut().snapshot();
+        Ok(copy.into())
+    },
+    ArgsLen::Exact(1)
+}
+
+// Runs the function as a task next to the others, see Vm::after_execute. Returns
+// the id of the task
+define_native! {
+    SPAWN,
+    |vm, mut args| {
+        let function = task_function(args.pop().expect("Expected a function"))?;
+        Ok(Value::Int(vm.spawn(function)))
+    },
+    ArgsLen::Exact(1)
+}
+
+// Lets the other tasks run. Functions called by natives, like the ones of
+// for_each, can't be suspended and keep running
+define_native! {
+    YIELD,
+    |vm, _| {
+        if vm.blocking == 0 {
+            vm.scheduler.switch = Some(Switch::Yield);
+        }
+        Ok(Value::Unit)
+    },
+    ArgsLen::Exact(0)
+}
+
+// Lets the other tasks run for at least 'ms' milliseconds, the thread sleeps when
+// none of them can run
+define_native! {
+    SLEEP,
+    |vm, args| {
+        let duration = millis(&args[0])?;
+        if vm.blocking == 0 {
+            vm.scheduler.switch = Some(Switch::Sleep(duration));
+        } else {
+            #[cfg(feature = "std")]
+            std::thread::sleep(duration);
+        }
+        Ok(Value::Unit)
+    },
+    ArgsLen::Exact(1)
+}
+
+// Runs the function as a task once 'ms' milliseconds passed, returns the id of the
+// task for clear_timer
+define_native! {
+    SET_TIMEOUT,
+    |vm, mut args| {
+        let function = task_function(args.pop().expect("Expected a function"))?;
+        let delay = millis(&args[0])?;
+        Ok(Value::Int(vm.spawn_timer(function, Some(delay), None)))
+    },
+    ArgsLen::Exact(2)
+}
+
+// Like set_timeout, the function runs again 'ms' milliseconds after each time it
+// returned until clear_timer is called with the id
+define_native! {
+    SET_INTERVAL,
+    |vm, mut args| {
+        let function = task_function(args.pop().expect("Expected a function"))?;
+        let every = millis(&args[0])?;
+        Ok(Value::Int(vm.spawn_timer(function, Some(every), Some(every))))
+    },
+    ArgsLen::Exact(2)
+}
+
+// Stops a timer, or a spawned task that didn't start yet. Returns whether there
+// was one to stop
+define_native! {
+    CLEAR_TIMER,
+    |vm, args| match args[0] {
+        Value::Int(id) => Ok(Value::Bool(vm.clear_timer(id))),
+        _ => Err(RuntimeError::TypeError),
+    },
+    ArgsLen::Exact(1)
+}
+
+// Tasks start by calling their function without arguments
+fn task_function(value: Value) -> RuntimeResult<UserFunction> {
+    let function = value.into_user_fn()?;
+    if function.args_len() != 0 {
+        return Err(RuntimeError::WrongNumberOfArgs {
+            expected: 0,
+            found: function.args_len(),
+        });
+    }
+    Ok(function)
+}
+
+fn millis(value: &Value) -> RuntimeResult<Duration> {
+    match value.convert_int() {
+        Some(ms) if ms >= 0 => Ok(Duration::from_millis(ms as u64)),
+        _ => Err(RuntimeError::TypeError),
+    }
+}
+
+// A table with 'send(value)' and 'receive()', which returns the values in the order
+// they were sent. Receiving from an empty channel waits until a task sends a value
+define_native! {
+    CHANNEL,
+    |_vm, _| {
+        let channel: Channel = Rc::default();
+        let sender = Rc::clone(&channel);
+        let send = NativeFunction::host(ArgsLen::Exact(1), move |_, mut args| {
+            sender.borrow_mut().push_back(args.pop().expect("Expected a value"));
+            Ok(Value::Unit)
+        });
+        let receive = NativeFunction::host(ArgsLen::Exact(0), move |vm, _| {
+            vm.receive(Rc::clone(&channel))
+        });
+        let mut table = Table::new();
+        table.set(Value::Embedded("send"), Value::Function(Function::Native(send)));
+        table.set(Value::Embedded("receive"), Value::Function(Function::Native(receive)));
+        Ok(table.into())
+    },
+    ArgsLen::Exact(0)
+}
+
+#[cfg(feature = "signals")]
+pub fn os_table() -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("on_signal"), ON_SIGNAL);
+    table
+}
+
+// Runs the function instead of stopping the process when it receives the signal,
+// "int" for Ctrl-C or "term". See Vm::on_signal
+#[cfg(feature = "signals")]
+define_native! {
+    ON_SIGNAL,
+    |vm, mut args| {
+        let name = args.pop().expect("Expected a signal");
+        let name = name.as_str()?;
+        let handler = task_function(args.pop().expect("Expected a function"))?;
+        let signal = super::Signal::from_name(name).ok_or_else(|| {
+            RuntimeError::Native(format!("unknown signal '{}', expected \"int\" or \"term\"", name))
+        })?;
+        vm.on_signal(signal, handler);
+        Ok(Value::Unit)
+    },
+    ArgsLen::Exact(2)
+}
+
+// Runs a script on a new thread, see vm::worker
+#[cfg(feature = "std")]
+define_native! {
+    SPAWN_WORKER,
+    |vm, args| Ok(super::worker::spawn(args[0].as_str()?, vm.sandboxed).into()),
+    ArgsLen::Exact(1)
+}
+
+// The table of the natives of a plugin, see ffi::plugin
+#[cfg(feature = "plugins")]
+define_native! {
+    REQUIRE_NATIVE,
+    |vm, args| {
+        vm.check_sandbox("require_native")?;
+        let path = args[0].as_str()?;
+        Ok(crate::ffi::plugin::require_native(path)?.into())
+    },
+    ArgsLen::Exact(1)
+}
@@ -8,3 +8,40 @@ unit_test! {
     ",
     Ok(Value::Int(3))
 }
+
+unit_test! {
+    clone_table,
+    "
+    let config = {\"width\" = 80, \"height\" = 24};
+    let copy = clone_table(config);
+    copy.width = 120;
+    config.height = 30;
+    (config.width, config.height, copy.width, copy.height)
+    ",
+    Ok(Value::Tuple(
+        vec![Value::Int(80), Value::Int(30), Value::Int(120), Value::Int(24)].into()
+    ))
+}
+
+unit_test! {
+    inspect,
+    "
+    let t = {\"items\" = {1, {2}}, \"empty\" = {}};
+    let cycle = {};
+    cycle.me = cycle;
+    (
+        inspect(t.items, 1), inspect(t.items, 2), inspect(t.empty, 0), inspect((t, 1), 0),
+        inspect(cycle, 5)
+    )
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::new_str("{\n    0: 1,\n    1: {...}\n}"),
+            Value::new_str("{\n    0: 1,\n    1: {\n        0: 2\n    }\n}"),
+            Value::new_str("{}"),
+            Value::new_str("({...}, 1)"),
+            Value::new_str("{\n    me: <cycle>\n}"),
+        ]
+        .into()
+    ))
+}
@@ -0,0 +1,106 @@
+use alloc::boxed::Box;
+use core::alloc::{GlobalAlloc, Layout};
+#[cfg(feature = "std")]
+use core::cell::Cell;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::{AtomicIsize, Ordering};
+#[cfg(feature = "std")]
+use std::alloc::System;
+
+#[cfg(feature = "std")]
+thread_local! {
+    // Bytes allocated minus the ones freed by the thread, through TrackingAllocator
+    static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+}
+
+// Without std there are no thread locals, a single counter is kept
+#[cfg(not(feature = "std"))]
+static ALLOCATED: AtomicIsize = AtomicIsize::new(0);
+
+// Counts the bytes every thread allocates for the allocator it wraps, the system
+// one by default. Hosts install it with #[global_allocator] to get
+// Stats::peak_memory and Vm::set_memory_limit, the counters stay 0 otherwise
+#[cfg(feature = "std")]
+pub struct TrackingAllocator<A = System>(pub A);
+
+// Without std the allocator to wrap has to be given
+#[cfg(not(feature = "std"))]
+pub struct TrackingAllocator<A>(pub A);
+
+#[cfg(feature = "std")]
+impl TrackingAllocator {
+    pub const fn new() -> Self {
+        TrackingAllocator(System)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for TrackingAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The thread may be exiting, its counter is gone then
+#[cfg(feature = "std")]
+fn count(bytes: isize) {
+    let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get().wrapping_add(bytes)));
+}
+
+#[cfg(not(feature = "std"))]
+fn count(bytes: isize) {
+    ALLOCATED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+        count(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.0.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            count(new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+// Whether TrackingAllocator is the global allocator, only then an allocation
+// moves the counter
+pub fn is_tracking() -> bool {
+    let before = allocated();
+    let probe = core::hint::black_box(Box::new(0u64));
+    let tracked = allocated() != before;
+    drop(probe);
+    tracked
+}
+
+// Live bytes of the current thread. Memory freed by another thread than the one
+// that allocated it moves the counters of both, only differences are meaningful
+#[cfg(feature = "std")]
+pub fn allocated() -> isize {
+    ALLOCATED.with(Cell::get)
+}
+
+#[cfg(not(feature = "std"))]
+pub fn allocated() -> isize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,142 @@
+// TCP and UDP sockets, the 'net' table. A socket is a table of natives owning it,
+// the calls block the thread until the operating system is done like readline
+use super::value::{ArgsLen, Function, NativeFunction, Table};
+use super::{RuntimeError, RuntimeResult, Value, Vm};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::rc::Rc;
+
+// The most bytes one recv returns
+const RECV_SIZE: usize = 64 * 1024;
+
+// 'connect(addr)' and 'listen(addr)' for TCP, 'bind(addr)' for UDP. Addresses are
+// strings like "127.0.0.1:8080" or "example.com:80"
+pub fn net_table() -> Table {
+    let mut table = Table::new();
+    set_native(&mut table, "connect", ArgsLen::Exact(1), |vm, args| {
+        vm.check_sandbox("net.connect")?;
+        let addr = args[0].as_str()?;
+        let stream = TcpStream::connect(addr).map_err(|error| failed("connect to", addr, error))?;
+        Ok(stream_table(stream).into())
+    });
+    set_native(&mut table, "listen", ArgsLen::Exact(1), |vm, args| {
+        vm.check_sandbox("net.listen")?;
+        let addr = args[0].as_str()?;
+        let listener = TcpListener::bind(addr).map_err(|error| failed("listen on", addr, error))?;
+        Ok(listener_table(listener).into())
+    });
+    set_native(&mut table, "bind", ArgsLen::Exact(1), |vm, args| {
+        vm.check_sandbox("net.bind")?;
+        let addr = args[0].as_str()?;
+        let socket = UdpSocket::bind(addr).map_err(|error| failed("bind", addr, error))?;
+        Ok(udp_table(socket).into())
+    });
+    table
+}
+
+pub(super) fn set_native<F>(table: &mut Table, name: &'static str, args_len: ArgsLen, function: F)
+where
+    F: Fn(&mut Vm, Vec<Value>) -> RuntimeResult<Value> + 'static,
+{
+    let native = NativeFunction::host(args_len, function);
+    table.set(
+        Value::Embedded(name),
+        Value::Function(Function::Native(native)),
+    );
+}
+
+fn failed(action: &str, addr: &str, error: io::Error) -> RuntimeError {
+    RuntimeError::Native(format!("{} '{}' failed: {}", action, addr, error))
+}
+
+fn addr_value(addr: io::Result<SocketAddr>) -> Value {
+    addr.map_or(Value::Nil, |addr| Value::new_str(addr.to_string()))
+}
+
+// None once closed
+type Shared<T> = Rc<RefCell<Option<T>>>;
+
+fn open<T, R>(socket: &Shared<T>, f: impl FnOnce(&mut T) -> io::Result<R>) -> RuntimeResult<R> {
+    match socket.borrow_mut().as_mut() {
+        Some(socket) => f(socket).map_err(|error| RuntimeError::Native(error.to_string())),
+        None => Err(RuntimeError::Native("the socket is closed".to_owned())),
+    }
+}
+
+// 'send(data)', 'recv()' returning what arrived or nil once the other side closed,
+// 'close()', and the address of the other side as 'peer'
+fn stream_table(stream: TcpStream) -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("peer"), addr_value(stream.peer_addr()));
+    let stream = Rc::new(RefCell::new(Some(stream)));
+    let socket = Rc::clone(&stream);
+    set_native(&mut table, "send", ArgsLen::Exact(1), move |_, args| {
+        let data = args[0].as_str()?;
+        open(&socket, |stream| stream.write_all(data.as_bytes()))?;
+        Ok(Value::Unit)
+    });
+    let socket = Rc::clone(&stream);
+    set_native(&mut table, "recv", ArgsLen::Exact(0), move |_, _| {
+        let mut buffer = vec![0; RECV_SIZE];
+        let len = open(&socket, |stream| stream.read(&mut buffer))?;
+        Ok(match len {
+            0 => Value::Nil,
+            len => Value::new_str(String::from_utf8_lossy(&buffer[..len])),
+        })
+    });
+    set_native(&mut table, "close", ArgsLen::Exact(0), move |_, _| {
+        stream.borrow_mut().take();
+        Ok(Value::Unit)
+    });
+    table
+}
+
+// 'accept()' returning the socket of the next connection, 'close()', and the
+// address listened on as 'addr', which has the port picked for port 0
+fn listener_table(listener: TcpListener) -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("addr"), addr_value(listener.local_addr()));
+    let listener = Rc::new(RefCell::new(Some(listener)));
+    let socket = Rc::clone(&listener);
+    set_native(&mut table, "accept", ArgsLen::Exact(0), move |_, _| {
+        let (stream, _) = open(&socket, |listener| listener.accept())?;
+        Ok(stream_table(stream).into())
+    });
+    set_native(&mut table, "close", ArgsLen::Exact(0), move |_, _| {
+        listener.borrow_mut().take();
+        Ok(Value::Unit)
+    });
+    table
+}
+
+// 'send(data, addr)', 'recv()' returning a table with the 'data' and the address
+// it came 'from', 'close()', and the bound address as 'addr'
+fn udp_table(socket: UdpSocket) -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("addr"), addr_value(socket.local_addr()));
+    let udp = Rc::new(RefCell::new(Some(socket)));
+    let socket = Rc::clone(&udp);
+    set_native(&mut table, "send", ArgsLen::Exact(2), move |_, args| {
+        let (data, addr) = (args[0].as_str()?, args[1].as_str()?);
+        open(&socket, |socket| socket.send_to(data.as_bytes(), addr))?;
+        Ok(Value::Unit)
+    });
+    let socket = Rc::clone(&udp);
+    set_native(&mut table, "recv", ArgsLen::Exact(0), move |_, _| {
+        let mut buffer = vec![0; RECV_SIZE];
+        let (len, from) = open(&socket, |socket| socket.recv_from(&mut buffer))?;
+        let mut datagram = Table::new();
+        datagram.set(
+            Value::Embedded("data"),
+            Value::new_str(String::from_utf8_lossy(&buffer[..len])),
+        );
+        datagram.set(Value::Embedded("from"), Value::new_str(from.to_string()));
+        Ok(datagram.into())
+    });
+    set_native(&mut table, "close", ArgsLen::Exact(0), move |_, _| {
+        udp.borrow_mut().take();
+        Ok(Value::Unit)
+    });
+    table
+}
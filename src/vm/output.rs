@@ -0,0 +1,31 @@
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+// Where print, println and the REPL echo write to instead of stdout, shared
+// with the vms running imported modules
+#[derive(Clone)]
+pub(super) struct Output(Rc<RefCell<Box<dyn Write>>>);
+
+impl Output {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Output(Rc::new(RefCell::new(out)))
+    }
+
+    pub fn write(&self, args: fmt::Arguments) -> fmt::Result {
+        self.0.borrow_mut().write_fmt(args)
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+impl PartialEq for Output {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
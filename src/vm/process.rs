@@ -0,0 +1,123 @@
+// Running programs, the 'proc' table. 'run' waits for the program and returns what
+// it printed, 'spawn' streams its input and output. Sandboxed vms can use neither
+use super::net::set_native;
+use super::value::{ArgsLen, Table};
+use super::{RuntimeError, RuntimeResult, Value};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+
+// The most bytes one recv returns
+const RECV_SIZE: usize = 64 * 1024;
+
+// 'run(cmd, args)' and 'spawn(cmd, args)', 'args' being an array of strings. The
+// program is looked up in PATH unless 'cmd' is a path
+pub fn proc_table() -> Table {
+    let mut table = Table::new();
+    set_native(&mut table, "run", ArgsLen::Exact(2), |vm, args| {
+        vm.check_sandbox("proc.run")?;
+        let output = command(&args[0], &args[1])?
+            .output()
+            .map_err(|error| failed(&args[0], error))?;
+        let mut result = Table::new();
+        result.set(Value::Embedded("status"), status_value(output.status));
+        result.set(
+            Value::Embedded("stdout"),
+            Value::new_str(String::from_utf8_lossy(&output.stdout)),
+        );
+        result.set(
+            Value::Embedded("stderr"),
+            Value::new_str(String::from_utf8_lossy(&output.stderr)),
+        );
+        Ok(result.into())
+    });
+    set_native(&mut table, "spawn", ArgsLen::Exact(2), |vm, args| {
+        vm.check_sandbox("proc.spawn")?;
+        let child = command(&args[0], &args[1])?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|error| failed(&args[0], error))?;
+        Ok(child_table(child).into())
+    });
+    table
+}
+
+fn command(cmd: &Value, args: &Value) -> RuntimeResult<Command> {
+    let mut command = Command::new(cmd.as_str()?);
+    let args = match args {
+        Value::Table(args) => args.borrow(),
+        _ => return Err(RuntimeError::TypeError),
+    };
+    for i in 0.. {
+        match args.get(&Value::Int(i)) {
+            Value::Nil => break,
+            arg => command.arg(arg.as_str()?),
+        };
+    }
+    Ok(command)
+}
+
+fn failed(cmd: &Value, error: io::Error) -> RuntimeError {
+    RuntimeError::Native(format!("could not run '{}': {}", cmd, error))
+}
+
+// The exit code, nil when a signal ended the program
+fn status_value(status: ExitStatus) -> Value {
+    status
+        .code()
+        .map_or(Value::Nil, |code| Value::Int(code.into()))
+}
+
+fn io_result<T>(result: io::Result<T>) -> RuntimeResult<T> {
+    result.map_err(|error| RuntimeError::Native(error.to_string()))
+}
+
+// 'send(data)' writing to the program's stdin, 'close()' closing it, 'recv()'
+// returning what the program printed or nil once it closed its stdout, 'wait()'
+// returning the exit status, 'kill()', and the process id as 'id'. The program
+// shares stderr with the script
+fn child_table(child: Child) -> Table {
+    let mut table = Table::new();
+    table.set(Value::Embedded("id"), Value::Int(child.id().into()));
+    let child = Rc::new(RefCell::new(child));
+    let process = Rc::clone(&child);
+    set_native(&mut table, "send", ArgsLen::Exact(1), move |_, args| {
+        let data = args[0].as_str()?;
+        match process.borrow_mut().stdin.as_mut() {
+            Some(stdin) => io_result(stdin.write_all(data.as_bytes()))?,
+            None => return Err(RuntimeError::Native("stdin is closed".to_owned())),
+        }
+        Ok(Value::Unit)
+    });
+    let process = Rc::clone(&child);
+    set_native(&mut table, "close", ArgsLen::Exact(0), move |_, _| {
+        process.borrow_mut().stdin.take();
+        Ok(Value::Unit)
+    });
+    let process = Rc::clone(&child);
+    set_native(&mut table, "recv", ArgsLen::Exact(0), move |_, _| {
+        let mut buffer = vec![0; RECV_SIZE];
+        let len = match process.borrow_mut().stdout.as_mut() {
+            Some(stdout) => io_result(stdout.read(&mut buffer))?,
+            None => 0,
+        };
+        Ok(match len {
+            0 => Value::Nil,
+            len => Value::new_str(String::from_utf8_lossy(&buffer[..len])),
+        })
+    });
+    let process = Rc::clone(&child);
+    set_native(&mut table, "wait", ArgsLen::Exact(0), move |_, _| {
+        let mut process = process.borrow_mut();
+        // The program may wait for the end of its input
+        process.stdin.take();
+        Ok(status_value(io_result(process.wait())?))
+    });
+    set_native(&mut table, "kill", ArgsLen::Exact(0), move |_, _| {
+        io_result(child.borrow_mut().kill())?;
+        Ok(Value::Unit)
+    });
+    table
+}
@@ -0,0 +1,216 @@
+use super::FuncProtoRef;
+use crate::compiler::Instruction;
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::fmt;
+use core::mem::{self, Discriminant};
+use core::time::Duration;
+use hashbrown::HashMap;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+// Without std there is no clock, the times of the functions stay zero
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Instant;
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    fn now() -> Self {
+        Instant
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::default()
+    }
+}
+
+// None is the main chunk, Some(n) the prototype n of the constant table
+type FunctionId = Option<usize>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Counters {
+    calls: u64,
+    instructions: u64,
+    self_time: Duration,
+    total_time: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ActiveCall {
+    function: FunctionId,
+    entered: Instant,
+    // Time spent in the functions called from this one
+    children: Duration,
+}
+
+// Collects the counters while the vm runs, see Vm::run_profiled
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) struct Profiler {
+    counters: HashMap<FunctionId, Counters>,
+    calls: Vec<ActiveCall>,
+    // Dispatches of each opcode, with the last instruction seen for its name
+    opcodes: HashMap<Discriminant<Instruction>, (Instruction, u64)>,
+}
+
+impl Profiler {
+    pub fn enter(&mut self, function: FunctionId) {
+        self.counters.entry(function).or_default().calls += 1;
+        self.calls.push(ActiveCall {
+            function,
+            entered: Instant::now(),
+            children: Duration::default(),
+        })
+    }
+
+    pub fn exit(&mut self) {
+        let call = match self.calls.pop() {
+            Some(call) => call,
+            None => return,
+        };
+        let elapsed = call.entered.elapsed();
+        // Recursive calls are already covered by the outermost one
+        let outermost = self.calls.iter().all(|c| c.function != call.function);
+        let counters = self.counters.entry(call.function).or_default();
+        counters.self_time += elapsed.saturating_sub(call.children);
+        if outermost {
+            counters.total_time += elapsed;
+        }
+        if let Some(caller) = self.calls.last_mut() {
+            caller.children += elapsed;
+        }
+    }
+
+    #[inline]
+    pub fn instruction(&mut self, instr: Instruction) {
+        if let Some(call) = self.calls.last() {
+            self.counters.entry(call.function).or_default().instructions += 1;
+        }
+        self.opcodes
+            .entry(mem::discriminant(&instr))
+            .or_insert((instr, 0))
+            .1 += 1;
+    }
+
+    pub fn finish(mut self, prototypes: &[FuncProtoRef]) -> Profile {
+        while !self.calls.is_empty() {
+            self.exit();
+        }
+        let mut functions: Vec<FunctionProfile> = self
+            .counters
+            .into_iter()
+            .map(|(function, counters)| {
+                let (name, line) = match function {
+                    None => ("main".to_owned(), None),
+                    Some(index) => (
+                        format!("fn #{}", index),
+                        prototypes
+                            .get(index)
+                            .and_then(|proto| proto.debug_info.line(0)),
+                    ),
+                };
+                FunctionProfile {
+                    name,
+                    line,
+                    calls: counters.calls,
+                    instructions: counters.instructions,
+                    self_time: counters.self_time,
+                    total_time: counters.total_time,
+                }
+            })
+            .collect();
+        functions.sort_by(|a, b| {
+            b.self_time
+                .cmp(&a.self_time)
+                .then(b.instructions.cmp(&a.instructions))
+                .then(a.name.cmp(&b.name))
+        });
+        let mut dispatches: Vec<(String, u64)> = self
+            .opcodes
+            .values()
+            .map(|(instr, count)| (opcode_name(instr), *count))
+            .collect();
+        dispatches.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        Profile {
+            functions,
+            dispatches,
+        }
+    }
+}
+
+// 'GetLocal' for 'GetLocal { index: 0, frame: 1 }'
+fn opcode_name(instr: &Instruction) -> String {
+    let debug = format!("{:?}", instr);
+    let end = debug.find([' ', '(']).unwrap_or(debug.len());
+    debug[..end].to_owned()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionProfile {
+    // 'main' or 'fn #n' like the disassembler names the prototypes
+    pub name: String,
+    // First line of the function body
+    pub line: Option<usize>,
+    pub calls: u64,
+    pub instructions: u64,
+    // Time spent in the function itself, without its callees
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+// Hottest functions first
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub functions: Vec<FunctionProfile>,
+    // Times each opcode was dispatched, most frequent first. A superinstruction is
+    // one dispatch for the instructions it fuses
+    pub dispatches: Vec<(String, u64)>,
+}
+
+impl Profile {
+    pub fn instructions(&self) -> u64 {
+        self.functions.iter().map(|f| f.instructions).sum()
+    }
+
+    pub fn function(&self, name: &str) -> Option<&FunctionProfile> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.instructions().max(1) as f64;
+        writeln!(
+            f,
+            "{:<20} {:>8} {:>12} {:>7} {:>12} {:>12}",
+            "function", "calls", "instructions", "%", "self (ms)", "total (ms)"
+        )?;
+        for function in &self.functions {
+            let name = match function.line {
+                Some(line) => format!("{} (line {})", function.name, line),
+                None => function.name.clone(),
+            };
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>12} {:>6.1}% {:>12.3} {:>12.3}",
+                name,
+                function.calls,
+                function.instructions,
+                function.instructions as f64 * 100.0 / total,
+                function.self_time.as_secs_f64() * 1000.0,
+                function.total_time.as_secs_f64() * 1000.0
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<20} {:>12} {:>7}", "opcode", "dispatches", "%")?;
+        for (name, count) in &self.dispatches {
+            writeln!(
+                f,
+                "{:<20} {:>12} {:>6.1}%",
+                name,
+                count,
+                *count as f64 * 100.0 / total
+            )?;
+        }
+        Ok(())
+    }
+}
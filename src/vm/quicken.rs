@@ -0,0 +1,106 @@
+#[cfg(feature = "threaded")]
+use super::dispatch::{handler, Handler};
+use super::{BinaryInstr, Instruction, RuntimeResult, Value, Vm};
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::fmt;
+
+// Executions of a Bin seeing operands of one type before it's specialized for them
+const THRESHOLD: u8 = 8;
+
+// The code a prototype runs: a copy of its instructions where a Bin that keeps
+// seeing two integers or two floats is rewritten to the instruction specialized
+// for them, and rewritten back when it sees something else
+#[derive(Clone)]
+pub(crate) struct Quickened {
+    code: Box<[Cell<Instruction>]>,
+    // Whether the operands were floats and how many times in a row
+    seen: Box<[Cell<(bool, u8)>]>,
+    #[cfg(feature = "threaded")]
+    handlers: Box<[Cell<Handler>]>,
+}
+
+impl Quickened {
+    pub fn new(instructions: &[Instruction]) -> Self {
+        Quickened {
+            code: instructions.iter().copied().map(Cell::new).collect(),
+            seen: instructions.iter().map(|_| Cell::new((false, 0))).collect(),
+            #[cfg(feature = "threaded")]
+            handlers: instructions.iter().map(|i| Cell::new(handler(i))).collect(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, pc: usize) -> Option<Instruction> {
+        self.code.get(pc).map(Cell::get)
+    }
+
+    #[cfg(feature = "threaded")]
+    #[inline(always)]
+    pub fn handler(&self, pc: usize) -> Handler {
+        self.handlers[pc].get()
+    }
+
+    fn rewrite(&self, pc: usize, instr: Instruction) {
+        self.code[pc].set(instr);
+        #[cfg(feature = "threaded")]
+        self.handlers[pc].set(handler(&instr));
+    }
+}
+
+// Derived from the instructions, which are compared instead
+impl PartialEq for Quickened {
+    fn eq(&self, _: &Quickened) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for Quickened {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quickened({})", self.code.len())
+    }
+}
+
+impl Vm {
+    pub(super) fn binary_quickening(&mut self, op: BinaryInstr) -> RuntimeResult<()> {
+        let floats = match &self.stack[..] {
+            [.., Value::Int(_), Value::Int(_)] => Some(false),
+            [.., Value::Number(_), Value::Number(_)] => Some(true),
+            _ => None,
+        };
+        let specialized = match floats {
+            Some(false) => Instruction::int_bin(op),
+            Some(true) => Instruction::float_bin(op),
+            None => Instruction::Bin(op),
+        };
+        if let Some(frame) = self.frames.last() {
+            if let (Some(proto), Some(floats)) = (frame.proto(), floats) {
+                let quickened = &proto.quickened;
+                let seen = &quickened.seen[frame.pc];
+                let count = match seen.get() {
+                    (seen_floats, count) if seen_floats == floats => count + 1,
+                    _ => 1,
+                };
+                if count < THRESHOLD {
+                    seen.set((floats, count));
+                } else if specialized != Instruction::Bin(op) {
+                    quickened.rewrite(frame.pc, specialized);
+                }
+            }
+        }
+        self.binary(op)
+    }
+
+    // A specialized instruction saw other operands. One the compiler chose stays,
+    // one rewritten from a Bin turns back into it and starts counting again
+    pub(super) fn deoptimize(&mut self, op: BinaryInstr) {
+        if let Some(frame) = self.frames.last() {
+            if let Some(proto) = frame.proto() {
+                if proto.instructions[frame.pc] == Instruction::Bin(op) {
+                    proto.quickened.rewrite(frame.pc, Instruction::Bin(op));
+                    proto.quickened.seen[frame.pc].set((false, 0));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+#[cfg(feature = "std")]
+use super::awaiting::{Awaiting, Executor};
+use super::{Frame, Integer, RuntimeError, RuntimeResult, UserFunction, Value, Vm};
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::mem;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+// A task the vm isn't running, with the stack and the call frames it runs on.
+// The source itself is task 0, the ones spawn creates count up from 1
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Task {
+    id: Integer,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    // Not started yet, the function is called when the task first runs
+    start: Option<UserFunction>,
+    #[cfg(feature = "std")]
+    wake_at: Option<Instant>,
+    // Runs once the channel has a value, which replaces the one receive returned
+    waiting: Option<Channel>,
+    // Set by set_interval, the function starts again that long after it returned
+    every: Option<(UserFunction, Duration)>,
+}
+
+impl Task {
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn start(
+        id: Integer,
+        function: UserFunction,
+        delay: Option<Duration>,
+        every: Option<Duration>,
+    ) -> Self {
+        Task {
+            id,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            every: every.map(|every| (function.clone(), every)),
+            start: Some(function),
+            #[cfg(feature = "std")]
+            wake_at: delay.map(|delay| Instant::now() + delay),
+            waiting: None,
+        }
+    }
+}
+
+// The values sent and not received yet, oldest first
+pub(super) type Channel = Rc<RefCell<VecDeque<Value>>>;
+
+// Set by the natives to switch tasks once they returned
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Switch {
+    Yield,
+    // Without std there is no clock, it only yields
+    Sleep(Duration),
+    Receive(Channel),
+}
+
+// Runs the tasks one at a time, each until it yields, sleeps or returns
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(super) struct Scheduler {
+    // Waiting to run, in the order they run
+    tasks: VecDeque<Task>,
+    current: Integer,
+    last_id: Integer,
+    pub switch: Option<Switch>,
+    // The value of the source, kept while spawned tasks still run
+    result: Option<Value>,
+    // The interval of the running task, see Task::every
+    every: Option<(UserFunction, Duration)>,
+    // The futures of the async natives tasks wait for
+    #[cfg(feature = "std")]
+    pub awaiting: Vec<Awaiting>,
+    // Set while Vm::run_until_yield runs, wakes the task polling the vm
+    #[cfg(feature = "std")]
+    pub executor: Option<Executor>,
+}
+
+impl Vm {
+    // Runs 'function' as a new task after the ones already waiting
+    pub(super) fn spawn(&mut self, function: UserFunction) -> Integer {
+        self.spawn_timer(function, None, None)
+    }
+
+    // Like spawn, the task starts once 'delay' passed. With 'every' the function
+    // starts again that long after each time it returned, until clear_timer
+    pub(super) fn spawn_timer(
+        &mut self,
+        function: UserFunction,
+        delay: Option<Duration>,
+        every: Option<Duration>,
+    ) -> Integer {
+        let scheduler = &mut self.scheduler;
+        scheduler.last_id += 1;
+        let task = Task::start(scheduler.last_id, function, delay, every);
+        scheduler.tasks.push_back(task);
+        scheduler.last_id
+    }
+
+    // Keeps the task 'id' from starting its function again, or at all when it
+    // hasn't started yet. Returns whether there was something to stop
+    pub(super) fn clear_timer(&mut self, id: Integer) -> bool {
+        let scheduler = &mut self.scheduler;
+        if id == scheduler.current {
+            return scheduler.every.take().is_some();
+        }
+        let index = match scheduler.tasks.iter().position(|task| task.id == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        if scheduler.tasks[index].start.is_some() {
+            scheduler.tasks.remove(index);
+            return true;
+        }
+        scheduler.tasks[index].every.take().is_some()
+    }
+
+    // What the loops do once 'execute' returned: switch tasks if a native asked for
+    // it, go on with the caller of the frame that returned, or finish the task.
+    // The value is the one of the source, once every task finished
+    pub(super) fn after_execute(&mut self) -> RuntimeResult<Option<Value>> {
+        if let Some(switch) = self.scheduler.switch.take() {
+            let task = self.suspend(switch);
+            self.scheduler.tasks.push_back(task);
+            self.run_next_task()?;
+            return Ok(None);
+        }
+        if !self.frames.is_empty() {
+            self.current_frame_mut()?.pc += 1;
+            return Ok(None);
+        }
+        let value = self.pop_stack()?;
+        if self.scheduler.current == 0 {
+            self.scheduler.result = Some(value);
+        }
+        if let Some((function, every)) = self.scheduler.every.take() {
+            let task = Task::start(self.scheduler.current, function, Some(every), Some(every));
+            self.scheduler.tasks.push_back(task);
+        }
+        if self.scheduler.tasks.is_empty() {
+            self.scheduler.current = 0;
+            return Ok(self.scheduler.result.take());
+        }
+        self.run_next_task()?;
+        Ok(None)
+    }
+
+    fn suspend(&mut self, switch: Switch) -> Task {
+        let mut task = Task {
+            id: self.scheduler.current,
+            stack: mem::take(&mut self.stack),
+            frames: mem::take(&mut self.frames),
+            start: None,
+            #[cfg(feature = "std")]
+            wake_at: None,
+            waiting: None,
+            every: self.scheduler.every.take(),
+        };
+        match switch {
+            Switch::Yield => (),
+            #[cfg(feature = "std")]
+            Switch::Sleep(duration) => task.wake_at = Some(Instant::now() + duration),
+            #[cfg(not(feature = "std"))]
+            Switch::Sleep(_) => (),
+            Switch::Receive(channel) => task.waiting = Some(channel),
+        }
+        task
+    }
+
+    // The next value of the channel, the task waits for one when it's empty
+    pub(super) fn receive(&mut self, channel: Channel) -> RuntimeResult<Value> {
+        if let Some(value) = channel.borrow_mut().pop_front() {
+            return Ok(value);
+        }
+        // Natives can't be suspended, the other tasks can't send anything meanwhile
+        if self.blocking > 0 {
+            return Err(RuntimeError::Deadlock);
+        }
+        self.scheduler.switch = Some(Switch::Receive(channel));
+        Ok(Value::Nil)
+    }
+
+    // Takes the first task that can run. Without one, Vm::run waits for the sleeping
+    // tasks and the futures of async natives, Vm::run_until_yield leaves the vm
+    // without a task for the executor to poll it again
+    pub(super) fn run_next_task(&mut self) -> RuntimeResult<()> {
+        let index = match self.next_task_index()? {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let task = self.scheduler.tasks.remove(index).expect("Expected a task");
+        self.scheduler.current = task.id;
+        self.stack = task.stack;
+        self.frames = task.frames;
+        self.scheduler.every = task.every;
+        if let Some(channel) = task.waiting {
+            let value = channel.borrow_mut().pop_front().expect("Expected a value");
+            *self
+                .stack
+                .last_mut()
+                .expect("Expected the value of receive") = value;
+        }
+        match task.start {
+            Some(function) => self.call_user(function, 0),
+            None => Ok(()),
+        }
+    }
+
+    fn next_task_index(&mut self) -> RuntimeResult<Option<usize>> {
+        loop {
+            #[cfg(feature = "std")]
+            self.poll_awaiting()?;
+            if let Some(index) = self.ready_task_index() {
+                return Ok(Some(index));
+            }
+            #[cfg(feature = "std")]
+            if let Some(executor) = &self.scheduler.executor {
+                return match self.scheduler.awaiting.is_empty() {
+                    // Only sleepers, the executor polls again right away
+                    true if self.sleeping().is_some() => {
+                        executor.wake_by_ref();
+                        Ok(None)
+                    }
+                    true => Err(RuntimeError::Deadlock),
+                    false => Ok(None),
+                };
+            }
+            self.wait()?;
+        }
+    }
+
+    fn ready_task_index(&self) -> Option<usize> {
+        let received = |task: &Task| match &task.waiting {
+            Some(channel) => !channel.borrow().is_empty(),
+            None => true,
+        };
+        #[cfg(feature = "std")]
+        {
+            let now = Instant::now();
+            let awake = |task: &Task| task.wake_at.is_none_or(|wake_at| wake_at <= now);
+            self.scheduler
+                .tasks
+                .iter()
+                .position(|task| awake(task) && received(task))
+        }
+        #[cfg(not(feature = "std"))]
+        self.scheduler.tasks.iter().position(received)
+    }
+
+    #[cfg(feature = "std")]
+    fn sleeping(&self) -> Option<Instant> {
+        self.scheduler
+            .tasks
+            .iter()
+            .filter_map(|task| task.wake_at)
+            .min()
+    }
+
+    // Blocks the thread until the first sleeper wakes or a future wakes it, but
+    // not past the deadline of Vm::run_with_timeout
+    #[cfg(feature = "std")]
+    fn wait(&self) -> RuntimeResult<()> {
+        let sleeping = self.sleeping();
+        if sleeping.is_none() && self.scheduler.awaiting.is_empty() {
+            return Err(RuntimeError::Deadlock);
+        }
+        let deadline = self.deadline.map(|(at, _)| at);
+        let until = match (sleeping, deadline) {
+            (Some(wake_at), Some(at)) => Some(wake_at.min(at)),
+            (wake_at, at) => wake_at.or(at),
+        };
+        let timeout = until.map(|until| until.saturating_duration_since(Instant::now()));
+        match (timeout, self.scheduler.awaiting.is_empty()) {
+            (Some(timeout), true) => std::thread::sleep(timeout),
+            (None, _) => std::thread::park(),
+            (Some(timeout), false) => std::thread::park_timeout(timeout),
+        }
+        self.check_deadline()
+    }
+
+    // Without a clock every task is awake, none of them can run
+    #[cfg(not(feature = "std"))]
+    fn wait(&self) -> RuntimeResult<()> {
+        Err(RuntimeError::Deadlock)
+    }
+
+    // Forgets the tasks of the last source, a new one starts as task 0
+    pub(super) fn reset_tasks(&mut self) {
+        self.scheduler = Scheduler::default();
+    }
+}
@@ -0,0 +1,137 @@
+// Handlers for the signals stopping a process. The operating system's handler only
+// records the signal, the vm looks at it between instructions and unwinds the
+// script to run the handler the script registered, see Vm::run
+use super::{RuntimeError, RuntimeResult, UserFunction, Value, Vm};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Received and not handled yet, one bit per signal
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    // Ctrl-C, "int" in scripts
+    Interrupt,
+    // "term" in scripts
+    Terminate,
+}
+
+impl Signal {
+    pub fn from_name(name: &str) -> Option<Signal> {
+        match name {
+            "int" => Some(Signal::Interrupt),
+            "term" => Some(Signal::Terminate),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "int",
+            Signal::Terminate => "term",
+        }
+    }
+
+    fn bit(self) -> usize {
+        1 << self as usize
+    }
+
+    // Records the signal as if the process received it, for hosts interrupting the
+    // script from another thread
+    pub fn raise(self) {
+        PENDING.fetch_or(self.bit(), Ordering::SeqCst);
+    }
+
+    #[cfg(unix)]
+    fn install(self) {
+        extern "C" fn record(signum: libc::c_int) {
+            let signal = match signum {
+                libc::SIGINT => Signal::Interrupt,
+                _ => Signal::Terminate,
+            };
+            signal.raise();
+        }
+
+        let signum = match self {
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+        };
+        let handler = record as extern "C" fn(libc::c_int);
+        unsafe { libc::signal(signum, handler as libc::sighandler_t) };
+    }
+
+    // Only Signal::raise reaches the scripts
+    #[cfg(not(unix))]
+    fn install(self) {}
+}
+
+impl Vm {
+    // Once the signal is received, the running script stops at the next check and
+    // 'handler' runs without arguments instead, its value is the one of Vm::run.
+    // The process isn't stopped by the signal anymore
+    pub fn on_signal(&mut self, signal: Signal, handler: UserFunction) {
+        self.signal_handlers
+            .retain(|(handled, _)| *handled != signal);
+        self.signal_handlers.push((signal, handler));
+        signal.install();
+    }
+
+    // Fails with Interrupted when a signal with a handler was received
+    pub(super) fn check_signals(&self) -> RuntimeResult<()> {
+        for (signal, _) in &self.signal_handlers {
+            let bit = signal.bit();
+            if PENDING.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+                return Err(RuntimeError::Interrupted(signal.name()));
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the handler of the signal that interrupted the script, on an empty stack
+    pub(super) fn handle_signal(&mut self, result: RuntimeResult<Value>) -> RuntimeResult<Value> {
+        let name = match &result {
+            Err(error) => match error.cause() {
+                RuntimeError::Interrupted(name) => *name,
+                _ => return result,
+            },
+            Ok(_) => return result,
+        };
+        let handler = self
+            .signal_handlers
+            .iter()
+            .find(|(signal, _)| signal.name() == name)
+            .map(|(_, handler)| handler.clone());
+        let handler = match handler {
+            Some(handler) => handler,
+            None => return result,
+        };
+        self.abort();
+        self.init_call();
+        let value = self.call_function(handler.into(), &[]);
+        self.frames.clear();
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::eval;
+    use crate::vm::Value;
+    use std::thread;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_unwind_to_the_handler() {
+        let source = "
+        os.on_signal(\"int\", fn() \"stopped\" end);
+        var i = 0;
+        while true then i = i + 1; end;
+        ";
+        let interrupt = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            unsafe { libc::raise(libc::SIGINT) };
+        });
+        assert_eq!(eval(source, ""), Ok(Value::new_str("stopped")));
+        interrupt.join().unwrap();
+    }
+}
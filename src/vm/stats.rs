@@ -0,0 +1,83 @@
+use super::{Function, Value};
+use alloc::rc::Rc;
+use hashbrown::HashSet;
+
+// Resource usage of a vm, see Vm::stats. Values are reference counted, so there
+// are no collections to count
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    // Live values, the ones reachable from the globals, the stack and the call frames
+    pub tables: usize,
+    pub strings: usize,
+    pub functions: usize,
+    // Highest number of values on the stack and of nested calls
+    pub peak_stack: usize,
+    pub peak_frames: usize,
+    // Executed since the vm was created, including the ones of imported modules
+    pub instructions: u64,
+    // Highest number of bytes allocated over the ones live when the source started
+    // running, sampled while it runs. Stays 0 without the TrackingAllocator
+    pub peak_memory: usize,
+}
+
+impl Stats {
+    // Counts what the vm keeps up to date while it runs
+    pub(super) fn record(&mut self, stack: usize, frames: usize) {
+        self.instructions += 1;
+        self.peak_stack = self.peak_stack.max(stack);
+        self.peak_frames = self.peak_frames.max(frames);
+    }
+
+    // Adds the counters of the vm that ran an imported module
+    pub(super) fn merge(&mut self, other: &Stats) {
+        self.instructions += other.instructions;
+        self.peak_stack = self.peak_stack.max(other.peak_stack);
+        self.peak_frames = self.peak_frames.max(other.peak_frames);
+        self.peak_memory = self.peak_memory.max(other.peak_memory);
+    }
+}
+
+// Walks the values once each, shared tables and strings are counted once
+#[derive(Default)]
+pub(super) struct Census {
+    tables: HashSet<*const ()>,
+    strings: HashSet<*const ()>,
+    // A function is its prototype and its environment
+    functions: HashSet<(*const (), *const ())>,
+}
+
+impl Census {
+    pub fn visit(&mut self, value: &Value) {
+        match value {
+            Value::Str(rc) => {
+                self.strings.insert(Rc::as_ptr(rc) as *const ());
+            }
+            Value::Table(rc) if self.tables.insert(Rc::as_ptr(rc) as *const ()) => {
+                let table = rc.borrow();
+                for (key, value) in table.pairs() {
+                    self.visit(key);
+                    self.visit(value);
+                }
+            }
+            Value::Tuple(values) => values.iter().for_each(|value| self.visit(value)),
+            Value::Function(Function::User(function)) => {
+                let proto = Rc::as_ptr(function.proto_ref()) as *const ();
+                let env = function
+                    .env()
+                    .map_or(core::ptr::null(), |env| Rc::as_ptr(env) as *const ());
+                if self.functions.insert((proto, env)) {
+                    if let Some(env) = function.env() {
+                        self.visit(&Value::Table(env.clone()));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn finish(self, stats: &mut Stats) {
+        stats.tables = self.tables.len();
+        stats.strings = self.strings.len();
+        stats.functions = self.functions.len();
+    }
+}
@@ -1,4 +1,5 @@
 use super::RuntimeError;
+use crate::compiler::CompileError;
 use crate::error::FluxError;
 use crate::parser::{ParserError, ParserErrorKind};
 use crate::vm::Value;
@@ -15,6 +16,120 @@ unit_test! {
     })))
 }
 
+unit_test! {
+    unicode_identifiers,
+    "
+    let größe = 2;
+    let 数 = größe * 3;
+    let ｘ = 1;
+    return 数 + x;
+    ",
+    Ok(Value::Int(7))
+}
+
+unit_test! {
+    quoted_strings,
+    r#"return 'say "hi"\n' == "say \"hi\"\n";"#,
+    Ok(Value::Bool(true))
+}
+
+unit_test! {
+    multi_line_strings,
+    r#"
+    let s = """
+        a
+          b
+        """;
+    return s == "a\n  b";
+    "#,
+    Ok(Value::Bool(true))
+}
+
+unit_test! {
+    word_logical_operators,
+    "
+    var calls = 0;
+    let f = fn() calls = calls + 1; true end;
+    let a = false and f();
+    let b = true or f();
+    let c = 1 < 2 and not 2 < 1 or f();
+    let d = nil or f() and 0;
+    return (a, b, c, d, calls);
+    ",
+    Ok(Value::Tuple(vec![
+        Value::Bool(false),
+        Value::Bool(true),
+        Value::Bool(true),
+        Value::Int(0),
+        Value::Int(1)
+    ].into()))
+}
+
+unit_test! {
+    unicode_escapes,
+    r#"return ("caf\u{e9} \x41", c'\u{1F600}');"#,
+    Ok(Value::Tuple(vec![
+        Value::new_str("café A"),
+        Value::Int(0x1F600)
+    ].into()))
+}
+
+unit_test! {
+    append_keeps_copies,
+    r#"
+    let s = "a";
+    let t = s;
+    let i = 0;
+    while i < 3 then
+        s = s + "b";
+        i = i + 1;
+    end
+    let u = s;
+    s = s + u;
+    return (s, t, u);
+    "#,
+    Ok(Value::Tuple(vec![
+        Value::new_str("abbbabbb"),
+        Value::new_str("a"),
+        Value::new_str("abbb")
+    ].into()))
+}
+
+// Specialized for the literal's type, other operands take the generic path
+unit_test! {
+    specialized_operators_accept_any_operand,
+    "
+    let f = 1.5;
+    let i = 2;
+    return (f + 1, i - 0.5, f < 2, i + 1.0, 3 - i);
+    ",
+    Ok(Value::Tuple(vec![
+        Value::Number(2.5),
+        Value::Number(1.5),
+        Value::Bool(true),
+        Value::Number(3.0),
+        Value::Int(1)
+    ].into()))
+}
+
+unit_test! {
+    char_literals,
+    r"return c'a' + c'\n' * 1000 + c'ş' * 1000000;",
+    Ok(Value::Int(97 + 10_000 + 351_000_000))
+}
+
+unit_test! {
+    float_literals_stay_floats,
+    "return (2.0, 9007199254740993 - 9007199254740992);",
+    Ok(Value::Tuple(vec![Value::Number(2.0), Value::Int(1)].into()))
+}
+
+unit_test! {
+    digit_separators,
+    "return 1_000_000 + 0xFF_FF + 0.2_5 * 4;",
+    Ok(Value::Number(1_065_536.0))
+}
+
 unit_test! {
     arithmetic,
     "
@@ -136,7 +251,7 @@ unit_test! {
     Ok(Value::Tuple(vec![
         Value::Int(10),
         Value::Int(5)
-    ]))
+    ].into()))
 } */
 
 unit_test! {
@@ -345,15 +460,465 @@ unit_test! {
     Ok(Value::Int(1024))
 }
 
+unit_test! {
+    for_loop_iterables,
+    "
+    var countdown = {
+        \"n\" = 3,
+        \"next\" = fn(self)
+            if self.n > 0 then
+                self.n = self.n - 1;
+                self.n + 1
+            else
+                nil
+            end
+        end,
+    };
+    var digits = 0;
+    for n in countdown do
+        digits = digits * 10 + n;
+    end
+    // Each loop in its own scope, the hidden iterator is declared by each of them
+    var count = 0;
+    do
+        for pair in pairs({\"a\" = 1, \"b\" = 2}) do
+            count = count + 1;
+        end
+    end
+    var last = nil;
+    do
+        for pair in ipairs({5, 6}) do
+            last = pair;
+        end
+    end
+    (digits, count, last)
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(321),
+            Value::Int(2),
+            Value::Tuple(vec![Value::Int(1), Value::Int(6)].into()),
+        ]
+        .into()
+    ))
+}
+
+unit_test! {
+    range_loops,
+    "
+    var sum = 0;
+    for i in 0..5 do
+        sum = sum + i;
+    end
+    // The end is evaluated once, an empty range runs no iteration
+    var n = 3;
+    for i in 1..n do
+        n = n + 1;
+    end
+    for i in 3..3 do
+        sum = sum + 100;
+    end
+    let count = fn(from, to)
+        let total = 0;
+        for i in from..to do
+            total = total + 1;
+        end
+        total
+    end;
+    (sum, n, count(-2, 2))
+    ",
+    Ok(Value::Tuple(
+        vec![Value::Int(10), Value::Int(5), Value::Int(4)].into()
+    ))
+}
+
+unit_test! {
+    range_bounds_in_source_order,
+    "
+    var order = \"\";
+    let mark = fn(name, value)
+        order = order + name;
+        value
+    end;
+    var runs = 0;
+    for i in mark(\"start\", 1)..mark(\"end\", 3) do
+        runs = runs + 1;
+    end
+    // The bounds see the variables outside the loop, not its counter
+    let i = 4;
+    for i in i - 2..i do
+        runs = runs + 1;
+    end
+    (order, runs)
+    ",
+    Ok(Value::Tuple(
+        vec![Value::new_str("startend"), Value::Int(4)].into()
+    ))
+}
+
+unit_test! {
+    for_loop_needs_an_iterable,
+    "for x in {1, 2} do end",
+    Err(RuntimeError::Native(
+        "for loops need a function or a table with a 'next' method".to_owned()
+    )
+    .into())
+}
+
+unit_test! {
+    generators,
+    "
+    fn count(n)
+        let i = 0;
+        while i < n then
+            yield i * 10;
+            i = i + 1;
+        end
+    end
+    var total = 0;
+    for x in count(4) do
+        total = total + x;
+    end
+    // Each call runs the body again, from its start
+    var pair = fn(a) yield (a, a) end;
+    var it = count(1);
+    (total, it(), it(), it(), pair(2)())
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(60),
+            Value::Int(0),
+            Value::Nil,
+            Value::Nil,
+            Value::Tuple(vec![Value::Int(2), Value::Int(2)].into()),
+        ]
+        .into()
+    ))
+}
+
+unit_test! {
+    block_locals_above_operands,
+    "
+    let a = 10;
+    let sum = 1 + do let c = 2; c * 3 end;
+    let pair = (do let i = 1; i end, do let j = 2; j end);
+    let t = {\"x\" = 0};
+    t[do let key = \"x\"; key end] = do let v = 41; v + 1 end;
+    let add = fn(x, y) x + y end;
+    let nested = do
+        let outer = 1;
+        outer + add(a, do let inner = 2; outer + inner end)
+    end;
+    (sum, pair, t.x, nested, a)
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(7),
+            Value::Tuple(vec![Value::Int(1), Value::Int(2)].into()),
+            Value::Int(42),
+            Value::Int(14),
+            Value::Int(10),
+        ]
+        .into()
+    ))
+}
+
+unit_test! {
+    break_and_continue,
+    "
+    var found = nil;
+    for i in 0..10 do
+        let square = i * i;
+        if square > 20 then
+            found = (i, square);
+            break;
+        end
+    end
+    var odd = 0;
+    for i in 0..10 do
+        if i % 2 == 0 then continue; end
+        odd = odd + i;
+    end
+    // Only the inner loop is left, 'continue' skips the rest of the while body
+    var n = 0;
+    var hits = 0;
+    while n < 4 then
+        n = n + 1;
+        if n == 2 then continue; end
+        for j in 0..100 do
+            if j == 3 then break; end
+            hits = hits + 1;
+        end
+    end
+    let first = if true then
+        let value = nil;
+        while true then
+            value = 7;
+            break;
+        end
+        value
+    else
+        nil
+    end;
+    (found, odd, hits, first)
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Tuple(vec![Value::Int(5), Value::Int(25)].into()),
+            Value::Int(25),
+            Value::Int(9),
+            Value::Int(7),
+        ]
+        .into()
+    ))
+}
+
+#[test]
+fn break_and_continue_need_a_loop() {
+    use crate::macros::eval;
+
+    for (source, keyword) in &[
+        ("break;", "break"),
+        (
+            "while true then let f = fn() continue; end; end",
+            "continue",
+        ),
+    ] {
+        assert_eq!(
+            eval(source).map_err(FluxError::without_location),
+            Err(FluxError::Compile(CompileError::OutsideLoop {
+                keyword: keyword.to_string(),
+                line: 1
+            }))
+        );
+    }
+    assert_eq!(
+        eval("while true then 1 + do break; 2 end; end").map_err(FluxError::without_location),
+        Err(FluxError::Compile(CompileError::LeaveExpression {
+            keyword: "break".to_owned(),
+            line: 1
+        }))
+    );
+}
+
+unit_test! {
+    yield_outside_function,
+    "yield 1;",
+    Err(FluxError::Compile(CompileError::YieldOutsideFunction { line: 1 }))
+}
+
+unit_test! {
+    tuple_access,
+    "
+    let t = (1, (2, 3), \"x\");
+    let i = 2;
+    (t.0, t.1.1, t.1.0, t[i])
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(1),
+            Value::Int(3),
+            Value::Int(2),
+            Value::new_str("x"),
+        ]
+        .into()
+    ))
+}
+
+#[test]
+fn tuple_index_out_of_range() {
+    use crate::macros::eval;
+
+    for (source, index) in &[("(1, 2).2", 2), ("let t = (1, 2); t[-1]", -1)] {
+        assert_eq!(
+            eval(source).map_err(FluxError::without_location),
+            Err(RuntimeError::IndexOutOfRange {
+                index: *index,
+                len: 2
+            }
+            .into())
+        );
+    }
+}
+
+unit_test! {
+    enums,
+    "
+    enum Color { Red, Green, Blue, }
+    fn name(color)
+        if color == Color.Red then \"red\" else \"other\" end
+    end
+    let c = Color;
+    (name(c.Red), name(Color.Blue), Color.Green == c.Green, Color.Red == Color.Blue)
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::new_str("red"),
+            Value::new_str("other"),
+            Value::Bool(true),
+            Value::Bool(false),
+        ]
+        .into()
+    ))
+}
+
+#[test]
+fn enums_are_frozen() {
+    use crate::macros::eval;
+
+    for source in &[
+        "enum E { A } let e = E; e.A = 1;",
+        "enum E { A } rawset(E, \"A\", 1);",
+    ] {
+        assert_eq!(
+            eval(source).map_err(FluxError::without_location),
+            Err(RuntimeError::FrozenTable {
+                key: Value::new_str("A")
+            }
+            .into())
+        );
+    }
+    assert_eq!(
+        eval("enum E { A } E = 1;"),
+        Err(FluxError::Compile(CompileError::AssignToEnum {
+            name: "E".to_owned()
+        }))
+    );
+    assert_eq!(
+        eval("enum E { A, B } E.C"),
+        Err(FluxError::Compile(CompileError::UnknownVariant {
+            name: "E".to_owned(),
+            variant: "C".to_owned(),
+            variants: vec!["A".to_owned(), "B".to_owned()],
+        }))
+    );
+}
+
+unit_test! {
+    casts,
+    "
+    let t = (1, 2.4) as (number, int);
+    (1 as number, 1 + 2.6 as int, \"a\" as str, t, assert_type(1, \"number\"), nil as any)
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Number(1.0),
+            Value::Int(4),
+            Value::new_str("a"),
+            Value::Tuple(vec![Value::Number(1.0), Value::Int(2)].into()),
+            Value::Int(1),
+            Value::Nil,
+        ]
+        .into()
+    ))
+}
+
+#[test]
+fn casts_check_the_type() {
+    use crate::macros::eval;
+
+    for (source, expected, found) in &[
+        ("\"a\" as int", "int", "str"),
+        ("(1, \"a\") as (int, int)", "(int, int)", "(int, str)"),
+        ("assert_type(1.5, \"int\")", "int", "number"),
+        ("assert_type(fn() end, \"table\")", "table", "fn"),
+    ] {
+        assert_eq!(
+            eval(source).map_err(FluxError::without_location),
+            Err(RuntimeError::WrongType {
+                expected: expected.to_string(),
+                found: found.to_string()
+            }
+            .into())
+        );
+    }
+    assert_eq!(
+        eval("assert_type({}, \"tabel\")").map_err(FluxError::without_location),
+        Err(RuntimeError::UnknownType("tabel".to_owned()).into())
+    );
+}
+
 unit_test! {
     global_variable,
     "foo = 5;",
     Err(FluxError::Parse(ParserError {
-        kind: ParserErrorKind::Undeclared { name: "foo".to_owned() },
+        kind: ParserErrorKind::Undeclared {
+            name: "foo".to_owned(),
+            suggestion: None
+        },
         line: 1,
+        span: None,
+    }))
+}
+
+unit_test! {
+    duplicate_local,
+    "
+    let x = 1;
+    let x = 2;
+    ",
+    Err(FluxError::Parse(ParserError {
+        kind: ParserErrorKind::Redeclaration { name: "x".to_owned() },
+        line: 3,
+        span: None,
     }))
 }
 
+unit_test! {
+    shadowing_in_inner_scope,
+    "
+    let x = 1;
+    let y = do
+        let x = 2;
+        let z = do
+            let x = 3;
+            x
+        end;
+        x + z
+    end;
+    x + y
+    ",
+    Ok(Value::Int(6))
+}
+
+unit_test! {
+    folded_string_concat,
+    "
+    let suffix = \"c\";
+    let s = \"a\" + \"b\" + suffix + \"d\" + \"e\";
+    assert(s == \"abcde\");
+    ",
+    Ok(Value::Unit)
+}
+
+unit_test! {
+    literal_table_is_copied,
+    "
+    let array = fn() { 1, 2, 3 } end;
+    let first = array();
+    first.x = 10;
+    assert(array().x == nil);
+    assert(array()[2] == 3);
+    let keyed = fn() { \"x\" = 1, \"y\" = 2 } end;
+    let k = keyed();
+    k.x = 5;
+    keyed().x + k.x + first.x
+    ",
+    Ok(Value::Int(16))
+}
+
+unit_test! {
+    if_stmt_keeps_stack_balanced,
+    "
+    let x = 1;
+    if x > 0 then 5; end
+    if x < 0 then 6; else 7; end
+    let y = 8;
+    y
+    ",
+    Ok(Value::Int(8))
+}
+
 unit_test! {
     remainder,
     "
@@ -364,21 +929,451 @@ unit_test! {
     Ok(Value::Unit)
 }
 
+#[test]
+fn script_args() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+
+    let source = "
+    let count = fn()
+        let i = 0;
+        while args[i] != nil then i = i + 1; end
+        i
+    end;
+    assert(count() == 3);
+    args[0] + \":\" + args[2]
+    ";
+    let compiled = compile(source);
+    let mut vm = Vm::new();
+    vm.set_args(&["tool.flux".to_owned(), "a".to_owned(), "b".to_owned()]);
+    assert_eq!(vm.run(&compiled), Ok(Value::new_str("tool.flux:b")));
+}
+
+unit_test! {
+    args_without_arguments,
+    "assert(args[0] == nil);",
+    Ok(Value::Unit)
+}
+
+unit_test! {
+    fn_info_describes_functions,
+    "
+    fn add(a, b) a + b end
+    fn counter()
+        let n = 0;
+        fn() n = n + 1; n end
+    end
+    var info = fn_info(add);
+    var closure = fn_info(counter());
+    var native = fn_info(print);
+    (
+        info.arity, info.native, info.name, info.upvalues,
+        closure.arity, closure.upvalues,
+        native.arity, native.native, native.name
+    )
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(2),
+            Value::Bool(false),
+            Value::new_str("add"),
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(1),
+            Value::Nil,
+            Value::Bool(true),
+            Value::new_str("print"),
+        ]
+        .into()
+    ))
+}
+
+unit_test! {
+    raw_table_access,
+    "
+    var t = {};
+    rawset(t, \"x\", 1);
+    var same = t;
+    (rawget(t, \"x\"), rawget(t, \"y\"), rawequal(t, same), rawequal(t, {}), rawequal(1, 1))
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(1),
+            Value::Nil,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Bool(true),
+        ]
+        .into()
+    ))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    tasks_take_turns,
+    "
+    var order = 0;
+    fn worker(id)
+        fn()
+            let i = 0;
+            while i < 3 then
+                order = order * 10 + id;
+                i = i + 1;
+                yield();
+            end;
+        end
+    end
+    spawn(worker(1));
+    spawn(worker(2));
+    sleep(1);
+    order
+    ",
+    Ok(Value::Int(121212))
+}
+
+#[test]
+fn functions_display_their_definition() {
+    use crate::macros::eval;
+
+    let source = "
+    fn add(a, b) a + b end
+    fn counter()
+        let n = 0;
+        fn() n = n + 1; n end
+    end
+    (add, counter(), print)
+    ";
+    let value = eval(source).unwrap();
+    assert_eq!(
+        value.to_string(),
+        "(fn add(2 args) @ line 2, fn(0 args, 1 upvalue) @ line 5, native fn(variadic))"
+    );
+}
+
+#[test]
+fn tasks_run_after_the_source() {
+    use crate::macros::eval;
+
+    let source = "
+    var runs = 0;
+    let task = fn() runs = runs + 1; end;
+    assert(spawn(task) == 1);
+    assert(spawn(fn() assert(runs == 1); end) == 2);
+    runs
+    ";
+    assert_eq!(eval(source), Ok(Value::Int(0)));
+    let error = eval("spawn(fn() assert(false); end); 0").unwrap_err();
+    assert_eq!(
+        error.without_location(),
+        RuntimeError::AssertionFailed(Value::Bool(false)).into()
+    );
+}
+
+unit_test! {
+    channels_wait_for_values,
+    "
+    let numbers = channel();
+    let sums = channel();
+    spawn(fn()
+        let total = 0;
+        let n = numbers.receive();
+        while n != nil then
+            total = total + n;
+            n = numbers.receive();
+        end;
+        sums.send(total);
+    end);
+    numbers.send(1);
+    numbers.send(2);
+    numbers.send(3);
+    numbers.send(nil);
+    sums.receive()
+    ",
+    Ok(Value::Int(6))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    timers_run_after_their_delay,
+    "
+    var order = 0;
+    set_timeout(fn() order = order * 10 + 2; end, 40);
+    set_timeout(fn() order = order * 10 + 1; end, 1);
+    clear_timer(set_timeout(fn() order = -1; end, 1));
+    var ticks = 0;
+    var ticker = 0;
+    ticker = set_interval(fn()
+        ticks = ticks + 1;
+        if ticks == 3 then
+            clear_timer(ticker);
+        end;
+    end, 1);
+    sleep(60);
+    (order, ticks)
+    ",
+    Ok(Value::Tuple(vec![Value::Int(12), Value::Int(3)].into()))
+}
+
+#[test]
+fn receiving_from_no_sender_deadlocks() {
+    use crate::macros::eval;
+
+    for source in &[
+        "channel().receive();",
+        "let c = channel(); spawn(fn() c.receive(); end); 1",
+        "let c = channel(); for_each({1}, fn(x) c.receive(); end);",
+    ] {
+        assert_eq!(
+            eval(source).map_err(FluxError::without_location),
+            Err(RuntimeError::Deadlock.into())
+        );
+    }
+}
+
+unit_test! {
+    undeclared_suggests_name,
+    "let count = 1; fn f(value) valu + count end",
+    Err(FluxError::Parse(ParserError {
+        kind: ParserErrorKind::Undeclared {
+            name: "valu".to_owned(),
+            suggestion: Some("value".to_owned())
+        },
+        line: 1,
+        span: None,
+    }))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    workers_copy_messages,
+    "
+    var squares = worker.spawn(\"src/vm/tests/worker.flux\");
+    squares.send(3);
+    squares.send(4);
+    squares.send(nil);
+    (squares.receive(), squares.receive(), squares.receive(), squares.join())
+    ",
+    Ok(Value::Tuple(
+        vec![Value::Int(9), Value::Int(16), Value::Nil, Value::new_str("done")].into()
+    ))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    tcp_sockets_talk,
+    "
+    var server = net.listen(\"127.0.0.1:0\");
+    var client = net.connect(server.addr);
+    var connection = server.accept();
+    client.send(\"ping\");
+    var received = connection.recv();
+    connection.send(\"pong\");
+    connection.close();
+    (received, client.recv(), client.recv())
+    ",
+    Ok(Value::Tuple(
+        vec![Value::new_str("ping"), Value::new_str("pong"), Value::Nil].into()
+    ))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    udp_sockets_talk,
+    "
+    var a = net.bind(\"127.0.0.1:0\");
+    var b = net.bind(\"127.0.0.1:0\");
+    a.send(\"hello\", b.addr);
+    var datagram = b.recv();
+    (datagram.data, datagram.from == a.addr)
+    ",
+    Ok(Value::Tuple(vec![Value::new_str("hello"), Value::Bool(true)].into()))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    programs_run_to_completion,
+    "
+    var result = proc.run(\"sh\", {\"-c\", \"echo out; echo err >&2; exit 3\"});
+    (result.status, result.stdout, result.stderr)
+    ",
+    Ok(Value::Tuple(
+        vec![Value::Int(3), Value::new_str("out\n"), Value::new_str("err\n")].into()
+    ))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    spawned_programs_stream,
+    "
+    var cat = proc.spawn(\"cat\", {});
+    cat.send(\"abc\");
+    cat.close();
+    (cat.recv(), cat.recv(), cat.wait())
+    ",
+    Ok(Value::Tuple(
+        vec![Value::new_str("abc"), Value::Nil, Value::Int(0)].into()
+    ))
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn sandboxed_natives_fail() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+
+    for (source, native) in &[
+        ("proc.run(\"true\", {})", "proc.run"),
+        ("net.connect(\"127.0.0.1:1\")", "net.connect"),
+        ("_G.x = 1;", "_G"),
+        ("rawset(_G, \"x\", 1)", "_G"),
+    ] {
+        let compiled = compile(source);
+        let mut vm = Vm::new();
+        vm.set_sandboxed(true);
+        assert_eq!(
+            vm.run(&compiled).map_err(RuntimeError::into_cause),
+            Err(RuntimeError::Sandboxed(native.to_string()))
+        );
+    }
+}
+
+unit_test! {
+    globals_table_reads_and_sets_the_globals,
+    "
+    var y = 1;
+    _G[\"answer\"] = 42;
+    _G.y = y + 1;
+    var found = 0;
+    for pair in pairs(_G) do
+        if pair.0 == \"answer\" or pair.0 == \"y\" then found = found + 1; end
+    end
+    (_G.answer, _G.missing, y, found)
+    ",
+    Ok(Value::Tuple(
+        vec![Value::Int(42), Value::Nil, Value::Int(2), Value::Int(2)].into()
+    ))
+}
+
+unit_test! {
+    numeric_functions,
+    "
+    let sum = fn(n)
+        let total = 0;
+        let i = 0;
+        while i < n then
+            if i % 3 == 0 then total = total + i; end
+            i = i + 1;
+        end;
+        total
+    end;
+    let mean = fn(a, b) (a + b) / 2 end;
+    let sign = fn(x)
+        if x < 0.0 then return -1; end
+        if x == 0.0 then return 0; end
+        1
+    end;
+    let between = fn(x, low, high) not (x < low) and not (x > high) end;
+    (sum(10), mean(1, 2), mean(1.5, 2), sign(-2.5), sign(0.0), between(3, 1, 5), 1 == 1.0)
+    ",
+    Ok(Value::Tuple(
+        vec![
+            Value::Int(18),
+            Value::Number(1.5),
+            Value::Number(1.75),
+            Value::Int(-1),
+            Value::Int(0),
+            Value::Bool(true),
+            Value::Bool(false)
+        ]
+        .into()
+    ))
+}
+
+#[cfg(feature = "std")]
+unit_test! {
+    functions_cannot_be_sent_to_workers,
+    "
+    var squares = worker.spawn(\"src/vm/tests/worker.flux\");
+    squares.send(fn() end);
+    ",
+    Err(RuntimeError::Native("functions can't be sent to a worker".to_owned()).into())
+}
+
+#[test]
+fn undefined_global_suggests_name() {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+    use crate::vm::Vm;
+
+    // Without the analyzer the name is only found missing at runtime
+    let ast = Parser::new("var counter = 1; print(countr);")
+        .parse_syntax()
+        .unwrap();
+    let compiled = Compiler::compile(SourceFile {
+        ast,
+        metadata: MetaData::default(),
+    })
+    .unwrap();
+    let error = Vm::new().run(&compiled).unwrap_err();
+    assert_eq!(
+        error.cause(),
+        &RuntimeError::UndefinedVariable {
+            name: "countr".to_owned(),
+            suggestion: Some("counter".to_owned())
+        }
+    );
+}
+
 #[test]
 fn divide_by_zero() {
-    use crate::util::eval;
+    use crate::macros::eval;
+
+    for source in &["5 / 0", "5 / 0.0", "5.0 / 0", "5.0 / 0.0"] {
+        assert_eq!(
+            eval(source).map_err(FluxError::without_location),
+            Err(RuntimeError::DivideByZero.into())
+        );
+    }
+}
 
-    assert_eq!(eval("5 / 0", ""), Err(RuntimeError::DivideByZero.into()));
-    assert_eq!(eval("5 / 0.0", ""), Err(RuntimeError::DivideByZero.into()));
-    assert_eq!(eval("5.0 / 0", ""), Err(RuntimeError::DivideByZero.into()));
+#[test]
+fn errors_know_their_instruction() {
+    use crate::compiler::{BinaryInstr, Instruction};
+    use crate::macros::eval;
+
+    // The module body is frame 1, the function frame 2
+    let source = "
+    let half = fn(x) return x / 0; end;
+    half(4);
+    ";
     assert_eq!(
-        eval("5.0 / 0.0", ""),
-        Err(RuntimeError::DivideByZero.into())
+        eval(source),
+        Err(RuntimeError::At {
+            pc: 2,
+            instr: Instruction::Bin(BinaryInstr::Div),
+            frame: 2,
+            source: Box::new(RuntimeError::DivideByZero),
+        }
+        .into())
     );
+
+    // Errors of natives are at the call, the ones of functions they call keep theirs
+    match eval("let t = {1, 2}; for_each(t, fn(x) x.y; end);") {
+        Err(FluxError::Runtime(error)) => match *error {
+            RuntimeError::At { instr, frame, .. } => {
+                assert!(matches!(instr, Instruction::GetFieldImm { .. }));
+                assert_eq!(frame, 2);
+            }
+            error => panic!("{:?}", error),
+        },
+        result => panic!("{:?}", result),
+    }
 }
 
 macro_rules! test_file {
     ($test_name: ident, $name:expr, $expected:expr) => {
+        #[cfg(feature = "std")]
         #[test]
         fn $test_name() {
             use crate::util::run_file;
@@ -393,7 +1388,7 @@ macro_rules! test_file {
                 pathbuf.set_extension("flux");
                 pathbuf
             };
-            let value = run_file(path.to_str().unwrap());
+            let value = run_file(path.to_str().unwrap(), &[]);
 
             assert_eq!(value, $expected);
         }
@@ -411,3 +1406,437 @@ test_file! {
     "import_closure",
     Ok(Value::Int(10))
 }
+
+#[test]
+fn profile_counts_calls_and_instructions() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+
+    let source = "
+    let fib = fn(n)
+        return if n <= 1 then n else fib(n - 1) + fib(n - 2) end;
+    end;
+    let square = fn(x) x * x end;
+    return fib(10) + square(2);
+    ";
+    let compiled = compile(source);
+    let (value, profile) = Vm::new().run_profiled(&compiled).unwrap();
+    assert_eq!(value, Value::Int(59));
+
+    let calls: Vec<(u64, Option<usize>)> = ["main", "fn #0", "fn #1"]
+        .iter()
+        .map(|name| {
+            let function = profile.function(name).unwrap();
+            (function.calls, function.line)
+        })
+        .collect();
+    assert_eq!(calls, vec![(1, None), (177, Some(3)), (1, Some(5))]);
+    assert!(profile.function("fn #0").unwrap().instructions > 177);
+    assert!(profile.to_string().contains("fn #0 (line 3)"));
+
+    // 'x * x' runs as one superinstruction, 'n <= 1' and 'n - 1' as another
+    let dispatches = |name: &str| {
+        profile
+            .dispatches
+            .iter()
+            .find(|(opcode, _)| opcode == name)
+            .map(|(_, count)| *count)
+    };
+    assert_eq!(dispatches("BinLocals"), Some(1));
+    assert_eq!(dispatches("BinLocalInt"), Some(177 + 2 * 88));
+    let total: u64 = profile.dispatches.iter().map(|(_, count)| count).sum();
+    assert_eq!(total, profile.instructions());
+}
+
+#[test]
+fn coverage_records_executed_lines() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+
+    let source = "
+    let abs = fn(x)
+        if x < 0 then
+            return -x;
+        end
+        return x;
+    end;
+    let unused = fn() 0 end;
+    return abs(3) + abs(4);
+    ";
+    let compiled = compile(source);
+    let (value, coverage) = Vm::new().run_with_coverage(&compiled).unwrap();
+    assert_eq!(value, Value::Int(7));
+
+    let hits: Vec<Option<u64>> = (1..=9).map(|line| coverage.hits(line)).collect();
+    assert_eq!(
+        hits,
+        vec![
+            None,
+            Some(1),
+            Some(2),
+            Some(0),
+            None,
+            Some(2),
+            None,
+            Some(1),
+            Some(1)
+        ]
+    );
+    let lcov = coverage.to_lcov("abs.flux");
+    assert!(lcov.starts_with("TN:\nSF:abs.flux\nDA:2,1\n"), "{}", lcov);
+    assert!(lcov.ends_with("LF:6\nLH:5\nend_of_record\n"), "{}", lcov);
+}
+
+#[test]
+fn bin_is_quickened_and_deoptimized() {
+    use crate::compiler::{BinaryInstr, Instruction};
+    use crate::macros::compile;
+    use crate::vm::Vm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let source = "
+    let add = fn(t) t.a + t.b end;
+    let sum = 0;
+    let i = 0;
+    while i < 20 then
+        sum = sum + add({ \"a\" = i, \"b\" = 1 });
+        i = i + 1;
+    end
+    (sum, add({ \"a\" = \"x\", \"b\" = \"y\" }), add({ \"a\" = 1, \"b\" = 2 }))
+    ";
+    let compiled = compile(source);
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new();
+    let adds = Rc::clone(&trace);
+    vm.set_trace(move |_, instr, _| {
+        if instr.bin_op() == Some(BinaryInstr::Add) {
+            adds.borrow_mut().push(*instr)
+        }
+    });
+    assert_eq!(
+        vm.run(&compiled),
+        Ok(Value::Tuple(
+            vec![Value::Int(210), Value::new_str("xy"), Value::Int(3)].into()
+        ))
+    );
+    // The function's add and the loop's 'sum + ...' became AddInt
+    let trace = trace.borrow();
+    assert!(trace.iter().filter(|i| **i == Instruction::AddInt).count() > 20);
+    // The strings ran the quickened add, which turned back into a Bin for the last call
+    assert_eq!(
+        trace[trace.len() - 2..],
+        [Instruction::AddInt, Instruction::Bin(BinaryInstr::Add)]
+    );
+}
+
+#[test]
+fn trace_sees_every_instruction() {
+    use crate::compiler::Instruction;
+    use crate::macros::compile;
+    use crate::vm::Vm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let compiled = compile("return 1 + 2;");
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = Vm::new();
+    let lines = Rc::clone(&trace);
+    vm.set_trace(move |frame, instr, stack| {
+        lines.borrow_mut().push((frame.pc(), *instr, stack.len()))
+    });
+    assert_eq!(vm.run(&compiled), Ok(Value::Int(3)));
+
+    let trace = trace.borrow();
+    let add = trace
+        .iter()
+        .find(|(_, instr, _)| *instr == Instruction::AddInt)
+        .unwrap();
+    assert_eq!(add.2, 2);
+    assert_eq!(
+        trace.last().unwrap().1,
+        Instruction::Return { return_value: true }
+    );
+}
+
+#[test]
+fn debugger_pauses_and_steps() {
+    use crate::macros::compile;
+    use crate::vm::{Resume, RunState, Vm};
+
+    let source = "
+    let square = fn(x)
+        let y = x * x;
+        return y;
+    end;
+    let a = 3;
+    let b = square(a);
+    return b + 1;
+    ";
+    let compiled = compile(source);
+    let mut vm = Vm::new();
+    vm.start(&compiled);
+    vm.set_breakpoint("", 4);
+
+    assert_eq!(vm.continue_run(), Ok(RunState::Paused { line: 4 }));
+    let call_stack = vm.call_stack();
+    let names: Vec<&str> = call_stack.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["square", "main"]);
+    assert_eq!(
+        call_stack[0].locals,
+        vec![
+            ("x".to_owned(), Value::Int(3)),
+            ("y".to_owned(), Value::Int(9))
+        ]
+    );
+    assert_eq!(call_stack[1].line, Some(7));
+    assert_eq!(call_stack[1].locals[1], ("a".to_owned(), Value::Int(3)));
+
+    assert_eq!(vm.resume(Resume::StepOut), Ok(RunState::Paused { line: 8 }));
+    assert_eq!(
+        vm.call_stack()[0].locals.last(),
+        Some(&("b".to_owned(), Value::Int(9)))
+    );
+    assert_eq!(
+        vm.resume(Resume::StepOver),
+        Ok(RunState::Finished(Value::Int(10)))
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn breakpoints_in_imported_files() {
+    use crate::util::load_file;
+    use crate::vm::{RunState, Vm};
+    use std::fs::canonicalize;
+    use std::path::PathBuf;
+
+    let mut path = canonicalize(PathBuf::from(file!())).unwrap();
+    path.pop();
+    path.push("tests");
+    path.push("import.flux");
+    let compiled = load_file(path.to_str().unwrap()).unwrap();
+    let mut vm = Vm::new();
+    vm.start(&compiled);
+    vm.set_breakpoint("tests/module.flux", 4);
+
+    assert_eq!(vm.continue_run(), Ok(RunState::Paused { line: 4 }));
+    let frame = vm.inspect_frame(0).unwrap();
+    assert!(frame.file.unwrap().ends_with("module.flux"));
+    assert_eq!(frame.locals, vec![("x".to_owned(), Value::Int(5))]);
+    let caller = vm.inspect_frame(1).unwrap();
+    assert_eq!((caller.name.as_str(), caller.line), ("main", Some(5)));
+    assert_eq!(vm.inspect_frame(2), None);
+
+    vm.clear_breakpoints("tests/module.flux");
+    // The call was the last statement of the module
+    assert_eq!(vm.step(), Ok(RunState::Finished(Value::Int(25))));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn runs_a_source_again() {
+    use crate::util::load_file;
+    use crate::vm::Vm;
+    use std::fs::canonicalize;
+    use std::path::PathBuf;
+
+    let mut path = canonicalize(PathBuf::from(file!())).unwrap();
+    path.pop();
+    path.push("tests");
+    path.push("import.flux");
+    let compiled = load_file(path.to_str().unwrap()).unwrap();
+    let mut vm = Vm::new();
+    assert_eq!(vm.run(&compiled), Ok(Value::Int(25)));
+    // Imports are shared too, not taken out of the source
+    assert_eq!(vm.run(&compiled), Ok(Value::Int(25)));
+    assert_eq!(Vm::new().run(&compiled), Ok(Value::Int(25)));
+}
+
+#[test]
+fn stats_count_live_values() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+
+    let run = |source: &str| {
+        let compiled = compile(source);
+        let mut vm = Vm::new();
+        vm.run(&compiled).unwrap();
+        vm.stats()
+    };
+    let empty = run("");
+    let source = "
+    let depth = fn(n)
+        return if n == 0 then 0 else depth(n - 1) + 1 end;
+    end;
+    var shared = {};
+    var t = {shared, shared, {}};
+    var f = fn(x) x end;
+    var d = depth(20);
+    ";
+    let stats = run(source);
+    assert_eq!(stats.tables - empty.tables, 3);
+    // The names of the globals
+    assert_eq!(stats.strings - empty.strings, 4);
+    assert_eq!(stats.functions - empty.functions, 1);
+    assert!(stats.peak_frames > 20);
+    assert!(stats.instructions > empty.instructions + 20 * 5);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn eval_to_string_captures_output_and_errors() {
+    use crate::util::eval_to_string;
+
+    assert_eq!(eval_to_string("print(1 + 2); print(\"a\");"), "3a");
+    let text = eval_to_string("println(\"before\"); 1 / 0;");
+    assert!(text.starts_with("before \n"));
+    assert!(text.contains("error"));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn repl_inputs_use_earlier_globals() {
+    use crate::session::Session;
+    use crate::util::eval_repl;
+    use crate::vm::Vm;
+
+    let mut vm = Vm::new();
+    let mut session = Session::new();
+    for input in ["var g = {\"a\" = 1};", "var h = g.a + 1;"] {
+        eval_repl(&mut vm, input).unwrap();
+        session.record(&vm, input);
+    }
+    assert_eq!(vm.global("h"), Some(&Value::Int(2)));
+
+    // A saved session loaded into a new repl
+    let saved = session.save(&vm);
+    let mut restored = Vm::new();
+    eval_repl(&mut restored, &saved).unwrap();
+    eval_repl(&mut restored, "var sum = g.a + h;").unwrap();
+    assert_eq!(restored.global("sum"), Some(&Value::Int(3)));
+}
+
+#[test]
+fn host_natives_get_their_arguments_in_order() {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::sourcefile::{MetaData, SourceFile};
+    use crate::vm::{ArgsLen, Vm};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let compile = |vm: &Vm, source: &str| {
+        let ast = Parser::new(source)
+            .with_globals(vm.global_names())
+            .parse_all()
+            .unwrap();
+        Compiler::compile(SourceFile {
+            ast,
+            metadata: MetaData::default(),
+        })
+        .unwrap()
+    };
+    let mut vm = Vm::new();
+    vm.register_native("sub", ArgsLen::Exact(2), |_, args| {
+        match (&args[0], &args[1]) {
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a - b)),
+            _ => Err(RuntimeError::TypeError),
+        }
+    });
+    let calls = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&calls);
+    vm.register_native("count", ArgsLen::Exact(0), move |_, _| {
+        counter.set(counter.get() + 1);
+        Ok(Value::Int(counter.get()))
+    });
+    let compiled = compile(&vm, "sub(10, 3) + sub(2, 1) + count() + count()");
+    assert_eq!(vm.run(&compiled), Ok(Value::Int(7 + 1 + 1 + 2)));
+    assert_eq!(calls.get(), 2);
+
+    vm.register_native("fail", ArgsLen::Variadic, |_, _| {
+        Err(RuntimeError::Native("no".to_owned()))
+    });
+    let compiled = compile(&vm, "fail()");
+    let error = vm.run(&compiled).unwrap_err();
+    assert_eq!(error.cause(), &RuntimeError::Native("no".to_owned()));
+}
+
+// Counts the allocations of the tests for the memory limit
+#[global_allocator]
+static ALLOCATOR: crate::vm::TrackingAllocator<std::alloc::System> =
+    crate::vm::TrackingAllocator(std::alloc::System);
+
+// Without std the counter is shared by the threads running the other tests
+#[cfg(feature = "std")]
+#[test]
+fn memory_limit_stops_the_script() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+
+    let source = "
+    var t = {};
+    var i = 0;
+    while i < 20000 then
+        t[i] = {i, i};
+        i = i + 1;
+    end;
+    i
+    ";
+    let compiled = compile(source);
+
+    let mut vm = Vm::new();
+    assert_eq!(vm.run(&compiled), Ok(Value::Int(20000)));
+    let used = vm.stats().peak_memory;
+    assert!(used > 20000 * 8, "{}", used);
+
+    let mut vm = Vm::new();
+    assert!(crate::vm::is_tracking());
+    assert_eq!(vm.set_memory_limit(Some(used / 2)), Ok(()));
+    let error = vm.run(&compiled).unwrap_err();
+    assert_eq!(
+        error.cause(),
+        &RuntimeError::OutOfMemory { limit: used / 2 }
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn timeout_stops_the_script() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+    use std::time::{Duration, Instant};
+
+    // Busy and sleeping scripts alike
+    for source in &[
+        "var i = 0; while true then i = i + 1; end; i",
+        "sleep(10000); 0",
+    ] {
+        let compiled = compile(source);
+        let limit = Duration::from_millis(50);
+        let start = Instant::now();
+        let error = Vm::new().run_with_timeout(&compiled, limit).unwrap_err();
+        assert_eq!(error.cause(), &RuntimeError::Timeout { limit });
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn vm_runs_again_after_a_timeout() {
+    use crate::macros::compile;
+    use crate::vm::Vm;
+    use std::time::Duration;
+
+    let mut vm = Vm::new();
+    for source in &[
+        "let spin = fn(n) let i = 0; while true then i = i + n; end end; spin(1)",
+        "spawn(fn() sleep(10000) end); sleep(10000); 0",
+    ] {
+        let limit = Duration::from_millis(50);
+        let error = vm.run_with_timeout(&compile(source), limit).unwrap_err();
+        assert_eq!(error.cause(), &RuntimeError::Timeout { limit });
+        let compiled = compile("let a = 2; let f = fn(x) x * a end; f(21)");
+        assert_eq!(vm.run(&compiled), Ok(Value::Int(42)));
+    }
+}
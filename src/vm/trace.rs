@@ -0,0 +1,48 @@
+use super::{Frame, Value};
+use crate::compiler::Instruction;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+// Called before each instruction with the current frame and the whole stack
+pub type TraceFn = dyn FnMut(&Frame, &Instruction, &[Value]);
+
+// Shared with the vms running imported modules
+#[derive(Clone)]
+pub(super) struct Trace(Rc<RefCell<Box<TraceFn>>>);
+
+impl Trace {
+    pub fn new(trace: Box<TraceFn>) -> Self {
+        Trace(Rc::new(RefCell::new(trace)))
+    }
+
+    #[inline]
+    pub fn call(&self, frame: &Frame, instr: &Instruction, stack: &[Value]) {
+        (self.0.borrow_mut())(frame, instr, stack)
+    }
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Trace")
+    }
+}
+
+impl PartialEq for Trace {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(feature = "std")]
+// Trace writing one line per instruction: the pc, the instruction and the stack depth
+pub fn trace_writer<W: Write + 'static>(mut out: W) -> impl FnMut(&Frame, &Instruction, &[Value]) {
+    move |frame, instr, stack| {
+        let instr = format!("{:?}", instr);
+        // Tracing is best effort, a closed sink does not stop the script
+        let _ = writeln!(out, "{:04} {:<40} {}", frame.pc(), instr, stack.len());
+    }
+}
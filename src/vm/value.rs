@@ -1,14 +1,23 @@
 use crate::vm::{RuntimeError, RuntimeResult};
-use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::fmt::{self, Display, Formatter};
-use std::hash::{Hash, Hasher};
-use std::rc::Rc;
+use alloc::rc::Rc;
+use alloc::string::String;
+use core::cell::RefCell;
+use core::fmt::{self, Display, Formatter};
+use core::hash::{Hash, Hasher};
 
-pub use function::{ArgsLen, FuncProtoRef, Function, NativeFunction, UserFunction};
+pub use function::{
+    ArgsLen, FuncProtoRef, Function, HostFn, NativeFn, NativeFunction, UserFunction,
+};
+pub use fx_hash::FxHashMap;
+pub use inspect::{Inspect, DISPLAY_DEPTH};
+pub use string::HashedStr;
 pub use table::{Table, TableRef};
 
+mod cast;
 mod function;
+mod fx_hash;
+mod inspect;
+mod string;
 mod table;
 
 pub type Integer = i64;
@@ -20,22 +29,23 @@ pub enum Value {
     Bool(bool),
     Int(Integer),
     Number(Float),
-    Str(Rc<String>),
+    Str(Rc<HashedStr>),
     Embedded(&'static str),
     Table(TableRef),
-    Tuple(Vec<Value>),
+    // Tuples are never changed in place, copies share the values
+    Tuple(Rc<[Value]>),
     Function(Function),
     Unit,
 }
 
 impl Value {
     pub fn new_str(string: impl Into<String>) -> Self {
-        Value::Str(Rc::new(string.into()))
+        Value::Str(Rc::new(HashedStr::new(string.into())))
     }
 
     pub fn as_str(&self) -> RuntimeResult<&str> {
         match self {
-            Value::Str(rc) => Ok(rc.as_ref()),
+            Value::Str(rc) => Ok(rc.as_str()),
             _ => Err(RuntimeError::TypeError),
         }
     }
@@ -129,13 +139,14 @@ impl Hash for Value {
                 4.hash(state);
                 (*d as u64).hash(state);
             }
+            // Only the cached hash of the contents, see HashedStr
             Value::Str(s) => {
                 5.hash(state);
-                (*s.as_str()).hash(state);
+                s.hash_code().hash(state);
             }
             Value::Embedded(string) => {
                 5.hash(state);
-                (*string).hash(state)
+                string::hash_str(string).hash(state)
             }
             Value::Table(t) => {
                 6.hash(state);
@@ -144,7 +155,7 @@ impl Hash for Value {
             }
             Value::Tuple(values) => {
                 7.hash(state);
-                for value in values {
+                for value in values.iter() {
                     value.hash(state)
                 }
             }
@@ -168,33 +179,9 @@ impl Display for Value {
             Value::Bool(b) => write!(f, "{}", b),
             Value::Int(i) => write!(f, "{}", i),
             Value::Number(n) => write!(f, "{}", n),
-            Value::Str(s) => {
-                let s: &String = s.borrow();
-                write!(f, "{}", s)
-            }
-            // Fix formatting
-            Value::Table(t) => {
-                let table = t.as_ref().borrow();
-                writeln!(f, "{{")?;
-                for (k, v) in table.pairs() {
-                    writeln!(f, "\t{}: {}", k, v)?;
-                }
-                writeln!(f, "}}")?;
-                Ok(())
-            }
-            Value::Tuple(values) => {
-                write!(f, "(")?;
-                write!(f, "{}", values[0])?;
-                for value in values.iter().skip(1) {
-                    write!(f, ", {}", value)?;
-                }
-                write!(f, ")")?;
-                Ok(())
-            }
-            Value::Function(function) => {
-                let is_native = if function.is_native() { "native " } else { "" };
-                write!(f, "{}fn({} args)", is_native, function.args_len())
-            }
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Table(_) | Value::Tuple(_) => write!(f, "{}", self.inspect(DISPLAY_DEPTH)),
+            Value::Function(function) => write!(f, "{}", function),
             Value::Unit => write!(f, "()"),
             Value::Embedded(string) => write!(f, "{}", string),
         }
@@ -215,7 +202,7 @@ impl From<TableRef> for Value {
 
 impl From<String> for Value {
     fn from(string: String) -> Self {
-        Value::Str(Rc::new(string))
+        Value::Str(Rc::new(HashedStr::new(string)))
     }
 }
 
@@ -246,17 +233,20 @@ impl From<Float> for Value {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
 
     // TODO
     #[test]
     fn hash_works() {
-        let mut map: HashMap<Value, ()> = HashMap::new();
+        let mut map: FxHashMap<Value, ()> = FxHashMap::default();
         // Value::Embedded == Value::Str
         let a: Value = "some literal".into();
         let b: Value = "some literal".to_string().into();
         map.insert(a, ());
         assert!(map.contains_key(&b));
+        // The cached hash of a built string matches the literal
+        let c = Value::new_str(format!("some {}", "literal"));
+        assert!(map.contains_key(&c));
+        assert!(!map.contains_key(&Value::new_str("some literals")));
         // Tables are hashed by adress
         let a: Value = Table::new().into();
         let b: Value = Table::new().into();
@@ -267,4 +257,13 @@ mod tests {
         // Value::Int == Value::Number
         //...
     }
+
+    #[test]
+    fn tuple_copies_share_values() {
+        let tuple = Value::Tuple(vec![Value::Int(1), Value::new_str("a")].into());
+        match (&tuple, &tuple.clone()) {
+            (Value::Tuple(a), Value::Tuple(b)) => assert!(Rc::ptr_eq(a, b)),
+            _ => unreachable!(),
+        }
+    }
 }
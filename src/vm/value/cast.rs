@@ -0,0 +1,103 @@
+use super::{Integer, Value};
+use crate::vm::{RuntimeError, RuntimeResult};
+use alloc::{borrow::ToOwned, string::String, string::ToString, vec::Vec};
+
+// The types of 'as' and assert_type, written like the annotations of parser::Type.
+// A type is the name of one, or a tuple of types like ("int", "str") for '(int, str)'
+
+impl Value {
+    // "int", "number", "str", "bool", "nil", "()", "table" or "fn", tuples name
+    // the types of their elements
+    pub fn type_name(&self) -> String {
+        match self {
+            Value::Nil => "nil".to_owned(),
+            Value::Bool(_) => "bool".to_owned(),
+            Value::Int(_) => "int".to_owned(),
+            Value::Number(_) => "number".to_owned(),
+            Value::Str(_) | Value::Embedded(_) => "str".to_owned(),
+            Value::Table(_) => "table".to_owned(),
+            Value::Function(_) => "fn".to_owned(),
+            Value::Unit => "()".to_owned(),
+            Value::Tuple(values) => {
+                let names: Vec<String> = values.iter().map(Value::type_name).collect();
+                format!("({})", names.join(", "))
+            }
+        }
+    }
+
+    // 'value as ty', ints and numbers are converted into each other like the int and
+    // number natives do
+    pub fn cast(self, ty: &Value) -> RuntimeResult<Value> {
+        self.conform(ty, true)
+    }
+
+    // The value itself if it has the type, an int is also a number
+    pub fn assert_type(self, ty: &Value) -> RuntimeResult<Value> {
+        self.conform(ty, false)
+    }
+
+    fn conform(self, ty: &Value, convert: bool) -> RuntimeResult<Value> {
+        let name = match ty {
+            Value::Tuple(types) => return self.conform_tuple(types, convert),
+            Value::Str(name) => name.as_str(),
+            Value::Embedded(name) => name,
+            _ => return Err(RuntimeError::TypeError),
+        };
+        let matches = match (name, &self) {
+            ("any", _) => true,
+            ("int", Value::Number(n)) if convert => return Ok(Value::Int(n.round() as Integer)),
+            ("number", Value::Int(i)) if convert => return Ok(Value::Number(*i as f64)),
+            ("number", Value::Int(_)) => true,
+            ("int" | "number" | "str" | "bool" | "nil" | "()" | "table" | "fn", value) => {
+                value.type_name() == name
+            }
+            _ => return Err(RuntimeError::UnknownType(name.to_owned())),
+        };
+        if matches {
+            Ok(self)
+        } else {
+            Err(RuntimeError::WrongType {
+                expected: name.to_owned(),
+                found: self.type_name(),
+            })
+        }
+    }
+
+    fn conform_tuple(self, types: &[Value], convert: bool) -> RuntimeResult<Value> {
+        let values = match &self {
+            Value::Tuple(values) if values.len() == types.len() => values,
+            _ => {
+                return Err(RuntimeError::WrongType {
+                    expected: type_text(types),
+                    found: self.type_name(),
+                })
+            }
+        };
+        let values = values
+            .iter()
+            .zip(types)
+            .map(|(value, ty)| value.clone().conform(ty, convert))
+            .collect::<RuntimeResult<Vec<Value>>>()
+            .map_err(|err| match err {
+                // The whole tuple is named, not the element
+                RuntimeError::WrongType { .. } => RuntimeError::WrongType {
+                    expected: type_text(types),
+                    found: self.type_name(),
+                },
+                err => err,
+            })?;
+        Ok(Value::Tuple(values.into()))
+    }
+}
+
+// '(int, str)' for the tuple of names
+fn type_text(types: &[Value]) -> String {
+    let names: Vec<String> = types
+        .iter()
+        .map(|ty| match ty {
+            Value::Tuple(types) => type_text(types),
+            ty => ty.to_string(),
+        })
+        .collect();
+    format!("({})", names.join(", "))
+}
@@ -1,9 +1,10 @@
 use super::{TableRef, Value};
 use crate::compiler::FuncProto;
 use crate::vm::{RuntimeResult, Vm};
-use std::fmt::{Debug, Display, Formatter};
-use std::hash::{Hash, Hasher};
-use std::rc::Rc;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Formatter};
+use core::hash::{Hash, Hasher};
 
 #[derive(Clone, Debug, Hash, PartialEq)]
 pub enum Function {
@@ -20,7 +21,15 @@ pub struct UserFunction {
     env: Option<TableRef>,
 }
 
-type NativeFn = fn(&mut Vm, Vec<Value>) -> RuntimeResult<Value>;
+#[derive(Clone)]
+pub enum NativeFn {
+    Builtin(fn(&mut Vm, Vec<Value>) -> RuntimeResult<Value>),
+    // Registered by the host with Vm::register_native, can hold state unlike the
+    // builtins and gets its arguments in order
+    Host(Rc<HostFn>),
+}
+
+pub type HostFn = dyn Fn(&mut Vm, Vec<Value>) -> RuntimeResult<Value>;
 
 #[derive(Clone)]
 pub struct NativeFunction {
@@ -90,6 +99,17 @@ impl UserFunction {
 }
 
 impl NativeFunction {
+    // A native running 'function', see NativeFn::Host
+    pub fn host<F>(args_len: ArgsLen, function: F) -> Self
+    where
+        F: Fn(&mut Vm, Vec<Value>) -> RuntimeResult<Value> + 'static,
+    {
+        NativeFunction {
+            function: NativeFn::Host(Rc::new(function)),
+            args_len,
+        }
+    }
+
     pub fn args_len(&self) -> ArgsLen {
         self.args_len
     }
@@ -116,25 +136,60 @@ impl Into<Value> for UserFunction {
 }
 
 impl Debug for NativeFunction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "native fn({})", self.args_len())
     }
 }
 
 impl PartialEq for NativeFunction {
     fn eq(&self, other: &NativeFunction) -> bool {
-        (self.function as *const NativeFn) == (other.function as *const NativeFn)
+        match (&self.function, &other.function) {
+            (NativeFn::Builtin(a), NativeFn::Builtin(b)) => *a as usize == *b as usize,
+            (NativeFn::Host(a), NativeFn::Host(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
     }
 }
 
 impl Hash for NativeFunction {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        (self.function as *const NativeFn).hash(state);
+        match &self.function {
+            NativeFn::Builtin(function) => (*function as usize).hash(state),
+            NativeFn::Host(function) => (Rc::as_ptr(function) as *const () as usize).hash(state),
+        }
+    }
+}
+
+// 'fn add(2 args) @ script.flux:10', with the upvalues the function captured
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        let user = match self {
+            Function::User(user) => user,
+            Function::Native(native) => return write!(f, "native fn({})", native.args_len()),
+        };
+        let debug_info = &user.proto_ref().debug_info;
+        write!(f, "fn")?;
+        if let Some(name) = debug_info.name() {
+            write!(f, " {}", name)?;
+        }
+        write!(f, "({}", ArgsLen::Exact(user.args_len()))?;
+        match user.env().map_or(0, |env| env.borrow().pairs().count()) {
+            0 => write!(f, ")")?,
+            1 => write!(f, ", 1 upvalue)")?,
+            n => write!(f, ", {} upvalues)", n)?,
+        }
+        // Sources compiled without a path have the empty name
+        let file = debug_info.file().filter(|file| !file.is_empty());
+        match (file, debug_info.start_line()) {
+            (Some(file), Some(line)) => write!(f, " @ {}:{}", file, line),
+            (None, Some(line)) => write!(f, " @ line {}", line),
+            _ => Ok(()),
+        }
     }
 }
 
 impl Display for ArgsLen {
-    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
         match self {
             ArgsLen::Variadic => write!(f, "variadic"),
             ArgsLen::Exact(n) => write!(f, "{} args", n),
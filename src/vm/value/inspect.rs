@@ -0,0 +1,97 @@
+use super::{Table, Value};
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt::{self, Display, Formatter};
+
+// Levels of nested tables Display shows, deeper ones are written '{...}'
+pub const DISPLAY_DEPTH: usize = 8;
+
+const INDENT: &str = "    ";
+
+// Writes the value with a line per table entry, indented by how deep the table
+// is. A table inside itself is written '<cycle>' instead of looping forever
+pub struct Inspect<'a> {
+    value: &'a Value,
+    depth: usize,
+}
+
+impl Value {
+    // Shows 'depth' levels of nested tables, see the inspect native
+    pub fn inspect(&self, depth: usize) -> Inspect<'_> {
+        Inspect { value: self, depth }
+    }
+}
+
+impl Display for Inspect<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            f,
+            max_depth: self.depth,
+            path: Vec::new(),
+        };
+        printer.value(self.value)
+    }
+}
+
+struct Printer<'a, 'b> {
+    f: &'a mut Formatter<'b>,
+    max_depth: usize,
+    // The tables being written, outermost first
+    path: Vec<*const RefCell<Table>>,
+}
+
+impl Printer<'_, '_> {
+    fn value(&mut self, value: &Value) -> fmt::Result {
+        match value {
+            Value::Table(table) => self.table(table),
+            Value::Tuple(values) => {
+                write!(self.f, "(")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(self.f, ", ")?;
+                    }
+                    self.value(value)?;
+                }
+                write!(self.f, ")")
+            }
+            value => write!(self.f, "{}", value),
+        }
+    }
+
+    fn table(&mut self, table: &RefCell<Table>) -> fmt::Result {
+        let ptr = table as *const RefCell<Table>;
+        if self.path.contains(&ptr) {
+            return write!(self.f, "<cycle>");
+        }
+        let table = table.borrow();
+        if table.pairs().next().is_none() {
+            return write!(self.f, "{{}}");
+        }
+        if self.path.len() >= self.max_depth {
+            return write!(self.f, "{{...}}");
+        }
+        self.path.push(ptr);
+        writeln!(self.f, "{{")?;
+        let mut pairs = table.pairs().peekable();
+        while let Some((key, value)) = pairs.next() {
+            self.indent()?;
+            self.value(key)?;
+            write!(self.f, ": ")?;
+            self.value(value)?;
+            match pairs.peek() {
+                Some(_) => writeln!(self.f, ",")?,
+                None => writeln!(self.f)?,
+            }
+        }
+        self.path.pop();
+        self.indent()?;
+        write!(self.f, "}}")
+    }
+
+    fn indent(&mut self) -> fmt::Result {
+        for _ in 0..self.path.len() {
+            write!(self.f, "{}", INDENT)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use super::fx_hash::FxHasher;
+use alloc::string::String;
+use core::cell::Cell;
+use core::fmt::{self, Display, Formatter};
+use core::hash::{Hash, Hasher};
+use core::ops::Deref;
+
+// A string that hashes its contents once, tables hash their keys on every get
+// and set and most keys are the same few field names. The hash is computed the
+// first time it's needed, strings being built up are never hashed
+#[derive(Debug, Clone)]
+pub struct HashedStr {
+    string: String,
+    hash: Cell<Option<u64>>,
+}
+
+impl HashedStr {
+    pub fn new(string: String) -> Self {
+        HashedStr {
+            string,
+            hash: Cell::new(None),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
+    pub fn hash_code(&self) -> u64 {
+        match self.hash.get() {
+            Some(hash) => hash,
+            None => {
+                let hash = hash_str(&self.string);
+                self.hash.set(Some(hash));
+                hash
+            }
+        }
+    }
+
+    pub fn push_str(&mut self, string: &str) {
+        self.string.push_str(string);
+        self.hash.set(None);
+    }
+}
+
+// The hash strings without a HashedStr (Value::Embedded) must agree with
+pub fn hash_str(string: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    string.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Deref for HashedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.string
+    }
+}
+
+impl PartialEq for HashedStr {
+    fn eq(&self, other: &HashedStr) -> bool {
+        self.string == other.string
+    }
+}
+
+impl Eq for HashedStr {}
+
+impl Display for HashedStr {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.string)
+    }
+}
+
+impl From<String> for HashedStr {
+    fn from(string: String) -> Self {
+        HashedStr::new(string)
+    }
+}
@@ -1,14 +1,25 @@
-use super::Value;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use super::{FxHashMap, Value};
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::iter;
+use core::mem;
 
 pub type TableRef = Rc<RefCell<Table>>;
 
-#[derive(Clone, Debug, PartialEq)]
+// Snapshots of snapshots read through a chain of bases, past this many the
+// entries are copied into one base again
+const MAX_DEPTH: usize = 8;
+
+#[derive(Clone, Debug, Default)]
 pub struct Table {
-    table: HashMap<Value, Value>,
-    array: Vec<(Value, Value)>,
+    table: FxHashMap<Value, Value>,
+    // Never changed after the table is made, so snapshots share it
+    array: Rc<[(Value, Value)]>,
+    // Entries frozen by a snapshot, the ones in 'table' shadow them
+    base: Option<Rc<Table>>,
+    // Sets are refused, see Table::freeze
+    frozen: bool,
 }
 
 impl Table {
@@ -24,15 +35,15 @@ impl Table {
 
     pub fn from_array(array: Vec<(Value, Value)>) -> Self {
         Table {
-            table: HashMap::new(),
-            array,
+            array: array.into(),
+            ..Table::default()
         }
     }
 
-    pub fn from_map(table: HashMap<Value, Value>) -> Self {
+    pub fn from_map(table: FxHashMap<Value, Value>) -> Self {
         Table {
             table,
-            array: vec![],
+            ..Table::default()
         }
     }
 
@@ -49,7 +60,49 @@ impl Table {
                 }
             }
         }
-        self.table.get(key).unwrap_or(&Self::NIL)
+        self.levels()
+            .find_map(|level| level.table.get(key))
+            .unwrap_or(&Self::NIL)
+    }
+
+    // A copy that shares the entries of this table. They move into a frozen base
+    // both tables read through and later sets go to their own maps, so copying a
+    // table to change a few fields doesn't copy the rest of it
+    pub fn snapshot(&mut self) -> Table {
+        if !self.table.is_empty() {
+            let frozen = if self.levels().count() < MAX_DEPTH {
+                Table {
+                    table: mem::take(&mut self.table),
+                    array: Rc::from([]),
+                    base: self.base.take(),
+                    frozen: false,
+                }
+            } else {
+                let table = self
+                    .hash_part()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                self.table = FxHashMap::default();
+                Table::from_map(table)
+            };
+            self.base = Some(Rc::new(frozen));
+        }
+        Table {
+            table: FxHashMap::default(),
+            array: self.array.clone(),
+            base: self.base.clone(),
+            frozen: false,
+        }
+    }
+
+    // Enum tables can't be changed after they are made, the vm refuses to set
+    // their fields. Copies made by snapshot can be changed again
+    pub fn freeze(&mut self) {
+        self.frozen = true
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
     }
 
     pub fn klass(&self) -> &Value {
@@ -57,21 +110,86 @@ impl Table {
     }
 
     pub fn pairs(&self) -> impl Iterator<Item = (&Value, &Value)> {
-        self.table
-            .iter()
+        self.hash_part()
             .chain(self.array.iter().map(|(v1, v2)| (v1, v2)))
     }
 
+    pub fn array_part(&self) -> &[(Value, Value)] {
+        &self.array
+    }
+
+    // The entries of every level that aren't shadowed by one above it
+    pub fn hash_part(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.levels().enumerate().flat_map(move |(depth, level)| {
+            level.table.iter().filter(move |(key, _)| {
+                !self
+                    .levels()
+                    .take(depth)
+                    .any(|above| above.table.contains_key(*key))
+            })
+        })
+    }
+
+    pub fn hash_len(&self) -> usize {
+        match self.base {
+            Some(_) => self.hash_part().count(),
+            None => self.table.len(),
+        }
+    }
+
     pub fn values(&self) -> impl Iterator<Item = &Value> {
-        self.array.iter().map(|(_, v)| v).chain(self.table.values())
+        self.array
+            .iter()
+            .map(|(_, v)| v)
+            .chain(self.hash_part().map(|(_, v)| v))
+    }
+
+    fn levels(&self) -> impl Iterator<Item = &Table> {
+        iter::successors(Some(self), |table| table.base.as_deref())
     }
 }
 
-impl Default for Table {
-    fn default() -> Self {
-        Table {
-            table: HashMap::new(),
-            array: Vec::new(),
+// Snapshots with the same entries are equal however they are stored
+impl PartialEq for Table {
+    fn eq(&self, other: &Table) -> bool {
+        self.array == other.array
+            && self.hash_len() == other.hash_len()
+            && self
+                .hash_part()
+                .all(|(key, value)| other.levels().find_map(|l| l.table.get(key)) == Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_share_entries_until_set() {
+        let mut table = Table::new();
+        table.set(Value::Embedded("a"), Value::Int(1));
+        table.set(Value::Embedded("b"), Value::Int(2));
+        let mut copy = table.snapshot();
+        assert_eq!(copy, table);
+        copy.set(Value::Embedded("a"), Value::Int(3));
+        table.set(Value::Embedded("c"), Value::Int(4));
+        assert_eq!(table.get(&Value::Embedded("a")), &Value::Int(1));
+        assert_eq!(copy.get(&Value::Embedded("a")), &Value::Int(3));
+        assert_eq!(copy.get(&Value::Embedded("b")), &Value::Int(2));
+        assert_eq!(copy.get(&Value::Embedded("c")), &Value::Nil);
+        assert_eq!(copy.hash_len(), 2);
+        assert_eq!(table.hash_len(), 3);
+    }
+
+    #[test]
+    fn deep_snapshots_are_flattened() {
+        let mut table = Table::new();
+        for i in 0..20 {
+            table.set(Value::Int(i), Value::Int(i));
+            let copy = table.snapshot();
+            assert_eq!(copy.hash_len(), i as usize + 1);
         }
+        assert!(table.levels().count() <= MAX_DEPTH + 1);
+        assert_eq!(table.get(&Value::Int(0)), &Value::Int(0));
     }
 }
@@ -0,0 +1,197 @@
+// Workers run a script on a thread of their own, in a vm of their own. The two vms
+// share nothing, the values they send each other are copied
+use super::lib::{worker_table, WORKER};
+use super::value::{ArgsLen, Function, NativeFunction, Table, TableRef};
+use super::{Float, Integer, RuntimeError, RuntimeResult, Value, Vm};
+use crate::util::load_file;
+use std::cell::RefCell;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+// A value copied out of the vm that made it, so it can move to another thread
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Nil,
+    Bool(bool),
+    Int(Integer),
+    Number(Float),
+    Str(String),
+    Table(Vec<(Message, Message)>),
+    Tuple(Vec<Message>),
+    Unit,
+}
+
+impl Message {
+    // Functions can't be copied, nor tables containing themselves
+    pub fn from_value(value: &Value) -> RuntimeResult<Message> {
+        Self::copy(value, &mut Vec::new())
+    }
+
+    // 'tables' are the ones being copied, 'value' is inside all of them
+    fn copy(value: &Value, tables: &mut Vec<TableRef>) -> RuntimeResult<Message> {
+        Ok(match value {
+            Value::Nil => Message::Nil,
+            Value::Bool(b) => Message::Bool(*b),
+            Value::Int(i) => Message::Int(*i),
+            Value::Number(n) => Message::Number(*n),
+            Value::Str(string) => Message::Str(string.as_str().to_owned()),
+            Value::Embedded(string) => Message::Str((*string).to_owned()),
+            Value::Table(table) => {
+                if tables.iter().any(|outer| outer.as_ptr() == table.as_ptr()) {
+                    return Err(RuntimeError::Native(
+                        "a table containing itself can't be sent to a worker".to_owned(),
+                    ));
+                }
+                tables.push(table.clone());
+                let pairs = table
+                    .borrow()
+                    .pairs()
+                    .map(|(key, value)| Ok((Self::copy(key, tables)?, Self::copy(value, tables)?)))
+                    .collect::<RuntimeResult<_>>()?;
+                tables.pop();
+                Message::Table(pairs)
+            }
+            Value::Tuple(values) => Message::Tuple(
+                values
+                    .iter()
+                    .map(|value| Self::copy(value, tables))
+                    .collect::<RuntimeResult<_>>()?,
+            ),
+            Value::Function(_) => {
+                return Err(RuntimeError::Native(
+                    "functions can't be sent to a worker".to_owned(),
+                ))
+            }
+            Value::Unit => Message::Unit,
+        })
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            Message::Nil => Value::Nil,
+            Message::Bool(b) => Value::Bool(b),
+            Message::Int(i) => Value::Int(i),
+            Message::Number(n) => Value::Number(n),
+            Message::Str(string) => Value::new_str(string),
+            Message::Table(pairs) => Table::from_map(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.into_value(), value.into_value()))
+                    .collect(),
+            )
+            .into(),
+            Message::Tuple(values) => {
+                Value::Tuple(values.into_iter().map(Message::into_value).collect())
+            }
+            Message::Unit => Value::Unit,
+        }
+    }
+}
+
+// 'send(value)' and 'receive()' talking to the other side. Receiving waits for a
+// value, blocking the thread, and returns nil once the other side finished
+fn endpoint(sender: Sender<Message>, receiver: Receiver<Message>) -> Table {
+    let send = NativeFunction::host(ArgsLen::Exact(1), move |_, args| {
+        // The other side finished, nobody would receive it
+        let _ = sender.send(Message::from_value(&args[0])?);
+        Ok(Value::Unit)
+    });
+    let receive = NativeFunction::host(ArgsLen::Exact(0), move |_, _| {
+        Ok(receiver.recv().map_or(Value::Nil, Message::into_value))
+    });
+    let mut table = Table::new();
+    table.set(
+        Value::Embedded("send"),
+        Value::Function(Function::Native(send)),
+    );
+    table.set(
+        Value::Embedded("receive"),
+        Value::Function(Function::Native(receive)),
+    );
+    table
+}
+
+// Starts running the script at 'path' on a new thread, sandboxed like the vm
+// spawning it. Its 'worker.parent' talks to the table returned, which also has
+// 'join()' to wait for the value of the script
+pub(super) fn spawn(path: &str, sandboxed: bool) -> Table {
+    let (to_worker, from_parent) = mpsc::channel();
+    let (to_parent, from_worker) = mpsc::channel();
+    let owned_path = path.to_owned();
+    let handle = thread::spawn(move || {
+        let parent = endpoint(to_parent, from_parent);
+        run(&owned_path, parent, sandboxed)
+    });
+
+    let path = path.to_owned();
+    let handle: RefCell<Option<JoinHandle<_>>> = RefCell::new(Some(handle));
+    let join = NativeFunction::host(ArgsLen::Exact(0), move |_, _| {
+        let handle = handle
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| RuntimeError::Native(format!("worker '{}' was joined already", path)))?;
+        match handle.join() {
+            Ok(Ok(message)) => Ok(message.into_value()),
+            Ok(Err(error)) => Err(RuntimeError::Native(format!(
+                "worker '{}' failed: {}",
+                path, error
+            ))),
+            Err(_) => Err(RuntimeError::Native(format!("worker '{}' panicked", path))),
+        }
+    });
+    let mut table = endpoint(to_worker, from_worker);
+    table.set(
+        Value::Embedded("join"),
+        Value::Function(Function::Native(join)),
+    );
+    table
+}
+
+fn run(path: &str, parent: Table, sandboxed: bool) -> Result<Message, String> {
+    let compiled = load_file(path).map_err(|error| error.to_string())?;
+    let mut vm = Vm::new();
+    vm.set_sandboxed(sandboxed);
+    vm.set_global(WORKER, worker_table(Some(parent)).into());
+    let value = vm.run(&compiled).map_err(|error| format!("{:?}", error))?;
+    Message::from_value(&value).map_err(|error| format!("{:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_copy_tables() {
+        let inner: Value = Table::from_array(vec![(Value::Int(0), Value::Bool(true))]).into();
+        let mut outer = Table::new();
+        outer.set(Value::Embedded("inner"), inner.clone());
+        outer.set(
+            Value::new_str("pair"),
+            Value::Tuple(vec![Value::Int(1), Value::Unit].into()),
+        );
+        let message = Message::from_value(&outer.into()).unwrap();
+
+        let copy = message.into_value().into_table().unwrap();
+        let copy = copy.borrow();
+        assert_eq!(
+            copy.get(&Value::Embedded("pair")),
+            &Value::Tuple(vec![Value::Int(1), Value::Unit].into())
+        );
+        let inner_copy = copy.get(&Value::Embedded("inner")).clone();
+        assert_ne!(inner_copy, inner);
+        assert_eq!(
+            inner_copy
+                .into_table()
+                .unwrap()
+                .borrow()
+                .get(&Value::Int(0)),
+            &Value::Bool(true)
+        );
+
+        let cyclic = inner.clone().into_table().unwrap();
+        cyclic
+            .borrow_mut()
+            .set(Value::Embedded("self"), inner.clone());
+        assert!(Message::from_value(&inner).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+// The wasm-bindgen wrapper for in-browser playgrounds. Build the module with
+// `cargo rustc --release --lib --target wasm32-unknown-unknown --no-default-features
+// --features wasm --crate-type cdylib` and generate the JavaScript glue with
+// `wasm-bindgen --target web` on the .wasm file
+use wasm_bindgen::prelude::*;
+
+// What the source printed followed by the rendered error, see util::eval_to_string
+#[wasm_bindgen]
+pub fn eval(source: &str) -> String {
+    crate::util::eval_to_string(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_returns_the_output() {
+        assert_eq!(eval("print(1 + 2);"), "3");
+        assert!(eval("1 / 0;").contains("error"));
+    }
+}